@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+use sherry_demon::event::file_event::{FileType, intern_path, intern_str, share_pair, SyncEvent, SyncEventKind};
+use sherry_demon::event::optimizer::optimize_events;
+use sherry_demon::helpers::PATH_SEP;
+
+// A batch like the initial drop of a big folder: every file is created, most are written a few more times,
+// some are renamed and some removed again
+fn build_batch(count: usize) -> Vec<SyncEvent> {
+    let base = intern_path(Path::new("/bench"));
+    let event = |kind: SyncEventKind, old_sync_path: String, sync_path: String, timestamp: usize| {
+        let (local_path, old_local_path) = share_pair(base.join(&sync_path).into(), base.join(&old_sync_path).into());
+        let (sync_path, old_sync_path) = share_pair(sync_path.into(), old_sync_path.into());
+        SyncEvent {
+            source_id: intern_str("bench"),
+            base: base.clone(),
+            file_type: FileType::File,
+            kind,
+            local_path,
+            old_local_path,
+            sync_path,
+            old_sync_path,
+            update_hash: "".to_string(),
+            size: 0,
+            timestamp: timestamp as i128,
+            attributes: None,
+        }
+    };
+    let files = count / 4 + 1;
+    let mut events = Vec::with_capacity(count);
+    for round in 0..4 {
+        for file in 0..files {
+            if events.len() == count {
+                return events;
+            }
+            let path = format!("dir{}{}file{}.bin", file % 100, PATH_SEP, file);
+            let timestamp = round * files + file;
+            events.push(match (round, file % 10) {
+                (0, _) => event(SyncEventKind::Created, path.clone(), path, timestamp),
+                (3, 0) => event(SyncEventKind::Moved, path.clone(), format!("{}.renamed", path), timestamp),
+                (3, 1) => event(SyncEventKind::Deleted, path.clone(), path, timestamp),
+                _ => event(SyncEventKind::Updated, path.clone(), path, timestamp),
+            });
+        }
+    }
+    events
+}
+
+fn bench_optimize_events(c: &mut Criterion) {
+    let mut group = c.benchmark_group("optimize_events");
+    group.sample_size(10);
+    for count in [1000, 10000, 100000] {
+        let events = build_batch(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &events, |b, events| b.iter(|| optimize_events(events)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_optimize_events);
+criterion_main!(benches);
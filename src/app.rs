@@ -3,14 +3,35 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use notify::{RecommendedWatcher, Watcher};
-use notify_debouncer_full::{DebounceEventResult, new_debouncer};
+use notify::Watcher;
+use notify_debouncer_full::DebounceEventResult;
 use tokio::sync::Mutex;
-
-use crate::config::{SherryConfig, SherryConfigJSON, SherryConfigWatcherJSON};
-use crate::event::event_processing::{BasedDebounceEvent, EventProcessingDebounce};
-use crate::logs::initialize_logs;
+use tokio::time::Instant;
+
+use crate::auth::start_token_refresh;
+use crate::container::start_container_setup;
+use crate::config::{read_logs_dir, SherryConfig, SherryConfigJSON, SherryConfigWatcherJSON};
+use crate::constants::SWITCH_DRAIN_TIMEOUT;
+use crate::drift::start_drift_repair;
+use crate::event::event_processing::{BasedDebounceEvent, EventProcessingDebounce, get_event_queue_stats};
+use crate::event::journal::replay_journal;
+use crate::event::retry_queue::start_retry_queue;
+use crate::features::start_feature_refresh;
+use crate::governor::start_load_governor;
+use crate::fs_watcher::{new_sherry_debouncer, set_polling, SherryWatcher};
+use crate::health::start_health;
+use crate::helpers::str_err_prefix;
+use crate::ipc::listener::start_ipc;
+use crate::logs::{initialize_logs, is_silent};
+use crate::ownership::start_owner_heartbeat;
+use crate::quiesce::{is_quiesced, start_quiesce_resume};
+use crate::reconcile::start_reconcile_scan;
+use crate::self_writes::is_self_write;
+use crate::server::scheduler::get_transfer_stats;
 use crate::server::socket::SocketClient;
+use crate::standby::{claim_primary, start_primary_heartbeat, wait_for_takeover};
+use crate::startup::{begin_startup, record_phase};
+use crate::watchers::{start_fetched_changes, start_storage_polling};
 
 fn get_source_by_path<'a>(config: &'a SherryConfigJSON, path: &PathBuf) -> Option<&'a SherryConfigWatcherJSON> {
     config.watchers.iter().find_map(|w| {
@@ -21,37 +42,87 @@ fn get_source_by_path<'a>(config: &'a SherryConfigJSON, path: &PathBuf) -> Optio
     })
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct AppOptions {
+    pub silent: bool,
+    pub container: bool,
+    pub polling: bool,
+    pub standby: bool,
+}
+
 #[derive(Clone)]
 pub struct App {
     pub config: Arc<Mutex<SherryConfig>>,
     pub socket: Arc<Mutex<SocketClient>>,
+    pub options: AppOptions,
 }
 
 impl App {
-    pub async fn new(config_dir: &PathBuf, silent: bool) -> Result<App, ()> {
-        initialize_logs(config_dir, silent);
+    pub async fn new(config_dir: &PathBuf, options: &AppOptions) -> Result<App, ()> {
+        set_polling(options.polling);
+        initialize_logs(&read_logs_dir(config_dir).await, options.silent, options.container);
 
         log::info!("Using configuration from: {:?}", config_dir);
-        log::info!("Using recommended watcher: {:?}", RecommendedWatcher::kind());
+        log::info!("Using watcher: {:?}", SherryWatcher::kind());
+
+        if options.standby {
+            wait_for_takeover(config_dir).await;
+        } else {
+            claim_primary(config_dir).await;
+        }
+        start_primary_heartbeat(config_dir);
+        // A standby's startup is timed from the takeover
+        begin_startup();
 
-        let config = SherryConfig::new(config_dir).await.expect("Unable to initialize configuration, maybe access is denied");
+        let started = Instant::now();
+        let config = SherryConfig::new(config_dir, options.container).await.expect("Unable to initialize configuration, maybe access is denied");
+        record_phase("config load", started.elapsed());
         log::info!("Initialized configuration");
 
+        let started = Instant::now();
         let socket = SocketClient::new(&config).await;
+        record_phase("socket connection", started.elapsed());
         log::info!("Connected to socket");
 
         Ok(App {
             config: Arc::new(Mutex::new(config)),
             socket: Arc::new(Mutex::new(socket)),
+            options: options.clone(),
         })
     }
 
     pub async fn listen(&mut self) {
+        start_token_refresh(self);
+        start_retry_queue(self);
+        start_fetched_changes(self);
+        start_storage_polling(self);
+        start_drift_repair(self);
+        start_quiesce_resume(self);
+        start_reconcile_scan(self);
+        start_owner_heartbeat(self);
+        start_feature_refresh(self);
+        start_load_governor();
+        let app = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = replay_journal(&app).await {
+                log::error!("Failed to replay journaled events: {}", e);
+            }
+        });
+        if let Err(e) = start_ipc(self).await {
+            log::error!("Failed to start IPC: {}", e);
+        }
+        if self.options.container {
+            if let Err(e) = start_health(self).await {
+                log::error!("Failed to start health endpoint: {}", e);
+            }
+            start_container_setup(self);
+        }
+
         let main_watcher_config = Arc::clone(&self.config);
         let mut event_processing_debounce_map = HashMap::new();
         let app = self.clone();
         let rt = tokio::runtime::Handle::current();
-        let debouncer = new_debouncer(Duration::from_millis(200), None, move |results: DebounceEventResult| {
+        let debouncer = new_sherry_debouncer(Duration::from_millis(200), move |results: DebounceEventResult| {
             rt.block_on(async {
                 if let Ok(results) = results {
                     let config = main_watcher_config.lock().await.get_main().await;
@@ -64,6 +135,9 @@ impl App {
                         if source_path.is_none() {
                             continue;
                         }
+                        if futures::future::join_all(result.paths.iter().map(|p| is_self_write(p))).await.into_iter().all(|v| v) {
+                            continue;
+                        }
 
                         let source = get_source_by_path(&config, &source_path.unwrap());
                         if source.is_none() {
@@ -80,15 +154,21 @@ impl App {
                             continue;
                         }
                         let source_id = watcher.source.clone();
-                        let source = config.sources.get(source_id.as_str());
-                        if source.is_none() {
-                            should_revalidate = true;
+                        // Churn of watchers being set up again, checked against the hash store once they resume
+                        if is_quiesced(&source_id) {
                             continue;
                         }
+                        let source = match config.sources.get(source_id.as_str()) {
+                            Some(source) => source,
+                            None => {
+                                should_revalidate = true;
+                                continue;
+                            }
+                        };
 
                         let debounce = event_processing_debounce_map
                             .entry(source_id.clone())
-                            .or_insert(EventProcessingDebounce::new(&rt, &app, &source_id));
+                            .or_insert(EventProcessingDebounce::new(&rt, &app, &source_id, &source.id, config.get_event_queue_capacity()));
                         debounce.send(BasedDebounceEvent {
                             event: result,
                             base: local_path,
@@ -109,4 +189,51 @@ impl App {
         }).unwrap();
         SherryConfig::listen(&self.config, &self.socket, &Arc::new(Mutex::new(debouncer))).await;
     }
+
+    // Restarts on another config directory once the event queues and transfers ran dry (or `SWITCH_DRAIN_TIMEOUT`
+    // passed). Whatever is still journaled stays with the old directory and is replayed when the demon runs on it again.
+    pub async fn switch_config_dir(&self, dir: &PathBuf) -> Result<(), String> {
+        if dir == &self.config.lock().await.get_path() {
+            return Err(format!("Already running on {:?}", dir));
+        }
+        tokio::fs::create_dir_all(dir).await.map_err(str_err_prefix("Error config dir creation"))?;
+        let exe = std::env::current_exe().map_err(str_err_prefix("Error executable path"))?;
+        let mut args = vec!["--config".to_string(), dir.to_str().unwrap().to_string()];
+        if is_silent() {
+            args.push("--silent".to_string());
+        }
+        if self.options.container {
+            args.push("--container".to_string());
+        } else if self.options.polling {
+            args.push("--polling".to_string());
+        }
+
+        let dir = dir.clone();
+        tokio::spawn(async move {
+            log::warn!("Switching to config directory {:?}, waiting for queued changes", &dir);
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_secs(SWITCH_DRAIN_TIMEOUT) {
+                let transfers = get_transfer_stats();
+                if transfers.active == 0 && transfers.queued == 0 && get_event_queue_stats().values().all(|q| q.depth == 0) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            log::warn!("Restarting on config directory {:?}", &dir);
+            let mut command = std::process::Command::new(exe);
+            command.args(&args);
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                let e = command.exec();
+                log::error!("Failed to restart on {:?}: {}", &dir, e);
+            }
+            #[cfg(not(unix))]
+            match command.spawn() {
+                Ok(_) => std::process::exit(0),
+                Err(e) => log::error!("Failed to restart on {:?}: {}", &dir, e),
+            }
+        });
+        Ok(())
+    }
 }
@@ -1,15 +1,46 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use tokio::time::Instant;
+
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
 
-use crate::config::SherryConfigJSON;
-use crate::constants::{AUTH_FILE, EXPIRATION_THRESHOLD};
-use crate::files::{initialize_json_file, read_json_file, write_json_file};
-use crate::helpers::{get_now, ordered_map};
+use crate::app::App;
+use crate::constants::{AUTH_FILE, DEVICE_LOGIN_SLOW_DOWN, EXPIRATION_THRESHOLD, FOLDER_TOKEN_THRESHOLD, TOKEN_REFRESH_BACKOFF, TOKEN_REFRESH_INTERVAL, TOKEN_REFRESH_RETRIES};
+use crate::files::{initialize_json_file, read_json_file, write_json_file_atomic};
+use crate::helpers::{get_now, ordered_map, str_err_prefix};
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+use crate::keychain::{is_keychain, load_folder_token, load_tokens, store_folder_token, store_tokens};
 use crate::server::api::ApiClient;
-use crate::server::types::ApiAuthResponse;
+use crate::server::session::subscribe_refreshed;
+use crate::server::types::{ApiAuthResponse, ApiDeviceCodeResponse, ApiDeviceTokenError, ApiUserResponse};
+
+#[derive(SerdeDiff, Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CredentialsKind {
+    #[default]
+    Token,
+    // long-lived key in `access_token`, never refreshed nor expired
+    ApiKey,
+    // login of a WebDAV server, the password is kept in `access_token`
+    Webdav,
+    // access key of an S3-compatible server in `username`, the secret key in `access_token`
+    S3,
+}
+
+// Only grants access to one folder, so a leaked token doesn't expose the rest of the account
+#[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderTokenJSON {
+    pub access_token: String,
+    pub expires_in: u64, // timestamp in seconds
+}
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -21,6 +52,61 @@ pub struct Credentials {
     pub refresh_token: String,
     pub expires_in: u64, // timestamp in seconds
     pub expired: bool,
+    #[serde(default)]
+    pub kind: CredentialsKind,
+    // folder id => token scoped to it, minted when the server supports it
+    #[serde(default, skip_serializing_if = "HashMap::is_empty", serialize_with = "ordered_map")]
+    pub folder_tokens: HashMap<String, FolderTokenJSON>,
+}
+
+impl Credentials {
+    pub fn is_refreshable(&self) -> bool {
+        self.kind == CredentialsKind::Token
+    }
+    // users of the Sherry API, the others only sign requests to their own servers
+    pub fn is_sherry(&self) -> bool {
+        matches!(self.kind, CredentialsKind::Token | CredentialsKind::ApiKey)
+    }
+    // Broken records (hand edits, a keychain entry that can't be read) are kept so they can be fixed or logged in again
+    pub fn get_invalid_reason(&self) -> Option<&'static str> {
+        if self.user_id.is_empty() {
+            Some("missing user id")
+        } else if self.access_token.is_empty() {
+            Some("missing access token")
+        } else if self.is_refreshable() && self.refresh_token.is_empty() {
+            Some("missing refresh token")
+        } else {
+            None
+        }
+    }
+    // The narrowest token that is still valid for the folder
+    pub fn get_folder_token(&self, folder_id: &String) -> &String {
+        match self.folder_tokens.get(folder_id) {
+            Some(token) if !is_folder_token_due(token) => &token.access_token,
+            _ => &self.access_token,
+        }
+    }
+    // Watchers of unusable users are suspended instead of dropped
+    pub fn is_usable(&self) -> bool {
+        !self.expired && self.get_invalid_reason().is_none()
+    }
+    // (header name, value) for API requests
+    pub fn get_auth_header(&self) -> (&'static str, String) {
+        match self.kind {
+            CredentialsKind::Token => ("Authorization", format!("Bearer {}", &self.access_token)),
+            CredentialsKind::ApiKey => ("X-Api-Key", self.access_token.clone()),
+            // never sent to the Sherry API
+            CredentialsKind::Webdav | CredentialsKind::S3 => ("Authorization", "".to_string()),
+        }
+    }
+    // The socket takes the token without a scheme
+    pub fn get_socket_auth_header(&self) -> (&'static str, String) {
+        match self.kind {
+            CredentialsKind::Token => ("authorization", self.access_token.clone()),
+            CredentialsKind::ApiKey => ("x-api-key", self.access_token.clone()),
+            CredentialsKind::Webdav | CredentialsKind::S3 => ("authorization", "".to_string()),
+        }
+    }
 }
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -31,22 +117,83 @@ pub struct SherryAuthorizationConfigJSON {
     pub records: HashMap<String, Credentials>,
 }
 
+// Tokens that made it into the keychain are blanked, the others stay in the file so nothing is lost
+fn to_file_config(config: &SherryAuthorizationConfigJSON) -> SherryAuthorizationConfigJSON {
+    let mut config = config.clone();
+    if !is_keychain() {
+        return config;
+    }
+    for user in config.records.values_mut() {
+        for (folder_id, token) in user.folder_tokens.iter_mut().filter(|(_, t)| !t.access_token.is_empty()) {
+            match store_folder_token(&user.user_id, folder_id, &token.access_token) {
+                Ok(_) => token.access_token = "".to_string(),
+                Err(e) => log::error!("{}, keeping the folder token in {}", e, AUTH_FILE),
+            }
+        }
+        if user.access_token.is_empty() && user.refresh_token.is_empty() {
+            continue;
+        }
+        match store_tokens(&user.user_id, &user.access_token, &user.refresh_token) {
+            Ok(_) => {
+                user.access_token = "".to_string();
+                user.refresh_token = "".to_string();
+            }
+            Err(e) => log::error!("{}, keeping the tokens in {}", e, AUTH_FILE),
+        }
+    }
+    config
+}
+
+// Blank tokens are looked up even with the keychain turned off, so turning it off moves them back into the file
+fn from_file_config(config: &SherryAuthorizationConfigJSON) -> SherryAuthorizationConfigJSON {
+    let mut config = config.clone();
+    for user in config.records.values_mut() {
+        for (folder_id, token) in user.folder_tokens.iter_mut().filter(|(_, t)| t.access_token.is_empty()) {
+            match load_folder_token(&user.user_id, folder_id) {
+                Ok(access_token) => token.access_token = access_token,
+                Err(e) => log::debug!("{}", e),
+            }
+        }
+        if !user.access_token.is_empty() || !user.refresh_token.is_empty() {
+            continue;
+        }
+        match load_tokens(&user.user_id) {
+            Ok((access_token, refresh_token)) => {
+                user.access_token = access_token;
+                user.refresh_token = refresh_token;
+            }
+            Err(e) if is_keychain() => log::warn!("{}", e),
+            Err(e) => log::debug!("{}", e),
+        }
+    }
+    config
+}
+
 pub async fn read_auth_config(dir: &Path) -> Result<SherryAuthorizationConfigJSON, String> {
-    read_json_file(dir.join(AUTH_FILE)).await
+    let raw = read_json_file::<SherryAuthorizationConfigJSON, _>(dir.join(AUTH_FILE)).await?;
+    let config = from_file_config(&raw);
+    // Tokens written in plain text by other tools are moved to the keychain right away
+    let file_config = to_file_config(&config);
+    if file_config != raw {
+        write_json_file_atomic(dir.join(AUTH_FILE), &file_config).await?;
+    }
+    Ok(config)
 }
 
-pub async fn write_auth_config(dir: &Path, config: &SherryAuthorizationConfigJSON) -> Result<(), String> {
-    write_json_file(dir.join(AUTH_FILE), config).await
+// Returns the written content
+pub async fn write_auth_config(dir: &Path, config: &SherryAuthorizationConfigJSON) -> Result<String, String> {
+    write_json_file_atomic(dir.join(AUTH_FILE), &to_file_config(config)).await
 }
 
 pub async fn initialize_auth_config(dir: &PathBuf) -> Result<SherryAuthorizationConfigJSON, String> {
     initialize_json_file(dir.join(AUTH_FILE), SherryAuthorizationConfigJSON {
         default: "".to_string(),
         records: HashMap::new(),
-    }).await
+    }).await?;
+    read_auth_config(dir).await
 }
 
-fn response_to_user(response: ApiAuthResponse) -> Credentials {
+pub fn response_to_user(response: ApiAuthResponse) -> Credentials {
     Credentials {
         user_id: response.user_id,
         email: response.email,
@@ -55,9 +202,172 @@ fn response_to_user(response: ApiAuthResponse) -> Credentials {
         refresh_token: response.refresh_token,
         expires_in: response.expires_in,
         expired: false,
+        kind: CredentialsKind::Token,
+        folder_tokens: HashMap::new(),
+    }
+}
+
+pub fn api_key_to_user(response: ApiUserResponse, api_key: &str) -> Credentials {
+    Credentials {
+        user_id: response.user_id,
+        email: response.email,
+        username: response.username,
+        access_token: api_key.to_string(),
+        refresh_token: "".to_string(),
+        expires_in: 0,
+        expired: false,
+        kind: CredentialsKind::ApiKey,
+        folder_tokens: HashMap::new(),
+    }
+}
+
+// Checks the key and returns the user it belongs to
+pub async fn login_with_api_key(api_url: &String, api_key: &String) -> Result<Credentials, String> {
+    let user = ApiClient::new(api_url, api_key).get_api_key_user().await.map_err(str_err_prefix("Error API Key Check"))?;
+    Ok(api_key_to_user(user, api_key))
+}
+
+pub async fn start_device_login(api_url: &String) -> Result<ApiDeviceCodeResponse, String> {
+    ApiClient::new(api_url, &"".to_string()).request_device_code().await.map_err(str_err_prefix("Error Device Code Request"))
+}
+
+// Polls until the code is confirmed in the browser, denied or expired
+pub async fn finish_device_login(api_url: &String, device_code: &String, interval: u64, expires_in: u64) -> Result<Credentials, String> {
+    let client = ApiClient::new(api_url, &"".to_string());
+    let deadline = Instant::now() + Duration::from_secs(expires_in);
+    let mut interval = interval.max(1);
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let res = client.poll_device_token(device_code).await.map_err(str_err_prefix("Error Device Token Request"))?;
+        if res.status().is_success() {
+            let user = res.json::<ApiAuthResponse>().await.map_err(str_err_prefix("Error Device Token Parse"))?;
+            return Ok(response_to_user(user));
+        }
+        let error = res.json::<ApiDeviceTokenError>().await.map_err(str_err_prefix("Error Device Token Parse"))?.error;
+        match error.as_str() {
+            "authorization_pending" => {}
+            "slow_down" => interval += DEVICE_LOGIN_SLOW_DOWN,
+            _ => return Err(format!("Login failed: {}", error)),
+        }
+    }
+    Err("Login failed: the code expired".to_string())
+}
+
+fn is_refresh_due(user: &Credentials) -> bool {
+    user.is_refreshable() && user.is_usable() && user.expires_in as i32 - EXPIRATION_THRESHOLD <= get_now()
+}
+
+// A blank token is one the keychain couldn't give back, it is minted again
+fn is_folder_token_due(token: &FolderTokenJSON) -> bool {
+    token.access_token.is_empty() || token.expires_in as i32 - FOLDER_TOKEN_THRESHOLD <= get_now()
+}
+
+// Only a 401 or 403 means the refresh token is gone, anything else may be a network hiccup
+pub fn is_refresh_rejected(e: &reqwest::Error) -> bool {
+    e.status().is_some_and(|s| s == StatusCode::UNAUTHORIZED || s == StatusCode::FORBIDDEN)
+}
+
+// Transient errors are retried with exponential backoff, if they persist the user is left as is for the next round
+async fn refresh_credentials(api_url: &String, user: &Credentials) -> Credentials {
+    log::info!("Refreshing token for {}", user.username);
+    for attempt in 0..=TOKEN_REFRESH_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(TOKEN_REFRESH_BACKOFF << (attempt - 1))).await;
+        }
+        match ApiClient::new(api_url, &user.access_token).refresh_token(&user.refresh_token).await {
+            Ok(v) => return Credentials { folder_tokens: user.folder_tokens.clone(), ..response_to_user(v) },
+            Err(e) if is_refresh_rejected(&e) => {
+                log::error!("Refresh token of {} was rejected: {}", user.username, e);
+                return Credentials { expired: true, ..user.clone() };
+            }
+            Err(e) => log::warn!("Failed to refresh token for {} (attempt {}): {}", user.username, attempt + 1, e),
+        }
     }
+    user.clone()
+}
+
+// Minted with the account token for every API folder of the user, tokens of folders that are gone are dropped.
+// Returns None once the server answered that it can't mint them, the account token is used for everything then.
+async fn mint_folder_tokens(api_url: &String, user: &Credentials, folder_ids: &Vec<&String>) -> Option<HashMap<String, FolderTokenJSON>> {
+    let client = ApiClient::new(api_url, &user.access_token);
+    let mut folder_tokens = user.folder_tokens.clone();
+    folder_tokens.retain(|id, _| folder_ids.contains(&id));
+    let due = folder_ids.iter().filter(|id| folder_tokens.get(**id).is_none_or(is_folder_token_due)).cloned().collect::<Vec<&String>>();
+    for folder_id in due {
+        match client.create_folder_token(folder_id).await {
+            Ok(token) => {
+                folder_tokens.insert(folder_id.to_string(), FolderTokenJSON { access_token: token.access_token, expires_in: token.expires_in });
+            }
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                log::info!("Server can't mint folder tokens, {} uses its account token for every folder", user.username);
+                return None;
+            }
+            Err(e) => log::warn!("Failed to mint a token of {} for folder {}: {}", user.username, folder_id, e),
+        }
+    }
+    Some(folder_tokens)
+}
+
+// An idle demon never revalidates its config, so tokens are refreshed on a timer before they run out
+pub fn start_token_refresh(app: &App) {
+    // Tokens refreshed by API clients after a 401
+    let mut refreshed = subscribe_refreshed();
+    let config = Arc::clone(&app.config);
+    tokio::spawn(async move {
+        while let Some(user) = refreshed.recv().await {
+            if let Err(e) = config.lock().await.update_credentials(&vec![user]).await {
+                log::error!("Failed to store refreshed tokens: {}", e);
+            }
+        }
+    });
+    let config = Arc::clone(&app.config);
+    tokio::spawn(async move {
+        loop {
+            let (data, auth) = {
+                let config = config.lock().await;
+                (config.get_main().await, config.get_auth().await)
+            };
+            let mut refreshed = vec![];
+            for user in auth.records.values().filter(|u| is_refresh_due(u)) {
+                let user_refreshed = refresh_credentials(&data.api_url, user).await;
+                if &user_refreshed != user {
+                    refreshed.push(user_refreshed);
+                }
+            }
+            if !refreshed.is_empty() {
+                if let Err(e) = config.lock().await.update_credentials(&refreshed).await {
+                    log::error!("Failed to store refreshed tokens: {}", e);
+                }
+            }
+            if !FOLDER_TOKENS_UNSUPPORTED.load(Ordering::SeqCst) {
+                for user in auth.records.values().filter(|u| u.is_sherry() && u.is_usable()) {
+                    let folder_ids = data.sources.values()
+                        .filter(|s| s.user_id == user.user_id && s.storage.is_none())
+                        .map(|s| &s.id)
+                        .collect::<Vec<&String>>();
+                    let folder_tokens = match mint_folder_tokens(&data.api_url, user, &folder_ids).await {
+                        Some(folder_tokens) => folder_tokens,
+                        None => {
+                            FOLDER_TOKENS_UNSUPPORTED.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                    };
+                    if folder_tokens == user.folder_tokens {
+                        continue;
+                    }
+                    if let Err(e) = config.lock().await.set_folder_tokens(&user.user_id, &folder_tokens).await {
+                        log::error!("Failed to store folder tokens: {}", e);
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(TOKEN_REFRESH_INTERVAL)).await;
+        }
+    });
 }
 
+// Set once for the process, a server upgrade is picked up on the next start
+static FOLDER_TOKENS_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
 pub struct RevalidateAuthMeta {
     pub new_users: Vec<Credentials>,
     pub deleted_users: Vec<Credentials>,
@@ -66,10 +376,8 @@ pub struct RevalidateAuthMeta {
     pub invalid_users: Vec<Credentials>,
 }
 
-pub async fn revalidate_auth(new: &SherryAuthorizationConfigJSON, old: &SherryAuthorizationConfigJSON, config: &SherryConfigJSON) -> (SherryAuthorizationConfigJSON, RevalidateAuthMeta) {
+pub async fn revalidate_auth(new: &SherryAuthorizationConfigJSON, old: &SherryAuthorizationConfigJSON) -> (SherryAuthorizationConfigJSON, RevalidateAuthMeta) {
     let mut auth = new.clone();
-    let api_url = &config.api_url;
-    let now = get_now();
 
     if auth.records.iter().find(|(_, u)| u.user_id == auth.default).is_none() {
         auth.default = "".to_string();
@@ -81,19 +389,20 @@ pub async fn revalidate_auth(new: &SherryAuthorizationConfigJSON, old: &SherryAu
     let mut updated_users: Vec<Credentials> = vec![];
     let mut invalid_users: Vec<Credentials> = vec![];
     for (key, user) in auth.records.iter() {
-        let mut user = user.clone();
-
-        if !user.expired {
-            let user_expiration = user.expires_in as i32;
-            if user_expiration < now {
-                user.expired = true
-            } else if user_expiration - EXPIRATION_THRESHOLD <= now {
-                log::info!("Refreshing token for {}", user.username);
-                match ApiClient::new(api_url, &user.access_token).refresh_token(&user.refresh_token).await {
-                    Err(_) => user.expired = true,
-                    Ok(v) => user = response_to_user(v)
-                };
+        let user = user.clone();
+
+        // Users are only marked expired by a rejected refresh, a run out access token is refreshed by the background task
+        if user.expired && old.records.get(key).is_some_and(|u| !u.expired) {
+            notify(UserMessage::new(MessageCode::AuthExpired, &[("user", &user.username)]));
+        }
+
+        if let Some(reason) = user.get_invalid_reason() {
+            if old.records.get(key).is_none_or(|u| u.get_invalid_reason().is_none()) {
+                log::error!("Credentials of {} are invalid: {}", key, reason);
+                notify(UserMessage::new(MessageCode::AuthInvalid, &[("user", key), ("reason", reason)]));
             }
+            invalid_users.push(user);
+            continue;
         }
 
         if old.records.contains_key(key) {
@@ -0,0 +1,50 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::SherryConfigWatcherJSON;
+use crate::helpers::{canonicalize_sync_path, PATH_SEP};
+
+// watcher hashes id -> remote sync paths left out by the watcher's include paths
+static AVAILABLE: std::sync::Mutex<BTreeMap<String, BTreeSet<String>>> = std::sync::Mutex::new(BTreeMap::new());
+
+// Replaced on every full fetch of the watcher
+pub fn set_available_paths(watcher: &SherryConfigWatcherJSON, sync_paths: Vec<String>) {
+    AVAILABLE.lock().unwrap().insert(watcher.hashes_id.clone(), sync_paths.into_iter().collect());
+}
+
+pub fn add_available_path(watcher: &SherryConfigWatcherJSON, sync_path: &str) {
+    AVAILABLE.lock().unwrap().entry(watcher.hashes_id.clone()).or_default().insert(sync_path.to_string());
+}
+
+pub fn remove_available_path(watcher: &SherryConfigWatcherJSON, sync_path: &str) {
+    if let Some(paths) = AVAILABLE.lock().unwrap().get_mut(&watcher.hashes_id) {
+        paths.remove(sync_path);
+    }
+}
+
+// The shortest parent of the path that doesn't contain an included path, e.g. `Photos/2023` for `Photos/2023/a.jpg`
+// when `Photos/2024` is included, so a gigantic folder is summarized by a few entries
+fn get_summary_path(watcher: &SherryConfigWatcherJSON, sync_path: &str) -> String {
+    let includes = watcher.include_paths.iter().map(|p| canonicalize_sync_path(p)).collect::<Vec<String>>();
+    let mut summary = String::new();
+    for part in sync_path.split(PATH_SEP) {
+        if !summary.is_empty() {
+            summary.push_str(PATH_SEP);
+        }
+        summary.push_str(part);
+        if !includes.iter().any(|p| p.starts_with(&format!("{}{}", summary, PATH_SEP))) {
+            break;
+        }
+    }
+    summary
+}
+
+// (summarized paths, number of files)
+pub fn get_available_paths(watcher: &SherryConfigWatcherJSON) -> (Vec<String>, usize) {
+    let available = AVAILABLE.lock().unwrap();
+    let paths = match available.get(&watcher.hashes_id) {
+        Some(paths) => paths.iter().filter(|p| !watcher.is_included(p)).collect::<Vec<&String>>(),
+        None => return (vec![], 0),
+    };
+    let summary = paths.iter().map(|p| get_summary_path(watcher, p)).collect::<BTreeSet<String>>();
+    (summary.into_iter().collect(), paths.len())
+}
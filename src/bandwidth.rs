@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_util::bytes::Bytes;
+
+use crate::config::SherryConfigJSON;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+// Bucket holds at most one second worth of bytes, chunks bigger than that put it into debt instead of stalling forever
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(kbps: u64) -> Self {
+        let rate = (kbps * 1024) as f64;
+        Self { rate, state: Mutex::new((rate, Instant::now())) }
+    }
+
+    pub async fn take(&self, amount: usize) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+            let (tokens, last) = *state;
+            let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.rate) - amount as f64;
+            *state = (tokens, now);
+            if tokens < 0.0 { Some(Duration::from_secs_f64(-tokens / self.rate)) } else { None }
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// folder id and direction -> limit in KB/s and its bucket
+type Buckets = BTreeMap<(String, Direction), (u64, Arc<TokenBucket>)>;
+
+// Transfers of a folder share one bucket per direction, so the limit applies to the folder as a whole
+static BUCKETS: std::sync::Mutex<Buckets> = std::sync::Mutex::new(BTreeMap::new());
+
+fn set_limit(source_id: &str, direction: Direction, kbps: Option<u64>) {
+    let mut buckets = BUCKETS.lock().unwrap();
+    let key = (source_id.to_string(), direction);
+    match kbps {
+        Some(kbps) if kbps > 0 => {
+            if buckets.get(&key).is_none_or(|(current, _)| *current != kbps) {
+                buckets.insert(key, (kbps, Arc::new(TokenBucket::new(kbps))));
+            }
+        }
+        _ => {
+            buckets.remove(&key);
+        }
+    }
+}
+
+pub fn set_bandwidth_limits(config: &SherryConfigJSON) {
+    for source in config.sources.values() {
+        set_limit(&source.id, Direction::Upload, source.max_upload_kbps);
+        set_limit(&source.id, Direction::Download, source.max_download_kbps);
+    }
+}
+
+fn get_bucket(source_id: &str, direction: Direction) -> Option<Arc<TokenBucket>> {
+    BUCKETS.lock().unwrap().get(&(source_id.to_string(), direction)).map(|(_, bucket)| Arc::clone(bucket))
+}
+
+pub fn limit_stream<S, T, E>(source_id: &str, direction: Direction, stream: S) -> impl Stream<Item=Result<T, E>> + Unpin + Send
+    where
+        S: Stream<Item=Result<T, E>> + Send + 'static,
+        T: AsRef<[u8]> + Send + 'static,
+        E: Send + 'static,
+{
+    let bucket = get_bucket(source_id, direction);
+    Box::pin(stream.then(move |chunk| {
+        let bucket = bucket.clone();
+        async move {
+            if let (Some(bucket), Ok(chunk)) = (&bucket, &chunk) {
+                bucket.take(chunk.as_ref().len()).await;
+            }
+            chunk
+        }
+    }))
+}
+
+pub fn limit_download<S, E>(source_id: &str, stream: S) -> impl Stream<Item=Result<Bytes, E>> + Unpin + Send
+    where
+        S: Stream<Item=Result<Bytes, E>> + Send + 'static,
+        E: Send + 'static,
+{
+    limit_stream(source_id, Direction::Download, stream)
+}
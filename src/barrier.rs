@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::app::App;
+use crate::constants::SYNC_BARRIER_TICK;
+use crate::drift::get_drifts;
+use crate::event::event_processing::get_event_queue_stats;
+use crate::event::journal::list_journal;
+use crate::quiesce::is_quiesced;
+use crate::watchers::count_fetched_changes;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncedJSON {
+    // userId@folderId
+    pub source: String,
+    // seconds
+    pub waited: u64,
+}
+
+// What keeps the source from being synced, empty once every local change is uploaded and its watchers are reconciled
+async fn get_pending(app: &App, key: &String) -> Result<Vec<String>, String> {
+    let (dir, config) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await)
+    };
+    if !config.sources.contains_key(key) {
+        return Err(format!("Source {} was removed", key));
+    }
+    let mut pending = config.watchers.iter()
+        .filter(|w| &w.source == key && !w.complete)
+        .map(|w| format!("watcher {} is fetching", &w.local_path))
+        .collect::<Vec<String>>();
+    if is_quiesced(key) {
+        pending.push("watchers are being set up".to_string());
+    }
+    let fetched = count_fetched_changes(key);
+    if fetched > 0 {
+        pending.push(format!("{} changes found by a fetch wait to be sent", fetched));
+    }
+    if let Some(queue) = get_event_queue_stats().get(key) {
+        if queue.depth > 0 || queue.batches > 0 {
+            pending.push(format!("{} changes queued, {} batches in progress", queue.depth, queue.batches));
+        }
+    }
+    let journal = list_journal(&dir, key).await?;
+    let waiting = journal.iter().filter(|e| e.manifest.is_some()).count();
+    if waiting > 0 {
+        pending.push(format!("{} changes wait for `manifest approve`", waiting));
+    }
+    if journal.len() > waiting {
+        pending.push(format!("{} changes are being sent, retried or held", journal.len() - waiting));
+    }
+    let drifts = get_drifts().iter().filter(|d| &d.source == key && d.repair.is_none()).count();
+    if drifts > 0 {
+        pending.push(format!("{} drifted paths wait for their repair", drifts));
+    }
+    Ok(pending)
+}
+
+// Synced has to hold on two checks in a row, a change made right before the call may still be debounced on the first
+pub async fn wait_until_synced(app: &App, source: &String, timeout: Option<u64>) -> Result<SyncedJSON, String> {
+    let key = {
+        let config = app.config.lock().await.get_main().await;
+        config.sources.iter()
+            .find(|(k, s)| *k == source || &s.id == source)
+            .map(|(k, _)| k.clone())
+            .ok_or(format!("Unknown source {}", source))?
+    };
+    let started = Instant::now();
+    let mut clean_checks = 0;
+    loop {
+        let pending = get_pending(app, &key).await?;
+        clean_checks = if pending.is_empty() { clean_checks + 1 } else { 0 };
+        if clean_checks >= 2 {
+            return Ok(SyncedJSON { source: key, waited: started.elapsed().as_secs() });
+        }
+        if timeout.is_some_and(|t| started.elapsed() >= Duration::from_secs(t)) {
+            return Err(format!("Source {} is not synced after {}s: {}", key, started.elapsed().as_secs(), pending.join(", ")));
+        }
+        tokio::time::sleep(Duration::from_secs(SYNC_BARRIER_TICK)).await;
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{get_hashes_dir, is_overlapping_path, SherryConfigJSON, SherryConfigWatcherJSON};
+use crate::hash::{read_hashes, WatcherHashJSON};
+use crate::helpers::{get_now_as_millis, PATH_SEP};
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleUserJSON {
+    pub user_id: String,
+    pub email: String,
+    pub username: String,
+}
+
+// Everything needed to move a setup to another machine, tokens stay behind and the users log in again
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryBundleJSON {
+    pub version: u32,
+    pub exported_at: i128,
+    pub config: SherryConfigJSON,
+    pub users: Vec<BundleUserJSON>,
+    pub default_user: String,
+    // stores of the exported watchers, so unchanged files aren't downloaded again
+    pub hashes: Vec<WatcherHashJSON>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleImportResult {
+    // local paths of the watchers added by this import
+    pub imported: Vec<String>,
+    // local path -> why it wasn't imported
+    pub skipped: Vec<(String, String)>,
+    // their watchers are imported once they log in
+    pub missing_users: Vec<BundleUserJSON>,
+}
+
+pub async fn export_bundle(dir: &Path, config: &SherryConfigJSON, users: Vec<BundleUserJSON>, default_user: &str) -> SherryBundleJSON {
+    let hashes_dir = get_hashes_dir(dir, config);
+    let mut hashes = vec![];
+    for watcher in &config.watchers {
+        match read_hashes(&hashes_dir, &watcher.hashes_id).await {
+            Ok(h) => hashes.push(h),
+            Err(e) => log::warn!("Exporting {} without its hashes: {}", &watcher.local_path, e),
+        }
+    }
+    SherryBundleJSON {
+        version: BUNDLE_VERSION,
+        exported_at: get_now_as_millis(),
+        config: SherryConfigJSON {
+            // machine specific, and proxy URLs may hold credentials
+            hashes_dir: None,
+            logs_dir: None,
+            proxy: None,
+            ..config.clone()
+        },
+        users,
+        default_user: default_user.to_string(),
+        hashes,
+    }
+}
+
+// `map` holds (old prefix, new prefix) pairs, the first matching one wins
+pub fn remap_path(path: &String, map: &Vec<(String, String)>) -> String {
+    for (old, new) in map {
+        let old = old.trim_end_matches(PATH_SEP);
+        if path == old {
+            return new.trim_end_matches(PATH_SEP).to_string();
+        }
+        if let Some(rest) = path.strip_prefix(&format!("{}{}", old, PATH_SEP)) {
+            return format!("{}{}{}", new.trim_end_matches(PATH_SEP), PATH_SEP, rest);
+        }
+    }
+    path.clone()
+}
+
+pub fn remap_hashes(hashes: &WatcherHashJSON, map: &Vec<(String, String)>) -> WatcherHashJSON {
+    WatcherHashJSON {
+        local_path: remap_path(&hashes.local_path, map),
+        hashes: hashes.hashes.iter().map(|(k, v)| (remap_path(k, map), v.clone())).collect(),
+        // the journal of this machine knows nothing about the files
+        journal_cursor: None,
+        ..hashes.clone()
+    }
+}
+
+// Watchers of the bundle whose users are logged in here, moved by `map`. Ones this machine has already are left out,
+// ones overlapping with a watcher of this machine or an earlier one of the bundle are skipped with the reason.
+pub fn select_watchers(config: &SherryConfigJSON, users: &HashSet<String>, bundle: &SherryBundleJSON, map: &Vec<(String, String)>) -> (Vec<SherryConfigWatcherJSON>, Vec<(String, String)>) {
+    let mut watchers: Vec<SherryConfigWatcherJSON> = vec![];
+    let mut skipped = vec![];
+    for watcher in &bundle.config.watchers {
+        let watcher = SherryConfigWatcherJSON {
+            local_path: remap_path(&watcher.local_path, map),
+            complete: false,
+            ..watcher.clone()
+        };
+        if !users.contains(&watcher.user_id) || config.watchers.iter().any(|w| w.hashes_id == watcher.hashes_id) {
+            continue;
+        }
+        if let Some(other) = config.watchers.iter().chain(&watchers).find(|w| is_overlapping_path(&w.local_path, &watcher.local_path)) {
+            skipped.push((watcher.local_path.clone(), format!("overlaps with the watcher at {}", &other.local_path)));
+            continue;
+        }
+        watchers.push(watcher);
+    }
+    (watchers, skipped)
+}
+
+// The settings of this machine stay as they are, the bundle only adds the watchers and the sources they sync.
+// A source this machine already has keeps its local settings.
+pub fn merge_bundle(config: &SherryConfigJSON, bundle: &SherryBundleJSON, watchers: &[SherryConfigWatcherJSON]) -> SherryConfigJSON {
+    let mut merged = config.clone();
+    for (key, source) in bundle.config.sources.iter().filter(|(k, _)| watchers.iter().any(|w| &w.source == *k)) {
+        merged.sources.entry(key.clone()).or_insert_with(|| source.clone());
+    }
+    merged.watchers.extend(watchers.iter().cloned());
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn source(id: &str, name: &str) -> serde_json::Value {
+        json!({
+            "id": id, "name": name, "access": "WRITE", "userId": "user", "ownerId": "user",
+            "maxFileSize": 0, "maxDirSize": 0, "allowDir": true, "allowedFileNames": [], "allowedFileTypes": [],
+        })
+    }
+
+    fn watcher(source: &str, local_path: &str, hashes_id: &str, user_id: &str) -> serde_json::Value {
+        json!({"source": source, "localPath": local_path, "hashesId": hashes_id, "userId": user_id, "complete": true})
+    }
+
+    fn config(api_url: &str, sources: serde_json::Value, watchers: serde_json::Value) -> SherryConfigJSON {
+        serde_json::from_value(json!({
+            "apiUrl": api_url, "socketUrl": api_url, "sources": sources, "watchers": watchers, "webhooks": [], "maxRetries": 3,
+        }))
+        .unwrap()
+    }
+
+    fn bundle(config: SherryConfigJSON) -> SherryBundleJSON {
+        SherryBundleJSON { version: BUNDLE_VERSION, exported_at: 0, config, users: vec![], default_user: "user".to_string(), hashes: vec![] }
+    }
+
+    fn users() -> HashSet<String> {
+        HashSet::from(["user".to_string()])
+    }
+
+    #[test]
+    fn remaps_paths() {
+        let map = vec![("/home/old/docs".to_string(), "/data/docs/".to_string()), ("/home/old".to_string(), "/home/new".to_string())];
+        assert_eq!(remap_path(&"/home/old/docs".to_string(), &map), "/data/docs");
+        assert_eq!(remap_path(&"/home/old/docs/a/b.txt".to_string(), &map), "/data/docs/a/b.txt");
+        assert_eq!(remap_path(&"/home/old/music".to_string(), &map), "/home/new/music");
+        assert_eq!(remap_path(&"/home/older/music".to_string(), &map), "/home/older/music");
+    }
+
+    #[test]
+    fn selects_remapped_watchers() {
+        let local = config("http://local", json!({}), json!([watcher("user@known", "/data/known", "known", "user")]));
+        let bundle = bundle(config(
+            "http://other",
+            json!({}),
+            json!([
+                watcher("user@known", "/home/old/known", "known", "user"),
+                watcher("other@folder", "/home/old/other", "other", "other"),
+                watcher("user@docs", "/home/old/docs", "docs", "user"),
+                watcher("user@nested", "/home/old/docs/nested", "nested", "user"),
+                watcher("user@inside", "/data/known/inside", "inside", "user"),
+            ]),
+        ));
+        let map = vec![("/home/old".to_string(), "/home/new".to_string())];
+
+        let (watchers, skipped) = select_watchers(&local, &users(), &bundle, &map);
+        assert_eq!(watchers.iter().map(|w| w.local_path.as_str()).collect::<Vec<&str>>(), ["/home/new/docs"]);
+        assert!(!watchers[0].complete);
+        assert_eq!(
+            skipped,
+            [
+                ("/home/new/docs/nested".to_string(), "overlaps with the watcher at /home/new/docs".to_string()),
+                ("/data/known/inside".to_string(), "overlaps with the watcher at /data/known".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_local_settings() {
+        let local = config("http://local", json!({"user@shared": source("shared", "local name")}), json!([]));
+        let mut bundle = bundle(config(
+            "http://other",
+            json!({
+                "user@shared": source("shared", "other name"),
+                "user@docs": source("docs", "docs"),
+                "user@unused": source("unused", "unused"),
+            }),
+            json!([watcher("user@shared", "/home/shared", "shared", "user"), watcher("user@docs", "/home/docs", "docs", "user")]),
+        ));
+        bundle.config.max_retries = Some(10);
+
+        let (watchers, _) = select_watchers(&local, &users(), &bundle, &vec![]);
+        let merged = merge_bundle(&local, &bundle, &watchers);
+        assert_eq!(merged.api_url, "http://local");
+        assert_eq!(merged.socket_url, "http://local");
+        assert_eq!(merged.max_retries, Some(3));
+        assert_eq!(merged.sources.len(), 2);
+        assert_eq!(merged.sources["user@shared"].name, "local name");
+        assert_eq!(merged.sources["user@docs"].name, "docs");
+        assert_eq!(merged.watchers, watchers);
+    }
+}
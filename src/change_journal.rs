@@ -0,0 +1,398 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// Where the hash store of a watcher was current in the change journal of its volume. The position only means something
+// within the same journal, a recreated journal (or FSEvents database) has another id.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeJournalCursor {
+    pub journal_id: String,
+    pub position: u64,
+}
+
+// Current end of the journal of the volume holding `path`, None when the platform or the volume has no journal
+pub async fn get_journal_cursor(path: &Path) -> Option<ChangeJournalCursor> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || platform::get_cursor(&path)).await.ok().flatten()
+}
+
+// Paths under `path` that changed after `cursor`, files and directories alike, some of them may not exist anymore.
+// None when the journal can't tell: another journal, entries that were already overwritten or dropped.
+pub async fn read_journal_changes(path: &Path, cursor: &ChangeJournalCursor) -> Option<Vec<PathBuf>> {
+    let (path, cursor) = (path.to_path_buf(), cursor.clone());
+    let changes = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || platform::read_changes(&path, &cursor)).await.ok().flatten()?
+    };
+    // Changes of the watched folder itself don't matter, only what is inside of it
+    let mut changes = changes.into_iter().filter(|p| p.starts_with(&path) && p != &path).collect::<Vec<PathBuf>>();
+    changes.sort();
+    changes.dedup();
+    Some(changes)
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::collections::HashMap;
+    use std::ffi::{c_void, OsString};
+    use std::os::windows::ffi::OsStringExt;
+    use std::path::{Component, Path, PathBuf, Prefix};
+
+    use super::ChangeJournalCursor;
+
+    type Handle = isize;
+
+    const INVALID_HANDLE_VALUE: Handle = -1;
+    const GENERIC_READ: u32 = 0x8000_0000;
+    const FILE_READ_ATTRIBUTES: u32 = 0x80;
+    const FILE_SHARE_ALL: u32 = 0x1 | 0x2 | 0x4;
+    const OPEN_EXISTING: u32 = 3;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FSCTL_QUERY_USN_JOURNAL: u32 = 0x0009_00f4;
+    const FSCTL_READ_USN_JOURNAL: u32 = 0x0009_00bb;
+    // fixed part of USN_RECORD_V2, the file name follows
+    const USN_RECORD_V2_SIZE: usize = 60;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct UsnJournalData {
+        usn_journal_id: u64,
+        first_usn: i64,
+        next_usn: i64,
+        lowest_valid_usn: i64,
+        max_usn: i64,
+        maximum_size: u64,
+        allocation_delta: u64,
+    }
+
+    #[repr(C)]
+    struct ReadUsnJournalData {
+        start_usn: i64,
+        reason_mask: u32,
+        return_only_on_close: u32,
+        timeout: u64,
+        bytes_to_wait_for: u64,
+        usn_journal_id: u64,
+    }
+
+    #[repr(C)]
+    struct FileIdDescriptor {
+        size: u32,
+        kind: i32,
+        // FileIdType, the union is as large as its 128 bit member
+        file_id: [u64; 2],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateFileW(name: *const u16, access: u32, share: u32, security: *const c_void, disposition: u32, flags: u32, template: Handle) -> Handle;
+        fn DeviceIoControl(device: Handle, code: u32, input: *const c_void, input_size: u32, output: *mut c_void, output_size: u32, returned: *mut u32, overlapped: *mut c_void) -> i32;
+        fn OpenFileById(volume: Handle, id: *const FileIdDescriptor, access: u32, share: u32, security: *const c_void, flags: u32) -> Handle;
+        fn GetFinalPathNameByHandleW(file: Handle, path: *mut u16, size: u32, flags: u32) -> u32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    struct Volume(Handle);
+
+    impl Drop for Volume {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    // Only drive letters, network shares have no journal we could read
+    fn open_volume(path: &Path) -> Option<Volume> {
+        let letter = match path.components().next()? {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => letter as char,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let name = format!(r"\\.\{}:", letter).encode_utf16().chain([0]).collect::<Vec<u16>>();
+        let handle = unsafe { CreateFileW(name.as_ptr(), GENERIC_READ, FILE_SHARE_ALL, std::ptr::null(), OPEN_EXISTING, 0, 0) };
+        if handle == INVALID_HANDLE_VALUE { None } else { Some(Volume(handle)) }
+    }
+
+    fn query_journal(volume: &Volume) -> Option<UsnJournalData> {
+        let mut journal = UsnJournalData::default();
+        let mut returned = 0u32;
+        let ok = unsafe {
+            DeviceIoControl(
+                volume.0, FSCTL_QUERY_USN_JOURNAL, std::ptr::null(), 0,
+                &mut journal as *mut UsnJournalData as *mut c_void, std::mem::size_of::<UsnJournalData>() as u32,
+                &mut returned, std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 { None } else { Some(journal) }
+    }
+
+    fn get_path_by_id(volume: &Volume, id: u64) -> Option<PathBuf> {
+        let descriptor = FileIdDescriptor { size: std::mem::size_of::<FileIdDescriptor>() as u32, kind: 0, file_id: [id, 0] };
+        let handle = unsafe { OpenFileById(volume.0, &descriptor, FILE_READ_ATTRIBUTES, FILE_SHARE_ALL, std::ptr::null(), FILE_FLAG_BACKUP_SEMANTICS) };
+        if handle == INVALID_HANDLE_VALUE {
+            return None;
+        }
+        let mut buffer = vec![0u16; 32768];
+        let length = unsafe { GetFinalPathNameByHandleW(handle, buffer.as_mut_ptr(), buffer.len() as u32, 0) };
+        unsafe { CloseHandle(handle) };
+        if length == 0 || length as usize >= buffer.len() {
+            return None;
+        }
+        let path = PathBuf::from(OsString::from_wide(&buffer[..length as usize]));
+        // \\?\C:\... to the form watchers are configured with
+        let path = path.to_str()?.strip_prefix(r"\\?\").map(PathBuf::from).unwrap_or(path);
+        Some(path)
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    pub fn get_cursor(path: &Path) -> Option<ChangeJournalCursor> {
+        let journal = query_journal(&open_volume(path)?)?;
+        Some(ChangeJournalCursor { journal_id: journal.usn_journal_id.to_string(), position: journal.next_usn as u64 })
+    }
+
+    // Records only carry the name and the id of the parent directory, parents are looked up by id. Parents that are gone
+    // are skipped, their own removal (or rename) is in the journal as well and covers everything below them.
+    pub fn read_changes(path: &Path, cursor: &ChangeJournalCursor) -> Option<Vec<PathBuf>> {
+        let volume = open_volume(path)?;
+        let journal = query_journal(&volume)?;
+        let start = cursor.position as i64;
+        if journal.usn_journal_id.to_string() != cursor.journal_id || start < journal.lowest_valid_usn.max(journal.first_usn) {
+            return None;
+        }
+
+        let mut parents: HashMap<u64, Option<PathBuf>> = HashMap::new();
+        let mut changes = vec![];
+        // u64 for the alignment of the records
+        let mut buffer = vec![0u64; 8192];
+        let mut next = start;
+        while next < journal.next_usn {
+            let request = ReadUsnJournalData {
+                start_usn: next,
+                reason_mask: u32::MAX,
+                return_only_on_close: 0,
+                timeout: 0,
+                bytes_to_wait_for: 0,
+                usn_journal_id: journal.usn_journal_id,
+            };
+            let mut returned = 0u32;
+            let ok = unsafe {
+                DeviceIoControl(
+                    volume.0, FSCTL_READ_USN_JOURNAL,
+                    &request as *const ReadUsnJournalData as *const c_void, std::mem::size_of::<ReadUsnJournalData>() as u32,
+                    buffer.as_mut_ptr() as *mut c_void, (buffer.len() * 8) as u32,
+                    &mut returned, std::ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return None;
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, returned as usize) };
+            if bytes.len() <= 8 {
+                break;
+            }
+            let mut offset = 8;
+            while offset + USN_RECORD_V2_SIZE <= bytes.len() {
+                let length = read_u32(bytes, offset) as usize;
+                if length == 0 || offset + length > bytes.len() {
+                    break;
+                }
+                if read_u16(bytes, offset + 4) == 2 {
+                    let parent = read_u64(bytes, offset + 16);
+                    let name_length = read_u16(bytes, offset + 56) as usize;
+                    let name_offset = read_u16(bytes, offset + 58) as usize;
+                    let name = bytes[offset + name_offset..offset + name_offset + name_length]
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .collect::<Vec<u16>>();
+                    let parent = parents.entry(parent).or_insert_with(|| get_path_by_id(&volume, parent));
+                    if let Some(parent) = parent {
+                        changes.push(parent.join(OsString::from_wide(&name)));
+                    }
+                }
+                offset += length;
+            }
+            let read_until = read_u64(bytes, 0) as i64;
+            if read_until <= next {
+                break;
+            }
+            next = read_until;
+        }
+        Some(changes)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::{c_char, c_void, CStr, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    use crate::constants::CHANGE_JOURNAL_REPLAY_TIMEOUT;
+
+    use super::ChangeJournalCursor;
+
+    type CFRef = *const c_void;
+
+    const K_CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+    const K_FS_EVENT_STREAM_CREATE_FLAG_NO_DEFER: u32 = 0x02;
+    const K_FS_EVENT_STREAM_CREATE_FLAG_FILE_EVENTS: u32 = 0x10;
+    const K_FS_EVENT_STREAM_EVENT_FLAG_USER_DROPPED: u32 = 0x02;
+    const K_FS_EVENT_STREAM_EVENT_FLAG_KERNEL_DROPPED: u32 = 0x04;
+    const K_FS_EVENT_STREAM_EVENT_FLAG_EVENT_IDS_WRAPPED: u32 = 0x08;
+    const K_FS_EVENT_STREAM_EVENT_FLAG_HISTORY_DONE: u32 = 0x10;
+    const K_FS_EVENT_STREAM_EVENT_FLAG_ROOT_CHANGED: u32 = 0x20;
+
+    #[repr(C)]
+    struct FSEventStreamContext {
+        version: isize,
+        info: *mut c_void,
+        retain: *const c_void,
+        release: *const c_void,
+        copy_description: *const c_void,
+    }
+
+    type FSEventStreamCallback = extern "C" fn(stream: CFRef, info: *mut c_void, count: usize, paths: *mut c_void, flags: *const u32, ids: *const u64);
+
+    #[link(name = "CoreServices", kind = "framework")]
+    extern "C" {
+        fn FSEventStreamCreate(allocator: CFRef, callback: FSEventStreamCallback, context: *const FSEventStreamContext, paths: CFRef, since_when: u64, latency: f64, flags: u32) -> CFRef;
+        fn FSEventStreamScheduleWithRunLoop(stream: CFRef, run_loop: CFRef, mode: CFRef);
+        fn FSEventStreamStart(stream: CFRef) -> u8;
+        fn FSEventStreamStop(stream: CFRef);
+        fn FSEventStreamInvalidate(stream: CFRef);
+        fn FSEventStreamRelease(stream: CFRef);
+        fn FSEventsGetCurrentEventId() -> u64;
+        fn FSEventsCopyUUIDForDevice(device: i32) -> CFRef;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFRunLoopDefaultMode: CFRef;
+        static kCFTypeArrayCallBacks: c_void;
+        fn CFRunLoopGetCurrent() -> CFRef;
+        fn CFRunLoopRunInMode(mode: CFRef, seconds: f64, return_after_source_handled: u8) -> i32;
+        fn CFStringCreateWithBytes(allocator: CFRef, bytes: *const u8, length: isize, encoding: u32, external: u8) -> CFRef;
+        fn CFStringGetCString(string: CFRef, buffer: *mut c_char, size: isize, encoding: u32) -> u8;
+        fn CFArrayCreate(allocator: CFRef, values: *const CFRef, count: isize, callbacks: *const c_void) -> CFRef;
+        fn CFUUIDCreateString(allocator: CFRef, uuid: CFRef) -> CFRef;
+        fn CFRelease(value: CFRef);
+    }
+
+    struct Replay {
+        changes: Vec<PathBuf>,
+        done: bool,
+        complete: bool,
+    }
+
+    extern "C" fn on_events(_stream: CFRef, info: *mut c_void, count: usize, paths: *mut c_void, flags: *const u32, _ids: *const u64) {
+        let replay = unsafe { &mut *(info as *mut Replay) };
+        let paths = unsafe { std::slice::from_raw_parts(paths as *const *const c_char, count) };
+        let flags = unsafe { std::slice::from_raw_parts(flags, count) };
+        for (path, flags) in paths.iter().zip(flags) {
+            if flags & K_FS_EVENT_STREAM_EVENT_FLAG_HISTORY_DONE != 0 {
+                replay.done = true;
+                continue;
+            }
+            let lost = K_FS_EVENT_STREAM_EVENT_FLAG_USER_DROPPED | K_FS_EVENT_STREAM_EVENT_FLAG_KERNEL_DROPPED
+                | K_FS_EVENT_STREAM_EVENT_FLAG_EVENT_IDS_WRAPPED | K_FS_EVENT_STREAM_EVENT_FLAG_ROOT_CHANGED;
+            if flags & lost != 0 {
+                replay.complete = false;
+            }
+            let path = unsafe { CStr::from_ptr(*path) };
+            replay.changes.push(PathBuf::from(OsStr::from_bytes(path.to_bytes())));
+        }
+    }
+
+    // The FSEvents database belongs to the volume, its UUID changes when the database is purged and ids start over
+    fn get_journal_id(path: &Path) -> Option<String> {
+        let device = path.metadata().ok()?.dev() as i32;
+        unsafe {
+            let uuid = FSEventsCopyUUIDForDevice(device);
+            if uuid.is_null() {
+                return None;
+            }
+            let string = CFUUIDCreateString(std::ptr::null(), uuid);
+            CFRelease(uuid);
+            let mut buffer = [0 as c_char; 64];
+            let ok = CFStringGetCString(string, buffer.as_mut_ptr(), buffer.len() as isize, K_CF_STRING_ENCODING_UTF8);
+            CFRelease(string);
+            if ok == 0 {
+                return None;
+            }
+            Some(CStr::from_ptr(buffer.as_ptr()).to_string_lossy().to_string())
+        }
+    }
+
+    pub fn get_cursor(path: &Path) -> Option<ChangeJournalCursor> {
+        Some(ChangeJournalCursor { journal_id: get_journal_id(path)?, position: unsafe { FSEventsGetCurrentEventId() } })
+    }
+
+    // A stream started at an old event id replays the history of the path first and marks its end
+    pub fn read_changes(path: &Path, cursor: &ChangeJournalCursor) -> Option<Vec<PathBuf>> {
+        if get_journal_id(path)? != cursor.journal_id {
+            return None;
+        }
+        let mut replay = Replay { changes: vec![], done: false, complete: true };
+        let context = FSEventStreamContext {
+            version: 0,
+            info: &mut replay as *mut Replay as *mut c_void,
+            retain: std::ptr::null(),
+            release: std::ptr::null(),
+            copy_description: std::ptr::null(),
+        };
+        let started = Instant::now();
+        unsafe {
+            let bytes = path.as_os_str().as_bytes();
+            let string = CFStringCreateWithBytes(std::ptr::null(), bytes.as_ptr(), bytes.len() as isize, K_CF_STRING_ENCODING_UTF8, 0);
+            let paths = CFArrayCreate(std::ptr::null(), &string, 1, &kCFTypeArrayCallBacks);
+            CFRelease(string);
+            let flags = K_FS_EVENT_STREAM_CREATE_FLAG_NO_DEFER | K_FS_EVENT_STREAM_CREATE_FLAG_FILE_EVENTS;
+            let stream = FSEventStreamCreate(std::ptr::null(), on_events, &context, paths, cursor.position, 0.0, flags);
+            CFRelease(paths);
+            if stream.is_null() {
+                return None;
+            }
+            FSEventStreamScheduleWithRunLoop(stream, CFRunLoopGetCurrent(), kCFRunLoopDefaultMode);
+            if FSEventStreamStart(stream) != 0 {
+                while !replay.done && started.elapsed() < Duration::from_secs(CHANGE_JOURNAL_REPLAY_TIMEOUT) {
+                    CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.1, 0);
+                }
+                FSEventStreamStop(stream);
+            }
+            FSEventStreamInvalidate(stream);
+            FSEventStreamRelease(stream);
+        }
+        if replay.done && replay.complete { Some(replay.changes) } else { None }
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod platform {
+    use std::path::{Path, PathBuf};
+
+    use super::ChangeJournalCursor;
+
+    // inotify has no history, watchers are revalidated by walking them
+    pub fn get_cursor(_path: &Path) -> Option<ChangeJournalCursor> {
+        None
+    }
+
+    pub fn read_changes(_path: &Path, _cursor: &ChangeJournalCursor) -> Option<Vec<PathBuf>> {
+        None
+    }
+}
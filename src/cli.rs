@@ -0,0 +1,673 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::process;
+
+use clap::Subcommand;
+
+use crate::bandwidth::Direction;
+use crate::bundle::BundleImportResult;
+use crate::config::{SherryConfigStorageJSON, StorageKind, SyncMode};
+use crate::consistency::{ConsistencyCategory, ConsistencyReportJSON};
+use crate::constants::CONFIG_FILE;
+use crate::files::write_json_file;
+use crate::helpers::{absolute_path, str_err_prefix};
+use crate::ipc::client::{send_request, subscribe};
+use crate::ipc::types::{IpcEvent, IpcRequest};
+use crate::progress::{HashProgress, TransferProgress};
+use crate::server::types::{ApiCreateFolderRequest, ApiDeviceCodeResponse};
+use crate::watchers::WatcherDiff;
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Remove orphaned hash files, stale queue entries and old logs of the running demon
+    Prune,
+    /// Show the state of the running demon
+    Status,
+    /// Inspect or restore previous versions of config.json and auth.json
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage watchers
+    Watcher {
+        #[command(subcommand)]
+        command: WatcherCommand,
+    },
+    /// Manage remote folders
+    Folder {
+        #[command(subcommand)]
+        command: FolderCommand,
+    },
+    /// Manage sources
+    Source {
+        #[command(subcommand)]
+        command: SourceCommand,
+    },
+    /// Manage users
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Compare the local files of a source with the server without transferring anything
+    Diff {
+        /// Source key or folder id
+        source: String,
+    },
+    /// Cross-check hash stores, the server and pending changes of every source of an account
+    Check {
+        /// Uses the default user without it
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Create a public download link for a synced file that expires
+    Share {
+        /// File inside a watcher
+        path: String,
+        /// Seconds the link works, 1 day by default and 30 days at most
+        #[arg(long)]
+        expires: Option<u64>,
+    },
+    /// Show recent warnings that need attention, like expired logins
+    Notifications,
+    /// Wait until every local change of a source is uploaded and its watchers are reconciled, fails on timeout
+    WaitUntilSynced {
+        /// Source key or folder id
+        source: String,
+        /// Seconds, waits indefinitely without it
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// Follow the progress of uploads, downloads and hashing until interrupted
+    Progress,
+    /// Inspect or resubmit events that ran out of retries
+    DeadLetters {
+        #[command(subcommand)]
+        command: DeadLettersCommand,
+    },
+    /// Inspect or clear files that are no longer uploaded after failing repeatedly
+    Quarantine {
+        #[command(subcommand)]
+        command: QuarantineCommand,
+    },
+    /// Keep back the changes of a file or directory, e.g. while a large file is still being edited
+    Hold {
+        #[command(subcommand)]
+        command: HoldCommand,
+    },
+    /// Review what batches upload, and approve the ones waiting because they are large
+    Manifest {
+        #[command(subcommand)]
+        command: ManifestCommand,
+    },
+    /// Change the console output and level of the demon's logs until it stops
+    Logging {
+        /// Write logs to the console too (true) or only to the log file (false)
+        #[arg(long)]
+        console: Option<bool>,
+        /// error, warn, info, debug or trace
+        #[arg(long)]
+        level: Option<String>,
+    },
+    /// Restart the demon on another config directory once its queued changes are sent
+    SwitchConfig {
+        path: String,
+    },
+    /// Move the setup to another machine
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BundleCommand {
+    /// Write config, users (without tokens) and hash stores to a file
+    Export {
+        file: String,
+    },
+    /// Take over an exported setup, asking to log in as each of its users
+    Import {
+        file: String,
+
+        /// Moves watchers from one directory to another, as OLD=NEW, can be repeated
+        #[arg(long)]
+        map: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// List saved versions
+    History,
+    /// Show what the last applied change did
+    Diff,
+    /// Restore the previous version, or the newest one saved before `--to`
+    Rollback {
+        #[arg(short, long, default_value = CONFIG_FILE)]
+        file: String,
+
+        /// Timestamp in milliseconds, as shown by `config history`
+        #[arg(short, long)]
+        to: Option<i128>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum WatcherCommand {
+    /// Sync a remote folder into a local directory
+    Add {
+        folder_id: String,
+        path: String,
+
+        /// Defaults to the default user
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// TWO_WAY, UPLOAD_ONLY or DOWNLOAD_ONLY
+        #[arg(short, long)]
+        mode: Option<String>,
+
+        /// Name of a template from `templates` in config.json
+        #[arg(short, long)]
+        template: Option<String>,
+    },
+    /// Sync a folder of a WebDAV server (Nextcloud, ownCloud, ...) into a local directory
+    AddWebdav {
+        path: String,
+        /// URL of the folder, e.g. https://cloud.example.com/remote.php/dav/files/me/Documents
+        url: String,
+
+        #[arg(short, long)]
+        username: String,
+
+        /// App password if the server has one for the account
+        #[arg(short, long)]
+        password: String,
+
+        /// TWO_WAY, UPLOAD_ONLY or DOWNLOAD_ONLY
+        #[arg(short, long)]
+        mode: Option<String>,
+    },
+    /// Sync a bucket of an S3-compatible server (AWS, MinIO, ...) into a local directory
+    AddS3 {
+        path: String,
+        /// Endpoint followed by the bucket and an optional prefix, e.g. https://minio.example.com/sync/laptop
+        url: String,
+
+        #[arg(long)]
+        access_key: String,
+
+        #[arg(long)]
+        secret_key: String,
+
+        /// Defaults to us-east-1
+        #[arg(short, long)]
+        region: Option<String>,
+
+        /// TWO_WAY, UPLOAD_ONLY or DOWNLOAD_ONLY
+        #[arg(short, long)]
+        mode: Option<String>,
+    },
+    /// Start syncing a remote path, the watcher syncs only its include paths from then on
+    Include {
+        path: String,
+        /// Path inside the remote folder
+        remote_path: String,
+    },
+    /// Stop syncing a remote path, local copies are kept
+    Exclude {
+        path: String,
+        /// Path inside the remote folder
+        remote_path: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SourceCommand {
+    /// Remove a source and all of its watchers
+    Remove {
+        /// Source key (userId@folderId) or folder id
+        source: String,
+    },
+    /// Download a remote file or directory now, even if it is outside the watchers' include paths
+    Fetch {
+        /// Source key (userId@folderId) or folder id
+        source: String,
+        /// Path inside the remote folder
+        path: String,
+    },
+    /// List the files of a source as they were at a point in time on this machine, optionally restoring them elsewhere
+    At {
+        /// Source key (userId@folderId) or folder id
+        source: String,
+        /// Timestamp in milliseconds, RFC 3339 or local "YYYY-MM-DD HH:MM"
+        time: String,
+        /// Empty directory outside the watchers to write the files into
+        #[arg(long)]
+        into: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum FolderCommand {
+    /// Create a remote folder from a local directory and start syncing it
+    Create {
+        path: String,
+
+        /// Defaults to the directory name
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Defaults to the default user
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// In bytes
+        #[arg(long)]
+        max_file_size: Option<u64>,
+
+        /// In bytes
+        #[arg(long)]
+        max_dir_size: Option<u64>,
+
+        /// Allow directories in the folder
+        #[arg(long)]
+        allow_dir: bool,
+
+        /// Only allow these file names, can be repeated
+        #[arg(long = "allow-name")]
+        allowed_file_names: Vec<String>,
+
+        /// Only allow these file types, can be repeated
+        #[arg(long = "allow-type")]
+        allowed_file_types: Vec<String>,
+
+        /// Name of a template from `templates` in config.json
+        #[arg(short, long)]
+        template: Option<String>,
+    },
+    /// Delete a remote folder you own and stop syncing it, local copies are kept
+    Delete {
+        /// Source key (userId@folderId) or folder id
+        source: String,
+
+        /// Required for folders shared with others or with many files
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Make a remote folder you own read-only for everyone
+    Archive {
+        /// Source key (userId@folderId) or folder id
+        source: String,
+
+        /// Restore an archived folder
+        #[arg(long)]
+        undo: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Set the user used when none is specified
+    Default {
+        user_id: String,
+    },
+    /// Log in with a long-lived API key, for servers that shouldn't depend on token refreshes
+    AddKey {
+        api_key: String,
+    },
+    /// Log in by confirming a code in the browser, for machines where typing a password is not an option
+    Login {
+        /// Open the verification page in the default browser
+        #[arg(long)]
+        open: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DeadLettersCommand {
+    /// List failed events with their errors
+    List,
+    /// Send failed events again
+    Resubmit {
+        /// Resubmit a single event, all of them by default
+        #[arg(short, long)]
+        id: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum QuarantineCommand {
+    /// List quarantined files with their last error
+    List,
+    /// Upload quarantined files again with their next change
+    Clear {
+        /// Clear a single file (remote or local path), all of them by default
+        #[arg(short, long)]
+        path: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HoldCommand {
+    /// List held paths with the number of changes waiting
+    List,
+    /// Detect and queue the changes of a path without uploading them
+    Add {
+        path: String,
+    },
+    /// Upload the queued changes of a held path and stop holding it
+    Release {
+        path: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ManifestCommand {
+    /// List the latest batches and the ones waiting for approval
+    List,
+    /// Upload a batch waiting for approval
+    Approve {
+        id: String,
+    },
+    /// Drop a batch waiting for approval, its files are uploaded with their next change
+    Reject {
+        id: String,
+    },
+}
+
+fn parse_sync_mode(mode: &Option<String>) -> Result<Option<SyncMode>, String> {
+    match mode {
+        Some(mode) => serde_json::from_value(serde_json::Value::String(mode.to_uppercase()))
+            .map(Some)
+            .map_err(|_| format!("Invalid sync mode {}", mode)),
+        None => Ok(None),
+    }
+}
+
+fn parse_time(time: &str) -> Result<i128, String> {
+    if let Ok(millis) = time.parse::<i128>() {
+        return Ok(millis);
+    }
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(date.timestamp_millis() as i128);
+    }
+    chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").ok()
+        .and_then(|date| date.and_local_timezone(chrono::Local).earliest())
+        .map(|date| date.timestamp_millis() as i128)
+        .ok_or(format!("Invalid time {}, expected milliseconds, RFC 3339 or YYYY-MM-DD HH:MM", time))
+}
+
+impl Command {
+    fn to_request(&self) -> Result<IpcRequest, String> {
+        Ok(match self {
+            Command::Prune => IpcRequest::Prune,
+            Command::Status => IpcRequest::Status,
+            Command::Notifications => IpcRequest::Notifications,
+            Command::WaitUntilSynced { source, timeout } => IpcRequest::WaitUntilSynced { source: source.clone(), timeout: *timeout },
+            Command::Progress => IpcRequest::Subscribe,
+            Command::Diff { source } => IpcRequest::DiffSource { source: source.clone() },
+            Command::Check { user } => IpcRequest::CheckConsistency { user_id: user.clone() },
+            Command::Share { path, expires } => IpcRequest::ShareFile { local_path: absolute_path(path).to_str().unwrap().to_string(), expires_in: *expires },
+            Command::Config { command } => match command {
+                ConfigCommand::History => IpcRequest::ConfigHistory,
+                ConfigCommand::Diff => IpcRequest::ConfigDiff,
+                ConfigCommand::Rollback { file, to } => IpcRequest::ConfigRollback { file: file.clone(), timestamp: *to },
+            },
+            Command::Watcher { command } => match command {
+                WatcherCommand::Add { folder_id, path, user, mode, template } => IpcRequest::AddWatcher {
+                    folder_id: folder_id.clone(),
+                    local_path: absolute_path(path).to_str().unwrap().to_string(),
+                    user_id: user.clone(),
+                    mode: parse_sync_mode(mode)?,
+                    template: template.clone(),
+                },
+                WatcherCommand::AddWebdav { path, url, username, password, mode } => IpcRequest::AddStorageWatcher {
+                    local_path: absolute_path(path).to_str().unwrap().to_string(),
+                    storage: SherryConfigStorageJSON { kind: StorageKind::Webdav, url: url.clone(), region: None },
+                    username: username.clone(),
+                    password: password.clone(),
+                    mode: parse_sync_mode(mode)?,
+                },
+                WatcherCommand::AddS3 { path, url, access_key, secret_key, region, mode } => IpcRequest::AddStorageWatcher {
+                    local_path: absolute_path(path).to_str().unwrap().to_string(),
+                    storage: SherryConfigStorageJSON { kind: StorageKind::S3, url: url.clone(), region: region.clone() },
+                    username: access_key.clone(),
+                    password: secret_key.clone(),
+                    mode: parse_sync_mode(mode)?,
+                },
+                WatcherCommand::Include { path, remote_path } => IpcRequest::IncludeWatcherPath {
+                    local_path: absolute_path(path).to_str().unwrap().to_string(),
+                    path: remote_path.clone(),
+                },
+                WatcherCommand::Exclude { path, remote_path } => IpcRequest::ExcludeWatcherPath {
+                    local_path: absolute_path(path).to_str().unwrap().to_string(),
+                    path: remote_path.clone(),
+                },
+            },
+            Command::Folder { command } => match command {
+                FolderCommand::Create { path, name, user, max_file_size, max_dir_size, allow_dir, allowed_file_names, allowed_file_types, template } => {
+                    let local_path = absolute_path(path);
+                    let name = match name {
+                        Some(name) => name.clone(),
+                        None => local_path.file_name().ok_or(format!("Can't name a folder after {}, pass --name", path))?.to_string_lossy().to_string(),
+                    };
+                    IpcRequest::CreateFolder {
+                        local_path: local_path.to_str().unwrap().to_string(),
+                        user_id: user.clone(),
+                        template: template.clone(),
+                        folder: ApiCreateFolderRequest {
+                            name,
+                            max_file_size: *max_file_size,
+                            max_dir_size: *max_dir_size,
+                            allow_dir: *allow_dir,
+                            allowed_file_names: allowed_file_names.clone(),
+                            allowed_file_types: allowed_file_types.clone(),
+                        },
+                    }
+                }
+                FolderCommand::Delete { source, yes } => IpcRequest::DeleteFolder { source: source.clone(), confirm: *yes },
+                FolderCommand::Archive { source, undo } => IpcRequest::ArchiveFolder { source: source.clone(), archived: !undo },
+            },
+            Command::Source { command } => match command {
+                SourceCommand::Remove { source } => IpcRequest::RemoveSource { source: source.clone() },
+                SourceCommand::Fetch { source, path } => IpcRequest::FetchPath { source: source.clone(), path: path.clone() },
+                SourceCommand::At { source, time, into } => IpcRequest::FolderAt {
+                    source: source.clone(),
+                    timestamp: parse_time(time)?,
+                    target: into.as_ref().map(|p| absolute_path(p).to_str().unwrap().to_string()),
+                },
+            },
+            Command::User { command } => match command {
+                UserCommand::Default { user_id } => IpcRequest::SetDefaultUser { user_id: user_id.clone() },
+                UserCommand::Login { .. } => IpcRequest::StartLogin,
+                UserCommand::AddKey { api_key } => IpcRequest::AddApiKey { api_key: api_key.clone() },
+            },
+            Command::DeadLetters { command } => match command {
+                DeadLettersCommand::List => IpcRequest::DeadLetters,
+                DeadLettersCommand::Resubmit { id } => IpcRequest::ResubmitDeadLetters { id: id.clone() },
+            },
+            Command::Quarantine { command } => match command {
+                QuarantineCommand::List => IpcRequest::Quarantine,
+                QuarantineCommand::Clear { path } => IpcRequest::ClearQuarantine { path: path.clone() },
+            },
+            Command::Hold { command } => match command {
+                HoldCommand::List => IpcRequest::Holds,
+                HoldCommand::Add { path } => IpcRequest::HoldPath { local_path: absolute_path(path).to_str().unwrap().to_string() },
+                HoldCommand::Release { path } => IpcRequest::ReleasePath { local_path: absolute_path(path).to_str().unwrap().to_string() },
+            },
+            Command::Manifest { command } => match command {
+                ManifestCommand::List => IpcRequest::Manifests,
+                ManifestCommand::Approve { id } => IpcRequest::ApproveManifest { id: id.clone() },
+                ManifestCommand::Reject { id } => IpcRequest::RejectManifest { id: id.clone() },
+            },
+            Command::Logging { console, level } => IpcRequest::SetLogging { silent: console.map(|c| !c), level: level.clone() },
+            Command::SwitchConfig { path } => IpcRequest::SwitchConfig { path: absolute_path(path).to_str().unwrap().to_string() },
+            Command::Bundle { command } => match command {
+                BundleCommand::Export { .. } => IpcRequest::ExportBundle,
+                BundleCommand::Import { file, map } => IpcRequest::ImportBundle {
+                    bundle: serde_json::from_str(&std::fs::read_to_string(file).map_err(str_err_prefix("Error File Read"))?)
+                        .map_err(str_err_prefix("Error JSON Parse"))?,
+                    path_map: map.iter().map(|m| match m.split_once('=') {
+                        Some((old, new)) => Ok((absolute_path(old).to_str().unwrap().to_string(), absolute_path(new).to_str().unwrap().to_string())),
+                        None => Err(format!("Invalid mapping {}, expected OLD=NEW", m)),
+                    }).collect::<Result<Vec<(String, String)>, String>>()?,
+                },
+            },
+        })
+    }
+}
+
+fn open_url(url: &String) {
+    let res = if cfg!(target_os = "windows") {
+        process::Command::new("cmd").args(["/C", "start", "", url]).spawn()
+    } else if cfg!(target_os = "macos") {
+        process::Command::new("open").arg(url).spawn()
+    } else {
+        process::Command::new("xdg-open").arg(url).spawn()
+    };
+    if res.is_err() {
+        eprintln!("Failed to open the browser, open the page manually");
+    }
+}
+
+async fn request(config_dir: &Path, request: IpcRequest) -> Result<serde_json::Value, String> {
+    let response = send_request(config_dir, request).await?;
+    if !response.ok {
+        return Err(response.error.unwrap_or_default());
+    }
+    Ok(response.data)
+}
+
+async fn run_login(config_dir: &Path, open: bool) -> Result<(), String> {
+    let device = serde_json::from_value::<ApiDeviceCodeResponse>(request(config_dir, IpcRequest::StartLogin).await?)
+        .map_err(str_err_prefix("Error JSON Parse"))?;
+    println!("Open {} and enter the code {}", &device.verification_uri, &device.user_code);
+    if open {
+        open_url(&device.verification_uri);
+    }
+    let user = request(config_dir, IpcRequest::FinishLogin {
+        device_code: device.device_code,
+        interval: device.interval,
+        expires_in: device.expires_in,
+    }).await?;
+    println!("{}", serde_json::to_string_pretty(&user).unwrap());
+    Ok(())
+}
+
+// Imports again after every login, until every user of the bundle is known or the user gives up
+async fn run_import(config_dir: &Path, request: IpcRequest) -> Result<(), String> {
+    let mut asked = HashSet::new();
+    loop {
+        let result = serde_json::from_value::<BundleImportResult>(self::request(config_dir, request.clone()).await?)
+            .map_err(str_err_prefix("Error JSON Parse"))?;
+        for path in &result.imported {
+            println!("Imported {}", path);
+        }
+        for (path, reason) in &result.skipped {
+            println!("Skipped {}: {}", path, reason);
+        }
+        let user = match result.missing_users.iter().find(|u| !asked.contains(&u.user_id)) {
+            Some(user) => user.clone(),
+            None => break,
+        };
+        asked.insert(user.user_id.clone());
+        println!("Log in as {} ({}) to import their watchers", &user.username, &user.email);
+        if let Err(e) = run_login(config_dir, false).await {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+// Local-only, remote-only and differing paths side by side, one table per watcher of the source
+fn print_diff(diffs: &Vec<WatcherDiff>) {
+    for diff in diffs {
+        println!("{}", &diff.local_path);
+        let columns = [&diff.local_only, &diff.remote_only, &diff.differing];
+        let headers = ["LOCAL ONLY", "REMOTE ONLY", "DIFFERING"];
+        let widths = columns.iter().zip(headers).map(|(c, h)| c.iter().map(|p| p.chars().count()).chain([h.len()]).max().unwrap_or(0)).collect::<Vec<usize>>();
+        let rows = columns.iter().map(|c| c.len()).max().unwrap_or(0);
+        println!("{:<w0$}  {:<w1$}  {}", headers[0], headers[1], headers[2], w0 = widths[0], w1 = widths[1]);
+        for i in 0..rows {
+            let cell = |c: &Vec<String>| c.get(i).cloned().unwrap_or_default();
+            println!("{:<w0$}  {:<w1$}  {}", cell(columns[0]), cell(columns[1]), cell(columns[2]), w0 = widths[0], w1 = widths[1]);
+        }
+        if rows == 0 {
+            println!("In sync");
+        }
+        println!();
+    }
+}
+
+// Issues grouped by category, then what couldn't be compared
+fn print_consistency(report: &ConsistencyReportJSON) {
+    println!("{} paths of {} sources checked, {} changes pending", report.checked, report.sources.len(), report.pending);
+    let mut categories = report.issues.iter().map(|i| i.category).collect::<Vec<ConsistencyCategory>>();
+    categories.dedup();
+    for category in categories {
+        println!();
+        println!("{}", serde_json::to_value(category).unwrap().as_str().unwrap());
+        for i in report.issues.iter().filter(|i| i.category == category) {
+            println!("  {}  {}  ({})", i.source.as_deref().unwrap_or("-"), &i.path, &i.action);
+        }
+    }
+    for (source, reason) in &report.unchecked {
+        println!();
+        println!("{} not checked: {}", source, reason);
+    }
+    if report.issues.is_empty() && report.unchecked.is_empty() {
+        println!("Consistent");
+    }
+}
+
+fn print_progress(progress: &TransferProgress) {
+    let direction = match progress.direction {
+        Direction::Upload => "upload",
+        Direction::Download => "download",
+    };
+    let percent = match (progress.done, progress.percent) {
+        (true, _) => "done".to_string(),
+        (false, Some(percent)) => format!("{}%", percent),
+        (false, None) => "-".to_string(),
+    };
+    println!("{:<8}  {:>4}  {} ({} of {} bytes)", direction, percent, progress.path, progress.bytes, progress.total);
+}
+
+fn print_hash_progress(progress: &HashProgress) {
+    let percent = if progress.done { "done".to_string() } else { format!("{}%", progress.hashed * 100 / progress.total.max(1)) };
+    println!("{:<8}  {:>4}  {} ({} of {} files)", "hash", percent, progress.local_path, progress.hashed, progress.total);
+}
+
+pub async fn run_command(config_dir: &Path, command: &Command) -> Result<(), String> {
+    match command {
+        Command::User { command: UserCommand::Login { open } } => return run_login(config_dir, *open).await,
+        Command::Bundle { command: BundleCommand::Import { .. } } => return run_import(config_dir, command.to_request()?).await,
+        Command::Progress => {
+            return subscribe(config_dir, |event| match event {
+                IpcEvent::TransferProgress(progress) => print_progress(&progress),
+                IpcEvent::HashProgress(progress) => print_hash_progress(&progress),
+            }).await;
+        }
+        Command::Diff { .. } => {
+            let diffs = serde_json::from_value::<Vec<WatcherDiff>>(request(config_dir, command.to_request()?).await?)
+                .map_err(str_err_prefix("Error JSON Parse"))?;
+            print_diff(&diffs);
+            return Ok(());
+        }
+        Command::Check { .. } => {
+            let report = serde_json::from_value::<ConsistencyReportJSON>(request(config_dir, command.to_request()?).await?)
+                .map_err(str_err_prefix("Error JSON Parse"))?;
+            print_consistency(&report);
+            return Ok(());
+        }
+        Command::Bundle { command: BundleCommand::Export { file } } => {
+            let bundle = request(config_dir, command.to_request()?).await?;
+            return write_json_file(file, &bundle).await;
+        }
+        _ => {}
+    }
+    let data = request(config_dir, command.to_request()?).await?;
+    println!("{}", serde_json::to_string_pretty(&data).unwrap());
+    Ok(())
+}
@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+pub mod diff;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
@@ -7,19 +9,46 @@ use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver};
 use std::time::Duration;
 
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use notify_debouncer_full::{DebounceEventResult, Debouncer, FileIdMap, new_debouncer};
+use home::home_dir;
+use notify::{RecursiveMode, Watcher};
+use notify_debouncer_full::DebounceEventResult;
+use path_clean::PathClean;
 use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
+use tokio::fs;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 
-use crate::auth::{initialize_auth_config, read_auth_config, revalidate_auth, SherryAuthorizationConfigJSON, write_auth_config};
-use crate::constants::{AUTH_FILE, CONFIG_FILE, DEFAULT_API_URL, DEFAULT_SOCKET_URL, ENV_API_URL, ENV_SOCKET_URL};
-use crate::files::{initialize_json_file, read_json_file, write_json_file};
-use crate::helpers::{ordered_map, str_err_prefix};
+use crate::auth::{Credentials, CredentialsKind, FolderTokenJSON, initialize_auth_config, read_auth_config, revalidate_auth, SherryAuthorizationConfigJSON, write_auth_config};
+use crate::bandwidth::set_bandwidth_limits;
+use crate::bundle::{BUNDLE_VERSION, BundleImportResult, merge_bundle, remap_hashes, select_watchers, SherryBundleJSON};
+use crate::constants::{AUTH_FILE, CONFIG_FILE, CRITICAL_PATHS, DEFAULT_API_URL, DEFAULT_APPROVAL_MAX_BYTES, DEFAULT_APPROVAL_MAX_FILES, DEFAULT_EVENT_QUEUE_CAPACITY, DEFAULT_MAX_CONCURRENT_UPLOADS, DEFAULT_MAX_RETRIES, DEFAULT_SOCKET_URL, ENV_API_URL, ENV_SOCKET_URL, FOLDER_DELETE_CONFIRM_FILES, HASHES_DIR, LOGS_DIR, RECONCILE_INTERVAL_MIN};
+use crate::features::set_features;
+use crate::governor::set_load_governor;
+use crate::startup::{finish_startup, record_folder_fetch, record_phase};
+use crate::files::{initialize_json_file, read_json_file, write_json_file_atomic};
+use crate::config::diff::ConfigDiff;
+use crate::event::cooldown::set_write_cooldown;
+use crate::fs_watcher::{new_sherry_debouncer, SherryDebouncer};
+use crate::hash::update_hashes;
+use crate::hash_store::collect_stores;
+use crate::history::save_history;
+use crate::keychain::{is_keychain, set_keychain};
+use crate::helpers::{canonicalize_sync_path, expand_env_vars, generate_random_id, get_default_state_dir, normalize_path, ordered_map, PATH_SEP, str_err_prefix};
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+use crate::ownership::{claim_watchers, get_foreign_owner, release_watchers};
+use crate::quiesce::{quiesce_watchers, settle_watchers};
 use crate::server::api::ApiClient;
+use crate::server::held::set_incomplete_folders;
+use crate::server::http::{set_proxy, set_tls};
+use crate::server::scheduler::set_transfer_priorities;
+use crate::server::session::set_sessions;
 use crate::server::socket::SocketClient;
-use crate::server::types::{ApiFolderPermissionAccessRights, ApiFolderResponse};
+use crate::server::storage::get_storage;
+use crate::server::types::{ApiCreateFolderRequest, ApiFolderPermissionAccessRights, ApiFolderResponse};
+use crate::templates::apply_template;
+use crate::watchdog::set_watchdog;
 use crate::watchers::actualize_watchers;
 
 #[derive(SerdeDiff, Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
@@ -43,6 +72,81 @@ pub struct SherryConfigSourceJSON {
     pub allow_dir: bool,
     pub allowed_file_names: Vec<String>,
     pub allowed_file_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload_kbps: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_kbps: Option<u64>,
+    // archived folders are read-only for everyone until the owner restores them
+    #[serde(default)]
+    pub archived: bool,
+    // compare every upload with what the server recorded
+    #[serde(default)]
+    pub verify_uploads: bool,
+    // send the executable and read-only flags of files and apply them on download
+    #[serde(default)]
+    pub sync_permissions: bool,
+    // transfers of folders with a higher priority go first, 0 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    // folders that live on another server instead of the Sherry API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<SherryConfigStorageJSON>,
+    // feature name -> enabled, for this folder only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<BTreeMap<String, bool>>,
+    // variant the server renders of photos and videos (e.g. "preview"), downloaded instead of the original by
+    // download-only watchers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_variant: Option<String>,
+}
+
+impl SherryConfigSourceJSON {
+    pub fn can_upload(&self) -> bool {
+        self.access != AccessRights::Read && !self.archived
+    }
+
+    // Variants can't be uploaded back, watchers that upload always get the original
+    pub fn get_download_variant(&self, mode: SyncMode) -> Option<&String> {
+        self.download_variant.as_ref().filter(|_| mode == SyncMode::DownloadOnly)
+    }
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StorageKind {
+    Webdav,
+    // S3-compatible object storage
+    S3,
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryConfigStorageJSON {
+    pub kind: StorageKind,
+    // collection of the folder, e.g. https://cloud.example.com/remote.php/dav/files/me/Documents,
+    // or the endpoint followed by the bucket and a prefix, e.g. https://minio.example.com/sync/laptop
+    pub url: String,
+    // S3 only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SyncMode {
+    #[default]
+    TwoWay,
+    UploadOnly,
+    DownloadOnly,
+}
+
+impl SyncMode {
+    pub fn can_upload(&self) -> bool {
+        *self != SyncMode::DownloadOnly
+    }
+    pub fn can_download(&self) -> bool {
+        *self != SyncMode::UploadOnly
+    }
 }
 
 #[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -53,10 +157,107 @@ pub struct SherryConfigWatcherJSON {
     pub hashes_id: String,
     pub user_id: String,
     pub complete: bool,
+    #[serde(default)]
+    pub mode: SyncMode,
+    // allows watching system critical directories
+    #[serde(default)]
+    pub force: bool,
+    // sync paths to sync, everything is synced when empty
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+    // sync paths that are never synced, taken from the template the watcher was created with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore_paths: Vec<String>,
+}
+
+fn is_sync_path_in(sync_path: &String, paths: &[String]) -> bool {
+    paths.iter().any(|p| {
+        let p = canonicalize_sync_path(p);
+        *sync_path == p || sync_path.starts_with(&format!("{}{}", p, PATH_SEP))
+    })
+}
+
+impl SherryConfigWatcherJSON {
+    pub fn is_included(&self, sync_path: &str) -> bool {
+        let sync_path = canonicalize_sync_path(sync_path);
+        (self.include_paths.is_empty() || is_sync_path_in(&sync_path, &self.include_paths)) && !is_sync_path_in(&sync_path, &self.ignore_paths)
+    }
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryConfigTemplateJSON {
+    // created in the local directory
+    #[serde(default)]
+    pub folders: Vec<String>,
+    // path in the folder -> local file it is copied from, only written into empty remote folders
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    // become the watcher's `ignorePaths`
+    #[serde(default)]
+    pub excludes: Vec<String>,
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryConfigWatchdogJSON {
+    // wall-clock budget of an event batch or watcher fetch in seconds, stalled runs are reported once per budget
+    pub budget: u64,
+    // abort stalled runs, their watchers are fetched again from scratch
+    #[serde(default)]
+    pub abort: bool,
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryConfigLoadGovernorJSON {
+    // percent of time tasks waited for the CPU (pressure stall information, the load average per core without it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    // percent of time tasks waited for disk IO, needs pressure stall information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io: Option<u32>,
+    // seconds hashing and transfers are held back at most, so a machine that is always busy still syncs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay: Option<u64>,
 }
 
 #[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
+pub struct SherryConfigBatchApprovalJSON {
+    // batches uploading more bytes wait for `manifest approve`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    // batches with more changes wait for `manifest approve`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+impl SherryConfigBatchApprovalJSON {
+    pub fn get_max_bytes(&self) -> u64 {
+        self.max_bytes.unwrap_or(DEFAULT_APPROVAL_MAX_BYTES)
+    }
+    pub fn get_max_files(&self) -> usize {
+        self.max_files.unwrap_or(DEFAULT_APPROVAL_MAX_FILES)
+    }
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SherryConfigTlsJSON {
+    // PEM file with additional root certificates
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    // only meant for self-hosted test servers
+    #[serde(default)]
+    pub skip_hostname_verification: bool,
+    // "1.0", "1.1" or "1.2"
+    #[serde(default)]
+    pub min_version: Option<String>,
+}
+
+#[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct SherryConfigJSON {
     pub api_url: String,
     pub socket_url: String,
@@ -65,14 +266,147 @@ pub struct SherryConfigJSON {
     pub sources: HashMap<String, SherryConfigSourceJSON>,
     pub watchers: Vec<SherryConfigWatcherJSON>,
     pub webhooks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashes_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_uploads: Option<u32>,
+    // changes of a source waiting to be batched, the filesystem watcher waits while it is full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_queue_capacity: Option<u32>,
+    // find files changed while the demon was stopped in the USN journal (Windows) or FSEvents history (macOS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_journal: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<SherryConfigTlsJSON>,
+    // keep tokens in the OS keychain instead of auth.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_keychain: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<SherryConfigWatchdogJSON>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_governor: Option<SherryConfigLoadGovernorJSON>,
+    // seconds, at most one upload per interval for files that are rewritten constantly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_cooldown: Option<u64>,
+    // name -> structure applied to watchers created with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub templates: Option<BTreeMap<String, SherryConfigTemplateJSON>>,
+    // feature name -> enabled, overrides what the server enables for the account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<BTreeMap<String, bool>>,
+    // large batches wait until they are approved, so a misplaced copy doesn't upload gigabytes unnoticed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_approval: Option<SherryConfigBatchApprovalJSON>,
+    // seconds, every watcher is hashed and compared with the server this often to catch changes the filesystem watcher missed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconcile_interval: Option<u64>,
 }
 
-async fn write_main_config(dir: &Path, config: &SherryConfigJSON) -> Result<(), String> {
-    write_json_file(dir.join(CONFIG_FILE), config).await
+impl SherryConfigJSON {
+    pub fn get_max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+    pub fn get_max_concurrent_uploads(&self) -> usize {
+        self.max_concurrent_uploads.unwrap_or(DEFAULT_MAX_CONCURRENT_UPLOADS).max(1) as usize
+    }
+    pub fn get_event_queue_capacity(&self) -> usize {
+        self.event_queue_capacity.unwrap_or(DEFAULT_EVENT_QUEUE_CAPACITY).max(1) as usize
+    }
+    pub fn get_reconcile_interval(&self) -> Option<u64> {
+        self.reconcile_interval.map(|i| i.max(RECONCILE_INTERVAL_MIN))
+    }
+}
+
+fn resolve_state_dir(dir: &Path, configured: &Option<String>, default: &str) -> PathBuf {
+    match configured {
+        Some(path) => normalize_path(&dir.join(path)),
+        None => get_default_state_dir(dir).join(default),
+    }
+}
+
+pub fn get_hashes_dir(dir: &Path, config: &SherryConfigJSON) -> PathBuf {
+    resolve_state_dir(dir, &config.hashes_dir, HASHES_DIR)
+}
+
+pub fn get_logs_dir(dir: &Path, config: &SherryConfigJSON) -> PathBuf {
+    resolve_state_dir(dir, &config.logs_dir, LOGS_DIR)
+}
+
+// Logs are initialized before the config is validated, so a broken config falls back to the default location
+pub async fn read_logs_dir(dir: &Path) -> PathBuf {
+    match read_main_config(dir).await {
+        Ok(config) => get_logs_dir(dir, &config),
+        Err(_) => get_default_state_dir(dir).join(LOGS_DIR),
+    }
+}
+
+fn interpolate_config(config: &SherryConfigJSON) -> SherryConfigJSON {
+    let mut config = config.clone();
+    config.api_url = expand_env_vars(&config.api_url);
+    config.socket_url = expand_env_vars(&config.socket_url);
+    config.hashes_dir = config.hashes_dir.as_deref().map(expand_env_vars);
+    config.logs_dir = config.logs_dir.as_deref().map(expand_env_vars);
+    config.proxy = config.proxy.as_deref().map(expand_env_vars);
+    for watcher in config.watchers.iter_mut() {
+        watcher.local_path = expand_env_vars(&watcher.local_path);
+    }
+    config
+}
+
+// Keeps `${VAR}` templates from the file on disk for values that still expand to the same thing
+fn preserve_config_templates(raw: &SherryConfigJSON, config: &SherryConfigJSON) -> SherryConfigJSON {
+    let mut config = config.clone();
+    if expand_env_vars(&raw.api_url) == config.api_url {
+        config.api_url = raw.api_url.clone();
+    }
+    if expand_env_vars(&raw.socket_url) == config.socket_url {
+        config.socket_url = raw.socket_url.clone();
+    }
+    if raw.hashes_dir.as_deref().map(expand_env_vars) == config.hashes_dir {
+        config.hashes_dir = raw.hashes_dir.clone();
+    }
+    if raw.logs_dir.as_deref().map(expand_env_vars) == config.logs_dir {
+        config.logs_dir = raw.logs_dir.clone();
+    }
+    if raw.proxy.as_deref().map(expand_env_vars) == config.proxy {
+        config.proxy = raw.proxy.clone();
+    }
+    for watcher in config.watchers.iter_mut() {
+        if let Some(raw_watcher) = raw.watchers.iter().find(|w| w.hashes_id == watcher.hashes_id) {
+            if expand_env_vars(&raw_watcher.local_path) == watcher.local_path {
+                watcher.local_path = raw_watcher.local_path.clone();
+            }
+        }
+    }
+    config
+}
+
+// Returns the written content
+async fn write_main_config(dir: &Path, config: &SherryConfigJSON) -> Result<String, String> {
+    let config = match read_json_file::<SherryConfigJSON, _>(dir.join(CONFIG_FILE)).await {
+        Ok(raw) => preserve_config_templates(&raw, config),
+        Err(_) => config.clone(),
+    };
+    write_json_file_atomic(dir.join(CONFIG_FILE), &config).await
+}
+
+// Files still holding what the demon committed last are its own writes, they are not read back as updates
+async fn is_committed(committed: &Mutex<HashMap<String, String>>, file: &str, path: &Path) -> bool {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    committed.lock().await.get(file).is_some_and(|c| *c == content)
 }
 
 async fn read_main_config(dir: &Path) -> Result<SherryConfigJSON, String> {
-    read_json_file(dir.join(CONFIG_FILE)).await
+    Ok(interpolate_config(&read_json_file(dir.join(CONFIG_FILE)).await?))
 }
 
 fn response_role_to_access(role: ApiFolderPermissionAccessRights) -> AccessRights {
@@ -96,9 +430,25 @@ fn response_to_folder(response: &ApiFolderResponse, user_id: &String) -> Result<
         allow_dir: response.allow_dir,
         allowed_file_names: response.allowed_file_names.iter().map(|n| n.name.clone()).collect(),
         allowed_file_types: response.allowed_file_types.iter().map(|t| t._type.clone()).collect(),
+        max_upload_kbps: None,
+        max_download_kbps: None,
+        archived: response.archived,
+        verify_uploads: false,
+        sync_permissions: false,
+        priority: None,
+        storage: None,
+        features: None,
+        download_variant: None,
     })
 }
 
+fn find_source(data: &SherryConfigJSON, source: &String) -> Result<(String, SherryConfigSourceJSON), String> {
+    data.sources.iter()
+        .find(|(k, s)| *k == source || &s.id == source)
+        .map(|(k, s)| (k.clone(), s.clone()))
+        .ok_or(format!("Unknown source {}", source))
+}
+
 struct RevalidateConfigMeta {
     pub invalid_watchers: Vec<SherryConfigWatcherJSON>,
     pub valid_watchers: Vec<SherryConfigWatcherJSON>,
@@ -111,6 +461,60 @@ struct RevalidateConfigMeta {
     pub updated_sources: HashMap<String, SherryConfigSourceJSON>,
 }
 
+fn get_unsafe_path_reason(path: &PathBuf, dir: &PathBuf, force: bool) -> Option<String> {
+    let path = path.clean();
+    let dir = dir.clean();
+    if path.starts_with(&dir) || dir.starts_with(&path) {
+        return Some(format!("{:?} overlaps with the config directory", path));
+    }
+    if force {
+        return None;
+    }
+    if path.parent().is_none() {
+        return Some(format!("{:?} is a filesystem root", path));
+    }
+    if home_dir().is_some_and(|home| home == path) {
+        return Some(format!("{:?} is the home directory", path));
+    }
+    if CRITICAL_PATHS.iter().any(|p| PathBuf::from(p).clean().to_str().unwrap().eq_ignore_ascii_case(path.to_str().unwrap())) {
+        return Some(format!("{:?} is a system directory", path));
+    }
+    None
+}
+
+pub fn is_overlapping_path(a: &str, b: &str) -> bool {
+    let a = normalize_path(&PathBuf::from(a).clean());
+    let b = normalize_path(&PathBuf::from(b).clean());
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+fn get_folder_id<'a>(config: &'a SherryConfigJSON, watcher: &SherryConfigWatcherJSON) -> Option<&'a String> {
+    config.sources.get(&watcher.source).map(|s| &s.id)
+}
+
+// Watchers already present in the old config win, the rest are taken in config order.
+// hashes id -> (local path of the watcher it overlaps with, whether both sync the same folder)
+fn get_overlapping_watchers(new: &SherryConfigJSON, old: &SherryConfigJSON) -> HashMap<String, (String, bool)> {
+    let mut ordered = new.watchers.iter().filter(|w| old.watchers.iter().any(|o| o.hashes_id == w.hashes_id)).collect::<Vec<_>>();
+    ordered.extend(new.watchers.iter().filter(|w| !old.watchers.iter().any(|o| o.hashes_id == w.hashes_id)));
+
+    let mut accepted: Vec<&SherryConfigWatcherJSON> = vec![];
+    let mut overlapping = HashMap::new();
+    for watcher in ordered {
+        match accepted.iter().find(|w| is_overlapping_path(&w.local_path, &watcher.local_path)) {
+            // The same folder twice would upload every change twice, one of them is enough
+            Some(other) if get_folder_id(new, other) == get_folder_id(new, watcher) => {
+                overlapping.insert(watcher.hashes_id.clone(), (other.local_path.clone(), true));
+            }
+            Some(other) => {
+                overlapping.insert(watcher.hashes_id.clone(), (other.local_path.clone(), false));
+            }
+            None => accepted.push(watcher),
+        }
+    }
+    overlapping
+}
+
 async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth: &SherryAuthorizationConfigJSON, is_init: bool, dir: &PathBuf) -> (SherryConfigJSON, RevalidateConfigMeta) {
     let mut invalid_watchers: Vec<SherryConfigWatcherJSON> = vec![];
     let mut valid_watchers: Vec<SherryConfigWatcherJSON> = vec![];
@@ -118,15 +522,35 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
     let mut updated_watchers: Vec<SherryConfigWatcherJSON> = vec![];
     let mut deleted_watchers: Vec<SherryConfigWatcherJSON> = vec![];
 
+    let overlapping_watchers = get_overlapping_watchers(new, old);
     for watcher in new.watchers.iter() {
+        if let Some((other, is_same_folder)) = overlapping_watchers.get(&watcher.hashes_id) {
+            let code = if *is_same_folder { MessageCode::WatcherDuplicate } else { MessageCode::WatcherOverlap };
+            notify(UserMessage::new(code, &[("path", &watcher.local_path), ("other", other)]));
+            invalid_watchers.push(watcher.clone());
+            continue;
+        }
         if !auth.records.contains_key(&watcher.user_id) || !new.sources.contains_key(&watcher.source) || !PathBuf::from(&watcher.local_path).exists() {
             invalid_watchers.push(watcher.clone());
             continue;
         }
+        if let Some(reason) = get_unsafe_path_reason(&PathBuf::from(&watcher.local_path), dir, watcher.force) {
+            log::error!("Refusing to watch {}: {}, set \"force\" to override", &watcher.local_path, reason);
+            invalid_watchers.push(watcher.clone());
+            continue;
+        }
+        if let Some(owner) = get_foreign_owner(&watcher.local_path, dir).await {
+            notify(UserMessage::new(MessageCode::WatcherClaimed, &[("path", &watcher.local_path), ("owner", &owner.describe())]));
+            invalid_watchers.push(watcher.clone());
+            continue;
+        }
         match old.watchers.iter().find(|w| w.local_path == watcher.local_path) {
             None => new_watchers.push(watcher.clone()),
             Some(old_watcher) => {
-                if old_watcher != watcher {
+                if old_watcher.include_paths != watcher.include_paths {
+                    // newly included paths have to be fetched
+                    updated_watchers.push(SherryConfigWatcherJSON { complete: false, ..watcher.clone() });
+                } else if old_watcher != watcher {
                     updated_watchers.push(watcher.clone());
                 } else {
                     valid_watchers.push(watcher.clone());
@@ -140,6 +564,9 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
         }
     }
 
+    claim_watchers(&[&valid_watchers[..], &new_watchers[..], &updated_watchers[..]].concat(), dir).await;
+    release_watchers(&deleted_watchers).await;
+
     log::info!("Invalid Watchers: {:?}", &invalid_watchers);
     log::info!("Valid Watchers: {:?}", &valid_watchers);
     log::info!("New Watchers: {:?}", &new_watchers);
@@ -151,6 +578,7 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
     let mut updated_sources: HashMap<String, SherryConfigSourceJSON> = HashMap::new();
     let mut invalid_sources: HashMap<String, SherryConfigSourceJSON> = HashMap::new();
 
+    let fetch_started = Instant::now();
     for (key, source) in new.sources.iter() {
         let source = source.clone();
 
@@ -159,10 +587,39 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
             continue;
         }
 
-        match ApiClient::new(&new.api_url, &auth.records.get(&source.user_id).unwrap().access_token).get_folder(&source.id).await {
+        let user = auth.records.get(&source.user_id).unwrap();
+        // Suspended until the user logs in again instead of being dropped, the API would reject the token anyway
+        if !user.is_usable() {
+            valid_sources.insert(key.clone(), source);
+            for watcher in current_watchers.iter_mut().filter(|w| w.source.eq(key)) {
+                watcher.complete = false;
+            }
+            continue;
+        }
+        // Other servers have no folder settings to refresh
+        if source.storage.is_some() {
+            valid_sources.insert(key.clone(), source);
+            continue;
+        }
+
+        let started = Instant::now();
+        let folder = ApiClient::new(&new.api_url, &user.access_token).get_folder(&source.id).await;
+        record_folder_fetch(key, started.elapsed());
+        match folder {
             Ok(folder) => {
                 match response_to_folder(&folder, &source.user_id) {
                     Ok(actual_source) => {
+                        // Limits are local settings, the server doesn't know about them
+                        let actual_source = SherryConfigSourceJSON {
+                            max_upload_kbps: source.max_upload_kbps,
+                            max_download_kbps: source.max_download_kbps,
+                            verify_uploads: source.verify_uploads,
+                            sync_permissions: source.sync_permissions,
+                            priority: source.priority,
+                            features: source.features.clone(),
+                            download_variant: source.download_variant.clone(),
+                            ..actual_source
+                        };
                         if actual_source != source {
                             updated_sources.insert(key.clone(), actual_source);
                         } else {
@@ -188,8 +645,11 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
         }
     }
 
+    record_phase("folder fetch", fetch_started.elapsed());
+
+    let started = Instant::now();
     let actualize_result = actualize_watchers(
-        dir,
+        &get_hashes_dir(dir, new),
         new,
         &auth.records,
         &valid_sources,
@@ -199,8 +659,9 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
                 .filter(|w| w.complete == false)
                 .map(|w| w.clone())
                 .collect(),
-        },
+        }.into_iter().filter(|w| auth.records.get(&w.user_id).is_some_and(|u| u.is_usable())).collect(),
     ).await;
+    record_phase("watcher fetch", started.elapsed());
     current_watchers.retain(|w| {
         if actualize_result.invalid_watchers.contains(w) {
             invalid_watchers.push(w.clone());
@@ -233,22 +694,73 @@ async fn revalidate_config(new: &SherryConfigJSON, old: &SherryConfigJSON, auth:
     )
 }
 
-async fn initialize_main_config(dir: &Path) -> Result<SherryConfigJSON, String> {
-    initialize_json_file(dir.join(CONFIG_FILE), SherryConfigJSON {
-        api_url: env::var(ENV_API_URL).unwrap_or(DEFAULT_API_URL.to_string()),
-        socket_url: env::var(ENV_SOCKET_URL).unwrap_or(DEFAULT_SOCKET_URL.to_string()),
+// In container mode urls are stored as templates, so they always follow the container environment
+fn get_initial_url(env_name: &str, default: &str, container: bool) -> String {
+    match env::var(env_name) {
+        Ok(_) if container => format!("${{{}}}", env_name),
+        Ok(v) => v,
+        Err(_) => default.to_string(),
+    }
+}
+
+fn default_main_config(container: bool) -> SherryConfigJSON {
+    SherryConfigJSON {
+        api_url: get_initial_url(ENV_API_URL, DEFAULT_API_URL, container),
+        socket_url: get_initial_url(ENV_SOCKET_URL, DEFAULT_SOCKET_URL, container),
         sources: HashMap::new(),
         watchers: Vec::new(),
         webhooks: Vec::new(),
-    }).await
+        hashes_dir: None,
+        logs_dir: None,
+        max_retries: None,
+        max_concurrent_uploads: None,
+        proxy: None,
+        tls: None,
+        use_keychain: None,
+        watchdog: None,
+        write_cooldown: None,
+        templates: None,
+        features: None,
+        event_queue_capacity: None,
+        load_governor: None,
+        change_journal: None,
+        batch_approval: None,
+        reconcile_interval: None,
+    }
 }
 
-async fn initialize_config_dir(dir: &PathBuf) -> Result<(SherryConfigJSON, SherryAuthorizationConfigJSON), String> {
+async fn initialize_main_config(dir: &Path, container: bool) -> Result<SherryConfigJSON, String> {
+    initialize_json_file(dir.join(CONFIG_FILE), default_main_config(container)).await.map(|c| interpolate_config(&c))
+}
+
+// A broken file is left for the user to fix, like one broken at runtime: the demon starts from the defaults and reports
+// it until it is fixed. Files that can't be created are an error.
+async fn initialize_config_dir(dir: &PathBuf, container: bool) -> Result<(SherryConfigJSON, SherryAuthorizationConfigJSON, HashMap<String, String>), String> {
     if !dir.exists() {
         tokio::fs::create_dir_all(dir).await.map_err(str_err_prefix("Error Creating Config Dir"))?;
     }
 
-    Ok((initialize_main_config(&dir).await?, initialize_auth_config(&dir).await?))
+    let mut errors = HashMap::new();
+    let config = match initialize_main_config(dir, container).await {
+        Ok(config) => config,
+        Err(e) if dir.join(CONFIG_FILE).exists() => {
+            log::error!("Config {:?} is invalid, starting from the defaults until it is fixed: {}", dir.join(CONFIG_FILE), e);
+            errors.insert(CONFIG_FILE.to_string(), e);
+            interpolate_config(&default_main_config(container))
+        }
+        Err(e) => return Err(e),
+    };
+    set_keychain(config.use_keychain.unwrap_or(false));
+    let auth = match initialize_auth_config(dir).await {
+        Ok(auth) => auth,
+        Err(e) if dir.join(AUTH_FILE).exists() => {
+            log::error!("Auth config {:?} is invalid, starting without users until it is fixed: {}", dir.join(AUTH_FILE), e);
+            errors.insert(AUTH_FILE.to_string(), e);
+            SherryAuthorizationConfigJSON { default: "".to_string(), records: HashMap::new() }
+        }
+        Err(e) => return Err(e),
+    };
+    Ok((config, auth, errors))
 }
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -267,13 +779,18 @@ pub struct SherryConfigUpdateEvent {
 pub struct SherryConfig {
     data: Arc<Mutex<SherryConfigJSON>>,
     auth: Arc<Mutex<SherryAuthorizationConfigJSON>>,
+    // file name -> parse error, files listed here are never overwritten
+    errors: Arc<Mutex<HashMap<String, String>>>,
+    last_diff: Arc<Mutex<Option<ConfigDiff>>>,
+    // file name -> content of the last commit
+    committed: Arc<Mutex<HashMap<String, String>>>,
     dir: PathBuf,
     receiver: Arc<Mutex<Receiver<SherryConfigUpdateEvent>>>,
 
-    watchers_debouncer: Arc<Mutex<Option<Arc<Mutex<Debouncer<RecommendedWatcher, FileIdMap>>>>>>,
+    watchers_debouncer: Arc<Mutex<Option<Arc<Mutex<SherryDebouncer>>>>>,
     socket: Arc<Mutex<Option<Arc<Mutex<SocketClient>>>>>,
 
-    debouncer: Arc<Mutex<Debouncer<RecommendedWatcher, FileIdMap>>>,
+    debouncer: Arc<Mutex<SherryDebouncer>>,
 }
 
 impl SherryConfig {
@@ -284,15 +801,45 @@ impl SherryConfig {
         *self.auth.lock().await = new_value.clone();
     }
     async fn commit(&self) {
-        write_main_config(&self.dir, &self.get_main().await).await.unwrap();
-        write_auth_config(&self.dir, &self.get_auth().await).await.unwrap();
+        let errors = self.get_errors().await;
+        if !errors.contains_key(CONFIG_FILE) {
+            let content = write_main_config(&self.dir, &self.get_main().await).await.unwrap();
+            self.committed.lock().await.insert(CONFIG_FILE.to_string(), content);
+            save_history(&self.dir, CONFIG_FILE).await.ok();
+        }
+        if !errors.contains_key(AUTH_FILE) {
+            let content = write_auth_config(&self.dir, &self.get_auth().await).await.unwrap();
+            self.committed.lock().await.insert(AUTH_FILE.to_string(), content);
+            save_history(&self.dir, AUTH_FILE).await.ok();
+        }
     }
 
     async fn apply_update(&mut self, update: &SherryConfigUpdateEvent, is_init: bool) {
-        let (valid_auth, auth_revalidation_meta) = revalidate_auth(&update.new.auth, &update.old.auth, &update.new.data).await;
+        if update.old != update.new {
+            let diff = ConfigDiff::new(&update.old, &update.new);
+            log::info!("Config changed: {}", serde_json::to_string(&diff).unwrap());
+            *self.last_diff.lock().await = Some(diff);
+        }
+        set_bandwidth_limits(&update.new.data);
+        set_transfer_priorities(&update.new.data);
+        set_features(&update.new.data);
+        set_proxy(&update.new.data.proxy);
+        set_tls(&update.new.data.tls);
+        set_sessions(&update.new.auth);
+        set_watchdog(&update.new.data.watchdog);
+        set_load_governor(&update.new.data.load_governor);
+        set_write_cooldown(&update.new.data.write_cooldown);
+        set_incomplete_folders(&update.new.data, is_init);
+        let use_keychain = update.new.data.use_keychain.unwrap_or(false);
+        let is_keychain_changed = use_keychain != is_keychain();
+        set_keychain(use_keychain);
+        let started = Instant::now();
+        let (valid_auth, auth_revalidation_meta) = revalidate_auth(&update.new.auth, &update.old.auth).await;
+        record_phase("auth revalidation", started.elapsed());
         let (valid_config, config_revalidation_meta) = revalidate_config(&update.new.data, &update.old.data, &valid_auth, is_init, &self.get_path()).await;
 
-        let mut should_commit = false;
+        // Moves the tokens between auth.json and the keychain
+        let mut should_commit = is_keychain_changed;
         if valid_auth != update.new.auth {
             self.set_auth(&valid_auth).await;
             should_commit = true;
@@ -301,10 +848,27 @@ impl SherryConfig {
             self.set_main(&valid_config).await;
             should_commit = true;
         }
+        set_incomplete_folders(&valid_config, false);
         if should_commit {
             self.commit().await;
         }
 
+        // Stores of removed watchers, on start also those of watchers removed while the demon was stopped. Watchers
+        // this update dropped as invalid keep theirs for now, and none go while config.json is broken: the defaults
+        // standing in for it have no watchers.
+        let is_config_broken = self.get_errors().await.contains_key(CONFIG_FILE);
+        if (is_init || !config_revalidation_meta.deleted_watchers.is_empty()) && !is_config_broken {
+            let keep = update.new.data.watchers.iter().chain(valid_config.watchers.iter())
+                .map(|w| w.hashes_id.clone())
+                .collect::<HashSet<String>>();
+            match collect_stores(&get_hashes_dir(&self.get_path(), &valid_config), &keep).await {
+                Ok(removed) if !removed.is_empty() => log::info!("Removed hash stores of removed watchers: {:?}", removed),
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to remove hash stores of removed watchers: {}", e),
+            }
+        }
+
+        let started = Instant::now();
         if update.old.data != valid_config {
             log::info!("Updating watchers");
 
@@ -312,6 +876,15 @@ impl SherryConfig {
             let mut debouncer = debouncer.lock().await;
             let watcher = debouncer.watcher();
 
+            // On start the fetch of every watcher already reconciled it
+            if !is_init {
+                quiesce_watchers(&[
+                    &config_revalidation_meta.invalid_watchers[..],
+                    &config_revalidation_meta.deleted_watchers[..],
+                    &config_revalidation_meta.updated_watchers[..],
+                    &config_revalidation_meta.new_watchers[..],
+                ].concat());
+            }
             for w in [
                 config_revalidation_meta.invalid_watchers,
                 config_revalidation_meta.deleted_watchers,
@@ -325,39 +898,53 @@ impl SherryConfig {
             ].concat() {
                 watcher.watch(Path::new(&w.local_path), RecursiveMode::Recursive).unwrap()
             }
+            settle_watchers();
         }
+        record_phase("watcher setup", started.elapsed());
 
-        if !auth_revalidation_meta.deleted_users.is_empty()
-            || !auth_revalidation_meta.new_users.is_empty()
-            || !auth_revalidation_meta.updated_users.is_empty()
-            || !auth_revalidation_meta.invalid_users.is_empty()
-        {
-            log::info!("Updating socket");
-            self.get_socket().await.lock().await.reconnect().await;
+        let changed_users = [
+            auth_revalidation_meta.deleted_users,
+            auth_revalidation_meta.new_users,
+            auth_revalidation_meta.updated_users,
+            auth_revalidation_meta.invalid_users,
+        ].concat().into_iter().map(|u| u.user_id).collect::<Vec<String>>();
+        if !changed_users.is_empty() {
+            log::info!("Updating socket for {:?}", &changed_users);
+            self.get_socket().await.lock().await.reconnect_users(&changed_users).await;
         }
         
         log::info!("Config updated");
     }
-    pub async fn new(dir: &PathBuf) -> Result<SherryConfig, ()> {
-        let data = initialize_config_dir(dir).await;
-        if data.is_err() { return Err(()); }
-        let (data, auth) = data.unwrap();
+    pub async fn new(dir: &PathBuf, container: bool) -> Result<SherryConfig, ()> {
+        let data = initialize_config_dir(dir, container).await;
+        if let Err(e) = &data {
+            log::error!("Unable to initialize configuration: {}", e);
+            return Err(());
+        }
+        let (data, auth, initial_errors) = data.unwrap();
+        for file in [CONFIG_FILE, AUTH_FILE].into_iter().filter(|f| !initial_errors.contains_key(*f)) {
+            save_history(dir, file).await.ok();
+        }
 
         let data = Arc::new(Mutex::new(data));
         let auth = Arc::new(Mutex::new(auth));
         let (tx, rx) = channel::<SherryConfigUpdateEvent>();
 
+        let errors = Arc::new(Mutex::new(initial_errors));
+
         let current_config = Arc::clone(&data);
         let current_auth = Arc::clone(&auth);
+        let current_errors = Arc::clone(&errors);
+        let committed = Arc::new(Mutex::new(HashMap::new()));
+        let current_committed = Arc::clone(&committed);
         let config_dir = dir.clone();
 
         let config_path = dir.join(CONFIG_FILE);
         let auth_path = dir.join(AUTH_FILE);
 
         let rt = tokio::runtime::Handle::current();
-        let debouncer = new_debouncer(
+        let debouncer = new_sherry_debouncer(
             Duration::from_millis(1000),
-            None,
             move |res: DebounceEventResult| {
                 rt.block_on(async {
                     if res.is_err() { return; }
@@ -372,18 +959,30 @@ impl SherryConfig {
                     };
                     for event in &event {
                         for path in &event.paths {
-                            if config_path.eq(path) {
-                                if let Ok(new_config) = read_main_config(&config_dir).await {
-                                    new.data = new_config;
-                                } else {
-                                    let _ = write_main_config(&config_dir, &old.data).await;
+                            if config_path.eq(path) && !is_committed(&current_committed, CONFIG_FILE, path).await {
+                                match read_main_config(&config_dir).await {
+                                    Ok(new_config) => {
+                                        new.data = new_config;
+                                        current_errors.lock().await.remove(CONFIG_FILE);
+                                        save_history(&config_dir, CONFIG_FILE).await.ok();
+                                    }
+                                    Err(e) => {
+                                        log::error!("Config {:?} is invalid, keeping the last valid state until it is fixed: {}", path, e);
+                                        current_errors.lock().await.insert(CONFIG_FILE.to_string(), e);
+                                    }
                                 }
                             }
-                            if auth_path.eq(path) {
-                                if let Ok(new_config) = read_auth_config(&config_dir).await {
-                                    new.auth = new_config;
-                                } else {
-                                    let _ = write_auth_config(&config_dir, &old.auth).await;
+                            if auth_path.eq(path) && !is_committed(&current_committed, AUTH_FILE, path).await {
+                                match read_auth_config(&config_dir).await {
+                                    Ok(new_config) => {
+                                        new.auth = new_config;
+                                        current_errors.lock().await.remove(AUTH_FILE);
+                                        save_history(&config_dir, AUTH_FILE).await.ok();
+                                    }
+                                    Err(e) => {
+                                        log::error!("Auth config {:?} is invalid, keeping the last valid state until it is fixed: {}", path, e);
+                                        current_errors.lock().await.insert(AUTH_FILE.to_string(), e);
+                                    }
                                 }
                             }
                         }
@@ -401,6 +1000,9 @@ impl SherryConfig {
         Ok(SherryConfig {
             data,
             auth,
+            errors,
+            last_diff: Arc::new(Mutex::new(None)),
+            committed,
             dir: dir.clone(),
             receiver: Arc::new(Mutex::new(rx)),
 
@@ -416,7 +1018,13 @@ impl SherryConfig {
     pub async fn get_auth(&self) -> SherryAuthorizationConfigJSON {
         self.auth.lock().await.clone()
     }
-    async fn get_data_debouncer(&self) -> Arc<Mutex<Debouncer<RecommendedWatcher, FileIdMap>>> {
+    pub async fn get_errors(&self) -> HashMap<String, String> {
+        self.errors.lock().await.clone()
+    }
+    pub async fn get_last_diff(&self) -> Option<ConfigDiff> {
+        self.last_diff.lock().await.clone()
+    }
+    async fn get_data_debouncer(&self) -> Arc<Mutex<SherryDebouncer>> {
         let a = self.watchers_debouncer.lock().await;
         a.clone().unwrap()
     }
@@ -440,6 +1048,351 @@ impl SherryConfig {
             new: update,
         }, false).await;
     }
+    // Applies a change to the in-memory state and commits it, so external tools don't have to race the demon on the files
+    async fn mutate<F>(&mut self, mutation: F) -> Result<(), String>
+        where
+            F: FnOnce(&mut SherryConfigUpdateData) -> Result<(), String>,
+    {
+        let errors = self.get_errors().await;
+        if !errors.is_empty() {
+            return Err(format!("Config files are invalid, fix them first: {:?}", errors));
+        }
+
+        let old = SherryConfigUpdateData {
+            data: self.get_main().await,
+            auth: self.get_auth().await,
+        };
+        let mut new = old.clone();
+        mutation(&mut new)?;
+
+        self.set_main(&new.data).await;
+        self.set_auth(&new.auth).await;
+        self.commit().await;
+        self.apply_update(&SherryConfigUpdateEvent { old, new }, false).await;
+        Ok(())
+    }
+    pub async fn add_watcher(&mut self, folder_id: &String, local_path: &String, user_id: &Option<String>, mode: SyncMode, template: &Option<String>) -> Result<SherryConfigWatcherJSON, String> {
+        let data = self.get_main().await;
+        let auth = self.get_auth().await;
+        let user_id = user_id.clone().unwrap_or(auth.default.clone());
+        let user = auth.records.get(&user_id).ok_or(format!("Unknown user {}", &user_id))?;
+        let template = match template {
+            Some(name) => Some(data.templates.as_ref().and_then(|t| t.get(name)).ok_or(format!("Unknown template {}", name))?.clone()),
+            None => None,
+        };
+
+        let client = ApiClient::new(&data.api_url, &user.access_token);
+        let folder = client.get_folder(folder_id).await.map_err(str_err_prefix("Error Folder Fetch"))?;
+        let source = response_to_folder(&folder, &user_id).map_err(|e| e.to_string())?;
+
+        let watcher = SherryConfigWatcherJSON {
+            source: format!("{}@{}", &user_id, folder_id),
+            local_path: local_path.clone(),
+            hashes_id: generate_random_id(),
+            user_id,
+            complete: false,
+            mode,
+            force: false,
+            include_paths: vec![],
+            ignore_paths: template.as_ref().map(|t| t.excludes.clone()).unwrap_or_default(),
+        };
+        self.mutate(|update| {
+            if let Some(other) = update.data.watchers.iter().find(|w| is_overlapping_path(&w.local_path, &watcher.local_path)) {
+                return Err(format!("{} overlaps with the watcher at {}", &watcher.local_path, &other.local_path));
+            }
+            update.data.sources.entry(watcher.source.clone()).or_insert(source);
+            update.data.watchers.push(watcher.clone());
+            Ok(())
+        }).await?;
+
+        // Seed files are meant for new folders, joining a folder that already has content only gets the folders
+        if let Some(mut template) = template {
+            let files = client.get_folder_files(folder_id).await.map_err(str_err_prefix("Error Folder Files Fetch"))?;
+            if !files.is_empty() {
+                template.files.clear();
+            }
+            apply_template(&template, local_path).await?;
+        }
+        Ok(watcher)
+    }
+    // The local directory is uploaded by the initial fetch of the new watcher
+    pub async fn create_folder(&mut self, local_path: &String, user_id: &Option<String>, folder: &ApiCreateFolderRequest, template: &Option<String>) -> Result<SherryConfigWatcherJSON, String> {
+        if !PathBuf::from(local_path).is_dir() {
+            return Err(format!("{} is not a directory", local_path));
+        }
+        // Checked before creating the folder, so a refused watcher doesn't leave an unused remote folder behind
+        let data = self.get_main().await;
+        if let Some(other) = data.watchers.iter().find(|w| is_overlapping_path(&w.local_path, local_path)) {
+            return Err(format!("{} overlaps with the watcher at {}", local_path, &other.local_path));
+        }
+        let auth = self.get_auth().await;
+        let user_id = user_id.clone().unwrap_or(auth.default.clone());
+        let user = auth.records.get(&user_id).ok_or(format!("Unknown user {}", &user_id))?;
+
+        let created = ApiClient::new(&data.api_url, &user.access_token).create_folder(folder).await
+            .map_err(str_err_prefix("Error Folder Create"))?;
+        log::info!("Created folder {} ({}) for {}", &created.name, &created.sherry_id, local_path);
+        self.add_watcher(&created.sherry_id, local_path, &Some(user_id), SyncMode::TwoWay, template).await
+    }
+    // For folders on other servers than the Sherry API. The login is kept as a user of its own, the URL is the folder id.
+    pub async fn add_storage_watcher(&mut self, local_path: &str, storage: &SherryConfigStorageJSON, username: &str, password: &str, mode: SyncMode) -> Result<SherryConfigWatcherJSON, String> {
+        let data = self.get_main().await;
+        let url = storage.url.trim_end_matches('/').to_string();
+        let parsed = reqwest::Url::parse(&url).map_err(str_err_prefix("Invalid storage URL"))?;
+        let kind = match storage.kind {
+            StorageKind::Webdav => CredentialsKind::Webdav,
+            StorageKind::S3 => CredentialsKind::S3,
+        };
+        let user = Credentials {
+            user_id: format!("{}:{}@{}", serde_json::to_value(storage.kind).unwrap().as_str().unwrap().to_lowercase(), username, parsed.host_str().unwrap_or_default()),
+            email: "".to_string(),
+            username: username.to_string(),
+            access_token: password.to_string(),
+            refresh_token: "".to_string(),
+            expires_in: 0,
+            expired: false,
+            kind,
+            folder_tokens: HashMap::new(),
+        };
+        let source = SherryConfigSourceJSON {
+            id: url.clone(),
+            name: parsed.path_segments().and_then(|mut s| s.next_back()).unwrap_or_default().to_string(),
+            access: AccessRights::Write,
+            user_id: user.user_id.clone(),
+            owner_id: user.user_id.clone(),
+            max_file_size: u64::MAX,
+            max_dir_size: u64::MAX,
+            allow_dir: true,
+            allowed_file_names: vec![],
+            allowed_file_types: vec![],
+            max_upload_kbps: None,
+            max_download_kbps: None,
+            archived: false,
+            verify_uploads: false,
+            sync_permissions: false,
+            priority: None,
+            features: None,
+            download_variant: None,
+            storage: Some(SherryConfigStorageJSON { url: url.clone(), ..storage.clone() }),
+        };
+        // Checks the URL and the login
+        get_storage(&data.api_url, &source, &user).list(&source.id).await.map_err(str_err_prefix("Error Storage Listing"))?;
+
+        let watcher = SherryConfigWatcherJSON {
+            source: format!("{}@{}", &user.user_id, &url),
+            local_path: local_path.to_string(),
+            hashes_id: generate_random_id(),
+            user_id: user.user_id.clone(),
+            complete: false,
+            mode,
+            force: false,
+            include_paths: vec![],
+            ignore_paths: vec![],
+        };
+        self.mutate(|update| {
+            if let Some(other) = update.data.watchers.iter().find(|w| is_overlapping_path(&w.local_path, &watcher.local_path)) {
+                return Err(format!("{} overlaps with the watcher at {}", &watcher.local_path, &other.local_path));
+            }
+            update.auth.records.insert(user.user_id.clone(), user.clone());
+            update.data.sources.entry(watcher.source.clone()).or_insert(source);
+            update.data.watchers.push(watcher.clone());
+            Ok(())
+        }).await?;
+        Ok(watcher)
+    }
+    // Local copies are kept. Without `confirm`, folders that are large or shared with others are refused.
+    pub async fn delete_folder(&mut self, source: &String, confirm: bool) -> Result<(), String> {
+        let data = self.get_main().await;
+        let auth = self.get_auth().await;
+        let (key, source) = find_source(&data, source)?;
+        if source.access != AccessRights::Owner {
+            return Err(format!("Only the owner can delete {}", &source.name));
+        }
+        let user = auth.records.get(&source.user_id).ok_or(format!("Unknown user {}", &source.user_id))?;
+        let client = ApiClient::new(&data.api_url, &user.access_token);
+
+        if !confirm {
+            let folder = client.get_folder(&source.id).await.map_err(str_err_prefix("Error Folder Fetch"))?;
+            let shared_with = folder.sherry_permission.iter().filter(|p| p.user_id != source.user_id).count();
+            if shared_with > 0 {
+                return Err(format!("{} is shared with {} other users, confirm to delete it", &source.name, shared_with));
+            }
+            let files = client.get_folder_files(&source.id).await.map_err(str_err_prefix("Error Folder Files Fetch"))?.len();
+            if files >= FOLDER_DELETE_CONFIRM_FILES {
+                return Err(format!("{} has {} files, confirm to delete it", &source.name, files));
+            }
+        }
+
+        client.delete_folder(&source.id).await.map_err(str_err_prefix("Error Folder Delete"))?;
+        log::info!("Deleted folder {} ({})", &source.name, &source.id);
+        self.remove_source(&key).await
+    }
+    pub async fn archive_folder(&mut self, source: &String, archived: bool) -> Result<SherryConfigSourceJSON, String> {
+        let data = self.get_main().await;
+        let auth = self.get_auth().await;
+        let (key, source) = find_source(&data, source)?;
+        if source.access != AccessRights::Owner {
+            return Err(format!("Only the owner can archive {}", &source.name));
+        }
+        let user = auth.records.get(&source.user_id).ok_or(format!("Unknown user {}", &source.user_id))?;
+
+        let folder = ApiClient::new(&data.api_url, &user.access_token).set_folder_archived(&source.id, archived).await
+            .map_err(str_err_prefix("Error Folder Archive"))?;
+        let updated = SherryConfigSourceJSON {
+            max_upload_kbps: source.max_upload_kbps,
+            max_download_kbps: source.max_download_kbps,
+            verify_uploads: source.verify_uploads,
+            sync_permissions: source.sync_permissions,
+            priority: source.priority,
+            features: source.features.clone(),
+            download_variant: source.download_variant.clone(),
+            ..response_to_folder(&folder, &source.user_id).map_err(|e| e.to_string())?
+        };
+        self.mutate(|update| {
+            update.data.sources.insert(key.clone(), updated.clone());
+            Ok(())
+        }).await?;
+        Ok(updated)
+    }
+    // Watchers of known users are taken over with their sources, the others wait for their users to log in and import again.
+    // Hash stores come along, so files already copied to this machine aren't downloaded again.
+    pub async fn import_bundle(&mut self, bundle: &SherryBundleJSON, path_map: &Vec<(String, String)>) -> Result<BundleImportResult, String> {
+        if bundle.version != BUNDLE_VERSION {
+            return Err(format!("Unsupported bundle version {}", bundle.version));
+        }
+        let data = self.get_main().await;
+        let auth = self.get_auth().await;
+        let hashes_dir = get_hashes_dir(&self.get_path(), &data);
+
+        let users = auth.records.keys().cloned().collect::<HashSet<String>>();
+        let (selected, skipped) = select_watchers(&data, &users, bundle, path_map);
+        let mut result = BundleImportResult {
+            imported: vec![],
+            skipped,
+            missing_users: bundle.users.iter().filter(|u| !users.contains(&u.user_id)).cloned().collect(),
+        };
+        let mut watchers = vec![];
+        for watcher in selected {
+            // A missing directory is created and downloaded in full
+            if let Err(e) = fs::create_dir_all(&watcher.local_path).await {
+                result.skipped.push((watcher.local_path.clone(), e.to_string()));
+                continue;
+            }
+            if let Some(hashes) = bundle.hashes.iter().find(|h| h.id == watcher.hashes_id) {
+                fs::create_dir_all(&hashes_dir).await.map_err(str_err_prefix("Error hashes dir creation"))?;
+                update_hashes(&hashes_dir, &remap_hashes(hashes, path_map)).await?;
+            }
+            result.imported.push(watcher.local_path.clone());
+            watchers.push(watcher);
+        }
+
+        self.mutate(|update| {
+            update.data = merge_bundle(&update.data, bundle, &watchers);
+            if update.auth.default.is_empty() && update.auth.records.contains_key(&bundle.default_user) {
+                update.auth.default = bundle.default_user.clone();
+            }
+            Ok(())
+        }).await?;
+        Ok(result)
+    }
+    pub async fn remove_source(&mut self, source: &String) -> Result<(), String> {
+        self.mutate(|update| {
+            let key = update.data.sources.iter()
+                .find_map(|(k, s)| if k == source || &s.id == source { Some(k.clone()) } else { None })
+                .ok_or(format!("Unknown source {}", source))?;
+            update.data.sources.remove(&key);
+            update.data.watchers.retain(|w| w.source != key);
+            Ok(())
+        }).await
+    }
+    // A changed allowlist marks the watcher incomplete, so newly included paths are fetched
+    pub async fn include_watcher_path(&mut self, local_path: &str, path: &str) -> Result<SherryConfigWatcherJSON, String> {
+        let path = canonicalize_sync_path(path);
+        let mut updated = None;
+        self.mutate(|update| {
+            let watcher = update.data.watchers.iter_mut()
+                .find(|w| Path::new(&w.local_path) == Path::new(local_path))
+                .ok_or(format!("Unknown watcher {}", local_path))?;
+            if !watcher.include_paths.iter().any(|p| canonicalize_sync_path(p) == path) {
+                watcher.include_paths.push(path.clone());
+            }
+            updated = Some(watcher.clone());
+            Ok(())
+        }).await?;
+        Ok(updated.unwrap())
+    }
+    // Local copies of excluded paths are kept, they just stop syncing
+    pub async fn exclude_watcher_path(&mut self, local_path: &str, path: &str) -> Result<SherryConfigWatcherJSON, String> {
+        let path = canonicalize_sync_path(path);
+        let mut updated = None;
+        self.mutate(|update| {
+            let watcher = update.data.watchers.iter_mut()
+                .find(|w| Path::new(&w.local_path) == Path::new(local_path))
+                .ok_or(format!("Unknown watcher {}", local_path))?;
+            let include_paths = watcher.include_paths.iter()
+                .filter(|p| canonicalize_sync_path(p) != path)
+                .cloned()
+                .collect::<Vec<String>>();
+            if include_paths.len() == watcher.include_paths.len() {
+                return Err(format!("{} is not included by the watcher at {}", path, local_path));
+            }
+            if include_paths.is_empty() {
+                return Err("Can't remove the last include path, the watcher would sync the whole folder".to_string());
+            }
+            watcher.include_paths = include_paths;
+            updated = Some(watcher.clone());
+            Ok(())
+        }).await?;
+        Ok(updated.unwrap())
+    }
+    // Updated users make `apply_update` reconnect the socket with the new tokens
+    pub async fn update_credentials(&mut self, users: &Vec<Credentials>) -> Result<(), String> {
+        self.mutate(|update| {
+            for user in users {
+                if let Some(record) = update.auth.records.get_mut(&user.user_id) {
+                    *record = user.clone();
+                }
+            }
+            Ok(())
+        }).await
+    }
+    // Only the folder tokens, a refresh of the account token in the meantime is kept
+    pub async fn set_folder_tokens(&mut self, user_id: &String, folder_tokens: &HashMap<String, FolderTokenJSON>) -> Result<(), String> {
+        self.mutate(|update| {
+            if let Some(record) = update.auth.records.get_mut(user_id) {
+                record.folder_tokens = folder_tokens.clone();
+            }
+            Ok(())
+        }).await
+    }
+    // The first user becomes the default one
+    pub async fn add_user(&mut self, user: &Credentials) -> Result<(), String> {
+        self.mutate(|update| {
+            update.auth.records.insert(user.user_id.clone(), user.clone());
+            if update.auth.default.is_empty() {
+                update.auth.default = user.user_id.clone();
+            }
+            Ok(())
+        }).await
+    }
+    // Incomplete watchers are fetched again, which reconciles whatever an aborted batch left behind
+    pub async fn reset_source_watchers(&mut self, source_id: &String) -> Result<(), String> {
+        self.mutate(|update| {
+            for watcher in update.data.watchers.iter_mut().filter(|w| &w.source == source_id) {
+                watcher.complete = false;
+            }
+            Ok(())
+        }).await
+    }
+    pub async fn set_default_user(&mut self, user_id: &String) -> Result<(), String> {
+        self.mutate(|update| {
+            if !update.auth.records.contains_key(user_id) {
+                return Err(format!("Unknown user {}", user_id));
+            }
+            update.auth.default = user_id.clone();
+            Ok(())
+        }).await
+    }
     pub async fn reinitialize(&mut self) {
         log::info!("Reinitialize state");
         {
@@ -461,19 +1414,36 @@ impl SherryConfig {
                     sources: Default::default(),
                     watchers: vec![],
                     webhooks: vec![],
+                    hashes_dir: None,
+                    logs_dir: None,
+                    max_retries: None,
+                    max_concurrent_uploads: None,
+                    proxy: None,
+                    tls: None,
+                    use_keychain: None,
+                    watchdog: None,
+                    write_cooldown: None,
+                    templates: None,
+                    features: None,
+                    event_queue_capacity: None,
+                    load_governor: None,
+                    change_journal: None,
+                    batch_approval: None,
+                    reconcile_interval: None,
                 },
                 auth: SherryAuthorizationConfigJSON { default: "".to_string(), records: Default::default() },
             },
             new: SherryConfigUpdateData { data, auth },
         }, true).await;
     }
-    pub async fn listen(self_mutex: &Arc<Mutex<SherryConfig>>, socket: &Arc<Mutex<SocketClient>>, watcher: &Arc<Mutex<Debouncer<RecommendedWatcher, FileIdMap>>>) {
+    pub async fn listen(self_mutex: &Arc<Mutex<SherryConfig>>, socket: &Arc<Mutex<SocketClient>>, watcher: &Arc<Mutex<SherryDebouncer>>) {
         async {
             let mut instance = self_mutex.lock().await;
             { instance.debouncer.lock().await.watcher().watch(&instance.get_path(), RecursiveMode::Recursive).unwrap(); }
             *instance.watchers_debouncer.lock().await = Some(watcher.clone());
             *instance.socket.lock().await = Some(socket.clone());
-            instance.reinitialize().await
+            instance.reinitialize().await;
+            finish_startup();
         }.await;
         let receiver = async {
             let config = self_mutex.lock().await;
@@ -481,7 +1451,6 @@ impl SherryConfig {
             receiver
         }.await;
         for update in receiver.lock().await.iter() {
-            log::info!("Config updated {:?}", &update.new);
             self_mutex.lock().await.apply_update(&update, false).await;
         }
     }
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+use serde_diff::Diff;
+
+use crate::config::{SherryConfigJSON, SherryConfigUpdateData};
+use crate::helpers::get_now_as_millis;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigDiff {
+    pub timestamp: i128,
+    pub api_url_changed: bool,
+    pub socket_url_changed: bool,
+    pub sources_added: Vec<String>,
+    pub sources_removed: Vec<String>,
+    pub sources_changed: Vec<String>,
+    // local paths
+    pub watchers_added: Vec<String>,
+    pub watchers_removed: Vec<String>,
+    pub watchers_changed: Vec<String>,
+    pub users_added: Vec<String>,
+    pub users_removed: Vec<String>,
+    pub users_changed: Vec<String>,
+    // serde_diff changes of config.json, auth.json is summarized by user ids only to keep tokens out of the logs
+    pub changes: serde_json::Value,
+}
+
+fn diff_keys<K: Eq + Hash + Clone, V: PartialEq>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> (Vec<K>, Vec<K>, Vec<K>) {
+    let added = new.keys().filter(|k| !old.contains_key(*k)).cloned().collect();
+    let removed = old.keys().filter(|k| !new.contains_key(*k)).cloned().collect();
+    let changed = new.iter().filter(|(k, v)| old.get(*k).is_some_and(|o| o != *v)).map(|(k, _)| k.clone()).collect();
+    (added, removed, changed)
+}
+
+fn redact(config: &SherryConfigJSON) -> SherryConfigJSON {
+    SherryConfigJSON {
+        proxy: config.proxy.as_ref().map(|p| match p.rsplit_once('@') {
+            Some((_, host)) => format!("***@{}", host),
+            None => p.clone(),
+        }),
+        ..config.clone()
+    }
+}
+
+impl ConfigDiff {
+    pub fn new(old: &SherryConfigUpdateData, new: &SherryConfigUpdateData) -> Self {
+        let watchers = |data: &SherryConfigJSON| data.watchers.iter().map(|w| (w.hashes_id.clone(), w.clone())).collect::<HashMap<_, _>>();
+        let (old_watchers, new_watchers) = (watchers(&old.data), watchers(&new.data));
+        let local_paths = |ids: Vec<String>| ids.iter()
+            .filter_map(|id| new_watchers.get(id).or(old_watchers.get(id)).map(|w| w.local_path.clone()))
+            .collect::<Vec<String>>();
+
+        let (sources_added, sources_removed, sources_changed) = diff_keys(&old.data.sources, &new.data.sources);
+        let (watchers_added, watchers_removed, watchers_changed) = diff_keys(&old_watchers, &new_watchers);
+        let (users_added, users_removed, users_changed) = diff_keys(&old.auth.records, &new.auth.records);
+
+        let (old_data, new_data) = (redact(&old.data), redact(&new.data));
+        ConfigDiff {
+            timestamp: get_now_as_millis(),
+            api_url_changed: old.data.api_url != new.data.api_url,
+            socket_url_changed: old.data.socket_url != new.data.socket_url,
+            sources_added,
+            sources_removed,
+            sources_changed,
+            watchers_added: local_paths(watchers_added),
+            watchers_removed: local_paths(watchers_removed),
+            watchers_changed: local_paths(watchers_changed),
+            users_added,
+            users_removed,
+            users_changed,
+            changes: serde_json::to_value(Diff::serializable(&old_data, &new_data)).unwrap_or_default(),
+        }
+    }
+}
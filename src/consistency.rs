@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::auth::Credentials;
+use crate::config::{get_hashes_dir, SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::event::event_processing::get_event_queue_stats;
+use crate::event::file_event::get_sync_path;
+use crate::event::journal::list_journal_entries;
+use crate::hash::read_hashes;
+use crate::hash_store::list_stores;
+use crate::helpers::{canonicalize_sync_path, sync_path_to_local};
+use crate::server::storage::get_storage;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ConsistencyCategory {
+    MissingLocally,
+    MissingRemotely,
+    HashMismatch,
+    OrphanedState,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyIssueJSON {
+    pub category: ConsistencyCategory,
+    // userId@folderId, none for state no source owns anymore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    // sync path, or the id of an orphaned hash store
+    pub path: String,
+    // what resolves it
+    pub action: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReportJSON {
+    pub user_id: String,
+    pub sources: Vec<String>,
+    // paths compared, pending ones left out
+    pub checked: usize,
+    // changes queued or journaled, they are expected to differ until they are sent
+    pub pending: usize,
+    // source -> why it couldn't be compared
+    pub unchecked: BTreeMap<String, String>,
+    pub issues: Vec<ConsistencyIssueJSON>,
+}
+
+struct SourceCheck {
+    checked: usize,
+    issues: Vec<ConsistencyIssueJSON>,
+}
+
+fn issue(category: ConsistencyCategory, source: &str, path: &str, action: &str) -> ConsistencyIssueJSON {
+    ConsistencyIssueJSON { category, source: Some(source.to_string()), path: path.to_string(), action: action.to_string() }
+}
+
+// The hash store against the disk and the listing, nothing is hashed: a store entry stands for the file it was written for
+async fn check_watcher(hashes_dir: &Path, key: &str, watcher: &SherryConfigWatcherJSON, remote: &BTreeMap<String, String>, pending: &HashSet<String>, check: &mut SourceCheck) -> Result<(), String> {
+    let watcher_path = PathBuf::from(&watcher.local_path);
+    let hashes = read_hashes(hashes_dir, &watcher.hashes_id).await?;
+    if hashes.local_path != watcher.local_path {
+        return Err(format!("hash store {} belongs to {}", &hashes.local_path, &watcher.local_path));
+    }
+    let local = hashes.hashes.iter()
+        .filter(|(_, h)| !h.hash.is_empty())
+        .map(|(path, h)| (get_sync_path(Path::new(path), &watcher_path), h.hash.clone()))
+        .filter(|(path, _)| watcher.is_included(path) && !pending.contains(path))
+        .collect::<BTreeMap<String, String>>();
+    let remote = remote.iter()
+        .filter(|(path, _)| watcher.is_included(path) && !pending.contains(*path))
+        .collect::<BTreeMap<&String, &String>>();
+
+    for (path, hash) in &local {
+        check.checked += 1;
+        let is_on_disk = sync_path_to_local(&watcher_path, path).is_ok_and(|p| p.is_file());
+        match remote.get(path) {
+            Some(_) if !is_on_disk => check.issues.push(issue(ConsistencyCategory::MissingLocally, key, path,
+                "removed locally without the removal being synced, `fetch` restores it")),
+            Some(r) if *r != hash => check.issues.push(issue(ConsistencyCategory::HashMismatch, key, path,
+                "the next reconciliation keeps the newer side")),
+            Some(_) => {}
+            None if !is_on_disk => check.issues.push(issue(ConsistencyCategory::OrphanedState, key, path,
+                "gone on both sides, dropped by the next rescan")),
+            None if watcher.mode.can_upload() => check.issues.push(issue(ConsistencyCategory::MissingRemotely, key, path,
+                "uploaded by the next reconciliation")),
+            None => check.issues.push(issue(ConsistencyCategory::MissingRemotely, key, path,
+                "the watcher doesn't upload, removed locally by the next fetch")),
+        }
+    }
+    for path in remote.keys().filter(|p| !local.contains_key(**p)) {
+        check.checked += 1;
+        if sync_path_to_local(&watcher_path, path).is_ok_and(|p| p.is_file()) {
+            check.issues.push(issue(ConsistencyCategory::HashMismatch, key, path, "the local file isn't in the hash store, the next fetch compares it"));
+        } else if watcher.mode.can_download() {
+            check.issues.push(issue(ConsistencyCategory::MissingLocally, key, path, "downloaded by the next fetch"));
+        }
+    }
+    Ok(())
+}
+
+async fn check_source(hashes_dir: &Path, api_url: &String, key: &str, source: &SherryConfigSourceJSON, watchers: Vec<&SherryConfigWatcherJSON>, user: &Credentials, pending: &HashSet<String>) -> Result<SourceCheck, String> {
+    if let Some(w) = watchers.iter().find(|w| !w.complete) {
+        return Err(format!("watcher {} is still fetching", &w.local_path));
+    }
+    let remote = get_storage(api_url, source, user).list(&source.id).await?.into_iter()
+        .filter(|f| !f.hash.is_empty())
+        .map(|f| (canonicalize_sync_path(&f.path), f.hash))
+        .collect::<BTreeMap<String, String>>();
+    let mut check = SourceCheck { checked: 0, issues: vec![] };
+    for watcher in watchers {
+        check_watcher(hashes_dir, key, watcher, &remote, pending, &mut check).await?;
+    }
+    Ok(check)
+}
+
+// Every source of the account, one listing each. State left behind by removed sources is reported with it: hash stores
+// no watcher uses and journaled changes of sources that are gone.
+pub async fn check_consistency(app: &App, user_id: &Option<String>) -> Result<ConsistencyReportJSON, String> {
+    let (dir, config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await)
+    };
+    let user_id = user_id.clone().unwrap_or(auth.default.clone());
+    let user = auth.records.get(&user_id).ok_or(format!("Unknown user {}", user_id))?;
+    let hashes_dir = get_hashes_dir(&dir, &config);
+    let journal = list_journal_entries(&dir).await?;
+    let queues = get_event_queue_stats();
+
+    let mut report = ConsistencyReportJSON { user_id: user_id.clone(), sources: vec![], checked: 0, pending: 0, unchecked: BTreeMap::new(), issues: vec![] };
+    let mut sources = config.sources.iter().filter(|(_, s)| s.user_id == user_id).collect::<Vec<_>>();
+    sources.sort_by_key(|(k, _)| *k);
+    for (key, source) in sources {
+        report.sources.push(key.clone());
+        let entries = journal.iter().filter(|e| &e.source == key).collect::<Vec<_>>();
+        let pending = entries.iter()
+            .flat_map(|e| [e.event.sync_path.to_string(), e.event.old_sync_path.to_string()])
+            .collect::<HashSet<String>>();
+        report.pending += entries.len() + queues.get(key).map_or(0, |q| q.depth);
+        let watchers = config.watchers.iter().filter(|w| &w.source == key).collect::<Vec<_>>();
+        match check_source(&hashes_dir, &config.api_url, key, source, watchers, user, &pending).await {
+            Ok(check) => {
+                report.checked += check.checked;
+                report.issues.extend(check.issues);
+            }
+            Err(e) => {
+                report.unchecked.insert(key.clone(), e);
+            }
+        }
+    }
+
+    let prefix = format!("{}@", user_id);
+    for e in journal.iter().filter(|e| e.source.starts_with(&prefix) && !config.sources.contains_key(&e.source)) {
+        report.issues.push(issue(ConsistencyCategory::OrphanedState, &e.source, &e.event.sync_path, "journaled for a removed source, dropped at the next start"));
+    }
+    let used = config.watchers.iter().map(|w| w.hashes_id.clone()).collect::<HashSet<String>>();
+    for (id, _) in list_stores(&hashes_dir).await?.into_iter().filter(|(id, _)| !used.contains(id)) {
+        report.issues.push(ConsistencyIssueJSON { category: ConsistencyCategory::OrphanedState, source: None, path: id, action: "hash store of no watcher, removed by `prune`".to_string() });
+    }
+    report.issues.sort_by(|a, b| (a.category, &a.source, &a.path).cmp(&(b.category, &b.source, &b.path)));
+    Ok(report)
+}
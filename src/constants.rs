@@ -1,14 +1,117 @@
 pub const ENV_CONFIG_DIR: &str = "SHERRY_CONFIG_PATH";
 pub const ENV_API_URL: &str = "SHERRY_API_URL";
 pub const ENV_SOCKET_URL: &str = "SHERRY_SOCKET_URL";
+pub const ENV_CONTAINER: &str = "SHERRY_CONTAINER";
+pub const ENV_HEALTH_PORT: &str = "SHERRY_HEALTH_PORT";
+pub const ENV_API_KEY: &str = "SHERRY_API_KEY";
+pub const ENV_WATCHERS: &str = "SHERRY_WATCHERS";
+pub const ENV_XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+pub const ENV_XDG_STATE_HOME: &str = "XDG_STATE_HOME";
+pub const ENV_PROXIES: &[&str] = &["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"];
 
 pub const DEFAULT_API_URL: &str = "http://localhost:3000";
 pub const DEFAULT_SOCKET_URL: &str = "ws://localhost:3001";
+pub const DEFAULT_HEALTH_PORT: u16 = 8080;
+pub const KEYCHAIN_SERVICE: &str = "sherry-demon";
 
 pub const CONFIG_DIR: &str = ".sherry";
+pub const CONTAINER_CONFIG_DIR: &str = "/data";
+pub const XDG_APP_DIR: &str = "sherry";
+pub const XDG_CONFIG_HOME_DEFAULT: &str = ".config";
+pub const XDG_STATE_HOME_DEFAULT: &str = ".local/state";
 pub const LOGS_DIR: &str = "logs";
 pub const CONFIG_FILE: &str = "config.json";
 pub const AUTH_FILE: &str = "auth.json";
 pub const HASHES_DIR: &str = "hashes";
+pub const HASHES_DB: &str = "hashes.db";
+pub const HASHES_DB_BACKUP: &str = "hashes.db.bak";
+pub const HASHES_DB_CORRUPT: &str = "hashes.db.corrupt";
+pub const IPC_FILE: &str = "ipc.json";
+pub const HISTORY_DIR: &str = "history";
+pub const DEAD_LETTERS_FILE: &str = "dead_letters.json";
+pub const JOURNAL_FILE: &str = "journal.json";
+pub const QUARANTINE_FILE: &str = "quarantine.json";
+pub const HOLDS_FILE: &str = "holds.json";
+pub const PRIMARY_FILE: &str = "primary.json";
+pub const MANIFESTS_FILE: &str = "manifests.json";
+pub const WATCHER_OWNER_FILE: &str = ".sherry-owner.json"; // in the root of every watched folder
+pub const CONFIG_HISTORY_SIZE: usize = 20;
+pub const NOTIFICATIONS_SIZE: usize = 50;
+pub const SKIPPED_UPLOADS_SIZE: usize = 100; // per source
+pub const MANIFESTS_SIZE: usize = 20; // pending manifests are kept on top of these
+pub const DRIFT_HISTORY_SIZE: usize = 50;
+pub const DEFAULT_APPROVAL_MAX_BYTES: u64 = 1073741824; // 1 GiB
+pub const DEFAULT_APPROVAL_MAX_FILES: usize = 500;
+pub const NOTIFICATIONS_REPEAT_DELAY: u64 = 60; // seconds
 pub const EXPIRATION_THRESHOLD: i32 = 604800; // 1 week in seconds
+pub const TOKEN_REFRESH_INTERVAL: u64 = 3600; // seconds
+pub const TOKEN_REFRESH_RETRIES: u32 = 5;
+pub const TOKEN_REFRESH_BACKOFF: u64 = 5; // seconds, doubled with every attempt
+pub const FOLDER_TOKEN_THRESHOLD: i32 = 7200; // seconds, folder tokens are minted again two refresh rounds before they run out
+pub const DEVICE_LOGIN_SLOW_DOWN: u64 = 5; // seconds added to the poll interval when asked to slow down
+pub const LOGS_RETENTION: u64 = 1209600; // 2 weeks in seconds
+pub const DEAD_LETTERS_RETENTION: u64 = 2592000; // 30 days in seconds, for `prune`
+pub const CHANGES_RETENTION: u64 = 7776000; // 90 days in seconds, how far back `prune` keeps the file history
+pub const POLL_INTERVAL: u64 = 2; // seconds
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: u32 = 4;
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: u32 = 100;
+pub const RETRY_DELAY: u64 = 1; // seconds, multiplied by the attempt number
+pub const DUPLICATE_EVENT_WINDOW: u64 = 30; // seconds, the same change from another watcher of the source is dropped
+pub const RETRY_QUEUE_BACKOFF: u64 = 30; // seconds, doubled with every attempt
+pub const RETRY_QUEUE_BACKOFF_MAX: u64 = 3600; // seconds
+pub const RETRY_QUEUE_MAX_ATTEMPTS: u32 = 8;
+pub const RETRY_QUEUE_TICK: u64 = 1; // seconds
+pub const QUARANTINE_REJECTIONS: u32 = 3; // in a row
+pub const INTEGRITY_MIN_MISMATCHES: u64 = 3;
+pub const INTEGRITY_MISMATCH_RATIO: f64 = 0.05;
+pub const INTEGRITY_VERIFY_ATTEMPTS: u32 = 3;
+pub const TRANSFER_CONCURRENCY: usize = 8; // initial limit, adjusted to the observed throughput
+pub const TRANSFER_CONCURRENCY_MIN: usize = 1;
+pub const TRANSFER_CONCURRENCY_MAX: usize = 64;
+pub const TRANSFER_THROUGHPUT_DROP: f64 = 0.5; // backs off when throughput falls below this share of the previous window
+pub const PROGRESS_STEP: u64 = 1024 * 1024; // bytes between progress events of transfers without a known size
+pub const HASH_CONCURRENCY: usize = 16; // files hashed at once while a whole watcher is hashed
+pub const PROGRESS_SUBSCRIBER_BACKLOG: usize = 256; // events kept for a slow subscriber before it skips ahead
+pub const SELF_WRITE_WINDOW: u64 = 5; // seconds
+pub const HELD_EVENTS_MAX: usize = 10000; // remote events held while their folders are fetched
+pub const WRITE_COOLDOWN_MIN: u64 = 2; // seconds, first cooldown of a file written again within `writeCooldown`
+pub const SLOW_REQUEST_THRESHOLD: u64 = 5; // seconds
+pub const DEFAULT_SHARE_LINK_EXPIRY: u64 = 86400; // seconds
+pub const SHARE_LINK_EXPIRY_MAX: u64 = 2592000; // 30 days in seconds
+pub const FOLDER_DELETE_CONFIRM_FILES: usize = 100; // deleting a folder with more files has to be confirmed
+pub const STORAGE_POLL_INTERVAL: u64 = 60; // seconds, servers without a socket are listed again this often
+pub const LOAD_SAMPLE_INTERVAL: u64 = 5; // seconds, system load is checked this often while the governor is on
+pub const DEFAULT_LOAD_MAX_DELAY: u64 = 300; // seconds
+pub const PRIMARY_HEARTBEAT_INTERVAL: u64 = 5; // seconds
+pub const PRIMARY_TAKEOVER_TIMEOUT: u64 = 30; // seconds without a heartbeat before a standby takes over
+pub const OWNER_HEARTBEAT_INTERVAL: u64 = 30; // seconds, the marker in the root of every watched folder is rewritten this often
+pub const OWNER_STALE_TIMEOUT: u64 = 120; // seconds without a heartbeat before a folder is taken from another demon
+pub const PRIMARY_CLAIM_SETTLE: u64 = 2; // seconds, standbys claiming at once find out which of them won
+pub const DRIFT_REPAIR_DELAY: u64 = 30; // seconds, a drifted path is verified again after at least this long
+pub const QUIESCE_SETTLE: u64 = 2; // seconds, events of rewatched sources are still dropped this long after the setup
+pub const QUIESCE_TICK: u64 = 1; // seconds
+pub const FETCHED_CHANGES_TICK: u64 = 1; // seconds, local changes found by fetches are sent this often
+pub const SWITCH_DRAIN_TIMEOUT: u64 = 60; // seconds a switch of the config directory waits for queued changes
+pub const RECONCILE_INTERVAL_MIN: u64 = 300; // seconds, shorter `reconcileInterval`s rescan big folders back to back
+pub const RECONCILE_TICK: u64 = 10; // seconds
+pub const SYNC_BARRIER_TICK: u64 = 1; // seconds between checks of `wait-until-synced`
+#[cfg(target_os = "macos")]
+pub const CHANGE_JOURNAL_REPLAY_TIMEOUT: u64 = 30; // seconds, FSEvents history of a watcher that takes longer is walked instead
+pub const FEATURES_REFRESH_INTERVAL: u64 = 900; // seconds, server-provided feature flags are fetched again this often
+pub const WEBDAV_NAMESPACE: &str = "urn:sherry:sync"; // of the dead property holding the content hash
+pub const DEFAULT_S3_REGION: &str = "us-east-1"; // MinIO and most other S3-compatible servers accept any region
+pub const S3_HASH_TAG: &str = "sherry-hash";
+pub const S3_TAG_CONCURRENCY: usize = 16; // tag requests at once while listing a bucket
+pub const VARIANT_HEADER: &str = "x-sherry-variant"; // names the variant a download is, missing for the original
 
+
+pub const CRITICAL_PATHS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/opt", "/proc", "/root", "/sbin", "/sys", "/usr", "/var",
+    "/System", "/Library", "/Applications", "/Users",
+    "C:\\", "C:\\Windows", "C:\\Program Files", "C:\\Program Files (x86)", "C:\\ProgramData", "C:\\Users",
+];
+
+// Names editors give the temp file they write before renaming it over the real one, or the backup they rename the real one to
+pub const ATOMIC_SAVE_PREFIXES: &[&str] = &[".#", ".goutputstream-"];
+pub const ATOMIC_SAVE_SUFFIXES: &[&str] = &[".tmp", "~", ".swp", ".swx", ".crswap", "___jb_tmp___", "___jb_old___"];
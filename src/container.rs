@@ -0,0 +1,113 @@
+use std::env;
+use std::path::Path;
+
+use path_clean::PathClean;
+
+use crate::app::App;
+use crate::auth::login_with_api_key;
+use crate::config::SyncMode;
+use crate::constants::{ENV_API_KEY, ENV_WATCHERS};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct EnvWatcher {
+    folder_id: String,
+    local_path: String,
+    mode: SyncMode,
+}
+
+// `<folder id>:<path>[:<mode>]`, like the volumes of a container
+fn parse_watcher(entry: &str) -> Result<EnvWatcher, String> {
+    let mut parts = entry.splitn(3, ':');
+    let (folder_id, local_path) = match (parts.next(), parts.next()) {
+        (Some(folder_id), Some(local_path)) if !folder_id.is_empty() && Path::new(local_path).is_absolute() => (folder_id, local_path),
+        _ => return Err(format!("Invalid watcher {}, expected <FOLDER ID>:<ABSOLUTE PATH>[:<MODE>]", entry)),
+    };
+    let mode = match parts.next() {
+        Some(mode) => serde_json::from_value(serde_json::Value::String(mode.to_uppercase())).map_err(|_| format!("Invalid sync mode {}", mode))?,
+        None => SyncMode::default(),
+    };
+    Ok(EnvWatcher { folder_id: folder_id.to_string(), local_path: Path::new(local_path).clean().to_str().unwrap().to_string(), mode })
+}
+
+fn parse_watchers(value: &str) -> Vec<Result<EnvWatcher, String>> {
+    value.split(',').map(str::trim).filter(|e| !e.is_empty()).map(parse_watcher).collect()
+}
+
+// The user of the key, logged in the first time the key is seen
+async fn add_env_user(app: &App, api_key: &String) -> Result<String, String> {
+    let (config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_main().await, config.get_auth().await)
+    };
+    if let Some(user) = auth.records.values().find(|u| &u.access_token == api_key) {
+        return Ok(user.user_id.clone());
+    }
+    let user = login_with_api_key(&config.api_url, api_key).await?;
+    app.config.lock().await.add_user(&user).await?;
+    log::info!("Added user {} from {}", &user.username, ENV_API_KEY);
+    Ok(user.user_id)
+}
+
+// Watchers listed in the environment that the config doesn't have yet are added like with `watcher add`. Entries removed
+// from the environment are left in the config, they are removed with `source remove`.
+async fn add_env_watchers(app: &App, user_id: &Option<String>, watchers: Vec<EnvWatcher>) {
+    for watcher in watchers {
+        let config = app.config.lock().await.get_main().await;
+        let is_known = config.watchers.iter().any(|w| {
+            Path::new(&w.local_path).clean() == Path::new(&watcher.local_path) && config.sources.get(&w.source).is_some_and(|s| s.id == watcher.folder_id)
+        });
+        if is_known {
+            continue;
+        }
+        match app.config.lock().await.add_watcher(&watcher.folder_id, &watcher.local_path, user_id, watcher.mode, &None).await {
+            Ok(_) => log::info!("Added watcher {} of folder {} from {}", &watcher.local_path, &watcher.folder_id, ENV_WATCHERS),
+            Err(e) => log::error!("Failed to add watcher {} of folder {} from {}: {}", &watcher.local_path, &watcher.folder_id, ENV_WATCHERS, e),
+        }
+    }
+}
+
+// A container can be set up from its environment alone: `SHERRY_API_KEY` logs a user in and `SHERRY_WATCHERS` lists
+// the folders it syncs, a volume keeping the config directory is only needed to not hash everything again on restarts
+pub fn start_container_setup(app: &App) {
+    let api_key = env::var(ENV_API_KEY).ok().filter(|k| !k.is_empty());
+    let watchers = env::var(ENV_WATCHERS).unwrap_or_default();
+    if api_key.is_none() && watchers.is_empty() {
+        return;
+    }
+    let app = app.clone();
+    tokio::spawn(async move {
+        let user_id = match &api_key {
+            Some(api_key) => match add_env_user(&app, api_key).await {
+                Ok(user_id) => Some(user_id),
+                Err(e) => {
+                    log::error!("Failed to log in with {}: {}", ENV_API_KEY, e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let mut valid = vec![];
+        for watcher in parse_watchers(&watchers) {
+            match watcher {
+                Ok(watcher) => valid.push(watcher),
+                Err(e) => log::error!("Ignoring a watcher of {}: {}", ENV_WATCHERS, e),
+            }
+        }
+        add_env_watchers(&app, &user_id, valid).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_watchers() {
+        let watchers = parse_watchers(" abc:/data/photos , def:/data/docs/:download_only,,ghi:docs,:/x,jkl:/y:sideways");
+        assert_eq!(watchers[0], Ok(EnvWatcher { folder_id: "abc".to_string(), local_path: "/data/photos".to_string(), mode: SyncMode::TwoWay }));
+        assert_eq!(watchers[1], Ok(EnvWatcher { folder_id: "def".to_string(), local_path: "/data/docs".to_string(), mode: SyncMode::DownloadOnly }));
+        assert!(watchers[2..].iter().all(|w| w.is_err()));
+        assert_eq!(watchers.len(), 5);
+    }
+}
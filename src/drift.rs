@@ -0,0 +1,209 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::config::{get_hashes_dir, SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::constants::{DRIFT_HISTORY_SIZE, DRIFT_REPAIR_DELAY};
+use crate::event::event_processing::send_events;
+use crate::event::file_event::{get_file_event, SyncEventKind};
+use crate::files::delete_path;
+use crate::hash::{FileHashJSON, get_file_hash, get_modified_millis, get_hashes, update_hashes};
+use crate::helpers::{canonicalize_sync_path, get_now_as_millis, normalize_path, sync_path_to_local};
+use crate::integrity::download_variant;
+use crate::self_writes::with_self_writes;
+use crate::server::storage::{get_storage, RemoteStorage};
+use crate::server::types::ApiFileResponse;
+
+// The transfer the hash store records as done while the other side disagrees
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DriftSide {
+    Upload,
+    Download,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftJSON {
+    // userId@folderId
+    pub source: String,
+    pub path: String,
+    pub side: DriftSide,
+    pub reason: String,
+    pub timestamp: i128,
+    // what the re-verification did, none while the path waits for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repair: Option<String>,
+}
+
+// Latest drifts, repaired ones are kept to be reported by `status`
+static DRIFTS: std::sync::Mutex<Vec<DriftJSON>> = std::sync::Mutex::new(Vec::new());
+
+pub fn record_drift(source: &String, path: &str, side: DriftSide, reason: String) {
+    let path = canonicalize_sync_path(path);
+    let mut drifts = DRIFTS.lock().unwrap();
+    if drifts.iter().any(|d| &d.source == source && d.path == path && d.repair.is_none()) {
+        return;
+    }
+    log::warn!("Hash store of {} drifted at {}: {}", source, &path, &reason);
+    drifts.push(DriftJSON { source: source.clone(), path, side, reason, timestamp: get_now_as_millis(), repair: None });
+    let excess = drifts.len().saturating_sub(DRIFT_HISTORY_SIZE);
+    drifts.drain(..excess);
+}
+
+pub fn get_drifts() -> Vec<DriftJSON> {
+    DRIFTS.lock().unwrap().clone()
+}
+
+fn set_repair(drift: &DriftJSON, repair: String) {
+    log::info!("Drift of {} at {} repaired: {}", &drift.source, &drift.path, &repair);
+    if let Some(d) = DRIFTS.lock().unwrap().iter_mut().find(|d| d.source == drift.source && d.path == drift.path && d.repair.is_none()) {
+        d.repair = Some(repair);
+    }
+}
+
+fn is_modified_after(path: &Path, timestamp: i128) -> bool {
+    path.metadata().ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .is_some_and(|m| m.as_millis() as i128 > timestamp)
+}
+
+// Both sides agree now: the store only lagged behind. Otherwise the transfer the store claimed is done again, unless the
+// local file changed after the drift was found, then its own change reconciles it.
+async fn repair_path(app: &App, hashes_dir: &PathBuf, source: &SherryConfigSourceJSON, watcher: &SherryConfigWatcherJSON, storage: &dyn RemoteStorage, remote: Option<&ApiFileResponse>, drift: &DriftJSON) -> Result<String, String> {
+    let watcher_path = PathBuf::from(&watcher.local_path);
+    let local_path = sync_path_to_local(&watcher_path, &drift.path)?;
+    let key = normalize_path(&local_path).to_str().unwrap().to_string();
+    let mut hashes = get_hashes(hashes_dir, source, &watcher_path, &watcher.hashes_id).await?;
+    let stored = hashes.hashes.get(&key).cloned();
+    let local_hash = get_file_hash(&local_path).await;
+    let remote_hash = remote.map_or("".to_string(), |r| r.hash.clone());
+
+    if local_hash == remote_hash {
+        match remote {
+            Some(r) => hashes.hashes.insert(key, FileHashJSON {
+                hash: r.hash.clone(),
+                timestamp: r.updated_at,
+                size: r.size,
+                modified: get_modified_millis(&local_path),
+                attributes: stored.and_then(|s| s.attributes),
+                variant: None,
+            }),
+            None => hashes.hashes.remove(&key),
+        };
+        update_hashes(hashes_dir, &hashes).await?;
+        return Ok("hash store corrected".to_string());
+    }
+
+    match drift.side {
+        DriftSide::Upload => {
+            if stored.map_or(String::new(), |s| s.hash) != local_hash {
+                return Ok("local file changed since, left to its own change".to_string());
+            }
+            // Out of the store, otherwise the upload is skipped as already synced
+            hashes.hashes.remove(&key);
+            update_hashes(hashes_dir, &hashes).await?;
+            let kind = match remote {
+                _ if local_hash.is_empty() => SyncEventKind::Deleted,
+                None => SyncEventKind::Created,
+                Some(_) => SyncEventKind::Updated,
+            };
+            send_events(app.clone(), &drift.source, vec![get_file_event(&source.id, &watcher_path, &local_path, kind)]).await;
+            Ok("uploaded again".to_string())
+        }
+        DriftSide::Download => {
+            if is_modified_after(&local_path, drift.timestamp) {
+                return Ok("local file changed since, left to its own change".to_string());
+            }
+            match remote {
+                Some(r) => {
+                    let variant = download_variant(storage, &source.id, &r.path, &local_path, &r.hash, r.size, source.get_download_variant(watcher.mode)).await?;
+                    let attributes = hashes.hashes.get(&key).and_then(|s| s.attributes.clone());
+                    hashes.hashes.insert(key, FileHashJSON {
+                        hash: r.hash.clone(),
+                        timestamp: r.updated_at,
+                        size: if variant.is_some() { local_path.metadata().map_or(0, |m| m.len()) } else { r.size },
+                        modified: get_modified_millis(&local_path),
+                        attributes,
+                        variant,
+                    });
+                    update_hashes(hashes_dir, &hashes).await?;
+                    Ok("downloaded again".to_string())
+                }
+                None => {
+                    with_self_writes(&vec![local_path.clone()], "", delete_path(&local_path)).await?;
+                    hashes.hashes.remove(&key);
+                    update_hashes(hashes_dir, &hashes).await?;
+                    Ok("removed locally".to_string())
+                }
+            }
+        }
+    }
+}
+
+// One listing per source, drifts of sources that can't be listed right now wait for the next round
+async fn repair_source(app: &App, source_key: &String, drifts: Vec<DriftJSON>) {
+    let (dir, config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await)
+    };
+    let source = match config.sources.get(source_key) {
+        Some(source) => source,
+        None => {
+            drifts.iter().for_each(|d| set_repair(d, "source was removed".to_string()));
+            return;
+        }
+    };
+    let user = match auth.records.get(&source.user_id) {
+        Some(user) if user.is_usable() => user,
+        _ => return,
+    };
+    let storage = get_storage(&config.api_url, source, user);
+    let remote = match storage.list(&source.id).await {
+        Ok(files) => files.into_iter().map(|f| (canonicalize_sync_path(&f.path), f)).collect::<HashMap<String, ApiFileResponse>>(),
+        Err(e) => {
+            log::warn!("Failed to list source {} for its drift repair: {}", source_key, e);
+            return;
+        }
+    };
+    let hashes_dir = get_hashes_dir(&dir, &config);
+    for drift in drifts {
+        let remote = remote.get(&drift.path).filter(|r| !r.hash.is_empty());
+        let watchers = config.watchers.iter().filter(|w| {
+            &w.source == source_key && w.is_included(&drift.path) && match drift.side {
+                DriftSide::Upload => w.mode.can_upload(),
+                DriftSide::Download => w.mode.can_download(),
+            }
+        });
+        let mut repairs = vec![];
+        for watcher in watchers {
+            match repair_path(app, &hashes_dir, source, watcher, storage.as_ref(), remote, &drift).await {
+                Ok(repair) => repairs.push(repair),
+                Err(e) => repairs.push(format!("failed: {}", e)),
+            }
+        }
+        set_repair(&drift, if repairs.is_empty() { "no watcher syncs the path anymore".to_string() } else { repairs.join(", ") });
+    }
+}
+
+// Drifts are re-verified after a delay, so transfers of the path that were still running have landed
+pub fn start_drift_repair(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(DRIFT_REPAIR_DELAY)).await;
+            let due = get_now_as_millis() - (DRIFT_REPAIR_DELAY * 1000) as i128;
+            let mut by_source: BTreeMap<String, Vec<DriftJSON>> = BTreeMap::new();
+            for drift in get_drifts().into_iter().filter(|d| d.repair.is_none() && d.timestamp <= due) {
+                by_source.entry(drift.source.clone()).or_default().push(drift);
+            }
+            for (source, drifts) in by_source {
+                repair_source(&app, &source, drifts).await;
+            }
+        }
+    });
+}
@@ -1,2 +1,11 @@
 pub mod file_event;
 pub mod event_processing;
+pub mod dead_letters;
+pub mod optimizer;
+pub mod cooldown;
+pub mod journal;
+pub mod retry_queue;
+pub mod quarantine;
+pub mod holds;
+pub mod limits;
+pub mod manifest;
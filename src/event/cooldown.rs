@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::constants::WRITE_COOLDOWN_MIN;
+use crate::event::file_event::{FileType, SyncEvent, SyncEventKind};
+
+struct PathCooldown {
+    last_upload: Option<Instant>,
+    // grows while the file keeps being rewritten, shrinks once it calms down
+    cooldown: Duration,
+    // an upload of the latest content is already scheduled
+    deferred: bool,
+}
+
+// seconds, 0 disables cooldowns
+static WRITE_COOLDOWN: AtomicU64 = AtomicU64::new(0);
+static COOLDOWNS: std::sync::Mutex<BTreeMap<PathBuf, PathCooldown>> = std::sync::Mutex::new(BTreeMap::new());
+
+pub fn set_write_cooldown(cooldown: &Option<u64>) {
+    WRITE_COOLDOWN.store(cooldown.unwrap_or(0), Ordering::SeqCst);
+}
+
+fn is_write(e: &SyncEvent) -> bool {
+    e.file_type == FileType::File && matches!(e.kind, SyncEventKind::Created | SyncEventKind::Updated)
+}
+
+// (events to send now, events to send after their delay)
+// Writes of a file uploaded shortly before are deferred, the deferred upload hashes whatever the file holds by then,
+// so a file rewritten every few seconds is uploaded at most once per cooldown and its final state is never lost
+pub fn apply_cooldowns(events: Vec<SyncEvent>) -> (Vec<SyncEvent>, Vec<(SyncEvent, Duration)>) {
+    let max = Duration::from_secs(WRITE_COOLDOWN.load(Ordering::SeqCst));
+    if max.is_zero() {
+        return (events, vec![]);
+    }
+
+    let now = Instant::now();
+    let mut cooldowns = COOLDOWNS.lock().unwrap();
+    cooldowns.retain(|_, c| c.deferred || c.last_upload.is_some_and(|t| now.duration_since(t) < max * 2));
+
+    let mut ready = vec![];
+    let mut deferred = vec![];
+    for e in events {
+        if !is_write(&e) {
+            ready.push(e);
+            continue;
+        }
+        let c = cooldowns.entry(e.local_path.to_path_buf()).or_insert(PathCooldown {
+            last_upload: None,
+            cooldown: Duration::ZERO,
+            deferred: false,
+        });
+        if c.deferred {
+            continue;
+        }
+        let elapsed = c.last_upload.map_or(max, |t| now.duration_since(t));
+        c.cooldown = if elapsed < max {
+            (c.cooldown * 2).max(Duration::from_secs(WRITE_COOLDOWN_MIN)).min(max)
+        } else {
+            c.cooldown / 2
+        };
+        if elapsed >= c.cooldown {
+            c.last_upload = Some(now);
+            ready.push(e);
+        } else {
+            log::info!("Deferring upload of {:?} for {}ms, it's written frequently", &e.local_path, (c.cooldown - elapsed).as_millis());
+            c.deferred = true;
+            deferred.push((e, c.cooldown - elapsed));
+        }
+    }
+    (ready, deferred)
+}
+
+// Called right before the deferred upload, later writes start a new cooldown
+pub fn finish_deferred(path: &Path) {
+    if let Some(c) = COOLDOWNS.lock().unwrap().get_mut(path) {
+        c.deferred = false;
+        c.last_upload = Some(Instant::now());
+    }
+}
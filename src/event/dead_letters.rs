@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::app::App;
+use crate::constants::DEAD_LETTERS_FILE;
+use crate::event::event_processing::send_event;
+use crate::event::file_event::SyncEvent;
+use crate::files::{initialize_json_file, write_json_file};
+use crate::helpers::{generate_random_id, get_default_state_dir, get_now_as_millis};
+use crate::server::storage::get_storage;
+
+// Every source debouncer may give up on events at the same time, the file is rewritten as a whole
+static DEAD_LETTERS_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterJSON {
+    pub id: String,
+    pub event: SyncEvent,
+    pub errors: Vec<String>,
+    pub timestamp: i128,
+}
+
+fn get_dead_letters_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(DEAD_LETTERS_FILE)
+}
+
+async fn read_dead_letters(dir: &Path) -> Result<Vec<DeadLetterJSON>, String> {
+    initialize_json_file(get_dead_letters_path(dir), vec![]).await
+}
+
+pub async fn push_dead_letter(dir: &Path, event: &SyncEvent, errors: &[String]) -> Result<(), String> {
+    let _lock = DEAD_LETTERS_LOCK.lock().await;
+    let mut letters = read_dead_letters(dir).await?;
+    log::error!("Moving event for {} to dead letters after {} attempts", &event.sync_path, errors.len());
+    letters.push(DeadLetterJSON {
+        id: generate_random_id(),
+        event: event.clone(),
+        errors: errors.to_vec(),
+        timestamp: get_now_as_millis(),
+    });
+    write_json_file(get_dead_letters_path(dir), &letters).await
+}
+
+pub async fn list_dead_letters(dir: &Path) -> Result<Vec<DeadLetterJSON>, String> {
+    let _lock = DEAD_LETTERS_LOCK.lock().await;
+    read_dead_letters(dir).await
+}
+
+// Letters of folders that are no longer configured, or that nobody resubmitted since `before`
+pub async fn prune_dead_letters(dir: &Path, folders: &HashSet<String>, before: i128) -> Result<usize, String> {
+    let _lock = DEAD_LETTERS_LOCK.lock().await;
+    let mut letters = read_dead_letters(dir).await?;
+    let count = letters.len();
+    letters.retain(|l| folders.contains(&*l.event.source_id) && l.timestamp >= before);
+    if letters.len() != count {
+        write_json_file(get_dead_letters_path(dir), &letters).await?;
+    }
+    Ok(count - letters.len())
+}
+
+async fn take_dead_letters(dir: &Path, id: &Option<String>) -> Result<Vec<DeadLetterJSON>, String> {
+    let _lock = DEAD_LETTERS_LOCK.lock().await;
+    let (taken, kept) = read_dead_letters(dir).await?.into_iter()
+        .partition::<Vec<DeadLetterJSON>, _>(|l| id.as_ref().is_none_or(|id| &l.id == id));
+    write_json_file(get_dead_letters_path(dir), &kept).await?;
+    Ok(taken)
+}
+
+// Sends dead letters again with a fresh retry budget, letters failing again go back with the new errors appended
+pub async fn resubmit_dead_letters(app: &App, id: &Option<String>) -> Result<Vec<String>, String> {
+    let (dir, config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await)
+    };
+
+    let letters = take_dead_letters(&dir, id).await?;
+    if letters.is_empty() && id.is_some() {
+        return Err(format!("Unknown dead letter {}", id.as_ref().unwrap()));
+    }
+
+    let mut sent = vec![];
+    for letter in letters {
+        let source = config.sources.values()
+            .find(|s| *s.id == *letter.event.source_id)
+            .and_then(|s| auth.records.get(&s.user_id).map(|u| (s, u)));
+        let result = match source {
+            Some((source, user)) => send_event(get_storage(&config.api_url, source, user).as_ref(), &letter.event, config.get_max_retries()).await,
+            None => Err(vec![format!("Source {} is not configured", &letter.event.source_id)]),
+        };
+        match result {
+            Ok(_) => sent.push(letter.id),
+            Err(errors) => push_dead_letter(&dir, &letter.event, &[letter.errors, errors].concat()).await?,
+        }
+    }
+    Ok(sent)
+}
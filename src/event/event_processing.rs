@@ -1,24 +1,150 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 use notify_debouncer_full::DebouncedEvent;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::time::Instant;
 
-use crate::config::{AccessRights, SherryConfigWatcherJSON};
-use crate::event::file_event::{complete_events, filter_events, get_sync_events, log_events, minify_results, optimize_events, SyncEvent, SyncEventKind};
-use crate::hash::{FileHashJSON, get_hashes, update_hashes};
+use crate::config::{get_hashes_dir, SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::event::file_event::{add_attributes, complete_events, FileType, filter_events, get_file_event, get_sync_events, log_events, minify_results, SharedPath, SharedStr, SyncEvent, SyncEventKind};
+use crate::event::optimizer::optimize_events;
+use crate::constants::{DUPLICATE_EVENT_WINDOW, RETRY_DELAY};
+use crate::drift::{DriftSide, record_drift};
+use crate::event::cooldown::{apply_cooldowns, finish_deferred};
+use crate::event::holds::{hold_event, is_held};
+use crate::event::limits::check_limits;
+use crate::event::manifest::review_batch;
+use crate::event::journal::{append_journal, queue_journal, remove_journal};
+use crate::event::quarantine::{is_quarantined, record_rejection, record_success};
+use crate::event::retry_queue::queue_retry;
+use crate::hash::{FileHashJSON, get_file_hash, get_hashes, get_modified_millis, update_hashes, WatcherHashJSON};
 use crate::helpers::get_now_as_millis;
-use crate::server::api::ApiClient;
+use crate::integrity::verify_uploads;
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+use crate::self_writes::is_self_write;
+use crate::server::scheduler::schedule_transfer;
+use crate::server::sequence::begin_event;
+use crate::server::storage::{get_storage, RemoteStorage, StorageCheck};
+use crate::watchdog::{finish_file, set_stage, start_file, watch};
+
+// (source id, kind, old sync path, sync path, hash) -> watcher that sent it and when
+type RecentEvents = BTreeMap<(SharedStr, String, SharedStr, SharedStr, String), (SharedPath, Instant)>;
+
+static RECENT_EVENTS: std::sync::Mutex<RecentEvents> = std::sync::Mutex::new(BTreeMap::new());
+
+fn prune_recent_events() {
+    let now = Instant::now();
+    RECENT_EVENTS.lock().unwrap().retain(|_, (_, sent)| now.duration_since(*sent) < Duration::from_secs(DUPLICATE_EVENT_WINDOW));
+}
+
+// Two watchers of one source see the same change when their directories overlap (or are copies kept in sync by
+// something else), only the first one is sent. A watcher repeating its own change is never a duplicate.
+fn is_duplicate(e: &SyncEvent) -> bool {
+    let key = (e.source_id.clone(), e.kind.to_string(), e.old_sync_path.clone(), e.sync_path.clone(), e.update_hash.clone());
+    let mut recent = RECENT_EVENTS.lock().unwrap();
+    match recent.get(&key) {
+        Some((base, _)) if base != &e.base => true,
+        _ => {
+            recent.insert(key, (e.base.clone(), Instant::now()));
+            false
+        }
+    }
+}
+
+// Err holds the error of every attempt once the retry budget is spent, rejections by the server are final and not retried.
+// Ok(false) when the server rejected the event.
+pub async fn send_event(storage: &dyn RemoteStorage, e: &SyncEvent, max_retries: u32) -> Result<bool, Vec<String>> {
+    let in_flight = begin_event(e).await;
+    let mut errors = vec![];
+    for attempt in 0..=max_retries {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(RETRY_DELAY * attempt as u64)).await;
+        }
+
+        match storage.check(e, in_flight.sequence).await {
+            Ok(StorageCheck::Accepted) => {}
+            Ok(StorageCheck::QuotaExceeded(reason)) => {
+                notify(UserMessage::new(MessageCode::QuotaExceeded, &[("folder", &e.source_id)]));
+                log::info!("Event for {} rejected: {}", &e.sync_path, reason);
+                return Ok(false);
+            }
+            Ok(StorageCheck::Rejected(reason)) => {
+                log::info!("Event for {} rejected: {}", &e.sync_path, reason);
+                return Ok(false);
+            }
+            Err(err) => {
+                errors.push(format!("Error verifying file: {}", err));
+                log::error!("{}", errors.last().unwrap());
+                continue;
+            }
+        }
+
+        let send = match e.kind {
+            SyncEventKind::Deleted => storage.delete(e, in_flight.sequence),
+            SyncEventKind::Moved => storage.move_path(e, in_flight.sequence),
+            _ => storage.put(e, in_flight.sequence),
+        };
+        match schedule_transfer(&e.source_id, e.size, e.kind == SyncEventKind::Deleted, send).await {
+            Ok(_) => return Ok(true),
+            Err(err) => {
+                errors.push(format!("Error sending file: {}", err));
+            }
+        }
+        log::error!("{}", errors.last().unwrap());
+    }
+    Err(errors)
+}
+
+// Removed files get the hash they were stored with and created files of the same size get theirs, so the optimizer
+// can tell a move from a new file. Batches without removals are left alone, hashing them here would be wasted.
+async fn add_content_hashes(dir: &PathBuf, source: &SherryConfigSourceJSON, watchers: &HashMap<String, &SherryConfigWatcherJSON>, events: Vec<SyncEvent>) -> Vec<SyncEvent> {
+    let mut hashes_map = HashMap::new();
+    let mut removed = HashSet::new();
+    let mut events = events;
+    for e in events.iter_mut().filter(|e| e.kind == SyncEventKind::Deleted && e.file_type == FileType::File) {
+        let watcher = match watchers.get(&e.base.to_str().unwrap().to_string()) {
+            Some(watcher) => watcher,
+            None => continue,
+        };
+        if !hashes_map.contains_key(&e.base) {
+            match get_hashes(dir, source, &e.base, &watcher.hashes_id).await {
+                Ok(hashes) => hashes_map.insert(e.base.clone(), hashes),
+                Err(_) => continue,
+            };
+        }
+        if let Some(h) = hashes_map.get(&e.base).and_then(|h| h.hashes.get(e.local_path.to_str().unwrap())) {
+            e.update_hash = h.hash.clone();
+            e.size = h.size;
+            removed.insert((e.base.clone(), h.size));
+        }
+    }
+    if removed.is_empty() {
+        return events;
+    }
+
+    for e in events.iter_mut().filter(|e| e.kind == SyncEventKind::Created && e.file_type == FileType::File) {
+        let size = match e.local_path.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => continue,
+        };
+        if removed.contains(&(e.base.clone(), size)) {
+            e.update_hash = get_file_hash(&e.local_path).await;
+            e.size = size;
+        }
+    }
+    events
+}
 
 pub async fn process_result(app: crate::app::App, source_id: &String, results: &Vec<BasedDebounceEvent>) {
-    let dir = app.config.lock().await.get_path();
     let config = app.config.lock().await.get_main().await;
-    let auth = app.config.lock().await.get_auth().await;
+    let config_dir = app.config.lock().await.get_path();
+    let dir = get_hashes_dir(&config_dir, &config);
 
     let source = config.sources.get(source_id);
     if source.is_none() {
@@ -27,13 +153,14 @@ pub async fn process_result(app: crate::app::App, source_id: &String, results: &
     let source = source.unwrap();
     let watchers: HashMap<String, &SherryConfigWatcherJSON> = config.watchers
         .iter()
-        .filter_map(|e| if e.source.eq(source_id) { Some((e.local_path.clone(), e)) } else { None })
+        .filter_map(|e| if e.source.eq(source_id) && e.mode.can_upload() { Some((e.local_path.clone(), e)) } else { None })
         .collect();
 
-    if source.access == AccessRights::Read {
+    if !source.can_upload() {
         return;
     }
 
+    set_stage("collecting events");
     let events = futures::future::join_all(minify_results(&results)
         .iter()
         .filter_map(|e| {
@@ -46,18 +173,116 @@ pub async fn process_result(app: crate::app::App, source_id: &String, results: &
         .collect::<Vec<_>>()).await.into_iter().flatten().collect::<Vec<SyncEvent>>();
     log_events("Received", &events);
 
+    let events = events.into_iter().filter(|e| {
+        watchers.get(e.base.to_str().unwrap()).is_some_and(|w| w.is_included(&e.sync_path))
+    }).collect::<Vec<SyncEvent>>();
+
+    let events = add_content_hashes(&dir, source, &watchers, events).await;
     let events = optimize_events(&events);
     log_events("Optimized", &events);
 
+    // Writes of the demon that were still in progress when the batch started
+    let is_echo = futures::future::join_all(events.iter().map(|e| async {
+        is_self_write(&e.local_path).await && (e.kind != SyncEventKind::Moved || is_self_write(&e.old_local_path).await)
+    })).await;
+    let events = events.into_iter().zip(is_echo).filter_map(|(e, is_echo)| if is_echo { None } else { Some(e) }).collect::<Vec<SyncEvent>>();
+
     let events = filter_events(&source, &events);
+    let events = check_limits(&dir, source, &watchers, events).await;
     log_events("Filtered", &events);
 
+    let (events, deferred) = apply_cooldowns(events);
+    for (e, delay) in deferred {
+        let app = app.clone();
+        let source_id = source_id.clone();
+        let batch = begin_batch(&source_id);
+        tokio::spawn(async move {
+            let _batch = batch;
+            tokio::time::sleep(delay).await;
+            finish_deferred(&e.local_path);
+            // A removal in the meantime is sent by its own event
+            if !e.local_path.exists() {
+                return;
+            }
+            if let Err(e) = watch(format!("Deferred upload of {}", &e.sync_path), send_events(app.clone(), &source_id, vec![e])).await {
+                log::error!("{}, refetching its watchers", e);
+                if let Err(e) = app.config.lock().await.reset_source_watchers(&source_id).await {
+                    log::error!("Failed to reset watchers of source {}: {}", &source_id, e);
+                }
+            }
+        });
+    }
+
+    // Large batches wait for approval
+    if let Some(events) = review_batch(&config_dir, &config.batch_approval, source_id, events).await {
+        send_events(app, source_id, events).await
+    }
+}
+
+// Changes found by comparing a watcher with the remote folder instead of by the filesystem watcher, checked like the
+// changes of a batch before they are sent
+pub async fn submit_events(app: crate::app::App, source_id: &String, events: Vec<SyncEvent>) {
+    let config = app.config.lock().await.get_main().await;
+    let config_dir = app.config.lock().await.get_path();
+    let dir = get_hashes_dir(&config_dir, &config);
+
+    let source = match config.sources.get(source_id) {
+        Some(source) if source.can_upload() => source,
+        _ => return,
+    };
+    let watchers: HashMap<String, &SherryConfigWatcherJSON> = config.watchers
+        .iter()
+        .filter_map(|e| if e.source.eq(source_id) && e.mode.can_upload() { Some((e.local_path.clone(), e)) } else { None })
+        .collect();
+
+    let events = events.into_iter().filter(|e| {
+        watchers.get(e.base.to_str().unwrap()).is_some_and(|w| w.is_included(&e.sync_path))
+    }).collect::<Vec<SyncEvent>>();
+    let events = filter_events(source, &events);
+    let events = check_limits(&dir, source, &watchers, events).await;
+    log_events("Filtered", &events);
+
+    if let Some(events) = review_batch(&config_dir, &config.batch_approval, source_id, events).await {
+        send_events(app, source_id, events).await
+    }
+}
+
+// Journaled until sent, so a crash halfway doesn't lose the events
+pub async fn send_events(app: crate::app::App, source_id: &String, events: Vec<SyncEvent>) {
+    let config = app.config.lock().await.get_main().await;
+    let config_dir = app.config.lock().await.get_path();
+    let dir = get_hashes_dir(&config_dir, &config);
+    let auth = app.config.lock().await.get_auth().await;
+
+    let source = match config.sources.get(source_id) {
+        Some(source) => source,
+        None => return,
+    };
+    let watchers: HashMap<String, &SherryConfigWatcherJSON> = config.watchers
+        .iter()
+        .filter_map(|e| if e.source.eq(source_id) && e.mode.can_upload() { Some((e.local_path.clone(), e)) } else { None })
+        .collect();
+
+    let journal_ids = match append_journal(&config_dir, source_id, &events, None).await {
+        Ok(ids) => ids,
+        Err(e) => {
+            log::error!("Failed to journal events: {}", e);
+            vec![]
+        }
+    };
+
+    set_stage("hashing");
     let events = complete_events(&events).await;
+    let events = if source.sync_permissions { add_attributes(events) } else { events };
     log_events("Completed", &events);
 
+    set_stage("sending");
+    prune_recent_events();
     let mut hashes_map = HashMap::new();
     let mut updated_hashes = HashMap::new();
-    for e in events {
+    let mut pending = vec![];
+    let mut held = vec![];
+    for (i, e) in events.into_iter().enumerate() {
         let watcher = match watchers.get(&e.base.to_str().unwrap().to_string()) {
             Some(watcher) => watcher,
             None => continue,
@@ -75,53 +300,69 @@ pub async fn process_result(app: crate::app::App, source_id: &String, results: &
             }
         };
 
-        match hashes.hashes.get(&e.local_path.to_str().unwrap().to_string()) {
-            Some(h) => {
-                if h.hash == e.update_hash {
-                    continue;
-                }
-            }
-            _ => {}
+        // A permission change alone keeps the hash
+        if hashes.hashes.get(e.local_path.to_str().unwrap())
+            .is_some_and(|h| h.hash == e.update_hash && (e.attributes.is_none() || h.attributes == e.attributes)) {
+            continue;
         }
 
-        let mut to_update = updated_hashes.entry(base.clone()).or_insert(hashes.clone());
-        match e.kind {
-            SyncEventKind::Deleted => {
-                to_update.hashes.remove(&e.local_path.to_str().unwrap().to_string());
-                to_update.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: "".to_string(), timestamp: get_now_as_millis(), size: 0 });
-            }
-            SyncEventKind::Moved => {
-                to_update.hashes.remove(&e.old_local_path.to_str().unwrap().to_string());
-                to_update.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: e.update_hash.clone(), timestamp: get_now_as_millis(), size: e.size });
-            }
-            _ => {
-                to_update.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: e.update_hash.clone(), timestamp: get_now_as_millis(), size: e.size });
-            }
+        if is_quarantined(&config_dir, source_id, &e.local_path).await {
+            log::warn!("Skipping quarantined {}", &e.sync_path);
+            continue;
         }
 
-        let client = ApiClient::new(&config.api_url, &auth.records.get(&source.user_id).unwrap().access_token);
-
-        match client.check_file(&e).await {
-            Ok(res) => {
-                if res.status() != 200 {
-                    continue;
-                }
-            }
-            Err(_) => {
-                continue;
+        if is_held(&config_dir, &e).await {
+            if let Some(id) = journal_ids.get(i) {
+                held.push(id.clone());
             }
+            hold_event(source_id, journal_ids.get(i).cloned(), e);
+            continue;
         }
 
-        match client.send_file(&e).await {
-            Ok(res) => {
-                if res.status() != 200 {
-                    log::error!("Error sending file: {}", res.text().await.unwrap());
-                    continue;
+        // Recorded as synced, the other watcher's upload carries the content
+        if is_duplicate(&e) {
+            log::info!("Skipping duplicate {:?} {} from {:?}", &e.kind, &e.sync_path, &e.base);
+            apply_event_hash(updated_hashes.entry(e.base.clone()).or_insert(hashes_map.get(&e.base).unwrap().clone()), &e);
+            continue;
+        }
+
+        pending.push((journal_ids.get(i).cloned(), e));
+    }
+
+    let mut sent = vec![];
+    let mut retried = vec![];
+    let uploads = Semaphore::new(config.get_max_concurrent_uploads());
+    for wave in split_waves(pending) {
+        let storage = get_storage(&config.api_url, source, auth.records.get(&source.user_id).unwrap());
+        let results = futures::future::join_all(wave.iter().map(|(_, e)| async {
+            let _permit = uploads.acquire().await.unwrap();
+            start_file(&e.sync_path, e.size);
+            let result = send_event(storage.as_ref(), e, config.get_max_retries()).await;
+            finish_file(e.size);
+            result
+        })).await;
+
+        for ((journal_id, e), result) in wave.into_iter().zip(results) {
+            match result {
+                Ok(is_sent) => {
+                    apply_event_hash(updated_hashes.entry(e.base.clone()).or_insert(hashes_map.get(&e.base).unwrap().clone()), &e);
+                    if is_sent {
+                        record_success(source_id, &e);
+                        sent.push(e.clone());
+                    } else {
+                        record_rejection(&config_dir, source_id, &e).await;
+                    }
+                    if let Some(id) = journal_id {
+                        remove_journal(&config_dir, &[id]).await.ok();
+                    }
+                }
+                // Stays journaled and out of the hash store until the retry queue is done with it
+                Err(errors) => {
+                    if let Some(id) = &journal_id {
+                        retried.push(id.clone());
+                    }
+                    queue_retry(source_id, &e, journal_id, errors);
                 }
-            }
-            Err(err) => {
-                log::error!("Error sending file: {}", err);
-                continue;
             }
         }
     }
@@ -130,27 +371,138 @@ pub async fn process_result(app: crate::app::App, source_id: &String, results: &
             update_hashes(&dir, &v).await.unwrap();
         }
     }
+    // Events that didn't need to be sent
+    remove_journal(&config_dir, &journal_ids.into_iter().filter(|id| !retried.contains(id) && !held.contains(id)).collect::<Vec<String>>()).await.ok();
+
+    if source.verify_uploads && !sent.is_empty() {
+        set_stage("verifying");
+        let storage = get_storage(&config.api_url, source, auth.records.get(&source.user_id).unwrap());
+        for (e, reason) in verify_uploads(storage.as_ref(), &source.id, &sent).await {
+            record_drift(source_id, &e.sync_path, DriftSide::Upload, reason);
+        }
+    }
+}
+
+fn get_event_paths(e: &SyncEvent) -> Vec<&SharedPath> {
+    if e.kind == SyncEventKind::Moved { vec![&e.local_path, &e.old_local_path] } else { vec![&e.local_path] }
+}
+
+fn is_overlapping(a: &SyncEvent, b: &SyncEvent) -> bool {
+    get_event_paths(a).iter().any(|a| get_event_paths(b).iter().any(|b| a.starts_with(b) || b.starts_with(a)))
+}
+
+// Events are sent concurrently in waves, an event touching a path (or a parent or child of it) of the current wave
+// starts the next one, so changes of the same file still reach the server in order
+fn split_waves(events: Vec<(Option<String>, SyncEvent)>) -> Vec<Vec<(Option<String>, SyncEvent)>> {
+    let mut waves: Vec<Vec<(Option<String>, SyncEvent)>> = vec![];
+    for entry in events {
+        match waves.last_mut() {
+            Some(wave) if !wave.iter().any(|(_, e)| is_overlapping(e, &entry.1)) => wave.push(entry),
+            _ => waves.push(vec![entry]),
+        }
+    }
+    waves
 }
 
-fn create_debounce(rt: &tokio::runtime::Handle, app: crate::app::App, source_id: &String, is_running: &Arc<Mutex<bool>>) -> Sender<BasedDebounceEvent> {
-    let source_id = source_id.clone();
+pub fn apply_event_hash(hashes: &mut WatcherHashJSON, e: &SyncEvent) {
+    match e.kind {
+        // Everything that was inside goes with it
+        SyncEventKind::Deleted if e.file_type == FileType::Dir => {
+            let now = get_now_as_millis();
+            for (_, hash) in hashes.hashes.iter_mut().filter(|(p, _)| Path::new(p).starts_with(&e.local_path)) {
+                *hash = FileHashJSON { hash: "".to_string(), timestamp: now, size: 0, modified: None, attributes: None, variant: None };
+            }
+        }
+        SyncEventKind::Deleted => {
+            hashes.hashes.remove(e.local_path.to_str().unwrap());
+            hashes.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: "".to_string(), timestamp: get_now_as_millis(), size: 0, modified: None, attributes: None, variant: None });
+        }
+        SyncEventKind::Moved => {
+            hashes.hashes.remove(e.old_local_path.to_str().unwrap());
+            hashes.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: e.update_hash.clone(), timestamp: get_now_as_millis(), size: e.size, modified: get_modified_millis(&e.local_path), attributes: e.attributes.clone(), variant: None });
+        }
+        _ => {
+            hashes.hashes.insert(e.local_path.to_str().unwrap().to_string(), FileHashJSON { hash: e.update_hash.clone(), timestamp: get_now_as_millis(), size: e.size, modified: get_modified_millis(&e.local_path), attributes: e.attributes.clone(), variant: None });
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventQueueStats {
+    // changes waiting to be batched
+    pub depth: usize,
+    pub capacity: usize,
+    pub peak: usize,
+    // times the filesystem watcher had to wait for room
+    pub full: u64,
+    // batches being collected or processed, deferred uploads included
+    #[serde(default)]
+    pub batches: usize,
+}
+
+// source id -> queue of the current batch
+static EVENT_QUEUES: std::sync::Mutex<BTreeMap<String, EventQueueStats>> = std::sync::Mutex::new(BTreeMap::new());
+
+// Counted from the first event of a batch until it was handed to `send_events`, which journals what it sends
+struct BatchGuard(String);
+
+fn begin_batch(source_id: &str) -> BatchGuard {
+    EVENT_QUEUES.lock().unwrap().entry(source_id.to_string()).or_default().batches += 1;
+    BatchGuard(source_id.to_string())
+}
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        if let Some(stats) = EVENT_QUEUES.lock().unwrap().get_mut(&self.0) {
+            stats.batches = stats.batches.saturating_sub(1);
+        }
+    }
+}
+
+fn record_queue_depth(source_id: &str, depth: usize, capacity: usize) {
+    let mut queues = EVENT_QUEUES.lock().unwrap();
+    let stats = queues.entry(source_id.to_string()).or_default();
+    stats.depth = depth;
+    stats.capacity = capacity;
+    stats.peak = stats.peak.max(depth);
+}
+
+fn record_queue_full(source_id: &str) {
+    EVENT_QUEUES.lock().unwrap().entry(source_id.to_string()).or_default().full += 1;
+}
+
+pub fn get_event_queue_stats() -> BTreeMap<String, EventQueueStats> {
+    EVENT_QUEUES.lock().unwrap().clone()
+}
+
+// Queued with the ids of their journal entries
+type QueuedEvent = (BasedDebounceEvent, Vec<String>);
+
+fn create_debounce(rt: &tokio::runtime::Handle, app: crate::app::App, source_id: &str, capacity: usize, is_running: &Arc<Mutex<bool>>) -> Sender<QueuedEvent> {
+    let source_id = source_id.to_string();
     let is_running = Arc::clone(is_running);
 
-    let (tx, mut rx) = mpsc::channel::<BasedDebounceEvent>(100);
+    let (tx, mut rx) = mpsc::channel::<QueuedEvent>(capacity);
+    let batch = begin_batch(&source_id);
     rt.spawn(async move {
+        let _batch = batch;
         { *is_running.lock().await = true; }
 
         let timeout = Duration::from_secs(1);
         let mut buffer = Vec::new();
+        let mut journal_ids = Vec::new();
         let mut last_event_time = Instant::now();
 
         loop {
             let mut is_conn_closed = false;
             while let Some(event) = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await.ok() {
                 match event {
-                    Some(event) => {
+                    Some((event, ids)) => {
                         last_event_time = Instant::now();
                         buffer.push(event);
+                        journal_ids.extend(ids);
+                        record_queue_depth(&source_id, rx.len(), capacity);
                     }
                     None => {
                         is_conn_closed = true;
@@ -162,8 +514,23 @@ fn create_debounce(rt: &tokio::runtime::Handle, app: crate::app::App, source_id:
         }
 
         { *is_running.lock().await = false; }
+        // Events already queued still belong to this batch, later sends start the next one
+        rx.close();
+        while let Some((event, ids)) = rx.recv().await {
+            buffer.push(event);
+            journal_ids.extend(ids);
+        }
+        record_queue_depth(&source_id, 0, capacity);
 
-        process_result(app, &source_id, &buffer).await;
+        if let Err(e) = watch(format!("Event batch of source {}", &source_id), process_result(app.clone(), &source_id, &buffer)).await {
+            log::error!("{}, refetching its watchers", e);
+            if let Err(e) = app.config.lock().await.reset_source_watchers(&source_id).await {
+                log::error!("Failed to reset watchers of source {}: {}", &source_id, e);
+            }
+        }
+        // Sent, filtered out or journaled again by `send_events`, a failed batch is found again by refetching the watchers
+        let dir = app.config.lock().await.get_path();
+        remove_journal(&dir, &journal_ids).await.ok();
     });
 
     tx
@@ -179,28 +546,66 @@ pub struct EventProcessingDebounce {
     _is_running: Arc<Mutex<bool>>,
     app: crate::app::App,
     source_id: String,
-    tx: Option<Sender<BasedDebounceEvent>>,
+    folder_id: String,
+    capacity: usize,
+    tx: Option<Sender<QueuedEvent>>,
     rt: tokio::runtime::Handle,
 }
 
 impl EventProcessingDebounce {
-    pub fn new(rt: &tokio::runtime::Handle, app: &crate::app::App, source_id: &String) -> EventProcessingDebounce {
+    pub fn new(rt: &tokio::runtime::Handle, app: &crate::app::App, source_id: &str, folder_id: &str, capacity: usize) -> EventProcessingDebounce {
         EventProcessingDebounce {
             _is_running: Arc::new(Mutex::new(false)),
             app: app.clone(),
-            source_id: source_id.clone(),
+            source_id: source_id.to_string(),
+            folder_id: folder_id.to_string(),
+            capacity,
             tx: None,
             rt: rt.clone(),
         }
     }
 
-    pub async fn send(&mut self, event: BasedDebounceEvent) {
-        if !self.is_running().await {
-            self.tx = Some(create_debounce(&self.rt, self.app.clone(), &self.source_id, &self._is_running));
+    // The paths of the change as they are now, a crash before the batch is sent replays them from the journal.
+    // Directories are left to the events of their files.
+    async fn journal(&self, event: &BasedDebounceEvent) -> Vec<String> {
+        let events = event.event.paths.iter().filter(|p| !p.is_dir()).map(|p| {
+            let kind = if p.exists() { SyncEventKind::Updated } else { SyncEventKind::Deleted };
+            get_file_event(&self.folder_id, &event.base, p, kind)
+        }).collect::<Vec<SyncEvent>>();
+        let dir = self.app.config.lock().await.get_path();
+        match queue_journal(&dir, &self.source_id, &events).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                log::error!("Failed to journal queued events: {}", e);
+                vec![]
+            }
         }
-        let tx = self.tx.clone().unwrap();
-        if let Err(e) = tx.send(event).await {
-            log::error!("Error sending event result: {:?}", e);
+    }
+
+    // Waits while the queue is full, which holds up the filesystem watcher instead of dropping changes.
+    // A batch that closed in the meantime hands the event back, it starts the next one.
+    pub async fn send(&mut self, event: BasedDebounceEvent) {
+        let ids = self.journal(&event).await;
+        let mut event = (event, ids);
+        loop {
+            if !self.is_running().await || self.tx.as_ref().is_none_or(|tx| tx.is_closed()) {
+                self.tx = Some(create_debounce(&self.rt, self.app.clone(), &self.source_id, self.capacity, &self._is_running));
+            }
+            let tx = self.tx.clone().unwrap();
+            if tx.capacity() == 0 {
+                record_queue_full(&self.source_id);
+                log::warn!("Event queue of source {} is full, waiting for the current batch", &self.source_id);
+            }
+            match tx.send(event).await {
+                Ok(_) => {
+                    record_queue_depth(&self.source_id, tx.max_capacity() - tx.capacity(), self.capacity);
+                    return;
+                }
+                Err(e) => {
+                    event = e.0;
+                    self.tx = None;
+                }
+            }
         }
     }
 
@@ -1,13 +1,12 @@
 use fmt::Display;
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
-use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use glob::Pattern;
-use notify::event::{DataChange, ModifyKind, RemoveKind, RenameMode};
+use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode};
 use notify::EventKind;
 use notify_debouncer_full::DebouncedEvent;
 use regex::Regex;
@@ -15,11 +14,15 @@ use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
 
 use crate::config::{SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::files::{FileAttributesJSON, read_file_attributes};
 use crate::event::event_processing::BasedDebounceEvent;
 use crate::hash::{get_file_hash, get_hashes};
-use crate::helpers::{get_now_as_millis, normalize_path, PATH_SEP};
+use crate::helpers::{canonicalize_sync_path, get_now_as_millis, normalize_path, PATH_SEP};
+use crate::names::unescape_name;
+use crate::ownership::is_owner_marker;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum SyncEventKind {
     Created,
     Updated,
@@ -46,19 +49,65 @@ impl Display for FileType {
     }
 }
 
-#[derive(Debug, Clone)]
+// Batches hold thousands of events repeating the same source, base and (old) paths, these are shared between them
+// and cloning an event only bumps reference counts
+pub type SharedStr = Arc<str>;
+pub type SharedPath = Arc<Path>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SyncEvent {
-    pub source_id: String,
-    pub base: PathBuf,
+    pub source_id: SharedStr,
+    pub base: SharedPath,
     pub file_type: FileType,
     pub kind: SyncEventKind,
-    pub local_path: PathBuf,
-    pub old_local_path: PathBuf,
-    pub sync_path: String,
-    pub old_sync_path: String,
+    pub local_path: SharedPath,
+    pub old_local_path: SharedPath,
+    pub sync_path: SharedStr,
+    pub old_sync_path: SharedStr,
     pub update_hash: String,
     pub size: u64,
     pub timestamp: i128,
+    // only read for sources that sync permissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributesJSON>,
+}
+
+static INTERNED_STRS: std::sync::Mutex<BTreeSet<SharedStr>> = std::sync::Mutex::new(BTreeSet::new());
+static INTERNED_PATHS: std::sync::Mutex<BTreeSet<SharedPath>> = std::sync::Mutex::new(BTreeSet::new());
+
+// Only for values with few distinct instances (source ids, bases), the tables are never pruned
+pub fn intern_str(value: &str) -> SharedStr {
+    let mut interned = INTERNED_STRS.lock().unwrap();
+    match interned.get(value) {
+        Some(shared) => shared.clone(),
+        None => {
+            let shared = SharedStr::from(value);
+            interned.insert(shared.clone());
+            shared
+        }
+    }
+}
+
+pub fn intern_path(value: &Path) -> SharedPath {
+    let mut interned = INTERNED_PATHS.lock().unwrap();
+    match interned.get(value) {
+        Some(shared) => shared.clone(),
+        None => {
+            let shared = SharedPath::from(value);
+            interned.insert(shared.clone());
+            shared
+        }
+    }
+}
+
+// The old value of an event that didn't move is the same allocation as the new one
+pub fn share_pair<T: ?Sized + PartialEq>(value: Arc<T>, old_value: Arc<T>) -> (Arc<T>, Arc<T>) {
+    if value == old_value {
+        (value.clone(), value)
+    } else {
+        (value, old_value)
+    }
 }
 
 pub fn log_events(name: &str, events: &Vec<SyncEvent>) {
@@ -74,36 +123,74 @@ fn result_cmp(a: &BasedDebounceEvent, b: &BasedDebounceEvent) -> Ordering {
     a.event.time.cmp(&b.event.time)
 }
 
+fn is_rename(result: &BasedDebounceEvent, mode: RenameMode) -> bool {
+    result.event.kind == EventKind::Modify(ModifyKind::Name(mode))
+}
+
+fn with_kind(result: &BasedDebounceEvent, kind: EventKind, paths: Vec<PathBuf>) -> BasedDebounceEvent {
+    BasedDebounceEvent {
+        event: DebouncedEvent {
+            event: notify::Event {
+                kind,
+                paths,
+                attrs: result.event.attrs.clone(),
+            },
+            time: result.event.time,
+        },
+        base: result.base.clone(),
+    }
+}
+
+// Renames may arrive as separate From and To events. They are paired by their rename cookie where the platform gives
+// one, otherwise only with the event right after. A From without its To left the watched folder and is a delete,
+// a To without its From came into it and is a create.
+fn pair_renames(results: &[BasedDebounceEvent]) -> Vec<BasedDebounceEvent> {
+    let mut paired = HashSet::new();
+    let mut paired_results = Vec::new();
+    for (i, result) in results.iter().enumerate() {
+        if paired.contains(&i) {
+            continue;
+        }
+        if is_rename(result, RenameMode::From) {
+            let tracker = result.event.attrs.tracker();
+            let to = match tracker {
+                Some(_) => results.iter().enumerate().skip(i + 1)
+                    .find(|(j, r)| !paired.contains(j) && is_rename(r, RenameMode::To) && r.event.attrs.tracker() == tracker),
+                None => results.get(i + 1).map(|r| (i + 1, r))
+                    .filter(|(_, r)| is_rename(r, RenameMode::To) && r.event.attrs.tracker().is_none()),
+            }.filter(|(_, r)| r.base == result.base);
+            paired_results.push(match to {
+                Some((j, to)) => {
+                    paired.insert(j);
+                    with_kind(to, EventKind::Modify(ModifyKind::Name(RenameMode::Both)), vec![
+                        result.event.paths.first().unwrap().clone(),
+                        to.event.paths.first().unwrap().clone(),
+                    ])
+                }
+                None => with_kind(result, EventKind::Remove(RemoveKind::Any), result.event.paths.clone()),
+            });
+        } else if is_rename(result, RenameMode::To) {
+            paired_results.push(with_kind(result, EventKind::Create(CreateKind::Any), result.event.paths.clone()));
+        } else {
+            paired_results.push(result.clone());
+        }
+    }
+    paired_results
+}
+
 pub fn minify_results(results: &Vec<BasedDebounceEvent>) -> Vec<BasedDebounceEvent> {
     let mut results = results.clone();
     results.sort_by(result_cmp);
+    let results = pair_renames(&results);
 
     let mut new_results = Vec::new();
     let mut remove_results = HashMap::new();
-    for (i, result) in results.iter().enumerate() {
+    for result in results.iter() {
         match result.event.kind {
             EventKind::Modify(modify_kind) => {
                 match modify_kind {
                     ModifyKind::Name(mode) => {
                         match mode {
-                            RenameMode::From => {
-                                let to = results.get(i + 1);
-                                if to.is_some() {
-                                    let to = &to.unwrap().event;
-                                    let from = &result.event;
-                                    new_results.push(BasedDebounceEvent {
-                                        event: DebouncedEvent {
-                                            event: notify::Event {
-                                                kind: EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
-                                                paths: vec![from.paths.first().unwrap().clone(), to.paths.first().unwrap().clone()],
-                                                attrs: to.attrs.clone(),
-                                            },
-                                            time: to.time,
-                                        },
-                                        base: result.base.clone(),
-                                    })
-                                }
-                            }
                             RenameMode::Both => {
                                 new_results.push(result.clone())
                             }
@@ -164,22 +251,46 @@ pub fn minify_results(results: &Vec<BasedDebounceEvent>) -> Vec<BasedDebounceEve
     new_results
 }
 
-pub fn get_sync_path(path: &PathBuf, base: &PathBuf) -> String {
-    normalize_path(&PathBuf::from(path
+pub fn get_sync_path(path: &Path, base: &Path) -> String {
+    canonicalize_sync_path(&path
         .strip_prefix(base).unwrap()
-        .iter().collect::<Vec<&OsStr>>()
-        .join(OsStr::new(PATH_SEP))
-    )).to_str().unwrap().to_string()
+        .iter().map(|s| unescape_name(s.to_str().unwrap()))
+        .collect::<Vec<String>>()
+        .join(PATH_SEP))
+}
+
+// A single file event, its hash is completed when it is sent
+pub fn get_file_event(source_id: &str, base: &Path, local_path: &Path, kind: SyncEventKind) -> SyncEvent {
+    let size = local_path.metadata().map_or(0, |m| m.len());
+    let sync_path = SharedStr::from(get_sync_path(local_path, base));
+    let local_path = SharedPath::from(local_path);
+    SyncEvent {
+        source_id: intern_str(source_id),
+        base: intern_path(base),
+        file_type: FileType::File,
+        kind,
+        local_path: local_path.clone(),
+        old_local_path: local_path,
+        sync_path: sync_path.clone(),
+        old_sync_path: sync_path,
+        update_hash: "".to_string(),
+        size,
+        timestamp: get_now_as_millis(),
+        attributes: None,
+    }
 }
 
-fn get_dir_file_events(config: &SherryConfigSourceJSON, path: &PathBuf, base: &PathBuf, kind: &SyncEventKind) -> Vec<SyncEvent> {
+fn get_dir_file_events(config: &SherryConfigSourceJSON, path: &Path, base: &PathBuf, kind: &SyncEventKind) -> Vec<SyncEvent> {
     let mut events = Vec::new();
-    let path = normalize_path(path);
+    let source_id = intern_str(&config.id);
+    let shared_base = intern_path(base);
+    let path = normalize_path(&path.to_path_buf());
     if path.is_file() {
-        let sync_path = get_sync_path(&path, base);
+        let sync_path = SharedStr::from(get_sync_path(&path, base));
+        let path = SharedPath::from(path);
         events.push(SyncEvent {
-            source_id: config.id.clone(),
-            base: base.clone(),
+            source_id: source_id.clone(),
+            base: shared_base.clone(),
             file_type: FileType::File,
             kind: kind.clone(),
             local_path: path.clone(),
@@ -189,6 +300,7 @@ fn get_dir_file_events(config: &SherryConfigSourceJSON, path: &PathBuf, base: &P
             update_hash: "".to_string(),
             size: 0,
             timestamp: get_now_as_millis(),
+            attributes: None,
         });
     } else if path.is_dir() {
         match path.read_dir() {
@@ -208,6 +320,39 @@ fn get_dir_file_events(config: &SherryConfigSourceJSON, path: &PathBuf, base: &P
     events
 }
 
+// A moved directory takes its files along, every file the hash store knows under the old path moves on its own,
+// so the server and the hash store both end up with the new paths
+async fn get_dir_move_events(config: &SherryConfigSourceJSON, local_path: &Path, old_local_path: &Path, base: &Path, dir: &PathBuf, watcher: &SherryConfigWatcherJSON) -> Vec<SyncEvent> {
+    let hashes = match get_hashes(dir, config, base, &watcher.hashes_id).await {
+        Ok(hashes) => hashes,
+        Err(_) => return vec![],
+    };
+    let timestamp = get_now_as_millis();
+    let source_id = intern_str(&config.id);
+    let shared_base = intern_path(base);
+    hashes.hashes.iter().filter_map(|(path, hash)| {
+        let old_file_path = PathBuf::from(path);
+        let file_path = normalize_path(&local_path.join(old_file_path.strip_prefix(old_local_path).ok()?));
+        if hash.hash.is_empty() {
+            return None;
+        }
+        Some(SyncEvent {
+            source_id: source_id.clone(),
+            base: shared_base.clone(),
+            file_type: FileType::File,
+            kind: SyncEventKind::Moved,
+            sync_path: get_sync_path(&file_path, base).into(),
+            old_sync_path: get_sync_path(&old_file_path, base).into(),
+            local_path: file_path.into(),
+            old_local_path: old_file_path.into(),
+            update_hash: "".to_string(),
+            size: hash.size,
+            timestamp,
+            attributes: None,
+        })
+    }).collect()
+}
+
 pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebounceEvent, dir: &PathBuf, watcher: &SherryConfigWatcherJSON) -> Vec<SyncEvent> {
     // Modify(Any) - file update
     // Modify(Name(Both)) file/dir rename
@@ -225,19 +370,45 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
         return events;
     }
 
-    let sync_path = get_sync_path(&local_path, base);
-    let old_sync_path = get_sync_path(&old_local_path, base);
+    let source_id = intern_str(&config.id);
+    let shared_base = intern_path(base);
+    let (sync_path, old_sync_path) = share_pair(
+        SharedStr::from(get_sync_path(&local_path, base)),
+        SharedStr::from(get_sync_path(&old_local_path, base)),
+    );
+    let (local_path, old_local_path) = share_pair(SharedPath::from(local_path), SharedPath::from(old_local_path));
 
     if !local_path.exists() {
         let hashes = get_hashes(dir, config, base, &watcher.hashes_id).await.unwrap();
+        // A removed directory is deleted with a single event instead of one per file it held
+        let is_dir = hashes.hashes.iter().any(|(path, hash)| {
+            !hash.hash.is_empty() && Path::new(path).strip_prefix(&local_path).is_ok_and(|p| !p.as_os_str().is_empty())
+        });
+        if is_dir {
+            events.push(SyncEvent {
+                source_id: source_id.clone(),
+                base: shared_base.clone(),
+                file_type: FileType::Dir,
+                kind: SyncEventKind::Deleted,
+                update_hash: "".to_string(),
+                size: 0,
+                local_path,
+                old_local_path,
+                sync_path,
+                old_sync_path,
+                timestamp: get_now_as_millis(),
+                attributes: None,
+            });
+            return events;
+        }
         let parent_path = Regex::new(r"/+$").unwrap().replace_all(local_path.to_str().unwrap(), PATH_SEP).to_string();
         hashes.hashes.iter().for_each(|(local_path, _)| {
             if local_path.starts_with(&parent_path) {
-                let local_path = PathBuf::from(local_path);
-                let sync_path = get_sync_path(&local_path, base);
+                let sync_path = SharedStr::from(get_sync_path(Path::new(local_path), base));
+                let local_path = SharedPath::from(Path::new(local_path));
                 events.push(SyncEvent {
-                    source_id: config.id.clone(),
-                    base: base.clone(),
+                    source_id: source_id.clone(),
+                    base: shared_base.clone(),
                     file_type: FileType::File,
                     kind: SyncEventKind::Deleted,
                     local_path: local_path.clone(),
@@ -247,6 +418,7 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                     update_hash: "".to_string(),
                     size: 0,
                     timestamp: get_now_as_millis(),
+                    attributes: None,
                 })
             }
         })
@@ -256,9 +428,14 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
         match result.kind {
             EventKind::Modify(kind) => {
                 if kind == ModifyKind::Name(RenameMode::Both) {
+                    let moved = get_dir_move_events(config, &local_path, &old_local_path, base, dir, watcher).await;
+                    if !moved.is_empty() {
+                        return moved;
+                    }
+                    // Nothing known inside, only the directory itself moves
                     events.push(SyncEvent {
-                        source_id: config.id.clone(),
-                        base: base.clone(),
+                        source_id: source_id.clone(),
+                        base: shared_base.clone(),
                         file_type: FileType::Dir,
                         kind: SyncEventKind::Moved,
                         update_hash: "".to_string(),
@@ -268,6 +445,7 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                         sync_path,
                         old_sync_path,
                         timestamp: get_now_as_millis(),
+                        attributes: None,
                     });
                 }
             }
@@ -276,8 +454,8 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
             }
             EventKind::Remove(_) => {
                 events.push(SyncEvent {
-                    source_id: config.id.clone(),
-                    base: base.clone(),
+                    source_id: source_id.clone(),
+                    base: shared_base.clone(),
                     file_type: FileType::Dir,
                     kind: SyncEventKind::Deleted,
                     update_hash: "".to_string(),
@@ -287,6 +465,7 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                     sync_path,
                     old_sync_path,
                     timestamp: get_now_as_millis(),
+                    attributes: None,
                 });
             }
             _ => {}
@@ -301,8 +480,8 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
             match kind {
                 ModifyKind::Name(_) => {
                     events.push(SyncEvent {
-                        source_id: config.id.clone(),
-                        base: base.clone(),
+                        source_id: source_id.clone(),
+                        base: shared_base.clone(),
                         file_type,
                         kind: SyncEventKind::Moved,
                         update_hash: "".to_string(),
@@ -312,12 +491,13 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                         sync_path,
                         old_sync_path,
                         timestamp: get_now_as_millis(),
+                        attributes: None,
                     })
                 }
                 _ => {
                     events.push(SyncEvent {
-                        source_id: config.id.clone(),
-                        base: base.clone(),
+                        source_id: source_id.clone(),
+                        base: shared_base.clone(),
                         file_type,
                         kind: SyncEventKind::Updated,
                         update_hash: "".to_string(),
@@ -327,14 +507,15 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                         sync_path,
                         old_sync_path,
                         timestamp: get_now_as_millis(),
+                        attributes: None,
                     })
                 }
             }
         }
         EventKind::Create(_) => {
             events.push(SyncEvent {
-                source_id: config.id.clone(),
-                base: base.clone(),
+                source_id: source_id.clone(),
+                base: shared_base.clone(),
                 file_type,
                 kind: SyncEventKind::Created,
                 update_hash: "".to_string(),
@@ -344,12 +525,13 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                 sync_path,
                 old_sync_path,
                 timestamp: get_now_as_millis(),
+                attributes: None,
             })
         }
         EventKind::Remove(_) => {
             events.push(SyncEvent {
-                source_id: config.id.clone(),
-                base: base.clone(),
+                source_id: source_id.clone(),
+                base: shared_base.clone(),
                 file_type,
                 kind: SyncEventKind::Deleted,
                 update_hash: "".to_string(),
@@ -359,6 +541,7 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
                 sync_path,
                 old_sync_path,
                 timestamp: get_now_as_millis(),
+                attributes: None,
             })
         }
         _ => {}
@@ -367,186 +550,36 @@ pub async fn get_sync_events(config: &SherryConfigSourceJSON, result: &BasedDebo
     events
 }
 
-#[derive(Debug, Clone)]
-struct FileLifetime {
-    events: Vec<SyncEvent>,
-    next: Option<String>,
+// What a folder accepts, files it doesn't are neither hashed nor synced
+pub struct SyncPathFilter {
+    allow_dir: bool,
+    globs: Vec<Pattern>,
 }
 
-
-fn resolve_event_pair(a: &SyncEvent, b: &SyncEvent) -> Vec<SyncEvent> {
-    match a.kind {
-        SyncEventKind::Deleted => {
-            vec![b.clone()]
-        }
-        SyncEventKind::Created => {
-            match b.kind {
-                SyncEventKind::Created | SyncEventKind::Updated => {
-                    vec![SyncEvent {
-                        kind: SyncEventKind::Created,
-                        ..b.clone()
-                    }]
-                }
-                SyncEventKind::Deleted => {
-                    vec![b.clone()]
-                }
-                SyncEventKind::Moved => {
-                    vec![SyncEvent {
-                        kind: SyncEventKind::Created,
-                        old_sync_path: b.sync_path.clone(),
-                        update_hash: a.update_hash.clone(),
-                        size: a.size,
-                        ..b.clone()
-                    }]
-                }
-            }
-        }
-        SyncEventKind::Updated => {
-            match b.kind {
-                SyncEventKind::Created | SyncEventKind::Updated => {
-                    vec![SyncEvent {
-                        kind: SyncEventKind::Updated,
-                        ..b.clone()
-                    }]
-                }
-                SyncEventKind::Deleted => {
-                    vec![b.clone()]
-                }
-                SyncEventKind::Moved => {
-                    vec![
-                        SyncEvent {
-                            kind: SyncEventKind::Deleted,
-                            ..a.clone()
-                        },
-                        SyncEvent {
-                            kind: SyncEventKind::Created,
-                            update_hash: a.update_hash.clone(),
-                            size: a.size,
-                            ..b.clone()
-                        },
-                    ]
-                }
-            }
-        }
-        SyncEventKind::Moved => {
-            match b.kind {
-                SyncEventKind::Created | SyncEventKind::Updated => {
-                    vec![
-                        SyncEvent {
-                            kind: SyncEventKind::Deleted,
-                            sync_path: a.old_sync_path.clone(),
-                            ..a.clone()
-                        },
-                        SyncEvent {
-                            kind: SyncEventKind::Created,
-                            update_hash: a.update_hash.clone(),
-                            size: a.size,
-                            ..b.clone()
-                        },
-                    ]
-                }
-                SyncEventKind::Deleted => {
-                    vec![SyncEvent {
-                        kind: SyncEventKind::Deleted,
-                        sync_path: a.old_sync_path.clone(),
-                        ..a.clone()
-                    }, b.clone()]
-                }
-                SyncEventKind::Moved => {
-                    if a.old_sync_path == b.sync_path {
-                        vec![]
-                    } else {
-                        vec![SyncEvent {
-                            old_sync_path: a.old_sync_path.clone(),
-                            ..b.clone()
-                        }]
-                    }
-                }
-            }
-        }
+impl SyncPathFilter {
+    pub fn new(config: &SherryConfigSourceJSON) -> Self {
+        let globs: Vec<Pattern> = config.allowed_file_names.iter()
+            .filter_map(|s| Pattern::new(s).ok())
+            .collect();
+        SyncPathFilter { allow_dir: config.allow_dir, globs }
     }
-}
-
-fn event_time_cmp(a: &SyncEvent, b: &SyncEvent) -> Ordering {
-    a.timestamp.cmp(&b.timestamp)
-}
 
-pub fn optimize_events(events: &Vec<SyncEvent>) -> Vec<SyncEvent> {
-    let mut file_lifetimes: HashMap<String, FileLifetime> = HashMap::new();
-    for event in events {
-        let lifetime = file_lifetimes.entry(event.old_sync_path.clone()).or_insert(FileLifetime {
-            events: Vec::new(),
-            next: None,
-        });
-        lifetime.events.push(event.clone());
-
-        if event.kind == SyncEventKind::Moved {
-            lifetime.next = Some(event.sync_path.clone());
+    pub fn is_allowed(&self, sync_path: &str) -> bool {
+        if is_owner_marker(sync_path) {
+            return false;
         }
-    }
-
-    let mut file_keys = Vec::new();
-    for key in file_lifetimes.keys() {
-        if file_lifetimes.values().find(|e| e.next.as_ref().is_some_and(|v| v == key.deref())).is_some() {
-            file_keys.push(key.clone())
-        } else {
-            file_keys.insert(0, key.clone())
+        if !self.allow_dir && sync_path.contains(PATH_SEP) {
+            return false;
         }
+        self.globs.is_empty() || self.globs.iter().any(|p| p.matches(sync_path))
     }
-
-    let mut new_events = Vec::new();
-
-    for key in file_keys {
-        let mut file_lifecycle_events = Vec::new();
-        let entry = file_lifetimes.remove(&key);
-        if entry.is_none() { continue; }
-        let mut entry = entry.unwrap();
-        file_lifecycle_events.extend(entry.events);
-        while entry.next.is_some() {
-            let next_entry = file_lifetimes.remove(entry.next.as_ref().unwrap());
-            if next_entry.is_none() { break; }
-            entry = next_entry.unwrap();
-            file_lifecycle_events.extend(entry.events);
-        }
-        file_lifecycle_events.sort_by(event_time_cmp);
-        let mut events_count = file_lifecycle_events.len();
-        loop {
-            let mut start_index = 0;
-            while start_index < file_lifecycle_events.len() && file_lifecycle_events[start_index..].len() > 1 {
-                let new_events = resolve_event_pair(
-                    &file_lifecycle_events.remove(start_index),
-                    &file_lifecycle_events.remove(start_index),
-                );
-                let e_len = new_events.len();
-                for (i, e) in new_events.into_iter().enumerate() {
-                    file_lifecycle_events.insert(start_index + i, e);
-                }
-                if e_len > 1 {
-                    start_index += e_len - 1;
-                }
-            }
-            if file_lifecycle_events.len() >= events_count { break; }
-            events_count = file_lifecycle_events.len();
-        }
-        new_events.extend(file_lifecycle_events);
-    }
-
-    new_events
 }
 
-pub fn filter_events(config: &SherryConfigSourceJSON, events: &Vec<SyncEvent>) -> Vec<SyncEvent> {
-    let globs: Vec<Pattern> = config.allowed_file_names.iter()
-        .filter_map(|s| match Pattern::new(s) {
-            Ok(m) => Some(m),
-            Err(_) => None
-        }).collect();
+pub fn filter_events(config: &SherryConfigSourceJSON, events: &[SyncEvent]) -> Vec<SyncEvent> {
+    let filter = SyncPathFilter::new(config);
 
     events.iter().filter_map(|e| {
-        if !config.allow_dir && e.sync_path.contains(PATH_SEP) {
-            return None;
-        }
-
-        if !globs.is_empty() && !globs.iter().any(|p| p.matches(&e.sync_path)) {
+        if !filter.is_allowed(&e.sync_path) {
             return None;
         }
 
@@ -559,9 +592,6 @@ pub fn filter_events(config: &SherryConfigSourceJSON, events: &Vec<SyncEvent>) -
             return None;
         }
         let metadata = metadata.unwrap();
-        if metadata.len() > config.max_file_size {
-            return None;
-        }
 
         Some(SyncEvent {
             size: if metadata.is_dir() { 0 } else { metadata.len() },
@@ -578,3 +608,14 @@ pub async fn complete_events(events: &Vec<SyncEvent>) -> Vec<SyncEvent> {
         }
     })).await.into_iter().collect()
 }
+
+// Only files carry attributes, a removed file has none to send
+pub fn add_attributes(events: Vec<SyncEvent>) -> Vec<SyncEvent> {
+    events.into_iter().map(|e| match e.kind {
+        SyncEventKind::Created | SyncEventKind::Updated | SyncEventKind::Moved if e.file_type == FileType::File => SyncEvent {
+            attributes: read_file_attributes(&e.local_path),
+            ..e
+        },
+        _ => e,
+    }).collect()
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::app::App;
+use crate::constants::HOLDS_FILE;
+use crate::event::event_processing::send_events;
+use crate::event::file_event::{SharedPath, SyncEvent, SyncEventKind};
+use crate::event::journal::remove_journal;
+use crate::event::optimizer::optimize_events;
+use crate::files::{initialize_json_file, write_json_file_atomic};
+use crate::helpers::{get_default_state_dir, get_now_as_millis};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HoldJSON {
+    // file or directory, everything inside a directory is held with it
+    pub local_path: PathBuf,
+    pub timestamp: i128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HoldStatus {
+    pub local_path: PathBuf,
+    pub timestamp: i128,
+    // changes waiting for the release
+    pub queued: usize,
+}
+
+struct HeldEvent {
+    source: String,
+    journal_id: Option<String>,
+    event: SyncEvent,
+}
+
+// Paths whose changes are kept back until released, e.g. a large file that is still being edited
+static HOLDS: Mutex<Option<Vec<HoldJSON>>> = Mutex::const_new(None);
+// Stay journaled while they wait, so a restart holds them again instead of losing them
+static HELD_EVENTS: std::sync::Mutex<Vec<HeldEvent>> = std::sync::Mutex::new(Vec::new());
+
+fn get_holds_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(HOLDS_FILE)
+}
+
+// Read once, the demon is the only writer
+async fn with_holds<T, F: FnOnce(&mut Vec<HoldJSON>) -> T>(dir: &Path, update: F) -> Result<T, String> {
+    let mut holds = HOLDS.lock().await;
+    if holds.is_none() {
+        *holds = Some(initialize_json_file(get_holds_path(dir), vec![]).await?);
+    }
+    let entries = holds.as_mut().unwrap();
+    let before = entries.clone();
+    let res = update(entries);
+    if *entries != before {
+        write_json_file_atomic(get_holds_path(dir), entries).await?;
+    }
+    Ok(res)
+}
+
+fn get_event_paths(e: &SyncEvent) -> Vec<&SharedPath> {
+    if e.kind == SyncEventKind::Moved { vec![&e.local_path, &e.old_local_path] } else { vec![&e.local_path] }
+}
+
+fn is_covered(holds: &[HoldJSON], e: &SyncEvent) -> bool {
+    get_event_paths(e).iter().any(|p| holds.iter().any(|h| p.starts_with(&h.local_path)))
+}
+
+pub async fn is_held(dir: &Path, e: &SyncEvent) -> bool {
+    with_holds(dir, |h| is_covered(h, e)).await.unwrap_or(false)
+}
+
+pub fn hold_event(source: &str, journal_id: Option<String>, event: SyncEvent) {
+    log::info!("Holding {:?} {}", &event.kind, &event.sync_path);
+    HELD_EVENTS.lock().unwrap().push(HeldEvent { source: source.to_string(), journal_id, event });
+}
+
+pub async fn add_hold(dir: &Path, local_path: &PathBuf) -> Result<HoldJSON, String> {
+    with_holds(dir, |h| {
+        if let Some(hold) = h.iter().find(|h| &h.local_path == local_path) {
+            return hold.clone();
+        }
+        let hold = HoldJSON { local_path: local_path.clone(), timestamp: get_now_as_millis() };
+        h.push(hold.clone());
+        hold
+    }).await
+}
+
+pub async fn list_holds(dir: &Path) -> Result<Vec<HoldStatus>, String> {
+    let holds = with_holds(dir, |h| h.clone()).await?;
+    let events = HELD_EVENTS.lock().unwrap();
+    Ok(holds.into_iter().map(|h| HoldStatus {
+        queued: events.iter().filter(|e| is_covered(std::slice::from_ref(&h), &e.event)).count(),
+        local_path: h.local_path,
+        timestamp: h.timestamp,
+    }).collect())
+}
+
+// Changes that aren't covered by another hold are sent in one go, collapsed as if they happened in a single batch
+pub async fn release_hold(app: &App, local_path: &PathBuf) -> Result<Vec<SyncEvent>, String> {
+    let dir = app.config.lock().await.get_path();
+    let (is_removed, remaining) = with_holds(&dir, |h| {
+        let count = h.len();
+        h.retain(|h| &h.local_path != local_path);
+        (h.len() < count, h.clone())
+    }).await?;
+    if !is_removed {
+        return Err(format!("{:?} is not held", local_path));
+    }
+
+    let released = {
+        let mut events = HELD_EVENTS.lock().unwrap();
+        let (released, kept) = std::mem::take(&mut *events).into_iter().partition::<Vec<HeldEvent>, _>(|e| !is_covered(&remaining, &e.event));
+        *events = kept;
+        released
+    };
+    remove_journal(&dir, &released.iter().filter_map(|e| e.journal_id.clone()).collect::<Vec<String>>()).await?;
+
+    let mut by_source: BTreeMap<String, Vec<SyncEvent>> = BTreeMap::new();
+    for e in released {
+        by_source.entry(e.source).or_default().push(e.event);
+    }
+    let mut sent = vec![];
+    for (source, events) in by_source {
+        let events = optimize_events(&events);
+        sent.extend(events.clone());
+        send_events(app.clone(), &source, events).await;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::app::App;
+use crate::constants::JOURNAL_FILE;
+use crate::event::event_processing::{send_events, submit_events};
+use crate::event::file_event::{SyncEvent, SyncEventKind};
+use crate::event::manifest::restore_pending;
+use crate::files::{initialize_json_file, write_json_file_atomic};
+use crate::helpers::{generate_random_id, get_default_state_dir};
+
+static JOURNAL_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntryJSON {
+    pub id: String,
+    // userId@folderId, several users may sync the same folder
+    pub source: String,
+    pub event: SyncEvent,
+    // batch waiting for approval
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+    // still in the queue of its batch, so not filtered yet
+    #[serde(default)]
+    pub queued: bool,
+}
+
+fn get_journal_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(JOURNAL_FILE)
+}
+
+async fn read_journal(dir: &Path) -> Result<Vec<JournalEntryJSON>, String> {
+    initialize_json_file(get_journal_path(dir), vec![]).await
+}
+
+async fn write_entries(dir: &Path, source: &str, events: &[SyncEvent], manifest: Option<&String>, queued: bool) -> Result<Vec<String>, String> {
+    if events.is_empty() {
+        return Ok(vec![]);
+    }
+    let _lock = JOURNAL_LOCK.lock().await;
+    let mut entries = read_journal(dir).await?;
+    let ids = events.iter().map(|_| generate_random_id()).collect::<Vec<String>>();
+    entries.extend(events.iter().zip(&ids).map(|(e, id)| JournalEntryJSON {
+        id: id.clone(),
+        source: source.to_string(),
+        event: e.clone(),
+        manifest: manifest.cloned(),
+        queued,
+    }));
+    write_json_file_atomic(get_journal_path(dir), &entries).await.map(|_| ids)
+}
+
+// Returns the ids of the entries, in the order of the events
+pub async fn append_journal(dir: &Path, source: &str, events: &[SyncEvent], manifest: Option<&String>) -> Result<Vec<String>, String> {
+    write_entries(dir, source, events, manifest, false).await
+}
+
+// Changes still waiting in the queue of a batch, replayed through the same checks as a batch when they weren't sent
+pub async fn queue_journal(dir: &Path, source: &str, events: &[SyncEvent]) -> Result<Vec<String>, String> {
+    write_entries(dir, source, events, None, true).await
+}
+
+pub async fn remove_journal(dir: &Path, ids: &[String]) -> Result<(), String> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let _lock = JOURNAL_LOCK.lock().await;
+    let mut entries = read_journal(dir).await?;
+    entries.retain(|e| !ids.contains(&e.id));
+    write_json_file_atomic(get_journal_path(dir), &entries).await.map(|_| ())
+}
+
+// Events of the source that aren't sent yet: in flight, retried, held or waiting for approval
+pub async fn list_journal(dir: &Path, source: &String) -> Result<Vec<JournalEntryJSON>, String> {
+    let _lock = JOURNAL_LOCK.lock().await;
+    Ok(read_journal(dir).await?.into_iter().filter(|e| &e.source == source).collect())
+}
+
+pub async fn list_journal_entries(dir: &Path) -> Result<Vec<JournalEntryJSON>, String> {
+    let _lock = JOURNAL_LOCK.lock().await;
+    read_journal(dir).await
+}
+
+// Entries of sources that are no longer configured can never be sent
+pub async fn prune_journal(dir: &Path, sources: &HashSet<String>) -> Result<usize, String> {
+    let _lock = JOURNAL_LOCK.lock().await;
+    let mut entries = read_journal(dir).await?;
+    let count = entries.len();
+    entries.retain(|e| sources.contains(&e.source));
+    if entries.len() != count {
+        write_json_file_atomic(get_journal_path(dir), &entries).await?;
+    }
+    Ok(count - entries.len())
+}
+
+async fn take_journal(dir: &Path) -> Result<Vec<JournalEntryJSON>, String> {
+    let _lock = JOURNAL_LOCK.lock().await;
+    let entries = read_journal(dir).await?;
+    write_json_file_atomic(get_journal_path(dir), &Vec::<JournalEntryJSON>::new()).await?;
+    Ok(entries)
+}
+
+// Journaled events by how they are replayed, userId@folderId -> events
+#[derive(Default)]
+struct Replay {
+    sent: BTreeMap<String, Vec<SyncEvent>>,
+    queued: BTreeMap<String, Vec<SyncEvent>>,
+    // (manifest, userId@folderId) -> events
+    pending: BTreeMap<(String, String), Vec<SyncEvent>>,
+}
+
+// Events the file system has overtaken (a deleted file that exists again, or the other way around) are dropped
+fn plan_replay(entries: Vec<JournalEntryJSON>) -> Replay {
+    let mut replay = Replay::default();
+    for entry in entries {
+        if (entry.event.kind == SyncEventKind::Deleted) == entry.event.local_path.exists() {
+            continue;
+        }
+        match entry.manifest {
+            Some(manifest) => replay.pending.entry((manifest, entry.source)).or_default().push(entry.event),
+            None if entry.queued => replay.queued.entry(entry.source).or_default().push(entry.event),
+            None => replay.sent.entry(entry.source).or_default().push(entry.event),
+        }
+    }
+    replay
+}
+
+// Events left over by a crash are sent again. Files changed since are hashed again, so their current content is sent.
+pub async fn replay_journal(app: &App) -> Result<(), String> {
+    let dir = app.config.lock().await.get_path();
+    let entries = take_journal(&dir).await?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    log::info!("Replaying {} journaled events", entries.len());
+
+    let Replay { mut sent, queued, pending } = plan_replay(entries);
+    // Batches still waiting for approval keep waiting, the others were decided on before the crash
+    for ((manifest, source), events) in pending {
+        if let Some(events) = restore_pending(&dir, &manifest, &source, events).await? {
+            sent.entry(source).or_default().extend(events);
+        }
+    }
+    for (source, events) in sent {
+        send_events(app.clone(), &source, events).await;
+    }
+    for (source, events) in queued {
+        submit_events(app.clone(), &source, events).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::file_event::get_file_event;
+
+    fn paths(events: &[SyncEvent]) -> Vec<String> {
+        events.iter().map(|e| e.sync_path.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn removes_sent_entries() {
+        let dir = std::env::temp_dir().join(generate_random_id());
+        std::fs::create_dir_all(&dir).unwrap();
+        let events = ["a", "b", "c"].map(|p| get_file_event("folder", &dir, &dir.join(p), SyncEventKind::Deleted));
+
+        let ids = append_journal(&dir, "user@folder", &events, None).await.unwrap();
+        assert_eq!(ids.len(), 3);
+        remove_journal(&dir, &ids[..2]).await.unwrap();
+        let left = list_journal_entries(&dir).await.unwrap();
+        assert_eq!(left.iter().map(|e| e.id.clone()).collect::<Vec<String>>(), ids[2..]);
+        assert_eq!(paths(&left.into_iter().map(|e| e.event).collect::<Vec<SyncEvent>>()), ["c"]);
+    }
+
+    #[tokio::test]
+    async fn replays_entries_after_a_restart() {
+        let dir = std::env::temp_dir().join(generate_random_id());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("written"), "1").unwrap();
+        std::fs::write(dir.join("recreated"), "1").unwrap();
+        let event = |path: &str, kind| get_file_event("folder", &dir, &dir.join(path), kind);
+
+        append_journal(&dir, "user@folder", &[event("written", SyncEventKind::Updated), event("removed", SyncEventKind::Deleted)], None).await.unwrap();
+        append_journal(&dir, "user@folder", &[event("recreated", SyncEventKind::Deleted)], None).await.unwrap();
+        append_journal(&dir, "user@folder", &[event("written", SyncEventKind::Updated)], Some(&"manifest".to_string())).await.unwrap();
+        queue_journal(&dir, "user@other", &[event("written", SyncEventKind::Updated), event("vanished", SyncEventKind::Updated)]).await.unwrap();
+
+        // Read once at start, a crash during the replay journals the events again as they are sent
+        let replay = plan_replay(take_journal(&dir).await.unwrap());
+        assert!(list_journal_entries(&dir).await.unwrap().is_empty());
+        assert_eq!(replay.sent.keys().collect::<Vec<&String>>(), ["user@folder"]);
+        assert_eq!(paths(&replay.sent["user@folder"]), ["written", "removed"]);
+        assert_eq!(paths(&replay.pending[&("manifest".to_string(), "user@folder".to_string())]), ["written"]);
+        assert_eq!(paths(&replay.queued["user@other"]), ["written"]);
+    }
+}
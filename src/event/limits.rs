@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::constants::SKIPPED_UPLOADS_SIZE;
+use crate::event::file_event::{FileType, SyncEvent, SyncEventKind};
+use crate::hash::get_hashes;
+use crate::helpers::get_now_as_millis;
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+use crate::server::types::ApiFileResponse;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedUpload {
+    pub sync_path: String,
+    pub size: u64,
+    pub reason: String,
+    pub timestamp: i128,
+}
+
+// source id -> bytes the folder holds, from its last listing plus the uploads queued since
+static USAGE: std::sync::Mutex<BTreeMap<String, u64>> = std::sync::Mutex::new(BTreeMap::new());
+// source id -> uploads left out by the folder limits, latest last
+static SKIPPED: std::sync::Mutex<BTreeMap<String, Vec<SkippedUpload>>> = std::sync::Mutex::new(BTreeMap::new());
+
+pub fn set_remote_usage(source_id: &str, files: &[ApiFileResponse]) {
+    let used = files.iter().filter(|f| !f.hash.is_empty()).map(|f| f.size).sum();
+    USAGE.lock().unwrap().insert(source_id.to_string(), used);
+}
+
+pub fn get_skipped_uploads() -> BTreeMap<String, Vec<SkippedUpload>> {
+    SKIPPED.lock().unwrap().clone()
+}
+
+fn record_skipped(source_id: &str, e: &SyncEvent, reason: String) {
+    log::warn!("Skipping {}: {}", &e.sync_path, &reason);
+    let mut skipped = SKIPPED.lock().unwrap();
+    let entries = skipped.entry(source_id.to_string()).or_default();
+    entries.retain(|s| s.sync_path != *e.sync_path);
+    entries.push(SkippedUpload { sync_path: e.sync_path.to_string(), size: e.size, reason, timestamp: get_now_as_millis() });
+    if entries.len() > SKIPPED_UPLOADS_SIZE {
+        entries.remove(0);
+    }
+}
+
+// A later change that fits, or the removal of the file, clears it
+fn clear_skipped(source_id: &String, e: &SyncEvent) {
+    if let Some(entries) = SKIPPED.lock().unwrap().get_mut(source_id) {
+        entries.retain(|s| s.sync_path != *e.sync_path && s.sync_path != *e.old_sync_path);
+    }
+}
+
+// Checked when a batch is queued instead of failing on the server after the whole body was sent. Sizes are only
+// known up to the last listing of the folder, uploads of other clients since then are found by the server's own check.
+pub async fn check_limits(dir: &PathBuf, source: &SherryConfigSourceJSON, watchers: &HashMap<String, &SherryConfigWatcherJSON>, events: Vec<SyncEvent>) -> Vec<SyncEvent> {
+    let mut hashes_map = HashMap::new();
+    let mut used = USAGE.lock().unwrap().get(&source.id).cloned();
+    let mut is_full = false;
+    let mut accepted = vec![];
+    for e in events {
+        if e.file_type == FileType::File && e.kind != SyncEventKind::Deleted && e.size > source.max_file_size {
+            record_skipped(&source.id, &e, format!("exceeds the file size limit of {} bytes", source.max_file_size));
+            continue;
+        }
+
+        let previous = match watchers.get(&e.base.to_str().unwrap().to_string()) {
+            Some(watcher) => {
+                if !hashes_map.contains_key(&e.base) {
+                    if let Ok(hashes) = get_hashes(dir, source, &e.base, &watcher.hashes_id).await {
+                        hashes_map.insert(e.base.clone(), hashes);
+                    }
+                }
+                hashes_map.get(&e.base)
+                    .and_then(|h| h.hashes.get(e.local_path.to_str().unwrap()))
+                    .map_or(0, |h| h.size)
+            }
+            None => 0,
+        };
+        if let Some(total) = used {
+            match e.kind {
+                SyncEventKind::Deleted => used = Some(total.saturating_sub(previous)),
+                SyncEventKind::Created | SyncEventKind::Updated if e.size > previous => {
+                    let grown = total + e.size - previous;
+                    if grown > source.max_dir_size {
+                        record_skipped(&source.id, &e, format!("exceeds the folder limit of {} bytes", source.max_dir_size));
+                        is_full = true;
+                        continue;
+                    }
+                    used = Some(grown);
+                }
+                SyncEventKind::Created | SyncEventKind::Updated => used = Some((total + e.size).saturating_sub(previous)),
+                SyncEventKind::Moved => {}
+            }
+        }
+        clear_skipped(&source.id, &e);
+        accepted.push(e);
+    }
+    if let Some(total) = used {
+        USAGE.lock().unwrap().insert(source.id.clone(), total);
+    }
+    if is_full {
+        notify(UserMessage::new(MessageCode::QuotaExceeded, &[("folder", &source.id)]));
+    }
+    accepted
+}
@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::app::App;
+use crate::config::SherryConfigBatchApprovalJSON;
+use crate::constants::{MANIFESTS_FILE, MANIFESTS_SIZE};
+use crate::event::event_processing::send_events;
+use crate::event::file_event::{SyncEvent, SyncEventKind};
+use crate::event::journal::{append_journal, remove_journal};
+use crate::files::{initialize_json_file, write_json_file_atomic};
+use crate::helpers::{generate_random_id, get_default_state_dir, get_now_as_millis};
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ManifestState {
+    Sent,
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestFileJSON {
+    pub path: String,
+    pub kind: SyncEventKind,
+    pub size: u64,
+}
+
+// What a batch is about to upload, kept for the latest batches and for every batch waiting for approval
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchManifestJSON {
+    pub id: String,
+    pub source: String,
+    pub timestamp: i128,
+    pub state: ManifestState,
+    pub files: Vec<ManifestFileJSON>,
+    // bytes of created and updated files
+    pub total_bytes: u64,
+}
+
+struct PendingBatch {
+    manifest: String,
+    source: String,
+    journal_ids: Vec<String>,
+    events: Vec<SyncEvent>,
+}
+
+static MANIFESTS: Mutex<Option<Vec<BatchManifestJSON>>> = Mutex::const_new(None);
+// Stay journaled with their manifest id while they wait, so a restart keeps them waiting
+static PENDING: std::sync::Mutex<Vec<PendingBatch>> = std::sync::Mutex::new(Vec::new());
+
+fn get_manifests_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(MANIFESTS_FILE)
+}
+
+// Read once, the demon is the only writer
+async fn with_manifests<T, F: FnOnce(&mut Vec<BatchManifestJSON>) -> T>(dir: &Path, update: F) -> Result<T, String> {
+    let mut manifests = MANIFESTS.lock().await;
+    if manifests.is_none() {
+        *manifests = Some(initialize_json_file(get_manifests_path(dir), vec![]).await?);
+    }
+    let entries = manifests.as_mut().unwrap();
+    let before = entries.clone();
+    let res = update(entries);
+    let decided = entries.iter().filter(|m| m.state != ManifestState::Pending).count();
+    let mut excess = decided.saturating_sub(MANIFESTS_SIZE);
+    entries.retain(|m| {
+        let is_pruned = excess > 0 && m.state != ManifestState::Pending;
+        if is_pruned {
+            excess -= 1;
+        }
+        !is_pruned
+    });
+    if *entries != before {
+        write_json_file_atomic(get_manifests_path(dir), entries).await?;
+    }
+    Ok(res)
+}
+
+fn build_manifest(source: &str, events: &[SyncEvent], state: ManifestState) -> BatchManifestJSON {
+    BatchManifestJSON {
+        id: generate_random_id(),
+        source: source.to_string(),
+        timestamp: get_now_as_millis(),
+        state,
+        files: events.iter().map(|e| ManifestFileJSON { path: e.sync_path.to_string(), kind: e.kind, size: e.size }).collect(),
+        total_bytes: events.iter().filter(|e| matches!(e.kind, SyncEventKind::Created | SyncEventKind::Updated)).map(|e| e.size).sum(),
+    }
+}
+
+fn is_approval_needed(approval: &Option<SherryConfigBatchApprovalJSON>, manifest: &BatchManifestJSON) -> bool {
+    approval.as_ref().is_some_and(|a| manifest.files.len() > a.get_max_files() || manifest.total_bytes > a.get_max_bytes())
+}
+
+// Returns the events to send now, None when the batch waits for `manifest approve`
+pub async fn review_batch(dir: &Path, approval: &Option<SherryConfigBatchApprovalJSON>, source: &String, events: Vec<SyncEvent>) -> Option<Vec<SyncEvent>> {
+    if events.is_empty() {
+        return Some(events);
+    }
+    let mut manifest = build_manifest(source, &events, ManifestState::Sent);
+    log::info!("Batch {} of {}: {} changes, {} bytes", &manifest.id, source, manifest.files.len(), manifest.total_bytes);
+    if !is_approval_needed(approval, &manifest) {
+        if let Err(e) = with_manifests(dir, |m| m.push(manifest)).await {
+            log::error!("Failed to write {}: {}", MANIFESTS_FILE, e);
+        }
+        return Some(events);
+    }
+
+    manifest.state = ManifestState::Pending;
+    let journal_ids = match append_journal(dir, source, &events, Some(&manifest.id)).await {
+        Ok(ids) => ids,
+        Err(e) => {
+            log::error!("Failed to journal events: {}", e);
+            vec![]
+        }
+    };
+    if let Err(e) = with_manifests(dir, |m| m.push(manifest.clone())).await {
+        log::error!("Failed to write {}: {}", MANIFESTS_FILE, e);
+    }
+    log::warn!("Batch {} of {} waits for approval", &manifest.id, source);
+    notify(UserMessage::new(MessageCode::BatchNeedsApproval, &[
+        ("id", &manifest.id),
+        ("folder", source),
+        ("files", &manifest.files.len().to_string()),
+        ("bytes", &manifest.total_bytes.to_string()),
+    ]));
+    PENDING.lock().unwrap().push(PendingBatch { manifest: manifest.id, source: source.clone(), journal_ids, events });
+    None
+}
+
+// Journaled events of a batch that still waits are queued again, the others are returned to be sent
+pub async fn restore_pending(dir: &Path, manifest: &String, source: &str, events: Vec<SyncEvent>) -> Result<Option<Vec<SyncEvent>>, String> {
+    let is_pending = with_manifests(dir, |m| m.iter().any(|m| &m.id == manifest && m.state == ManifestState::Pending)).await?;
+    if !is_pending {
+        return Ok(Some(events));
+    }
+    let journal_ids = append_journal(dir, source, &events, Some(manifest)).await?;
+    PENDING.lock().unwrap().push(PendingBatch { manifest: manifest.clone(), source: source.to_string(), journal_ids, events });
+    Ok(None)
+}
+
+pub async fn list_manifests(dir: &Path) -> Result<Vec<BatchManifestJSON>, String> {
+    with_manifests(dir, |m| m.clone()).await
+}
+
+async fn decide_manifest(dir: &Path, id: &String, state: ManifestState) -> Result<(BatchManifestJSON, PendingBatch), String> {
+    let batch = {
+        let mut pending = PENDING.lock().unwrap();
+        match pending.iter().position(|p| &p.manifest == id) {
+            Some(index) => pending.remove(index),
+            None => return Err(format!("No batch {} waits for approval", id)),
+        }
+    };
+    remove_journal(dir, &batch.journal_ids).await?;
+    let manifest = with_manifests(dir, |m| {
+        let manifest = m.iter_mut().find(|m| &m.id == id)?;
+        manifest.state = state;
+        Some(manifest.clone())
+    }).await?;
+    Ok((manifest.unwrap_or(build_manifest(&batch.source, &batch.events, state)), batch))
+}
+
+pub async fn approve_manifest(app: &App, id: &String) -> Result<BatchManifestJSON, String> {
+    let dir = app.config.lock().await.get_path();
+    let (manifest, batch) = decide_manifest(&dir, id, ManifestState::Approved).await?;
+    log::info!("Batch {} of {} approved", id, &batch.source);
+    send_events(app.clone(), &batch.source, batch.events).await;
+    Ok(manifest)
+}
+
+// The files are left as they are, they are uploaded with their next change or the next fetch of their watcher
+pub async fn reject_manifest(dir: &Path, id: &String) -> Result<BatchManifestJSON, String> {
+    let (manifest, batch) = decide_manifest(dir, id, ManifestState::Rejected).await?;
+    log::info!("Batch {} of {} rejected", id, &batch.source);
+    Ok(manifest)
+}
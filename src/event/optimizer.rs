@@ -0,0 +1,463 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::constants::{ATOMIC_SAVE_PREFIXES, ATOMIC_SAVE_SUFFIXES};
+use crate::event::file_event::{FileType, SharedPath, SharedStr, SyncEvent, SyncEventKind};
+use crate::helpers::PATH_SEP;
+
+#[derive(Default)]
+struct FileLifetime {
+    state: FileState,
+    // positions in the batch of the event that first took the file away from its origin and of its latest event
+    left_origin: Option<usize>,
+    last: usize,
+}
+
+// Net change of a file over a batch, fed its events in time order and followed through moves: `origin` is where the
+// file was before the batch (None when the batch creates it), `last` the latest event and so where it is now
+#[derive(Default)]
+struct FileState {
+    origin: Option<(SharedStr, SharedPath)>,
+    last: Option<SyncEvent>,
+    exists: bool,
+    modified: bool,
+    // removed and then written again at its origin
+    recreated: bool,
+    // of the latest write, the move detection needs it
+    content: (String, u64),
+}
+
+impl FileState {
+    fn apply(&mut self, event: &SyncEvent) {
+        if self.last.is_none() && event.kind != SyncEventKind::Created {
+            self.origin = Some((event.old_sync_path.clone(), event.old_local_path.clone()));
+            self.exists = true;
+        }
+        match event.kind {
+            SyncEventKind::Deleted => self.exists = false,
+            kind => {
+                if !self.exists {
+                    self.recreated = self.origin.is_some();
+                    self.modified = true;
+                }
+                if kind != SyncEventKind::Moved {
+                    self.modified = true;
+                    self.content = (event.update_hash.clone(), event.size);
+                }
+                self.exists = true;
+            }
+        }
+        self.last = Some(event.clone());
+    }
+
+    fn deleted_origin(&self, last: &SyncEvent) -> Option<SyncEvent> {
+        self.origin.as_ref().filter(|(path, _)| path != &last.sync_path).map(|(path, local_path)| SyncEvent {
+            kind: SyncEventKind::Deleted,
+            sync_path: path.clone(),
+            old_sync_path: path.clone(),
+            local_path: local_path.clone(),
+            old_local_path: local_path.clone(),
+            update_hash: "".to_string(),
+            size: 0,
+            ..last.clone()
+        })
+    }
+
+    fn written(&self, last: &SyncEvent, kind: SyncEventKind) -> SyncEvent {
+        SyncEvent {
+            kind,
+            old_sync_path: last.sync_path.clone(),
+            old_local_path: last.local_path.clone(),
+            update_hash: self.content.0.clone(),
+            size: self.content.1,
+            ..last.clone()
+        }
+    }
+
+    // The removal from the origin, due when the file left there, and the events due where it ended up. A removal at the end
+    // is sent there as well, a move may have replaced a file there. When another file used the origin while this one was
+    // away (`origin_kept` is false) the origin is no longer this file's: a move from there can't be sent, as it frees the
+    // origin only when it is sent, and a file back at its origin has to be written again.
+    fn finish(self, origin_kept: bool) -> (Option<SyncEvent>, Vec<SyncEvent>) {
+        let last = match &self.last {
+            Some(last) => last,
+            None => return (None, vec![]),
+        };
+        if !self.exists {
+            // The stored content is only this file's when it never left its origin, a move can only be made from that
+            let removal = match &self.origin {
+                Some((path, _)) if path == &last.sync_path && origin_kept => last.clone(),
+                _ => SyncEvent { update_hash: "".to_string(), size: 0, ..last.clone() },
+            };
+            return (self.deleted_origin(last), vec![removal]);
+        }
+        match &self.origin {
+            None => (None, vec![self.written(last, SyncEventKind::Created)]),
+            Some((path, _)) if path == &last.sync_path => match (self.modified, origin_kept) {
+                (false, true) => (None, vec![]),
+                (true, true) if !self.recreated => (None, vec![self.written(last, SyncEventKind::Updated)]),
+                _ => (None, vec![self.written(last, SyncEventKind::Created)]),
+            },
+            Some((path, local_path)) if !self.modified && origin_kept => (None, vec![SyncEvent {
+                kind: SyncEventKind::Moved,
+                old_sync_path: path.clone(),
+                old_local_path: local_path.clone(),
+                ..last.clone()
+            }]),
+            // Changed and moved, the new content has to be uploaded anyway
+            Some(_) => (self.deleted_origin(last), vec![self.written(last, SyncEventKind::Created)]),
+        }
+    }
+}
+
+fn is_atomic_save_path(sync_path: &str) -> bool {
+    let name = sync_path.rsplit(PATH_SEP).next().unwrap_or(sync_path);
+    ATOMIC_SAVE_PREFIXES.iter().any(|p| name.starts_with(p)) || ATOMIC_SAVE_SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+// Editors save by writing a temp file and renaming it over the real one. A temp name written in the batch and then renamed
+// onto a real name becomes an update of the real file, one written and removed again is left out. Anything else with a
+// temp name is a file like any other: one that stays, or a real file renamed to a backup, is synced as it is.
+fn collapse_atomic_saves(events: &[SyncEvent]) -> Vec<SyncEvent> {
+    // temp path -> positions of its events since it was written in the batch
+    let mut written: HashMap<&SharedStr, Vec<usize>> = HashMap::new();
+    let mut dropped = HashSet::new();
+    let mut saved = HashSet::new();
+    for (i, e) in events.iter().enumerate().filter(|(_, e)| e.file_type == FileType::File) {
+        match e.kind {
+            SyncEventKind::Created | SyncEventKind::Updated if is_atomic_save_path(&e.sync_path) => {
+                written.entry(&e.sync_path).or_default().push(i);
+            }
+            SyncEventKind::Deleted => {
+                if let Some(positions) = written.remove(&e.sync_path) {
+                    dropped.extend(positions);
+                    dropped.insert(i);
+                }
+            }
+            SyncEventKind::Moved => {
+                let positions = match written.remove(&e.old_sync_path) {
+                    Some(positions) => positions,
+                    None => continue,
+                };
+                if is_atomic_save_path(&e.sync_path) {
+                    written.entry(&e.sync_path).or_default().extend(positions.into_iter().chain([i]));
+                } else {
+                    dropped.extend(positions);
+                    saved.insert(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    events.iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(i, e)| match saved.contains(&i) {
+        true => SyncEvent {
+            kind: SyncEventKind::Updated,
+            old_sync_path: e.sync_path.clone(),
+            old_local_path: e.local_path.clone(),
+            ..e.clone()
+        },
+        false => e.clone(),
+    }).collect()
+}
+
+// A file written or removed where another one left overwrites the removal, as when one file is removed and written again
+fn overwrite_removals(events: Vec<SyncEvent>) -> Vec<SyncEvent> {
+    let mut removed: HashMap<SharedStr, usize> = HashMap::new();
+    let mut dropped = HashSet::new();
+    let mut events = events;
+    for (i, e) in events.iter_mut().enumerate() {
+        let pending = removed.remove(&e.sync_path);
+        removed.remove(&e.old_sync_path);
+        match e.kind {
+            SyncEventKind::Created | SyncEventKind::Updated if pending.is_some() => {
+                dropped.extend(pending);
+                e.kind = SyncEventKind::Created;
+            }
+            SyncEventKind::Deleted => {
+                dropped.extend(pending);
+                removed.insert(e.sync_path.clone(), i);
+            }
+            _ => {}
+        }
+    }
+    events.into_iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(_, e)| e).collect()
+}
+
+// A file removed from one place and created with the same content and size in another of the same watcher was moved,
+// notify reports moves between directories like that. Hashes are only known when the caller filled them in.
+fn detect_content_moves(events: Vec<SyncEvent>) -> Vec<SyncEvent> {
+    let is_candidate = |e: &SyncEvent, kind: SyncEventKind| e.kind == kind && e.file_type == FileType::File && !e.update_hash.is_empty();
+    let mut deleted: HashMap<(SharedPath, String, u64), Vec<usize>> = HashMap::new();
+    for (i, e) in events.iter().enumerate().rev() {
+        if is_candidate(e, SyncEventKind::Deleted) {
+            deleted.entry((e.base.clone(), e.update_hash.clone(), e.size)).or_default().push(i);
+        }
+    }
+
+    // The move is sent in place of the creation, so neither path may be used by anything sent in between
+    let mut positions: HashMap<&SharedStr, Vec<usize>> = HashMap::new();
+    for (i, e) in events.iter().enumerate() {
+        positions.entry(&e.sync_path).or_default().push(i);
+    }
+    let used_between = |path: &SharedStr, a: usize, b: usize| {
+        let (a, b) = (a.min(b), a.max(b));
+        positions.get(path).is_some_and(|p| p.iter().any(|i| a < *i && *i < b))
+    };
+
+    let mut moved_from: HashMap<usize, usize> = HashMap::new();
+    for (i, e) in events.iter().enumerate() {
+        if !is_candidate(e, SyncEventKind::Created) {
+            continue;
+        }
+        let candidates = match deleted.get_mut(&(e.base.clone(), e.update_hash.clone(), e.size)) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+        let from = candidates.iter().rposition(|from| {
+            let removed = &events[*from].sync_path;
+            removed != &e.sync_path && !used_between(removed, *from, i) && !used_between(&e.sync_path, *from, i)
+        });
+        if let Some(from) = from {
+            moved_from.insert(i, candidates.remove(from));
+        }
+    }
+    // Removals that weren't matched go out without content, like any other removal
+    let sources = moved_from.values().copied().collect::<HashSet<usize>>();
+    events.iter().enumerate().filter_map(|(i, e)| {
+        if sources.contains(&i) {
+            return None;
+        }
+        Some(match moved_from.get(&i) {
+            Some(from) => SyncEvent {
+                kind: SyncEventKind::Moved,
+                old_sync_path: events[*from].sync_path.clone(),
+                old_local_path: events[*from].local_path.clone(),
+                ..e.clone()
+            },
+            None if is_candidate(e, SyncEventKind::Deleted) => SyncEvent { update_hash: "".to_string(), size: 0, ..e.clone() },
+            None => e.clone(),
+        })
+    }).collect()
+}
+
+// Collapses the events of a batch into the fewest events with the same outcome, following files through move chains.
+// Pure, so it can be reasoned about apart from the filesystem.
+pub fn optimize_events(events: &[SyncEvent]) -> Vec<SyncEvent> {
+    let mut events = events.to_vec();
+    // Stable, events of the same millisecond keep the order they were reported in
+    events.sort_by_key(|e| e.timestamp);
+    let events = collapse_atomic_saves(&events);
+
+    // A file is followed by the path it has at the time, a path freed by a move starts a new file when it's written again
+    let mut lifetimes: Vec<FileLifetime> = vec![];
+    let mut at: HashMap<&SharedStr, usize> = HashMap::new();
+    // positions and files of the events using each path
+    let mut uses: HashMap<&SharedStr, Vec<(usize, usize)>> = HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        let current = match event.kind {
+            SyncEventKind::Moved => at.remove(&event.old_sync_path),
+            _ => at.get(&event.sync_path).copied(),
+        };
+        let id = current.unwrap_or_else(|| {
+            lifetimes.push(FileLifetime::default());
+            lifetimes.len() - 1
+        });
+        at.insert(&event.sync_path, id);
+        uses.entry(&event.sync_path).or_default().push((i, id));
+
+        let lifetime = &mut lifetimes[id];
+        if lifetime.left_origin.is_none() && matches!(event.kind, SyncEventKind::Moved | SyncEventKind::Deleted) {
+            lifetime.left_origin = Some(i);
+        }
+        lifetime.last = i;
+        lifetime.state.apply(event);
+    }
+
+    // Each event is sent at the position of the one it stands for, so files that took each other's paths keep their order
+    let mut new_events = Vec::with_capacity(events.len());
+    for (id, lifetime) in lifetimes.into_iter().enumerate() {
+        let left = lifetime.left_origin.unwrap_or(lifetime.last);
+        let origin_kept = lifetime.state.origin.as_ref().and_then(|(path, _)| uses.get(path))
+            .is_none_or(|uses| !uses.iter().any(|(i, other)| *other != id && left < *i && *i < lifetime.last));
+        let (removal, rest) = lifetime.state.finish(origin_kept);
+        if let (Some(removal), Some(left)) = (removal, lifetime.left_origin) {
+            new_events.push((left, SyncEvent { timestamp: events[left].timestamp, ..removal }));
+        }
+        new_events.extend(rest.into_iter().map(|e| (lifetime.last, e)));
+    }
+    new_events.sort_by_key(|(position, _)| *position);
+
+    detect_content_moves(overwrite_removals(new_events.into_iter().map(|(_, e)| e).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::event::file_event::{intern_path, intern_str, share_pair};
+
+    const NAMES: [&str; 5] = ["a", "b", "c", "d/e", "d/f"];
+
+    fn event(kind: SyncEventKind, old_sync_path: &str, sync_path: &str, content: &str, timestamp: usize) -> SyncEvent {
+        let base = intern_path(Path::new("/base"));
+        let (local_path, old_local_path) = share_pair(base.join(sync_path).into(), base.join(old_sync_path).into());
+        let (sync_path, old_sync_path) = share_pair(sync_path.into(), old_sync_path.into());
+        SyncEvent {
+            source_id: intern_str("source"),
+            base,
+            file_type: FileType::File,
+            kind,
+            local_path,
+            old_local_path,
+            sync_path,
+            old_sync_path,
+            update_hash: content.to_string(),
+            size: content.len() as u64,
+            timestamp: timestamp as i128,
+            attributes: None,
+        }
+    }
+
+    // sync path -> content
+    type Files = BTreeMap<String, String>;
+
+    // Uploads read the file as it is once the batch is sent, on disk when given and else as the event has it
+    fn apply(files: &mut Files, e: &SyncEvent, disk: Option<&Files>) {
+        match e.kind {
+            SyncEventKind::Created | SyncEventKind::Updated => {
+                match disk.map_or(Some(&e.update_hash), |disk| disk.get(&*e.sync_path)) {
+                    Some(content) => files.insert(e.sync_path.to_string(), content.clone()),
+                    None => files.remove(&*e.sync_path),
+                };
+            }
+            SyncEventKind::Deleted => {
+                files.remove(&*e.sync_path);
+            }
+            SyncEventKind::Moved => {
+                if let Some(content) = files.remove(&*e.old_sync_path) {
+                    files.insert(e.sync_path.to_string(), content);
+                }
+            }
+        }
+    }
+
+    fn apply_all(files: &Files, events: &[SyncEvent], disk: Option<&Files>) -> Files {
+        let mut files = files.clone();
+        events.iter().for_each(|e| apply(&mut files, e, disk));
+        files
+    }
+
+    fn describe(events: &[SyncEvent]) -> Vec<(SyncEventKind, String, String, String)> {
+        events.iter().map(|e| (e.kind, e.old_sync_path.to_string(), e.sync_path.to_string(), e.update_hash.clone())).collect()
+    }
+
+    // Files before the batch and the events of a batch that is possible from there, as the filesystem watcher reports it:
+    // removals carry the content they removed, moves never replace a file
+    fn batch() -> impl Strategy<Value=(Files, Vec<SyncEvent>)> {
+        let files = proptest::collection::btree_map(proptest::sample::select(NAMES.to_vec()), 0..4u8, 0..NAMES.len());
+        let ops = proptest::collection::vec((0..4u8, 0..NAMES.len(), 0..NAMES.len(), 0..4u8), 0..24);
+        (files, ops).prop_map(|(files, ops)| {
+            let initial = files.into_iter().map(|(name, content)| (name.to_string(), format!("v{}", content))).collect::<Files>();
+            let mut current = initial.clone();
+            let mut events = vec![];
+            for (op, from, to, content) in ops {
+                let (from, to, content) = (NAMES[from], NAMES[to], format!("v{}", content));
+                let e = match op {
+                    0 if current.contains_key(from) => event(SyncEventKind::Updated, from, from, &content, events.len()),
+                    0 | 1 => event(SyncEventKind::Created, from, from, &content, events.len()),
+                    // Removals get the content stored before the batch
+                    2 if current.contains_key(from) => {
+                        event(SyncEventKind::Deleted, from, from, initial.get(from).map_or("", |c| c.as_str()), events.len())
+                    }
+                    3 if current.contains_key(from) && !current.contains_key(to) => {
+                        event(SyncEventKind::Moved, from, to, &current[from], events.len())
+                    }
+                    _ => continue,
+                };
+                if e.kind == SyncEventKind::Created && current.contains_key(from) {
+                    continue;
+                }
+                apply(&mut current, &e, None);
+                events.push(e);
+            }
+            (initial, events)
+        })
+    }
+
+    #[test]
+    fn collapses_atomic_saves() {
+        let events = vec![
+            event(SyncEventKind::Created, "a.txt.tmp", "a.txt.tmp", "", 0),
+            event(SyncEventKind::Updated, "a.txt.tmp", "a.txt.tmp", "v1", 1),
+            event(SyncEventKind::Moved, "a.txt.tmp", "a.txt", "v1", 2),
+            event(SyncEventKind::Created, ".#b.txt", ".#b.txt", "", 3),
+            event(SyncEventKind::Deleted, ".#b.txt", ".#b.txt", "", 4),
+        ];
+        assert_eq!(describe(&optimize_events(&events)), vec![(SyncEventKind::Updated, "a.txt".to_string(), "a.txt".to_string(), "v1".to_string())]);
+    }
+
+    #[test]
+    fn keeps_renames_to_backup_names() {
+        let events = vec![event(SyncEventKind::Moved, "report.txt", "report.txt~", "v1", 0)];
+        assert_eq!(describe(&optimize_events(&events)), describe(&events));
+
+        let events = vec![event(SyncEventKind::Moved, "report.txt", "report.tmp", "v1", 0)];
+        assert_eq!(describe(&optimize_events(&events)), describe(&events));
+    }
+
+    #[test]
+    fn keeps_temp_names_that_stay() {
+        let events = vec![
+            event(SyncEventKind::Created, "report.tmp", "report.tmp", "", 0),
+            event(SyncEventKind::Updated, "report.tmp", "report.tmp", "v1", 1),
+        ];
+        assert_eq!(describe(&optimize_events(&events)), vec![(SyncEventKind::Created, "report.tmp".to_string(), "report.tmp".to_string(), "v1".to_string())]);
+    }
+
+    proptest! {
+        #[test]
+        fn keeps_the_outcome((files, events) in batch()) {
+            let disk = apply_all(&files, &events, None);
+            prop_assert_eq!(apply_all(&files, &optimize_events(&events), Some(&disk)), disk);
+        }
+
+        #[test]
+        fn is_idempotent((_, events) in batch()) {
+            let optimized = optimize_events(&events);
+            prop_assert_eq!(describe(&optimize_events(&optimized)), describe(&optimized));
+        }
+
+        #[test]
+        fn never_grows((_, events) in batch()) {
+            prop_assert!(optimize_events(&events).len() <= events.len());
+        }
+
+        #[test]
+        fn keeps_deletes((files, events) in batch()) {
+            let optimized = optimize_events(&events);
+            let remaining = apply_all(&files, &events, None);
+            for gone in files.keys().filter(|p| !remaining.contains_key(*p)) {
+                prop_assert!(
+                    optimized.iter().any(|e| match e.kind {
+                        SyncEventKind::Deleted => &*e.sync_path == gone,
+                        SyncEventKind::Moved => &*e.old_sync_path == gone,
+                        _ => false,
+                    }),
+                    "removal of {} was lost: {:?}", gone, describe(&optimized)
+                );
+            }
+        }
+
+        #[test]
+        fn collapses_move_chains(chain in proptest::sample::subsequence(NAMES.to_vec(), 2..=NAMES.len()).prop_shuffle()) {
+            let events = chain.windows(2).enumerate().map(|(i, w)| event(SyncEventKind::Moved, w[0], w[1], "", i)).collect::<Vec<SyncEvent>>();
+            prop_assert_eq!(
+                describe(&optimize_events(&events)),
+                vec![(SyncEventKind::Moved, chain[0].to_string(), chain[chain.len() - 1].to_string(), "".to_string())]
+            );
+        }
+    }
+}
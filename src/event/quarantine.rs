@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::constants::{QUARANTINE_FILE, QUARANTINE_REJECTIONS};
+use crate::event::file_event::SyncEvent;
+use crate::files::{initialize_json_file, write_json_file_atomic};
+use crate::helpers::{get_default_state_dir, get_now_as_millis};
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantineJSON {
+    // userId@folderId
+    pub source: String,
+    pub sync_path: String,
+    pub local_path: PathBuf,
+    pub last_error: String,
+    pub timestamp: i128,
+}
+
+// Files that keep failing are left alone until the user clears them, instead of being sent with every change
+static QUARANTINE: Mutex<Option<Vec<QuarantineJSON>>> = Mutex::const_new(None);
+// (source, local path) -> rejections in a row
+static REJECTIONS: std::sync::Mutex<BTreeMap<(String, PathBuf), u32>> = std::sync::Mutex::new(BTreeMap::new());
+
+fn get_quarantine_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(QUARANTINE_FILE)
+}
+
+// Read once, the demon is the only writer
+async fn with_quarantine<T, F: FnOnce(&mut Vec<QuarantineJSON>) -> T>(dir: &Path, update: F) -> Result<T, String> {
+    let mut quarantine = QUARANTINE.lock().await;
+    if quarantine.is_none() {
+        *quarantine = Some(initialize_json_file(get_quarantine_path(dir), vec![]).await?);
+    }
+    let entries = quarantine.as_mut().unwrap();
+    let before = entries.clone();
+    let res = update(entries);
+    if *entries != before {
+        write_json_file_atomic(get_quarantine_path(dir), entries).await?;
+    }
+    Ok(res)
+}
+
+pub async fn is_quarantined(dir: &Path, source: &String, local_path: &Path) -> bool {
+    with_quarantine(dir, |q| q.iter().any(|e| &e.source == source && e.local_path.as_path() == local_path)).await.unwrap_or(false)
+}
+
+pub async fn quarantine(dir: &Path, source: &String, event: &SyncEvent, error: &String) {
+    REJECTIONS.lock().unwrap().remove(&(source.clone(), event.local_path.to_path_buf()));
+    let is_added = with_quarantine(dir, |q| {
+        if q.iter().any(|e| &e.source == source && e.local_path.as_path() == &*event.local_path) {
+            return false;
+        }
+        q.push(QuarantineJSON {
+            source: source.clone(),
+            sync_path: event.sync_path.to_string(),
+            local_path: event.local_path.to_path_buf(),
+            last_error: error.clone(),
+            timestamp: get_now_as_millis(),
+        });
+        true
+    }).await;
+    match is_added {
+        Ok(true) => {
+            log::error!("Quarantined {:?}: {}", &event.local_path, error);
+            notify(UserMessage::new(MessageCode::FileQuarantined, &[("path", &event.sync_path)]));
+        }
+        Ok(false) => {}
+        Err(e) => log::error!("Failed to quarantine {:?}: {}", &event.local_path, e),
+    }
+}
+
+// Quarantines the file once the server rejected it too many times in a row
+pub async fn record_rejection(dir: &Path, source: &String, event: &SyncEvent) {
+    let key = (source.clone(), event.local_path.to_path_buf());
+    let rejections = {
+        let mut rejections = REJECTIONS.lock().unwrap();
+        let count = rejections.entry(key).or_default();
+        *count += 1;
+        *count
+    };
+    if rejections >= QUARANTINE_REJECTIONS {
+        quarantine(dir, source, event, &format!("rejected by the server {} times in a row", rejections)).await;
+    }
+}
+
+pub fn record_success(source: &str, event: &SyncEvent) {
+    REJECTIONS.lock().unwrap().remove(&(source.to_string(), event.local_path.to_path_buf()));
+}
+
+pub async fn list_quarantine(dir: &Path) -> Result<Vec<QuarantineJSON>, String> {
+    with_quarantine(dir, |q| q.clone()).await
+}
+
+// Files of sources that are no longer configured, or that are gone, can't fail again
+pub async fn prune_quarantine(dir: &Path, sources: &HashSet<String>) -> Result<usize, String> {
+    with_quarantine(dir, |q| {
+        let count = q.len();
+        q.retain(|e| sources.contains(&e.source) && e.local_path.exists());
+        count - q.len()
+    }).await
+}
+
+// Cleared files are uploaded again with their next change
+pub async fn clear_quarantine(dir: &Path, path: &Option<String>) -> Result<Vec<QuarantineJSON>, String> {
+    with_quarantine(dir, |q| {
+        let (cleared, kept) = std::mem::take(q).into_iter()
+            .partition::<Vec<QuarantineJSON>, _>(|e| path.as_ref().is_none_or(|p| &e.sync_path == p || e.local_path.to_str() == Some(p)));
+        *q = kept;
+        cleared
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::file_event::{get_file_event, SyncEventKind};
+    use crate::helpers::generate_random_id;
+
+    // The quarantine is read once, so every test uses sources of its own
+    fn setup() -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(generate_random_id());
+        std::fs::create_dir_all(&dir).unwrap();
+        (dir, format!("user@{}", generate_random_id()))
+    }
+
+    fn event(dir: &Path, path: &str) -> SyncEvent {
+        get_file_event("folder", dir, &dir.join(path), SyncEventKind::Updated)
+    }
+
+    #[tokio::test]
+    async fn quarantines_after_repeated_rejections() {
+        let (dir, source) = setup();
+        let event = event(&dir, "file");
+        for _ in 1..QUARANTINE_REJECTIONS {
+            record_rejection(&dir, &source, &event).await;
+            assert!(!is_quarantined(&dir, &source, &event.local_path).await);
+        }
+        record_rejection(&dir, &source, &event).await;
+        assert!(is_quarantined(&dir, &source, &event.local_path).await);
+        assert!(!is_quarantined(&dir, &"user@other".to_string(), &event.local_path).await);
+    }
+
+    #[tokio::test]
+    async fn restarts_counting_after_a_success() {
+        let (dir, source) = setup();
+        let event = event(&dir, "file");
+        for _ in 0..2 {
+            for _ in 1..QUARANTINE_REJECTIONS {
+                record_rejection(&dir, &source, &event).await;
+            }
+            record_success(&source, &event);
+        }
+        assert!(!is_quarantined(&dir, &source, &event.local_path).await);
+    }
+
+    #[tokio::test]
+    async fn releases_cleared_files() {
+        let (dir, source) = setup();
+        let (first, second) = (event(&dir, "first"), event(&dir, "second"));
+        quarantine(&dir, &source, &first, &"error".to_string()).await;
+        quarantine(&dir, &source, &second, &"error".to_string()).await;
+
+        let cleared = clear_quarantine(&dir, &Some(first.sync_path.to_string())).await.unwrap();
+        assert_eq!(cleared.iter().map(|e| &e.local_path).collect::<Vec<&PathBuf>>(), [&first.local_path.to_path_buf()]);
+        assert!(!is_quarantined(&dir, &source, &first.local_path).await);
+        assert!(is_quarantined(&dir, &source, &second.local_path).await);
+
+        let cleared = clear_quarantine(&dir, &Some(second.local_path.to_str().unwrap().to_string())).await.unwrap();
+        assert_eq!(cleared.len(), 1);
+        assert!(!is_quarantined(&dir, &source, &second.local_path).await);
+        // A released file that fails again starts over
+        record_rejection(&dir, &source, &second).await;
+        assert!(!is_quarantined(&dir, &source, &second.local_path).await);
+    }
+}
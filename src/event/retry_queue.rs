@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::app::App;
+use crate::config::get_hashes_dir;
+use crate::constants::{RETRY_QUEUE_BACKOFF, RETRY_QUEUE_BACKOFF_MAX, RETRY_QUEUE_MAX_ATTEMPTS, RETRY_QUEUE_TICK};
+use crate::event::dead_letters::push_dead_letter;
+use crate::event::event_processing::{apply_event_hash, send_event};
+use crate::event::file_event::{complete_events, SyncEvent, SyncEventKind};
+use crate::event::journal::remove_journal;
+use crate::event::quarantine::quarantine;
+use crate::hash::{get_hashes, update_hashes};
+use crate::server::storage::get_storage;
+
+struct RetryEntry {
+    // userId@folderId
+    source: String,
+    event: SyncEvent,
+    journal_id: Option<String>,
+    attempts: u32,
+    next_attempt: Instant,
+    errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryStatus {
+    pub source: String,
+    pub path: String,
+    pub attempts: u32,
+    // seconds
+    pub next_attempt_in: u64,
+    pub last_error: String,
+}
+
+// Uploads that ran out of immediate retries wait here with exponential backoff before they end up in the dead letters
+static RETRIES: std::sync::Mutex<BTreeMap<u64, RetryEntry>> = std::sync::Mutex::new(BTreeMap::new());
+static NEXT_RETRY: AtomicU64 = AtomicU64::new(0);
+
+fn get_backoff(attempts: u32) -> Duration {
+    Duration::from_secs(RETRY_QUEUE_BACKOFF.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(RETRY_QUEUE_BACKOFF_MAX))
+}
+
+impl RetryEntry {
+    // Counts a failed attempt and schedules the next one, false once the entry has run out of attempts
+    fn fail(&mut self, errors: Vec<String>, now: Instant) -> bool {
+        self.errors.extend(errors);
+        self.attempts += 1;
+        if self.attempts >= RETRY_QUEUE_MAX_ATTEMPTS {
+            return false;
+        }
+        self.next_attempt = now + get_backoff(self.attempts);
+        true
+    }
+}
+
+pub fn queue_retry(source: &str, event: &SyncEvent, journal_id: Option<String>, errors: Vec<String>) {
+    log::warn!("Upload of {} failed, retrying in {}s", &event.sync_path, get_backoff(1).as_secs());
+    RETRIES.lock().unwrap().insert(NEXT_RETRY.fetch_add(1, Ordering::SeqCst), RetryEntry {
+        source: source.to_string(),
+        event: event.clone(),
+        journal_id,
+        attempts: 1,
+        next_attempt: Instant::now() + get_backoff(1),
+        errors,
+    });
+}
+
+pub fn get_retry_status() -> Vec<RetryStatus> {
+    let now = Instant::now();
+    RETRIES.lock().unwrap().values().map(|r| RetryStatus {
+        source: r.source.clone(),
+        path: r.event.sync_path.to_string(),
+        attempts: r.attempts,
+        next_attempt_in: r.next_attempt.saturating_duration_since(now).as_secs(),
+        last_error: r.errors.last().cloned().unwrap_or_default(),
+    }).collect()
+}
+
+// Ok(()) once the entry is done with, Err(errors) to keep retrying
+async fn retry(app: &App, entry: &RetryEntry) -> Result<(), Vec<String>> {
+    let (dir, config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await)
+    };
+    let source = match config.sources.get(&entry.source) {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+    let watcher = match config.watchers.iter().find(|w| w.source == entry.source && w.local_path == entry.event.base.to_str().unwrap()) {
+        Some(watcher) => watcher,
+        None => return Ok(()),
+    };
+    // Overtaken by later events, which are sent on their own
+    if (entry.event.kind == SyncEventKind::Deleted) == entry.event.local_path.exists() {
+        return Ok(());
+    }
+    let event = complete_events(&vec![entry.event.clone()]).await.remove(0);
+    let user = auth.records.get(&source.user_id).ok_or(vec![format!("Unknown user {}", &source.user_id)])?;
+
+    send_event(get_storage(&config.api_url, source, user).as_ref(), &event, 0).await?;
+    let hashes_dir = get_hashes_dir(&dir, &config);
+    if let Ok(mut hashes) = get_hashes(&hashes_dir, source, &event.base, &watcher.hashes_id).await {
+        apply_event_hash(&mut hashes, &event);
+        update_hashes(&hashes_dir, &hashes).await.ok();
+    }
+    log::info!("Upload of {} succeeded after {} retries", &event.sync_path, entry.attempts);
+    Ok(())
+}
+
+pub fn start_retry_queue(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(RETRY_QUEUE_TICK)).await;
+            let due = {
+                let mut retries = RETRIES.lock().unwrap();
+                let now = Instant::now();
+                let ids = retries.iter().filter(|(_, r)| r.next_attempt <= now).map(|(id, _)| *id).collect::<Vec<u64>>();
+                ids.into_iter().filter_map(|id| retries.remove(&id).map(|r| (id, r))).collect::<Vec<(u64, RetryEntry)>>()
+            };
+            for (id, mut entry) in due {
+                let dir = app.config.lock().await.get_path();
+                match retry(&app, &entry).await {
+                    Ok(_) => {}
+                    Err(errors) => {
+                        if entry.fail(errors, Instant::now()) {
+                            log::warn!("Upload of {} failed {} times, retrying in {}s", &entry.event.sync_path, entry.attempts, get_backoff(entry.attempts).as_secs());
+                            RETRIES.lock().unwrap().insert(id, entry);
+                            continue;
+                        }
+                        push_dead_letter(&dir, &entry.event, &entry.errors).await.ok();
+                        quarantine(&dir, &entry.source, &entry.event, &entry.errors.last().cloned().unwrap_or_default()).await;
+                    }
+                }
+                if let Some(journal_id) = entry.journal_id {
+                    remove_journal(&dir, &[journal_id]).await.ok();
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::event::file_event::get_file_event;
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_max() {
+        let backoff = (1..=10).map(|a| get_backoff(a).as_secs()).collect::<Vec<u64>>();
+        assert_eq!(backoff, [30, 60, 120, 240, 480, 960, 1920, 3600, 3600, 3600]);
+        assert_eq!(get_backoff(u32::MAX).as_secs(), RETRY_QUEUE_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn gives_up_after_the_max_attempts() {
+        let base = PathBuf::from("/watched");
+        let now = Instant::now();
+        let mut entry = RetryEntry {
+            source: "user@folder".to_string(),
+            event: get_file_event("folder", &base, &base.join("file"), SyncEventKind::Updated),
+            journal_id: None,
+            attempts: 1,
+            next_attempt: now + get_backoff(1),
+            errors: vec!["error 1".to_string()],
+        };
+        for attempts in 2..RETRY_QUEUE_MAX_ATTEMPTS {
+            assert!(entry.fail(vec![format!("error {}", attempts)], now));
+            assert_eq!(entry.attempts, attempts);
+            assert_eq!(entry.next_attempt, now + get_backoff(attempts));
+        }
+        assert!(!entry.fail(vec!["last error".to_string()], now));
+        assert_eq!(entry.attempts, RETRY_QUEUE_MAX_ATTEMPTS);
+        assert_eq!(entry.errors.len(), RETRY_QUEUE_MAX_ATTEMPTS as usize);
+        assert_eq!(entry.errors.last().unwrap(), "last error");
+    }
+}
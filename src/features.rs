@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::config::SherryConfigJSON;
+use crate::constants::FEATURES_REFRESH_INTERVAL;
+use crate::server::api::ApiClient;
+
+// Subsystems that are switched on per folder or account until they are proven, names are the keys in `features`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "camelCase")]
+pub enum Feature {
+    DeltaSync,
+    P2p,
+    OnDemandFiles,
+}
+
+pub const FEATURES: [Feature; 3] = [Feature::DeltaSync, Feature::P2p, Feature::OnDemandFiles];
+
+impl Feature {
+    pub fn name(&self) -> String {
+        serde_json::to_value(self).ok().and_then(|v| v.as_str().map(|s| s.to_string())).unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct FeatureFlags {
+    global: BTreeMap<String, bool>,
+    // source id -> flags of the folder
+    sources: BTreeMap<String, BTreeMap<String, bool>>,
+    // source id -> user id, to find the account flags of a folder
+    users: BTreeMap<String, String>,
+    // user id -> flags the server enabled for the account
+    server: BTreeMap<String, BTreeMap<String, bool>>,
+}
+
+static FLAGS: std::sync::Mutex<Option<FeatureFlags>> = std::sync::Mutex::new(None);
+
+pub fn set_features(config: &SherryConfigJSON) {
+    let mut flags = FLAGS.lock().unwrap();
+    let flags = flags.get_or_insert_with(FeatureFlags::default);
+    flags.global = config.features.clone().unwrap_or_default();
+    flags.sources = config.sources.values().filter_map(|s| s.features.clone().map(|f| (s.id.clone(), f))).collect();
+    flags.users = config.sources.values().map(|s| (s.id.clone(), s.user_id.clone())).collect();
+}
+
+fn set_server_features(user_id: &str, features: BTreeMap<String, bool>) {
+    FLAGS.lock().unwrap().get_or_insert_with(FeatureFlags::default).server.insert(user_id.to_string(), features);
+}
+
+// The folder wins over the config, the config over the server, so a flag can always be turned off locally
+pub fn is_enabled(feature: Feature, source_id: &String) -> bool {
+    let flags = FLAGS.lock().unwrap();
+    let flags = match flags.as_ref() {
+        Some(flags) => flags,
+        None => return false,
+    };
+    let name = feature.name();
+    flags.sources.get(source_id).and_then(|f| f.get(&name))
+        .or_else(|| flags.global.get(&name))
+        .or_else(|| flags.users.get(source_id).and_then(|u| flags.server.get(u)).and_then(|f| f.get(&name)))
+        .copied()
+        .unwrap_or(false)
+}
+
+pub fn get_source_features(source_id: &String) -> BTreeMap<String, bool> {
+    FEATURES.iter().map(|f| (f.name(), is_enabled(*f, source_id))).collect()
+}
+
+// A failed fetch keeps the last known flags, features shouldn't flip while the server is unreachable
+pub fn start_feature_refresh(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            let (config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_main().await, config.get_auth().await)
+            };
+            for user in auth.records.values().filter(|u| u.is_sherry() && u.is_usable()) {
+                match ApiClient::new(&config.api_url, &user.access_token).get_features().await {
+                    Ok(features) => set_server_features(&user.user_id, features),
+                    Err(e) => log::warn!("Failed to fetch features of {}: {}", &user.username, e),
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(FEATURES_REFRESH_INTERVAL)).await;
+        }
+    });
+}
@@ -1,9 +1,9 @@
-use std::future::Future;
 use std::path::{Path, PathBuf};
 
 use futures::{Stream, StreamExt};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_diff::SerdeDiff;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_util::bytes::Bytes;
@@ -20,6 +20,44 @@ pub async fn write_json_file<T, P: AsRef<Path>>(path: P, value: &T) -> Result<()
     ).await.map_err(str_err_prefix("Error File Write"))
 }
 
+// Readable by the owner only, set before anything is written so the content never shows up with wider permissions
+pub async fn write_private_json_file<T, P: AsRef<Path>>(path: P, value: &T) -> Result<(), String>
+    where
+        T: ?Sized + serde::Serialize,
+{
+    let content = serde_json::to_string_pretty(value).map_err(str_err_prefix("Error JSON Encode"))?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await.map_err(str_err_prefix("Error File Open"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // `mode` only applies to new files, one left by an earlier run keeps its permissions otherwise
+        file.set_permissions(std::fs::Permissions::from_mode(0o600)).await.map_err(str_err_prefix("Error File Permissions"))?;
+    }
+    file.write_all(content.as_bytes()).await.map_err(str_err_prefix("Error File Write"))?;
+    file.flush().await.map_err(str_err_prefix("Error File Write"))
+}
+
+// Written to a temporary file and renamed over the target, so readers never see a partial file.
+// Returns the content of the file, which is left untouched when it already has it.
+pub async fn write_json_file_atomic<T, P: AsRef<Path>>(path: P, value: &T) -> Result<String, String>
+    where
+        T: ?Sized + serde::Serialize,
+{
+    let path = path.as_ref();
+    let content = serde_json::to_string_pretty(value).map_err(str_err_prefix("Error JSON Encode"))?;
+    if fs::read_to_string(path).await.is_ok_and(|current| current == content) {
+        return Ok(content);
+    }
+    let tmp_path = path.with_file_name(format!(".{}.tmp", path.file_name().unwrap().to_str().unwrap()));
+    fs::write(&tmp_path, &content).await.map_err(str_err_prefix("Error File Write"))?;
+    fs::rename(&tmp_path, path).await.map_err(str_err_prefix("Error File Rename"))?;
+    Ok(content)
+}
+
 pub async fn get_file_string<P: AsRef<Path>>(path: P) -> Result<String, String> {
     let mut buf = String::new();
     fs::File::open(path).await
@@ -43,6 +81,8 @@ pub async fn initialize_json_file<T, P: AsRef<Path>>(path: P, default: T) -> Res
 {
     match read_json_file(&path).await {
         Ok(v) => Ok(v),
+        // An existing but broken file is left for the user to fix instead of being reset
+        Err(e) if path.as_ref().exists() => Err(e),
         Err(_) => {
             write_json_file(&path, &default).await?;
             Ok(default)
@@ -50,22 +90,6 @@ pub async fn initialize_json_file<T, P: AsRef<Path>>(path: P, default: T) -> Res
     }
 }
 
-pub async fn initialize_json_file_with<T, P: AsRef<Path>, C, Fut>(path: P, default: &C) -> Result<T, String>
-    where
-        T: DeserializeOwned + Serialize,
-        C: Fn() -> Fut,
-        Fut: Future<Output=T>,
-{
-    match read_json_file(&path).await {
-        Ok(v) => Ok(v),
-        Err(_) => {
-            let value = default().await;
-            write_json_file(&path, &value).await?;
-            Ok(value)
-        }
-    }
-}
-
 async fn create_file(path: &PathBuf) -> Result<fs::File, String> {
     match fs::create_dir_all(path.parent().unwrap()).await {
         Err(e) => {
@@ -75,10 +99,14 @@ async fn create_file(path: &PathBuf) -> Result<fs::File, String> {
         }
         _ => (),
     };
+    // A read-only attribute synced from another client doesn't block the next download
+    if read_file_attributes(path).is_some_and(|a| a.read_only == Some(true)) {
+        set_file_attributes(path, &FileAttributesJSON { executable: None, read_only: Some(false) })?;
+    }
     fs::File::create(path).await.map_err(str_err_prefix("Error File Create"))
 }
 
-pub async fn write_file_from_stream(path: &PathBuf, mut stream: impl Stream<Item=Result<Bytes, reqwest::Error>> + Unpin) -> Result<(), String> {
+pub async fn write_file_from_stream(path: &PathBuf, mut stream: impl Stream<Item=Result<Bytes, String>> + Unpin) -> Result<(), String> {
     let mut file = create_file(path).await?;
     while let Some(chunk_result) = stream.next().await {
         let chunk = chunk_result.map_err(str_err_prefix("Invalid chunk"))?;
@@ -87,7 +115,7 @@ pub async fn write_file_from_stream(path: &PathBuf, mut stream: impl Stream<Item
     Ok(())
 }
 
-pub async fn write_files_from_stream(paths: &Vec<PathBuf>, mut stream: impl Stream<Item=Result<Bytes, reqwest::Error>> + Unpin) -> Result<(), String> {
+pub async fn write_files_from_stream(paths: &[PathBuf], mut stream: impl Stream<Item=Result<Bytes, String>> + Unpin) -> Result<(), String> {
     let mut files = futures::future::join_all(paths.iter().map(|p| create_file(&p))).await.into_iter().filter_map(|v| v.ok()).collect::<Vec<fs::File>>();
 
     while let Some(chunk_result) = stream.next().await {
@@ -113,3 +141,100 @@ pub async fn rename_path(old: &PathBuf, new: &PathBuf) -> Result<(), String> {
     fs::rename(old, new).await.map_err(str_err_prefix("Error File/Folder Rename"))?;
     Ok(())
 }
+
+// Birth time can only be set on Windows and macOS, elsewhere downloads keep the time they were written at
+#[cfg(any(windows, target_os = "macos"))]
+pub fn set_file_created(path: &PathBuf, created_at: i128) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    let created = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(created_at.max(0) as u64);
+    std::fs::File::options().write(true).open(path)
+        .map_err(str_err_prefix("Error File Open"))?
+        .set_times(std::fs::FileTimes::new().set_created(created))
+        .map_err(str_err_prefix("Error File Times"))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn set_file_created(_path: &PathBuf, _created_at: i128) -> Result<(), String> {
+    Ok(())
+}
+
+// Permissions that mean the same on every platform. A flag the platform can't tell (the executable bit on Windows)
+// is left out, so it never clears what another client set.
+#[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAttributesJSON {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+}
+
+#[cfg(unix)]
+pub fn read_file_attributes(path: &Path) -> Option<FileAttributesJSON> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path).ok().filter(|m| m.is_file())?.permissions().mode();
+    Some(FileAttributesJSON {
+        executable: Some(mode & 0o111 != 0),
+        read_only: Some(mode & 0o200 == 0),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn read_file_attributes(path: &Path) -> Option<FileAttributesJSON> {
+    let metadata = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+    Some(FileAttributesJSON {
+        executable: None,
+        read_only: Some(metadata.permissions().readonly()),
+    })
+}
+
+// Executable goes to whoever may read the file, writable only to the owner
+#[cfg(unix)]
+pub fn set_file_attributes(path: &Path, attributes: &FileAttributesJSON) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = std::fs::metadata(path).map_err(str_err_prefix("Error File Metadata"))?.permissions();
+    let mut mode = permissions.mode();
+    match attributes.executable {
+        Some(true) => mode |= (mode & 0o444) >> 2,
+        Some(false) => mode &= !0o111,
+        None => {}
+    }
+    match attributes.read_only {
+        Some(true) => mode &= !0o222,
+        Some(false) => mode |= 0o200,
+        None => {}
+    }
+    if mode == permissions.mode() {
+        return Ok(());
+    }
+    permissions.set_mode(mode);
+    std::fs::set_permissions(path, permissions).map_err(str_err_prefix("Error File Permissions"))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_attributes(path: &Path, attributes: &FileAttributesJSON) -> Result<(), String> {
+    let mut permissions = std::fs::metadata(path).map_err(str_err_prefix("Error File Metadata"))?.permissions();
+    match attributes.read_only {
+        Some(read_only) if read_only != permissions.readonly() => {
+            permissions.set_readonly(read_only);
+            std::fs::set_permissions(path, permissions).map_err(str_err_prefix("Error File Permissions"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Returns what the file ended up with, the platform may not support every flag
+pub fn apply_file_attributes(path: &Path, attributes: &Option<FileAttributesJSON>) -> Option<FileAttributesJSON> {
+    if let Some(attributes) = attributes {
+        if let Err(e) = set_file_attributes(path, attributes) {
+            log::warn!("Failed to set permissions of {:?}: {}", path, e);
+        }
+    }
+    read_file_attributes(path)
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use notify::{Config, EventHandler, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind};
+use notify_debouncer_full::{DebounceEventHandler, Debouncer, FileIdMap, new_debouncer_opt};
+
+use crate::constants::POLL_INTERVAL;
+
+// Set once on startup, bind mounts and network shares often don't propagate native events
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_polling(value: bool) {
+    POLLING.store(value, Ordering::SeqCst);
+}
+
+pub enum SherryWatcher {
+    Recommended(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl Watcher for SherryWatcher {
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> notify::Result<Self> {
+        if POLLING.load(Ordering::SeqCst) {
+            Ok(SherryWatcher::Poll(PollWatcher::new(event_handler, config)?))
+        } else {
+            Ok(SherryWatcher::Recommended(RecommendedWatcher::new(event_handler, config)?))
+        }
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        match self {
+            SherryWatcher::Recommended(w) => w.watch(path, recursive_mode),
+            SherryWatcher::Poll(w) => w.watch(path, recursive_mode),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            SherryWatcher::Recommended(w) => w.unwatch(path),
+            SherryWatcher::Poll(w) => w.unwatch(path),
+        }
+    }
+
+    fn configure(&mut self, option: Config) -> notify::Result<bool> {
+        match self {
+            SherryWatcher::Recommended(w) => w.configure(option),
+            SherryWatcher::Poll(w) => w.configure(option),
+        }
+    }
+
+    fn kind() -> WatcherKind where Self: Sized {
+        if POLLING.load(Ordering::SeqCst) {
+            WatcherKind::PollWatcher
+        } else {
+            RecommendedWatcher::kind()
+        }
+    }
+}
+
+pub type SherryDebouncer = Debouncer<SherryWatcher, FileIdMap>;
+
+pub fn new_sherry_debouncer<F: DebounceEventHandler>(timeout: Duration, event_handler: F) -> Result<SherryDebouncer, notify::Error> {
+    new_debouncer_opt::<F, SherryWatcher, FileIdMap>(
+        timeout,
+        None,
+        event_handler,
+        FileIdMap::new(),
+        Config::default().with_poll_interval(Duration::from_secs(POLL_INTERVAL)),
+    )
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::SherryConfigLoadGovernorJSON;
+use crate::constants::{DEFAULT_LOAD_MAX_DELAY, LOAD_SAMPLE_INTERVAL};
+
+static GOVERNOR: std::sync::Mutex<Option<SherryConfigLoadGovernorJSON>> = std::sync::Mutex::new(None);
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_load_governor(governor: &Option<SherryConfigLoadGovernorJSON>) {
+    *GOVERNOR.lock().unwrap() = governor.clone();
+    if governor.is_none() {
+        BUSY.store(false, Ordering::SeqCst);
+    }
+}
+
+pub fn is_system_busy() -> bool {
+    BUSY.load(Ordering::SeqCst)
+}
+
+// `some avg10` of /proc/pressure/<resource>: share of the last 10 seconds at least one task was stalled on it
+fn read_pressure(resource: &str) -> Option<f64> {
+    let pressure = std::fs::read_to_string(format!("/proc/pressure/{}", resource)).ok()?;
+    let some = pressure.lines().find(|l| l.starts_with("some"))?;
+    some.split_whitespace().find_map(|f| f.strip_prefix("avg10="))?.parse().ok()
+}
+
+fn read_load_per_core() -> Option<f64> {
+    let load = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load = load.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cores = std::thread::available_parallelism().map_or(1, |c| c.get());
+    Some(load / cores as f64 * 100.0)
+}
+
+fn is_over(value: Option<f64>, threshold: Option<u32>) -> bool {
+    matches!((value, threshold), (Some(value), Some(threshold)) if value >= threshold as f64)
+}
+
+fn sample(governor: &SherryConfigLoadGovernorJSON) -> bool {
+    is_over(read_pressure("cpu").or_else(read_load_per_core), governor.cpu) || is_over(read_pressure("io"), governor.io)
+}
+
+pub fn start_load_governor() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(LOAD_SAMPLE_INTERVAL)).await;
+            let governor = GOVERNOR.lock().unwrap().clone();
+            let busy = governor.is_some_and(|g| sample(&g));
+            if BUSY.swap(busy, Ordering::SeqCst) != busy {
+                log::info!("{}", if busy { "System is busy, holding back hashing and transfers" } else { "System load is back to normal" });
+            }
+        }
+    });
+}
+
+// Hashing and transfers wait here while the machine is busy (video calls, builds), at most `maxDelay` seconds
+pub async fn yield_to_load() {
+    if !is_system_busy() {
+        return;
+    }
+    let max_delay = GOVERNOR.lock().unwrap().as_ref().and_then(|g| g.max_delay).unwrap_or(DEFAULT_LOAD_MAX_DELAY);
+    let started = Instant::now();
+    while is_system_busy() && started.elapsed() < Duration::from_secs(max_delay) {
+        tokio::time::sleep(Duration::from_secs(LOAD_SAMPLE_INTERVAL)).await;
+    }
+}
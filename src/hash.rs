@@ -1,15 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hasher;
 use tokio::fs;
-use std::path::PathBuf;
+use std::path::{MAIN_SEPARATOR, Path, PathBuf};
+use std::time::SystemTime;
 
+use futures::StreamExt;
 use glob::{glob, GlobResult};
+use seahash::SeaHasher;
 use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
 
+use crate::change_journal::{ChangeJournalCursor, get_journal_cursor, read_journal_changes};
 use crate::config::SherryConfigSourceJSON;
-use crate::constants::HASHES_DIR;
-use crate::files::{initialize_json_file_with, write_json_file};
-use crate::helpers::{get_now_as_millis, normalize_path, ordered_map, str_err_prefix};
+use crate::constants::HASH_CONCURRENCY;
+use crate::event::file_event::{get_sync_path, SyncPathFilter};
+use crate::files::FileAttributesJSON;
+use crate::governor::yield_to_load;
+use crate::hash_store::{load_store, save_store};
+use crate::helpers::{get_now_as_millis, normalize_path, ordered_map, str_err_prefix, PATH_SEP};
+use crate::progress::HashProgressTracker;
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -17,6 +26,15 @@ pub struct FileHashJSON {
     pub hash: String,
     pub timestamp: i128,
     pub size: u64,
+    // modification time of the local file when the entry was written, unchanged size and mtime skip rehashing it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<i128>,
+    // last synced permissions, for sources that sync them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributesJSON>,
+    // the local file is this variant of the original `hash` stands for, `size` and `modified` are the variant's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
 }
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -27,58 +45,247 @@ pub struct WatcherHashJSON {
     pub local_path: String,
     #[serde(serialize_with = "ordered_map")]
     pub hashes: HashMap<String, FileHashJSON>,
+    // taken right before the store was last revalidated, see `changeJournal`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal_cursor: Option<ChangeJournalCursor>,
+    // sync path of a directory ("" for the root) -> rolled-up hash of the files below it, see `roll_up_directories`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty", serialize_with = "ordered_map")]
+    pub directories: HashMap<String, String>,
 }
 
-pub async fn get_file_hash(path: &PathBuf) -> String {
+pub async fn get_file_hash(path: &Path) -> String {
     if path.is_dir() {
         return "".to_string();
     }
-    match tokio::fs::read(path).await {
+    yield_to_load().await;
+    // CPU bound, so it runs on a blocking thread instead of holding up the runtime
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match std::fs::read(&path) {
         Ok(content) => {
             seahash::hash(&content).to_string()
         }
         Err(_) => {
             "".to_string()
         }
+    }).await.unwrap_or_default()
+}
+
+pub async fn has_file_hash(path: &Path, hash: &String) -> bool {
+    path.is_file() && &get_file_hash(path).await == hash
+}
+
+pub fn get_modified_millis(path: &Path) -> Option<i128> {
+    let modified = path.metadata().ok()?.modified().ok()?;
+    Some(modified.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_millis() as i128)
+}
+
+// Entries of `previous` are trusted when the file still has the same size and modification time. Entries written
+// before the mtime was stored fall back to the file not being modified after the entry was.
+async fn hash_file(path: PathBuf, previous: &HashMap<String, FileHashJSON>) -> (String, FileHashJSON) {
+    let res = normalize_path(&path);
+    let key = res.to_str().unwrap().to_string();
+    let modified = get_modified_millis(&res);
+    if let Some(known) = previous.get(&key) {
+        let is_unchanged = !known.hash.is_empty()
+            && res.metadata().is_ok_and(|m| m.len() == known.size)
+            && modified.is_some_and(|m| known.modified.map_or(m <= known.timestamp, |k| k == m));
+        if is_unchanged {
+            return (key, FileHashJSON { modified, ..known.clone() });
+        }
     }
+    (key, FileHashJSON {
+        hash: get_file_hash(&res).await,
+        timestamp: get_now_as_millis(),
+        size: res.metadata().unwrap().len(),
+        modified,
+        attributes: None,
+        variant: None,
+    })
 }
 
-async fn build_hashes(hashes_id: &String, source: &SherryConfigSourceJSON, local_path: &PathBuf) -> WatcherHashJSON {
+// At most `HASH_CONCURRENCY` files at once, a future per file of a big tree runs out of file descriptors. Files the
+// folder doesn't accept are left out, they would only turn into uploads `filter_events` drops.
+async fn hash_tree(base: &Path, local_path: &Path, filter: &SyncPathFilter, previous: &HashMap<String, FileHashJSON>) -> HashMap<String, FileHashJSON> {
     let binding = local_path.join("**/*");
     let to_search = binding.to_str().unwrap();
-    let glob_files = glob(to_search).unwrap();
+    let files = glob(to_search).unwrap()
+        .filter(|v: &GlobResult| v.as_ref().unwrap().is_file())
+        .map(|v| v.unwrap())
+        .filter(|f| filter.is_allowed(&get_sync_path(f, base)))
+        .collect::<Vec<PathBuf>>();
+
+    let mut progress = HashProgressTracker::new(local_path.to_str().unwrap(), files.len());
+    futures::stream::iter(files)
+        .map(|f| hash_file(f, previous))
+        .buffer_unordered(HASH_CONCURRENCY)
+        .inspect(|_| progress.advance())
+        .collect().await
+}
+
+fn get_parent_dirs(sync_path: &str) -> impl Iterator<Item=&str> {
+    std::iter::once("").chain(sync_path.match_indices(PATH_SEP).map(|(i, _)| &sync_path[..i]))
+}
+
+// Hash of every directory over the sync paths and hashes of the files below it, in path order. Both sides of a sync
+// roll up the same way, so a directory with the same hash on both holds the same files and can be skipped as a whole.
+pub fn roll_up_directories<I: IntoIterator<Item=(String, String)>>(files: I) -> HashMap<String, String> {
+    let files = files.into_iter().filter(|(_, hash)| !hash.is_empty()).collect::<BTreeMap<String, String>>();
+    let mut hashers: HashMap<&str, SeaHasher> = HashMap::new();
+    for (path, hash) in &files {
+        for dir in get_parent_dirs(path) {
+            let hasher = hashers.entry(dir).or_default();
+            hasher.write(path.as_bytes());
+            hasher.write(hash.as_bytes());
+        }
+    }
+    hashers.into_iter().map(|(dir, h)| (dir.to_string(), h.finish().to_string())).collect()
+}
+
+// Directories with the same rolled-up hash on both sides, a path below any of them is in sync
+pub fn is_in_unchanged_dir(sync_path: &str, unchanged: &HashSet<String>) -> bool {
+    !unchanged.is_empty() && get_parent_dirs(sync_path).any(|d| unchanged.contains(d))
+}
+
+// Every write of a store goes through here, so its directory hashes always match its files
+async fn store_hashes(hashes_dir: &Path, hashes: WatcherHashJSON) -> Result<WatcherHashJSON, String> {
+    let base = PathBuf::from(&hashes.local_path);
+    let directories = roll_up_directories(hashes.hashes.iter()
+        .filter(|(k, _)| Path::new(k).starts_with(&base))
+        .map(|(k, v)| (get_sync_path(Path::new(k), &base), v.hash.clone())));
+    let hashes = WatcherHashJSON { directories, ..hashes };
+    save_store(hashes_dir, &hashes).await?;
+    Ok(hashes)
+}
 
+async fn build_hashes(hashes_id: &str, source: &SherryConfigSourceJSON, local_path: &Path, previous: &HashMap<String, FileHashJSON>) -> WatcherHashJSON {
     WatcherHashJSON {
-        id: hashes_id.clone(),
+        id: hashes_id.to_string(),
         source_id: source.id.clone(),
         local_path: local_path.to_str().unwrap().to_string(),
-        hashes: futures::future::join_all(glob_files
-            .filter(|v: &GlobResult| v.as_ref().unwrap().is_file())
-            .map(|v| async move {
-                let res = normalize_path(&v.unwrap());
-                (res.to_str().unwrap().to_string(), FileHashJSON {
-                    hash: get_file_hash(&res).await,
-                    timestamp: get_now_as_millis(),
-                    size: res.metadata().unwrap().len(),
-                })
-            })).await.into_iter().collect(),
+        hashes: hash_tree(local_path, local_path, &SyncPathFilter::new(source), previous).await,
+        journal_cursor: None,
+        directories: HashMap::new(),
     }
 }
 
-pub async fn get_hashes(dir: &PathBuf, source: &SherryConfigSourceJSON, local_path: &PathBuf, hashes_id: &String) -> Result<WatcherHashJSON, String> {
-    let hashes_dir = dir.join(HASHES_DIR);
+// Only the paths the journal reports are looked at again: files are hashed like in `build_hashes`, directories
+// (created, moved in, or too busy for the journal to list their files) are walked, and whatever is gone is dropped
+// with everything that was below it
+async fn apply_journal_changes(source: &SherryConfigSourceJSON, previous: &WatcherHashJSON, changes: &Vec<PathBuf>) -> HashMap<String, FileHashJSON> {
+    let base = Path::new(&previous.local_path);
+    let filter = SyncPathFilter::new(source);
+    let mut hashes = previous.hashes.clone();
+    for change in changes {
+        let change = normalize_path(change);
+        let key = change.to_str().unwrap().to_string();
+        let below = format!("{}{}", key.trim_end_matches(MAIN_SEPARATOR), MAIN_SEPARATOR);
+        if change.is_file() && filter.is_allowed(&get_sync_path(&change, base)) {
+            let (key, hash) = hash_file(change, &previous.hashes).await;
+            hashes.insert(key, hash);
+            continue;
+        }
+        hashes.retain(|k, _| k != &key && !k.starts_with(&below));
+        if change.is_dir() {
+            hashes.extend(hash_tree(base, &change, &filter, &previous.hashes).await);
+        }
+    }
+    hashes
+}
+
+// Files that differ from the store (new ones included) and stored files that are gone, unchanged size and mtime
+// are trusted like in `revalidate_hashes`. The store itself is left as it is, the differences still have to be synced.
+pub async fn find_local_changes(source: &SherryConfigSourceJSON, hashes: &WatcherHashJSON) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let base = Path::new(&hashes.local_path);
+    let current = hash_tree(base, base, &SyncPathFilter::new(source), &hashes.hashes).await;
+    let changed = current.iter()
+        .filter(|(k, v)| hashes.hashes.get(*k).is_none_or(|h| h.hash != v.hash))
+        .map(|(k, _)| PathBuf::from(k))
+        .collect();
+    let removed = hashes.hashes.iter()
+        .filter(|(k, h)| !h.hash.is_empty() && !current.contains_key(*k))
+        .map(|(k, _)| PathBuf::from(k))
+        .collect();
+    (changed, removed)
+}
+
+pub async fn get_hashes(hashes_dir: &PathBuf, source: &SherryConfigSourceJSON, local_path: &Path, hashes_id: &String) -> Result<WatcherHashJSON, String> {
     fs::create_dir_all(&hashes_dir).await.map_err(str_err_prefix("Error hashes dir creation"))?;
-    initialize_json_file_with(&hashes_dir.join(format!("{}.json", hashes_id)), &|| async { build_hashes(hashes_id, source, local_path).await }).await
+    if let Some(hashes) = load_store(hashes_dir, hashes_id).await? {
+        return Ok(hashes);
+    }
+    store_hashes(hashes_dir, build_hashes(hashes_id, source, local_path, &HashMap::new()).await).await
 }
 
-pub async fn update_hashes(dir: &PathBuf, hashes: &WatcherHashJSON) -> Result<(), String> {
-    write_json_file(dir.join(HASHES_DIR).join(format!("{}.json", hashes.id)), hashes).await
+pub async fn read_hashes(hashes_dir: &Path, hashes_id: &String) -> Result<WatcherHashJSON, String> {
+    load_store(hashes_dir, hashes_id).await?.ok_or(format!("No hash store {}", hashes_id))
 }
 
-pub async fn recreate_hashes(dir: &PathBuf, hashes_id: &String, source: &SherryConfigSourceJSON, local_path: &PathBuf) -> Result<WatcherHashJSON, String> {
-    let hashes_dir = dir.join(HASHES_DIR);
+pub async fn update_hashes(hashes_dir: &PathBuf, hashes: &WatcherHashJSON) -> Result<(), String> {
     fs::create_dir_all(&hashes_dir).await.map_err(str_err_prefix("Error hashes dir creation"))?;
-    let hashes = build_hashes(hashes_id, source, local_path).await;
-    write_json_file(&hashes_dir.join(format!("{}.json", hashes_id)), &hashes).await?;
-    Ok(hashes)
+    store_hashes(hashes_dir, hashes.clone()).await.map(|_| ())
+}
+
+pub async fn recreate_hashes(hashes_dir: &PathBuf, hashes_id: &str, source: &SherryConfigSourceJSON, local_path: &Path) -> Result<WatcherHashJSON, String> {
+    fs::create_dir_all(&hashes_dir).await.map_err(str_err_prefix("Error hashes dir creation"))?;
+    store_hashes(hashes_dir, build_hashes(hashes_id, source, local_path, &HashMap::new()).await).await
+}
+
+// Like `recreate_hashes` every file is hashed again without trusting sizes and mtimes, but entries whose content is
+// unchanged keep their timestamp and attributes, so changes the filesystem watcher missed are the only differences left.
+// A variant never hashes like its original, it is kept as long as its size and mtime are.
+pub async fn rescan_hashes(hashes_dir: &PathBuf, hashes_id: &String, source: &SherryConfigSourceJSON, local_path: &Path) -> Result<WatcherHashJSON, String> {
+    let previous = match load_store(hashes_dir, hashes_id).await? {
+        Some(previous) if previous.local_path == local_path.to_str().unwrap() => previous.hashes,
+        _ => HashMap::new(),
+    };
+    let mut hashes = recreate_hashes(hashes_dir, hashes_id, source, local_path).await?;
+    for (key, hash) in hashes.hashes.iter_mut() {
+        let is_unchanged = |k: &&FileHashJSON| match k.variant {
+            Some(_) => k.size == hash.size && k.modified.is_some() && k.modified == hash.modified,
+            None => k.hash == hash.hash,
+        };
+        if let Some(known) = previous.get(key).filter(is_unchanged) {
+            *hash = FileHashJSON { modified: hash.modified, ..known.clone() };
+        }
+    }
+    store_hashes(hashes_dir, hashes).await
+}
+
+// Cheap alternative to `recreate_hashes` for stores that survived a restart, only files changed while offline are hashed again.
+// With `use_journal` the files to look at come from the change journal of the OS instead of walking the whole watcher.
+pub async fn revalidate_hashes(hashes_dir: &PathBuf, hashes_id: &String, source: &SherryConfigSourceJSON, local_path: &Path, use_journal: bool) -> Result<WatcherHashJSON, String> {
+    // Before reading, changes made while revalidating are seen again next time
+    let cursor = if use_journal { get_journal_cursor(local_path).await } else { None };
+    let previous = match load_store(hashes_dir, hashes_id).await {
+        Ok(Some(previous)) if previous.local_path == local_path.to_str().unwrap() => previous,
+        _ => {
+            let hashes = WatcherHashJSON { journal_cursor: cursor, ..recreate_hashes(hashes_dir, hashes_id, source, local_path).await? };
+            update_hashes(hashes_dir, &hashes).await?;
+            return Ok(hashes);
+        }
+    };
+    let changes = match (&cursor, &previous.journal_cursor) {
+        (Some(_), Some(previous_cursor)) => read_journal_changes(local_path, previous_cursor).await,
+        _ => None,
+    };
+    let hashes = match &changes {
+        Some(changes) => WatcherHashJSON {
+            hashes: apply_journal_changes(source, &previous, changes).await,
+            journal_cursor: cursor,
+            ..previous.clone()
+        },
+        None => WatcherHashJSON {
+            journal_cursor: cursor,
+            ..build_hashes(hashes_id, source, local_path, &previous.hashes).await
+        },
+    };
+    let rehashed = hashes.hashes.iter().filter(|(k, v)| previous.hashes.get(*k) != Some(v)).count();
+    match changes {
+        Some(changes) => log::info!(
+            "Revalidated hashes of {} from the change journal: {} paths reported, {} of {} files changed",
+            &hashes.local_path, changes.len(), rehashed, hashes.hashes.len(),
+        ),
+        None => log::info!("Revalidated hashes of {}: {} of {} files changed", &hashes.local_path, rehashed, hashes.hashes.len()),
+    }
+    store_hashes(hashes_dir, hashes).await
 }
\ No newline at end of file
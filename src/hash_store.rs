@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, ErrorCode, OptionalExtension, params};
+use tokio::fs;
+
+use crate::constants::{HASHES_DB, HASHES_DB_BACKUP, HASHES_DB_CORRUPT};
+use crate::files::read_json_file;
+use crate::hash::{FileHashJSON, WatcherHashJSON};
+use crate::helpers::{get_now_as_millis, str_err_prefix};
+
+// All hash stores of a hashes dir live in one database, a batch only writes the entries it changed. The last state
+// loaded or saved of every store is kept, it is what a save is compared with.
+static STORES: std::sync::Mutex<BTreeMap<(PathBuf, String), WatcherHashJSON>> = std::sync::Mutex::new(BTreeMap::new());
+// Hashes dirs whose database was checked (and backed up) since the start
+static CHECKED: std::sync::Mutex<BTreeSet<PathBuf>> = std::sync::Mutex::new(BTreeSet::new());
+
+fn get_db_path(hashes_dir: &Path) -> PathBuf {
+    hashes_dir.join(HASHES_DB)
+}
+
+fn is_intact(path: &Path) -> bool {
+    Connection::open(path)
+        .and_then(|db| db.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0)))
+        .is_ok_and(|res| res == "ok")
+}
+
+// Written through a temporary file, so a crash while backing up leaves the previous backup as it was
+fn back_up_db(db: &Connection, hashes_dir: &Path) -> Result<(), String> {
+    let tmp_path = hashes_dir.join(format!("{}.tmp", HASHES_DB_BACKUP));
+    std::fs::remove_file(&tmp_path).ok();
+    db.execute("VACUUM INTO ?1", params![tmp_path.to_str().unwrap()]).map_err(str_err_prefix("Error hashes db backup"))?;
+    std::fs::rename(&tmp_path, hashes_dir.join(HASHES_DB_BACKUP)).map_err(str_err_prefix("Error hashes db backup"))
+}
+
+// A corrupted database is moved aside and replaced with the backup taken on the last start. Without a usable backup the
+// stores are built again from the watched folders, removals made while the demon was stopped aren't known then.
+fn recover_db(hashes_dir: &Path) {
+    let db_path = get_db_path(hashes_dir);
+    if !db_path.is_file() || is_intact(&db_path) {
+        return;
+    }
+    let corrupt_path = hashes_dir.join(HASHES_DB_CORRUPT);
+    std::fs::rename(&db_path, &corrupt_path).ok();
+    // A hot journal would be rolled back into the backup
+    std::fs::remove_file(hashes_dir.join(format!("{}-journal", HASHES_DB))).ok();
+    STORES.lock().unwrap().retain(|(dir, _), _| dir != hashes_dir);
+    log::error!("{} is corrupted, moved it to {}", HASHES_DB, HASHES_DB_CORRUPT);
+
+    let backup_path = hashes_dir.join(HASHES_DB_BACKUP);
+    if is_intact(&backup_path) && std::fs::copy(&backup_path, &db_path).is_ok() {
+        log::warn!("Restored {} from {}, changes since are found by the next revalidation", HASHES_DB, HASHES_DB_BACKUP);
+    } else {
+        log::warn!("No usable {}, hash stores are built again from the watched folders", HASHES_DB_BACKUP);
+    }
+}
+
+// Errors saying the file is damaged have it checked again on the next open
+fn map_db_err(hashes_dir: &Path, prefix: &'static str) -> impl Fn(rusqlite::Error) -> String {
+    let hashes_dir = hashes_dir.to_path_buf();
+    move |e| {
+        if matches!(e.sqlite_error_code(), Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)) {
+            CHECKED.lock().unwrap().remove(&hashes_dir);
+        }
+        str_err_prefix(prefix)(e)
+    }
+}
+
+// Checked for corruption and backed up the first time it is opened
+fn open_db(hashes_dir: &Path) -> Result<Connection, String> {
+    let mut checked = CHECKED.lock().unwrap();
+    if checked.contains(hashes_dir) {
+        drop(checked);
+        return connect_db(hashes_dir);
+    }
+    recover_db(hashes_dir);
+    let db = connect_db(hashes_dir)?;
+    if let Err(e) = back_up_db(&db, hashes_dir) {
+        log::warn!("Failed to back up {}: {}", HASHES_DB, e);
+    }
+    checked.insert(hashes_dir.to_path_buf());
+    Ok(db)
+}
+
+// Default rollback journal, WAL doesn't work on the network shares standbys use
+fn connect_db(hashes_dir: &Path) -> Result<Connection, String> {
+    let db = Connection::open(get_db_path(hashes_dir)).map_err(map_db_err(hashes_dir, "Error hashes db open"))?;
+    db.busy_timeout(std::time::Duration::from_secs(5)).map_err(str_err_prefix("Error hashes db open"))?;
+    db.execute_batch("
+        CREATE TABLE IF NOT EXISTS stores (
+            id TEXT PRIMARY KEY,
+            source_id TEXT NOT NULL,
+            local_path TEXT NOT NULL,
+            journal_cursor TEXT
+        );
+        CREATE TABLE IF NOT EXISTS entries (
+            store_id TEXT NOT NULL,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            size INTEGER NOT NULL,
+            modified INTEGER,
+            attributes TEXT,
+            variant TEXT,
+            PRIMARY KEY (store_id, path)
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS directories (
+            store_id TEXT NOT NULL,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            PRIMARY KEY (store_id, path)
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS changes (
+            store_id TEXT NOT NULL,
+            path TEXT NOT NULL,
+            hash TEXT,
+            size INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS changes_path ON changes (store_id, path);
+    ").map_err(map_db_err(hashes_dir, "Error hashes db init"))?;
+    // Databases created before variants were stored
+    if db.prepare("SELECT variant FROM entries LIMIT 0").is_err() {
+        db.execute("ALTER TABLE entries ADD COLUMN variant TEXT", []).map_err(map_db_err(hashes_dir, "Error hashes db migration"))?;
+    }
+    Ok(db)
+}
+
+fn read_store(db: &Connection, id: &String) -> Result<Option<WatcherHashJSON>, rusqlite::Error> {
+    let store = db.query_row(
+        "SELECT source_id, local_path, journal_cursor FROM stores WHERE id = ?1",
+        params![id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?)),
+    ).optional()?;
+    let (source_id, local_path, journal_cursor) = match store {
+        Some(store) => store,
+        None => return Ok(None),
+    };
+    let mut statement = db.prepare("SELECT path, hash, timestamp, size, modified, attributes, variant FROM entries WHERE store_id = ?1")?;
+    let hashes = statement.query_map(params![id], |row| {
+        Ok((row.get::<_, String>(0)?, FileHashJSON {
+            hash: row.get(1)?,
+            timestamp: row.get::<_, i64>(2)? as i128,
+            size: row.get::<_, i64>(3)? as u64,
+            modified: row.get::<_, Option<i64>>(4)?.map(|m| m as i128),
+            attributes: row.get::<_, Option<String>>(5)?.and_then(|a| serde_json::from_str(&a).ok()),
+            variant: row.get(6)?,
+        }))
+    })?.collect::<Result<HashMap<String, FileHashJSON>, _>>()?;
+    let directories = db.prepare("SELECT path, hash FROM directories WHERE store_id = ?1")?
+        .query_map(params![id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<HashMap<String, String>, _>>()?;
+    Ok(Some(WatcherHashJSON {
+        id: id.clone(),
+        source_id,
+        local_path,
+        hashes,
+        journal_cursor: journal_cursor.and_then(|c| serde_json::from_str(&c).ok()),
+        directories,
+    }))
+}
+
+// Hash and size of every file of the store as the database has it
+fn read_entries(tx: &Connection, id: &String) -> Result<HashMap<String, (String, u64)>, rusqlite::Error> {
+    tx.prepare("SELECT path, hash, size FROM entries WHERE store_id = ?1")?
+        .query_map(params![id], |row| Ok((row.get::<_, String>(0)?, (row.get::<_, String>(1)?, row.get::<_, i64>(2)? as u64))))?
+        .collect()
+}
+
+// Every file whose content changed is appended to the history of the store, a removal without a hash. The first write
+// of a store without history records all of its files, that is as far back as its history goes.
+fn write_changes(tx: &Connection, hashes: &WatcherHashJSON, previous: Option<&WatcherHashJSON>) -> Result<(), rusqlite::Error> {
+    let has_history = tx.query_row("SELECT EXISTS (SELECT 1 FROM changes WHERE store_id = ?1)", params![&hashes.id], |row| row.get::<_, bool>(0))?;
+    let before = match previous {
+        _ if !has_history => HashMap::new(),
+        Some(previous) => previous.hashes.iter().map(|(k, v)| (k.clone(), (v.hash.clone(), v.size))).collect(),
+        None => read_entries(tx, &hashes.id)?,
+    };
+    let now = get_now_as_millis() as i64;
+    let mut insert = tx.prepare("INSERT INTO changes (store_id, path, hash, size, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    for (path, h) in hashes.hashes.iter().filter(|(k, v)| before.get(*k) != Some(&(v.hash.clone(), v.size))) {
+        insert.execute(params![&hashes.id, path, &h.hash, h.size as i64, now])?;
+    }
+    for path in before.keys().filter(|k| !hashes.hashes.contains_key(*k)) {
+        insert.execute(params![&hashes.id, path, None::<String>, 0, now])?;
+    }
+    Ok(())
+}
+
+// Without a previous state every entry of the store is written again
+fn write_store(db: &mut Connection, hashes: &WatcherHashJSON, previous: Option<&WatcherHashJSON>) -> Result<(), rusqlite::Error> {
+    let tx = db.transaction()?;
+    tx.execute(
+        "INSERT OR REPLACE INTO stores (id, source_id, local_path, journal_cursor) VALUES (?1, ?2, ?3, ?4)",
+        params![&hashes.id, &hashes.source_id, &hashes.local_path, hashes.journal_cursor.as_ref().map(|c| serde_json::to_string(c).unwrap())],
+    )?;
+    write_changes(&tx, hashes, previous)?;
+    if previous.is_none() {
+        tx.execute("DELETE FROM entries WHERE store_id = ?1", params![&hashes.id])?;
+        tx.execute("DELETE FROM directories WHERE store_id = ?1", params![&hashes.id])?;
+    }
+    {
+        let mut upsert = tx.prepare(
+            "INSERT OR REPLACE INTO entries (store_id, path, hash, timestamp, size, modified, attributes, variant) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+        )?;
+        for (path, h) in hashes.hashes.iter().filter(|(k, v)| previous.is_none_or(|p| p.hashes.get(*k) != Some(v))) {
+            upsert.execute(params![
+                &hashes.id,
+                path,
+                &h.hash,
+                h.timestamp as i64,
+                h.size as i64,
+                h.modified.map(|m| m as i64),
+                h.attributes.as_ref().map(|a| serde_json::to_string(a).unwrap()),
+                &h.variant,
+            ])?;
+        }
+        let mut delete = tx.prepare("DELETE FROM entries WHERE store_id = ?1 AND path = ?2")?;
+        for path in previous.iter().flat_map(|p| p.hashes.keys()).filter(|k| !hashes.hashes.contains_key(*k)) {
+            delete.execute(params![&hashes.id, path])?;
+        }
+        let mut upsert = tx.prepare("INSERT OR REPLACE INTO directories (store_id, path, hash) VALUES (?1, ?2, ?3)")?;
+        for (path, hash) in hashes.directories.iter().filter(|(k, v)| previous.is_none_or(|p| p.directories.get(*k) != Some(v))) {
+            upsert.execute(params![&hashes.id, path, hash])?;
+        }
+        let mut delete = tx.prepare("DELETE FROM directories WHERE store_id = ?1 AND path = ?2")?;
+        for path in previous.iter().flat_map(|p| p.directories.keys()).filter(|k| !hashes.directories.contains_key(*k)) {
+            delete.execute(params![&hashes.id, path])?;
+        }
+    }
+    tx.commit()
+}
+
+// Stores written as `<id>.json` before the database are moved into it the first time they are read. One cut short by a
+// crash is kept as `<id>.json.corrupt` and the store is built again.
+async fn migrate_json_store(hashes_dir: &Path, id: &String) -> Result<Option<WatcherHashJSON>, String> {
+    let json_path = hashes_dir.join(format!("{}.json", id));
+    if !json_path.is_file() {
+        return Ok(None);
+    }
+    let hashes: WatcherHashJSON = match read_json_file(&json_path).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            log::error!("Hash store {}.json is corrupted, building it again: {}", id, e);
+            tokio::fs::rename(&json_path, hashes_dir.join(format!("{}.json.corrupt", id))).await.ok();
+            return Ok(None);
+        }
+    };
+    let (dir, store) = (hashes_dir.to_path_buf(), hashes.clone());
+    tokio::task::spawn_blocking(move || write_store(&mut open_db(&dir)?, &store, None).map_err(map_db_err(&dir, "Error hashes db write")))
+        .await.map_err(str_err_prefix("Error hashes db write"))??;
+    tokio::fs::remove_file(&json_path).await.ok();
+    log::info!("Moved hash store {} into {}", id, HASHES_DB);
+    Ok(Some(hashes))
+}
+
+pub async fn load_store(hashes_dir: &Path, id: &String) -> Result<Option<WatcherHashJSON>, String> {
+    let key = (hashes_dir.to_path_buf(), id.clone());
+    if let Some(hashes) = STORES.lock().unwrap().get(&key) {
+        return Ok(Some(hashes.clone()));
+    }
+    let (dir, store_id) = key.clone();
+    let hashes = tokio::task::spawn_blocking(move || read_store(&open_db(&dir)?, &store_id).map_err(map_db_err(&dir, "Error hashes db read")))
+        .await.map_err(str_err_prefix("Error hashes db read"))??;
+    let hashes = match hashes {
+        Some(hashes) => Some(hashes),
+        None => migrate_json_store(hashes_dir, id).await?,
+    };
+    if let Some(hashes) = &hashes {
+        STORES.lock().unwrap().insert(key, hashes.clone());
+    }
+    Ok(hashes)
+}
+
+pub async fn save_store(hashes_dir: &Path, hashes: &WatcherHashJSON) -> Result<(), String> {
+    let key = (hashes_dir.to_path_buf(), hashes.id.clone());
+    let previous = STORES.lock().unwrap().get(&key).cloned();
+    if previous.as_ref() == Some(hashes) {
+        return Ok(());
+    }
+    let (dir, store) = (hashes_dir.to_path_buf(), hashes.clone());
+    tokio::task::spawn_blocking(move || write_store(&mut open_db(&dir)?, &store, previous.as_ref()).map_err(map_db_err(&dir, "Error hashes db write")))
+        .await.map_err(str_err_prefix("Error hashes db write"))??;
+    STORES.lock().unwrap().insert(key, hashes.clone());
+    Ok(())
+}
+
+fn read_store_ids(db: &Connection) -> Result<Vec<(String, String)>, rusqlite::Error> {
+    db.prepare("SELECT id, source_id FROM stores")?
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect()
+}
+
+// Ids of the stores with the folder id of their source
+pub async fn list_stores(hashes_dir: &Path) -> Result<Vec<(String, String)>, String> {
+    if !get_db_path(hashes_dir).is_file() {
+        return Ok(vec![]);
+    }
+    let dir = hashes_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || read_store_ids(&open_db(&dir)?).map_err(map_db_err(&dir, "Error hashes db read")))
+        .await.map_err(str_err_prefix("Error hashes db read"))?
+}
+
+fn delete_stores(db: &mut Connection, keep: &HashSet<String>) -> Result<Vec<String>, rusqlite::Error> {
+    let ids = db.prepare("SELECT id FROM stores")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?
+        .into_iter().filter(|id| !keep.contains(id)).collect::<Vec<String>>();
+    let tx = db.transaction()?;
+    for id in &ids {
+        tx.execute("DELETE FROM entries WHERE store_id = ?1", params![id])?;
+        tx.execute("DELETE FROM directories WHERE store_id = ?1", params![id])?;
+        tx.execute("DELETE FROM changes WHERE store_id = ?1", params![id])?;
+        tx.execute("DELETE FROM stores WHERE id = ?1", params![id])?;
+    }
+    tx.commit()?;
+    Ok(ids)
+}
+
+// Removes the stores no watcher uses anymore, returns their ids
+pub async fn prune_stores(hashes_dir: &Path, keep: &HashSet<String>) -> Result<Vec<String>, String> {
+    if !get_db_path(hashes_dir).is_file() {
+        return Ok(vec![]);
+    }
+    let (dir, keep) = (hashes_dir.to_path_buf(), keep.clone());
+    let removed = tokio::task::spawn_blocking(move || delete_stores(&mut open_db(&dir)?, &keep).map_err(map_db_err(&dir, "Error hashes db prune")))
+        .await.map_err(str_err_prefix("Error hashes db prune"))??;
+    let mut stores = STORES.lock().unwrap();
+    for id in &removed {
+        stores.remove(&(hashes_dir.to_path_buf(), id.clone()));
+    }
+    Ok(removed)
+}
+
+// Like `prune_stores`, and stores of removed watchers that were never moved into the database go too
+pub async fn collect_stores(hashes_dir: &Path, keep: &HashSet<String>) -> Result<Vec<String>, String> {
+    let mut removed = prune_stores(hashes_dir, keep).await?;
+    let mut entries = match fs::read_dir(hashes_dir).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(removed),
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_str().unwrap_or_default().to_string();
+        if let Some(id) = name.strip_suffix(".json").filter(|id| !keep.contains(*id)) {
+            fs::remove_file(entry.path()).await.map_err(str_err_prefix("Error hash store remove"))?;
+            removed.push(id.to_string());
+        }
+    }
+    Ok(removed)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredChange {
+    // local path, like the keys of the store
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    pub timestamp: i128,
+}
+
+// The files of the store at `timestamp` with the change that left them so, in path order. The first
+// value is the oldest moment the history knows, none without any history.
+fn read_changes(db: &Connection, id: &String, timestamp: i128) -> Result<(Option<i128>, Vec<StoredChange>), rusqlite::Error> {
+    let since = db.query_row("SELECT MIN(timestamp) FROM changes WHERE store_id = ?1", params![id], |row| row.get::<_, Option<i64>>(0))?;
+    let changes = db.prepare("
+        SELECT path, hash, size, timestamp FROM changes WHERE rowid IN (
+            SELECT MAX(rowid) FROM changes WHERE store_id = ?1 AND timestamp <= ?2 GROUP BY path
+        ) AND hash IS NOT NULL ORDER BY path
+    ")?
+        .query_map(params![id, timestamp as i64], |row| Ok(StoredChange {
+            path: row.get(0)?,
+            hash: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+            timestamp: row.get::<_, i64>(3)? as i128,
+        }))?
+        .collect::<Result<Vec<StoredChange>, _>>()?;
+    Ok((since.map(|s| s as i128), changes))
+}
+
+pub async fn list_changes_at(hashes_dir: &Path, id: &str, timestamp: i128) -> Result<(Option<i128>, Vec<StoredChange>), String> {
+    if !get_db_path(hashes_dir).is_file() {
+        return Ok((None, vec![]));
+    }
+    let (dir, id) = (hashes_dir.to_path_buf(), id.to_string());
+    tokio::task::spawn_blocking(move || read_changes(&open_db(&dir)?, &id, timestamp).map_err(map_db_err(&dir, "Error hashes db read")))
+        .await.map_err(str_err_prefix("Error hashes db read"))?
+}
+
+// Changes before `before` are folded into the state at `before`: the last one of every file is kept and moved there,
+// removals go. Returns the number of changes dropped.
+fn compact_changes(db: &mut Connection, before: i128) -> Result<usize, rusqlite::Error> {
+    let tx = db.transaction()?;
+    let removed = tx.execute("
+        DELETE FROM changes WHERE timestamp < ?1 AND (hash IS NULL OR rowid NOT IN (
+            SELECT MAX(rowid) FROM changes WHERE timestamp < ?1 GROUP BY store_id, path
+        ))
+    ", params![before as i64])?;
+    tx.execute("UPDATE changes SET timestamp = ?1 WHERE timestamp < ?1", params![before as i64])?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+pub async fn prune_changes(hashes_dir: &Path, before: i128) -> Result<usize, String> {
+    if !get_db_path(hashes_dir).is_file() {
+        return Ok(0);
+    }
+    let dir = hashes_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || compact_changes(&mut open_db(&dir)?, before).map_err(map_db_err(&dir, "Error hashes db prune")))
+        .await.map_err(str_err_prefix("Error hashes db prune"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::generate_random_id;
+
+    fn store(files: &[(&str, &str)]) -> WatcherHashJSON {
+        WatcherHashJSON {
+            id: "store".to_string(),
+            source_id: "folder".to_string(),
+            local_path: "/watched".to_string(),
+            hashes: files.iter().map(|(path, hash)| (path.to_string(), FileHashJSON {
+                hash: hash.to_string(),
+                timestamp: 0,
+                size: hash.len() as u64,
+                modified: None,
+                attributes: None,
+                variant: None,
+            })).collect(),
+            journal_cursor: None,
+            directories: HashMap::new(),
+        }
+    }
+
+    fn files_at(db: &Connection, timestamp: i128) -> Vec<(String, String)> {
+        read_changes(db, &"store".to_string(), timestamp).unwrap().1.into_iter().map(|c| (c.path, c.hash)).collect()
+    }
+
+    // Changes are stamped with the time they are written, the test moves them apart afterwards
+    fn write_at(db: &mut Connection, hashes: &WatcherHashJSON, previous: Option<&WatcherHashJSON>, timestamp: i64) {
+        write_store(db, hashes, previous).unwrap();
+        db.execute("UPDATE changes SET timestamp = ?1 WHERE timestamp > ?1", params![timestamp]).unwrap();
+    }
+
+    #[test]
+    fn lists_the_files_at_a_point_in_time() {
+        let dir = std::env::temp_dir().join(generate_random_id());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut db = connect_db(&dir).unwrap();
+        let first = store(&[("/watched/a", "1"), ("/watched/b", "2")]);
+        let second = store(&[("/watched/a", "3"), ("/watched/c", "4")]);
+        let third = store(&[("/watched/c", "4")]);
+        write_at(&mut db, &first, None, 10);
+        write_at(&mut db, &second, Some(&first), 20);
+        // without a previous state the database is compared with
+        write_at(&mut db, &third, None, 30);
+
+        assert_eq!(read_changes(&db, &"store".to_string(), 5).unwrap(), (Some(10), vec![]));
+        let pairs = |files: &[(&str, &str)]| files.iter().map(|(p, h)| (p.to_string(), h.to_string())).collect::<Vec<_>>();
+        assert_eq!(files_at(&db, 10), pairs(&[("/watched/a", "1"), ("/watched/b", "2")]));
+        assert_eq!(files_at(&db, 25), pairs(&[("/watched/a", "3"), ("/watched/c", "4")]));
+        assert_eq!(files_at(&db, 30), pairs(&[("/watched/c", "4")]));
+
+        // compacting keeps the state at the cutoff and everything after it
+        assert_eq!(compact_changes(&mut db, 25).unwrap(), 3);
+        assert_eq!(read_changes(&db, &"store".to_string(), 25).unwrap().0, Some(25));
+        assert_eq!(files_at(&db, 25), pairs(&[("/watched/a", "3"), ("/watched/c", "4")]));
+        assert_eq!(files_at(&db, 30), pairs(&[("/watched/c", "4")]));
+        drop(db);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,47 @@
+use std::env;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::app::App;
+use crate::constants::{DEFAULT_HEALTH_PORT, ENV_HEALTH_PORT};
+use crate::helpers::str_err_prefix;
+use crate::status::get_status;
+
+async fn is_healthy(app: &App) -> bool {
+    get_status(app).await.socket_connected
+}
+
+pub async fn start_health(app: &App) -> Result<(), String> {
+    let port = env::var(ENV_HEALTH_PORT).ok().and_then(|p| p.parse::<u16>().ok()).unwrap_or(DEFAULT_HEALTH_PORT);
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.map_err(str_err_prefix("Error Health Bind"))?;
+    log::info!("Health endpoint listening on 0.0.0.0:{}", port);
+
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Health accept failed: {}", e);
+                    continue;
+                }
+            };
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+
+            let (status, body) = if is_healthy(&app).await {
+                ("200 OK", r#"{"status":"ok"}"#)
+            } else {
+                ("503 Service Unavailable", r#"{"status":"offline"}"#)
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body,
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    Ok(())
+}
@@ -1,11 +1,19 @@
 use std::collections::{BTreeMap, HashMap};
-use std::ffi::OsStr;
+use std::collections::hash_map::RandomState;
+use std::env;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Component, Path, PathBuf, Prefix};
 use std::time::SystemTime;
 
+use home::home_dir;
+use path_clean::PathClean;
 use regex::Regex;
 use serde::{Serialize, Serializer};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::constants::{CONFIG_DIR, ENV_XDG_CONFIG_HOME, ENV_XDG_STATE_HOME, XDG_APP_DIR, XDG_CONFIG_HOME_DEFAULT, XDG_STATE_HOME_DEFAULT};
+use crate::names::escape_name;
 
 pub fn ordered_map<S, K: Ord + Serialize, V: Serialize>(
     value: &HashMap<K, V>,
@@ -26,17 +34,130 @@ pub fn str_err_prefix<T: ToString + 'static>(prefix: impl Display) -> impl Fn(T)
     }
 }
 
+pub fn expand_env_vars(value: &str) -> String {
+    Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap()
+        .replace_all(value, |caps: &regex::Captures| {
+            match env::var(&caps[1]) {
+                Ok(v) => v,
+                Err(_) => {
+                    log::warn!("Environment variable {} is not set, keeping it as is", &caps[1]);
+                    caps[0].to_string()
+                }
+            }
+        }).to_string()
+}
+
 pub const PATH_SEP: &str = "/";
 
+// None for verbatim and device paths, they are passed to the OS as is, so separators must not be touched
+fn normalize_prefix(prefix: Prefix) -> Option<String> {
+    match prefix {
+        Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => Some(format!("{}:", (disk as char).to_ascii_uppercase())),
+        Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+            Some(format!("{0}{0}{1}{0}{2}", PATH_SEP, server.to_str().unwrap(), share.to_str().unwrap()))
+        }
+        Prefix::Verbatim(_) | Prefix::DeviceNS(_) => None,
+    }
+}
+
+// Local form of a path: forward slashes, single separators, drive letters and UNC prefixes preserved
 pub fn normalize_path(p: &PathBuf) -> PathBuf {
-    PathBuf::from(
-        Regex::new(r"[\\/]+").unwrap()
-            .replace_all(
-                p.iter().collect::<Vec<&OsStr>>()
-                    .join(OsStr::new(PATH_SEP))
-                    .to_str().unwrap(),
-                PATH_SEP).to_string()
-    )
+    let mut prefix = String::new();
+    let mut has_root = false;
+    let mut parts: Vec<&str> = vec![];
+    for component in p.components() {
+        match component {
+            Component::Prefix(prefix_component) => match normalize_prefix(prefix_component.kind()) {
+                Some(v) => prefix = v,
+                None => return p.clone(),
+            },
+            Component::RootDir => has_root = true,
+            Component::CurDir => {}
+            Component::ParentDir => parts.push(".."),
+            Component::Normal(part) => parts.extend(part.to_str().unwrap().split('\\').filter(|s| !s.is_empty())),
+        }
+    }
+    PathBuf::from(format!("{}{}{}", prefix, if has_root { PATH_SEP } else { "" }, parts.join(PATH_SEP)))
+}
+
+// Server facing form of a relative path: forward slashes, no leading or trailing separator, no `.` segments, NFC
+pub fn canonicalize_sync_path(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect::<Vec<&str>>()
+        .join(PATH_SEP)
+        .nfc()
+        .collect()
+}
+
+pub fn absolute_path(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        path
+    } else {
+        env::current_dir().unwrap().join(path)
+    }.clean()
+}
+
+// Server paths come from other clients, one climbing out of the watcher with `..` is refused rather than joined
+pub fn check_sync_path(sync_path: &str) -> Result<(), String> {
+    match canonicalize_sync_path(sync_path).split(PATH_SEP).any(|s| s == "..") {
+        true => Err(format!("Path {} leaves the watcher", sync_path)),
+        false => Ok(()),
+    }
+}
+
+// Names this platform can't hold are escaped, `get_sync_path` turns them back
+pub fn sync_path_to_local(base: &Path, sync_path: &str) -> Result<PathBuf, String> {
+    check_sync_path(sync_path)?;
+    Ok(base.join(canonicalize_sync_path(sync_path).split(PATH_SEP).map(escape_name).collect::<PathBuf>()))
+}
+
+fn get_xdg_dir(env_name: &str, default: &str) -> PathBuf {
+    match env::var(env_name) {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir().unwrap().join(default),
+    }
+}
+
+fn get_xdg_config_dir() -> PathBuf {
+    get_xdg_dir(ENV_XDG_CONFIG_HOME, XDG_CONFIG_HOME_DEFAULT).join(XDG_APP_DIR)
+}
+
+// Existing `~/.sherry` installs keep working, fresh Linux installs follow the XDG base directory spec
+pub fn get_default_config_dir() -> PathBuf {
+    let legacy_dir = home_dir().unwrap().join(CONFIG_DIR);
+    if cfg!(target_os = "linux") && !legacy_dir.exists() {
+        get_xdg_config_dir()
+    } else {
+        legacy_dir
+    }
+}
+
+// Hashes and logs live next to the config unless the config itself is in the XDG location
+pub fn get_default_state_dir(config_dir: &Path) -> PathBuf {
+    if cfg!(target_os = "linux") && config_dir == get_xdg_config_dir() {
+        get_xdg_dir(ENV_XDG_STATE_HOME, XDG_STATE_HOME_DEFAULT).join(XDG_APP_DIR)
+    } else {
+        config_dir.to_path_buf()
+    }
+}
+
+// RandomState is seeded from the OS on creation, which is enough for ids but not for secrets
+pub fn generate_random_id() -> String {
+    (0..2).map(|_| format!("{:016x}", RandomState::new().build_hasher().finish())).collect()
+}
+
+// 256 bits straight from the OS, for tokens that grant access
+pub fn generate_secret() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(str_err_prefix("Error Random"))?;
+    Ok(hex::encode(bytes))
+}
+
+// Takes as long wherever the first difference is, so a secret can't be guessed byte by byte
+pub fn is_same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
 pub fn get_now() -> i32 {
@@ -46,3 +167,87 @@ pub fn get_now() -> i32 {
 pub fn get_now_as_millis() -> i128 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i128
 }
+
+fn decode_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// Text of the first element named `name`, for the few XML answers of storage servers. Servers pick their own
+// namespace prefixes, so elements are matched by their local name.
+pub fn get_xml_element(xml: &str, name: &str) -> Option<String> {
+    Regex::new(&format!(r"(?s)<(?:[\w-]+:)?{0}(?:\s[^>]*)?>(.*?)</(?:[\w-]+:)?{0}>", name)).unwrap()
+        .captures(xml)
+        .map(|c| decode_xml(c[1].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::file_event::get_sync_path;
+
+    use super::*;
+
+    #[test]
+    fn normalizes_prefixes() {
+        use std::ffi::OsStr;
+
+        assert_eq!(normalize_prefix(Prefix::Disk(b'c')), Some("C:".to_string()));
+        assert_eq!(normalize_prefix(Prefix::VerbatimDisk(b'C')), Some("C:".to_string()));
+        assert_eq!(normalize_prefix(Prefix::UNC(OsStr::new("server"), OsStr::new("share"))), Some("//server/share".to_string()));
+        assert_eq!(normalize_prefix(Prefix::VerbatimUNC(OsStr::new("server"), OsStr::new("share"))), Some("//server/share".to_string()));
+        assert_eq!(normalize_prefix(Prefix::Verbatim(OsStr::new("foo"))), None);
+        assert_eq!(normalize_prefix(Prefix::DeviceNS(OsStr::new("pipe"))), None);
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path(&PathBuf::from("/a//b/./c/")), PathBuf::from("/a/b/c"));
+        assert_eq!(normalize_path(&PathBuf::from("a/../b")), PathBuf::from("a/../b"));
+    }
+
+    #[test]
+    fn compares_secrets() {
+        let secret = generate_secret().unwrap();
+        assert_eq!(secret.len(), 64);
+        assert_ne!(secret, generate_secret().unwrap());
+        assert!(is_same_secret(&secret, &secret.clone()));
+        assert!(!is_same_secret(&secret, &secret[1..]));
+        let last = if secret.ends_with('0') { "1" } else { "0" };
+        assert!(!is_same_secret(&secret, &format!("{}{}", &secret[..63], last)));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn normalizes_windows_paths() {
+        let cases = [
+            (r"C:\x", "C:/x"),
+            (r"c:\x\y", "C:/x/y"),
+            (r"\\server\share\x", "//server/share/x"),
+            (r"\\?\C:\x", "C:/x"),
+            (r"\\?\UNC\server\share\x", "//server/share/x"),
+            (r"\\?\foo\x", r"\\?\foo\x"),
+        ];
+        for (path, normalized) in cases {
+            assert_eq!(normalize_path(&PathBuf::from(path)), PathBuf::from(normalized), "{}", path);
+        }
+    }
+
+    #[test]
+    fn sync_paths_round_trip() {
+        let base = Path::new("/watcher");
+        let paths = ["a", "a/b.txt", "/a//./b/", "a\\b\\c", "", "CON", "dir/aux.txt", "trailing. ", "100%", "%41", "e\u{301}", "a..b", "...", "..a"];
+        for path in paths {
+            let local_path = sync_path_to_local(base, path).unwrap();
+            assert!(local_path.starts_with(base), "{} left the watcher", path);
+            assert_eq!(get_sync_path(&local_path, base), canonicalize_sync_path(path), "{} didn't round trip", path);
+        }
+    }
+
+    #[test]
+    fn sync_paths_leaving_the_watcher_are_refused() {
+        let base = Path::new("/watcher");
+        for path in ["..", "../../.bashrc", "a/../../b", "a/..", "..\\x", "./../x", "/../x"] {
+            assert!(sync_path_to_local(base, path).is_err(), "{} was joined", path);
+            assert!(check_sync_path(path).is_err());
+        }
+    }
+}
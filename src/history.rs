@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::constants::{CONFIG_HISTORY_SIZE, HISTORY_DIR};
+use crate::files::get_file_string;
+use crate::helpers::{get_now_as_millis, str_err_prefix};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigHistoryEntry {
+    pub file: String,
+    pub timestamp: i128,
+    pub path: PathBuf,
+}
+
+fn get_stem(file: &str) -> &str {
+    file.strip_suffix(".json").unwrap_or(file)
+}
+
+async fn get_file_history(dir: &Path, file: &str) -> Vec<ConfigHistoryEntry> {
+    let prefix = format!("{}.", get_stem(file));
+    let mut entries = vec![];
+    if let Ok(mut dir_entries) = fs::read_dir(dir.join(HISTORY_DIR)).await {
+        while let Ok(Some(entry)) = dir_entries.next_entry().await {
+            let name = entry.file_name().to_str().unwrap_or_default().to_string();
+            let timestamp = name.strip_prefix(&prefix)
+                .and_then(|n| n.strip_suffix(".json"))
+                .and_then(|n| n.parse::<i128>().ok());
+            if let Some(timestamp) = timestamp {
+                entries.push(ConfigHistoryEntry { file: file.to_string(), timestamp, path: entry.path() });
+            }
+        }
+    }
+    entries.sort_by_key(|e| e.timestamp);
+    entries
+}
+
+pub async fn save_history(dir: &Path, file: &str) -> Result<(), String> {
+    let content = get_file_string(dir.join(file)).await?;
+    let history = get_file_history(dir, file).await;
+    if let Some(last) = history.last() {
+        if get_file_string(&last.path).await.is_ok_and(|c| c == content) {
+            return Ok(());
+        }
+    }
+
+    let history_dir = dir.join(HISTORY_DIR);
+    fs::create_dir_all(&history_dir).await.map_err(str_err_prefix("Error history dir creation"))?;
+    fs::write(history_dir.join(format!("{}.{}.json", get_stem(file), get_now_as_millis())), content).await
+        .map_err(str_err_prefix("Error File Write"))?;
+
+    if history.len() + 1 > CONFIG_HISTORY_SIZE {
+        for entry in &history[..history.len() + 1 - CONFIG_HISTORY_SIZE] {
+            fs::remove_file(&entry.path).await.ok();
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_history(dir: &Path, files: &[&str]) -> Vec<ConfigHistoryEntry> {
+    let mut entries = vec![];
+    for file in files {
+        entries.extend(get_file_history(dir, file).await);
+    }
+    entries
+}
+
+// Restores the newest version older than `timestamp`, or the one before the current version.
+// The config watcher picks the restored file up like any other edit.
+pub async fn rollback(dir: &Path, file: &str, timestamp: Option<i128>) -> Result<ConfigHistoryEntry, String> {
+    let history = get_file_history(dir, file).await;
+    let target = match timestamp {
+        Some(timestamp) => history.iter().rev().find(|e| e.timestamp <= timestamp),
+        None => history.iter().rev().nth(1),
+    }.ok_or(format!("No history version of {} to roll back to", file))?;
+
+    let content = get_file_string(&target.path).await?;
+    fs::write(dir.join(file), content).await.map_err(str_err_prefix("Error File Write"))?;
+    log::info!("Rolled back {} to version {}", file, target.timestamp);
+    Ok(target.clone())
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bandwidth::{Direction, limit_download};
+use crate::constants::{INTEGRITY_MIN_MISMATCHES, INTEGRITY_MISMATCH_RATIO, INTEGRITY_VERIFY_ATTEMPTS};
+use crate::files::write_file_from_stream;
+use crate::event::file_event::{FileType, SyncEvent, SyncEventKind};
+use crate::hash::has_file_hash;
+use crate::helpers::{canonicalize_sync_path, str_err_prefix};
+use crate::messages::{MessageCode, UserMessage};
+use crate::notifications::notify;
+use crate::progress::track_progress;
+use crate::self_writes::with_self_writes;
+use crate::server::scheduler::schedule_transfer;
+use crate::server::storage::{FOREIGN_HASH_PREFIX, RemoteStorage};
+use crate::server::types::ApiFileResponse;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityStats {
+    pub verified: u64,
+    pub mismatches: u64,
+    // set once mismatches cross the threshold, mismatching downloads are fetched again from then on
+    pub verify_all: bool,
+    // uploads of sources with `verifyUploads`, compared with what the server recorded
+    #[serde(default)]
+    pub uploads_verified: u64,
+    #[serde(default)]
+    pub upload_mismatches: u64,
+}
+
+// source id -> stats
+static STATS: std::sync::Mutex<BTreeMap<String, IntegrityStats>> = std::sync::Mutex::new(BTreeMap::new());
+
+fn record_transfer(source_id: &String, is_match: bool) {
+    let mut stats = STATS.lock().unwrap();
+    let entry = stats.entry(source_id.clone()).or_default();
+    entry.verified += 1;
+    if is_match {
+        return;
+    }
+    entry.mismatches += 1;
+    let ratio = entry.mismatches as f64 / entry.verified as f64;
+    if !entry.verify_all && entry.mismatches >= INTEGRITY_MIN_MISMATCHES && ratio >= INTEGRITY_MISMATCH_RATIO {
+        entry.verify_all = true;
+        log::error!("Integrity alarm for source {}: {} of {} downloads didn't match their checksum, verifying everything from now on", source_id, entry.mismatches, entry.verified);
+    }
+}
+
+pub fn is_verify_all(source_id: &String) -> bool {
+    STATS.lock().unwrap().get(source_id).is_some_and(|s| s.verify_all)
+}
+
+pub fn get_integrity_stats() -> BTreeMap<String, IntegrityStats> {
+    STATS.lock().unwrap().clone()
+}
+
+fn get_upload_mismatch(e: &SyncEvent, remote: &HashMap<String, ApiFileResponse>) -> Option<String> {
+    if e.kind == SyncEventKind::Moved && remote.contains_key(&canonicalize_sync_path(&e.old_sync_path)) {
+        return Some(format!("{} is still recorded at its old path {}", &e.sync_path, &e.old_sync_path));
+    }
+    if e.kind == SyncEventKind::Deleted && e.file_type == FileType::Dir {
+        let prefix = format!("{}/", canonicalize_sync_path(&e.sync_path));
+        return remote.keys().find(|p| p.starts_with(&prefix)).map(|p| format!("{} is still recorded after the removal of {}", p, &e.sync_path));
+    }
+    match (e.kind, remote.get(&canonicalize_sync_path(&e.sync_path))) {
+        (SyncEventKind::Deleted, Some(_)) => Some(format!("{} is still recorded after its removal", &e.sync_path)),
+        (SyncEventKind::Deleted, None) => None,
+        (_, None) => Some(format!("{} is not recorded", &e.sync_path)),
+        (_, Some(f)) if e.file_type == FileType::File && (f.hash != e.update_hash || f.size != e.size) => {
+            Some(format!("{} is recorded with hash {} and {} bytes, uploaded {} and {} bytes", &e.sync_path, &f.hash, f.size, &e.update_hash, e.size))
+        }
+        _ => None,
+    }
+}
+
+// One listing of the folder per batch, later changes by others may show up as mismatches too.
+// Returns the mismatched events with what the listing disagrees on.
+pub async fn verify_uploads<'a>(storage: &dyn RemoteStorage, source_id: &String, events: &'a [SyncEvent]) -> Vec<(&'a SyncEvent, String)> {
+    let remote = match storage.list(source_id).await {
+        Ok(files) => files.into_iter().map(|f| (canonicalize_sync_path(&f.path), f)).collect::<HashMap<String, ApiFileResponse>>(),
+        Err(e) => {
+            log::error!("Failed to verify uploads of source {}: {}", source_id, e);
+            return vec![];
+        }
+    };
+    let mismatches = events.iter().filter_map(|e| get_upload_mismatch(e, &remote).map(|m| (e, m))).collect::<Vec<_>>();
+    for (_, mismatch) in &mismatches {
+        log::error!("Upload mismatch in source {}: {}", source_id, mismatch);
+    }
+    {
+        let mut stats = STATS.lock().unwrap();
+        let entry = stats.entry(source_id.clone()).or_default();
+        entry.uploads_verified += events.len() as u64;
+        entry.upload_mismatches += mismatches.len() as u64;
+    }
+    if !mismatches.is_empty() {
+        notify(UserMessage::new(MessageCode::UploadMismatch, &[("count", &mismatches.len().to_string())]));
+    }
+    mismatches
+}
+
+pub async fn verify_download(source_id: &String, path: &PathBuf, hash: &String) -> bool {
+    if hash.is_empty() || hash.starts_with(FOREIGN_HASH_PREFIX) {
+        return true;
+    }
+    let is_match = has_file_hash(path, hash).await;
+    if !is_match {
+        log::warn!("Checksum mismatch for {:?} downloaded from source {}", path, source_id);
+    }
+    record_transfer(source_id, is_match);
+    is_match
+}
+
+pub async fn download_file(storage: &dyn RemoteStorage, source_id: &String, sync_path: &str, local_path: &PathBuf, hash: &String, size: u64) -> Result<(), String> {
+    let verify_all = is_verify_all(source_id);
+    let attempts = if verify_all { INTEGRITY_VERIFY_ATTEMPTS } else { 1 };
+    for _ in 0..attempts {
+        schedule_transfer(source_id, size, false, async {
+            let stream = storage.get(source_id, sync_path).await.map_err(str_err_prefix("Error File Download"))?;
+            let stream = track_progress(source_id, Direction::Download, sync_path, size, limit_download(source_id, stream));
+            with_self_writes(&vec![local_path.clone()], hash, write_file_from_stream(local_path, stream)).await
+        }).await?;
+        if verify_download(source_id, local_path, hash).await {
+            return Ok(());
+        }
+    }
+    if verify_all {
+        return Err(format!("Checksum mismatch for {:?} after {} attempts", local_path, attempts));
+    }
+    Ok(())
+}
+
+// A variant can't be verified against the hash of its original, an original sent instead is verified like any download.
+// Returns the variant written, none for the original.
+pub async fn download_variant(storage: &dyn RemoteStorage, source_id: &String, sync_path: &str, local_path: &PathBuf, hash: &String, size: u64, variant: Option<&String>) -> Result<Option<String>, String> {
+    let variant = match variant {
+        Some(variant) => variant,
+        None => return download_file(storage, source_id, sync_path, local_path, hash, size).await.map(|_| None),
+    };
+    let is_variant = schedule_transfer(source_id, size, false, async {
+        let (stream, is_variant) = storage.get_variant(source_id, sync_path, variant).await.map_err(str_err_prefix("Error File Download"))?;
+        let stream = track_progress(source_id, Direction::Download, sync_path, size, limit_download(source_id, stream));
+        with_self_writes(&vec![local_path.clone()], hash, write_file_from_stream(local_path, stream)).await?;
+        Ok::<bool, String>(is_variant)
+    }).await?;
+    if is_variant {
+        return Ok(Some(variant.clone()));
+    }
+    if !verify_download(source_id, local_path, hash).await && is_verify_all(source_id) {
+        download_file(storage, source_id, sync_path, local_path, hash, size).await?;
+    }
+    Ok(None)
+}
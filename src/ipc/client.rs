@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::constants::IPC_FILE;
+use crate::files::read_json_file;
+use crate::helpers::str_err_prefix;
+use crate::ipc::types::{IpcEndpointJSON, IpcEvent, IpcMessage, IpcRequest, IpcResponse};
+
+async fn open_request(dir: &Path, request: IpcRequest) -> Result<(Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf), String> {
+    let endpoint: IpcEndpointJSON = read_json_file(dir.join(IPC_FILE)).await
+        .map_err(|_| "Demon is not running for this config directory".to_string())?;
+
+    let stream = TcpStream::connect(("127.0.0.1", endpoint.port)).await.map_err(str_err_prefix("Error IPC Connect"))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut payload = serde_json::to_string(&IpcMessage { token: endpoint.token, request })
+        .map_err(str_err_prefix("Error JSON Encode"))?;
+    payload.push('\n');
+    writer.write_all(payload.as_bytes()).await.map_err(str_err_prefix("Error IPC Write"))?;
+    Ok((BufReader::new(reader).lines(), writer))
+}
+
+async fn read_line<T: DeserializeOwned>(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<Option<T>, String> {
+    match lines.next_line().await.map_err(str_err_prefix("Error IPC Read"))? {
+        Some(line) => serde_json::from_str(&line).map(Some).map_err(str_err_prefix("Error JSON Parse")),
+        None => Ok(None),
+    }
+}
+
+pub async fn send_request(dir: &Path, request: IpcRequest) -> Result<IpcResponse, String> {
+    let (mut lines, _writer) = open_request(dir, request).await?;
+    read_line(&mut lines).await?.ok_or("IPC connection closed".to_string())
+}
+
+// Calls `on_event` for every event pushed by the demon, until it stops
+pub async fn subscribe<F: FnMut(IpcEvent)>(dir: &Path, mut on_event: F) -> Result<(), String> {
+    let (mut lines, _writer) = open_request(dir, IpcRequest::Subscribe).await?;
+    let response: IpcResponse = read_line(&mut lines).await?.ok_or("IPC connection closed".to_string())?;
+    if !response.ok {
+        return Err(response.error.unwrap_or_default());
+    }
+    while let Some(event) = read_line(&mut lines).await? {
+        on_event(event);
+    }
+    Ok(())
+}
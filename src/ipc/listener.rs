@@ -0,0 +1,321 @@
+use std::path::PathBuf;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde::Serialize;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::app::App;
+use crate::auth::{finish_device_login, login_with_api_key, start_device_login};
+use crate::barrier::wait_until_synced;
+use crate::bundle::{BundleUserJSON, export_bundle};
+use crate::config::get_hashes_dir;
+use crate::consistency::check_consistency;
+use crate::constants::{AUTH_FILE, CONFIG_FILE, IPC_FILE};
+use crate::event::dead_letters::{list_dead_letters, resubmit_dead_letters};
+use crate::event::holds::{add_hold, list_holds, release_hold};
+use crate::event::manifest::{approve_manifest, list_manifests, reject_manifest};
+use crate::event::quarantine::{clear_quarantine, list_quarantine};
+use crate::files::write_private_json_file;
+use crate::helpers::{generate_secret, is_same_secret, str_err_prefix};
+use crate::history::{list_history, rollback};
+use crate::ipc::types::{IpcEndpointJSON, IpcEvent, IpcMessage, IpcRequest, IpcResponse};
+use crate::logs::set_log_options;
+use crate::maintenance::prune_state;
+use crate::notifications::list_notifications;
+use crate::progress::{subscribe_hash_progress, subscribe_progress};
+use crate::share::create_share_link;
+use crate::snapshot::get_folder_at;
+use crate::status::get_status;
+use crate::watchers::{diff_watcher, fetch_watcher_path};
+
+async fn handle_request(app: &App, request: IpcRequest) -> Result<serde_json::Value, String> {
+    match request {
+        IpcRequest::Prune => {
+            let (dir, config, errors) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_errors().await)
+            };
+            // The defaults standing in for a broken config.json have no watchers, every store would look orphaned
+            if errors.contains_key(CONFIG_FILE) {
+                return Err(format!("Config files are invalid, fix them first: {:?}", errors));
+            }
+            let report = prune_state(&dir, &config).await?;
+            serde_json::to_value(report).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Status => {
+            serde_json::to_value(get_status(app).await).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Subscribe => Err("Subscriptions take over the connection".to_string()),
+        IpcRequest::ConfigHistory => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(list_history(&dir, &[CONFIG_FILE, AUTH_FILE]).await).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ConfigDiff => {
+            serde_json::to_value(app.config.lock().await.get_last_diff().await).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ConfigRollback { file, timestamp } => {
+            if file != CONFIG_FILE && file != AUTH_FILE {
+                return Err(format!("Unknown config file {}", file));
+            }
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(rollback(&dir, &file, timestamp).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::AddWatcher { folder_id, local_path, user_id, mode, template } => {
+            let watcher = app.config.lock().await.add_watcher(&folder_id, &local_path, &user_id, mode.unwrap_or_default(), &template).await?;
+            serde_json::to_value(watcher).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::AddStorageWatcher { local_path, storage, username, password, mode } => {
+            let watcher = app.config.lock().await.add_storage_watcher(&local_path, &storage, &username, &password, mode.unwrap_or_default()).await?;
+            serde_json::to_value(watcher).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::CreateFolder { local_path, user_id, template, folder } => {
+            let watcher = app.config.lock().await.create_folder(&local_path, &user_id, &folder, &template).await?;
+            serde_json::to_value(watcher).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::DeleteFolder { source, confirm } => {
+            app.config.lock().await.delete_folder(&source, confirm).await?;
+            Ok(serde_json::Value::Null)
+        }
+        IpcRequest::ArchiveFolder { source, archived } => {
+            let source = app.config.lock().await.archive_folder(&source, archived).await?;
+            serde_json::to_value(source).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ExportBundle => {
+            let (dir, config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_auth().await)
+            };
+            let users = auth.records.values().map(|u| BundleUserJSON {
+                user_id: u.user_id.clone(),
+                email: u.email.clone(),
+                username: u.username.clone(),
+            }).collect();
+            serde_json::to_value(export_bundle(&dir, &config, users, &auth.default).await).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ImportBundle { bundle, path_map } => {
+            let result = app.config.lock().await.import_bundle(&bundle, &path_map).await?;
+            serde_json::to_value(result).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::IncludeWatcherPath { local_path, path } => {
+            let watcher = app.config.lock().await.include_watcher_path(&local_path, &path).await?;
+            serde_json::to_value(watcher).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ExcludeWatcherPath { local_path, path } => {
+            let watcher = app.config.lock().await.exclude_watcher_path(&local_path, &path).await?;
+            serde_json::to_value(watcher).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::RemoveSource { source } => {
+            app.config.lock().await.remove_source(&source).await?;
+            Ok(serde_json::Value::Null)
+        }
+        IpcRequest::SetDefaultUser { user_id } => {
+            app.config.lock().await.set_default_user(&user_id).await?;
+            Ok(serde_json::Value::Null)
+        }
+        IpcRequest::StartLogin => {
+            let api_url = app.config.lock().await.get_main().await.api_url;
+            serde_json::to_value(start_device_login(&api_url).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::AddApiKey { api_key } => {
+            let api_url = app.config.lock().await.get_main().await.api_url;
+            let user = login_with_api_key(&api_url, &api_key).await?;
+            app.config.lock().await.add_user(&user).await?;
+            Ok(serde_json::json!({"userId": user.user_id, "username": user.username, "email": user.email}))
+        }
+        IpcRequest::FinishLogin { device_code, interval, expires_in } => {
+            // The config lock is only taken once the login completes, polling can take minutes
+            let api_url = app.config.lock().await.get_main().await.api_url;
+            let user = finish_device_login(&api_url, &device_code, interval, expires_in).await?;
+            app.config.lock().await.add_user(&user).await?;
+            Ok(serde_json::json!({"userId": user.user_id, "username": user.username, "email": user.email}))
+        }
+        IpcRequest::FetchPath { source, path } => {
+            let (dir, config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_auth().await)
+            };
+            let (key, source) = config.sources.iter()
+                .find(|(k, s)| *k == &source || s.id == source)
+                .ok_or(format!("Unknown source {}", source))?;
+            let user = auth.records.get(&source.user_id).ok_or(format!("Unknown user {}", source.user_id))?;
+            let hashes_dir = get_hashes_dir(&dir, &config);
+
+            let mut fetched = vec![];
+            for watcher in config.watchers.iter().filter(|w| &w.source == key) {
+                fetched.extend(fetch_watcher_path(&hashes_dir, &config, watcher, source, user, &path).await?);
+            }
+            serde_json::to_value(fetched).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::DiffSource { source } => {
+            let (dir, config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_auth().await)
+            };
+            let (key, source) = config.sources.iter()
+                .find(|(k, s)| *k == &source || s.id == source)
+                .ok_or(format!("Unknown source {}", source))?;
+            let user = auth.records.get(&source.user_id).ok_or(format!("Unknown user {}", source.user_id))?;
+            let hashes_dir = get_hashes_dir(&dir, &config);
+
+            let mut diffs = vec![];
+            for watcher in config.watchers.iter().filter(|w| &w.source == key) {
+                diffs.push(diff_watcher(&hashes_dir, &config, watcher, source, user).await?);
+            }
+            serde_json::to_value(diffs).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::CheckConsistency { user_id } => {
+            serde_json::to_value(check_consistency(app, &user_id).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ShareFile { local_path, expires_in } => {
+            serde_json::to_value(create_share_link(app, &local_path, expires_in).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Notifications => {
+            serde_json::to_value(list_notifications()).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::DeadLetters => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(list_dead_letters(&dir).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ResubmitDeadLetters { id } => {
+            serde_json::to_value(resubmit_dead_letters(app, &id).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Quarantine => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(list_quarantine(&dir).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ClearQuarantine { path } => {
+            let dir = app.config.lock().await.get_path();
+            let cleared = clear_quarantine(&dir, &path).await?;
+            if let Some(path) = path.filter(|_| cleared.is_empty()) {
+                return Err(format!("{} is not quarantined", path));
+            }
+            serde_json::to_value(cleared).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Holds => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(list_holds(&dir).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::HoldPath { local_path } => {
+            let config = app.config.lock().await.get_main().await;
+            let local_path = PathBuf::from(&local_path);
+            if !config.watchers.iter().any(|w| local_path.starts_with(&w.local_path)) {
+                return Err(format!("{:?} is not inside a watcher", local_path));
+            }
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(add_hold(&dir, &local_path).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ReleasePath { local_path } => {
+            serde_json::to_value(release_hold(app, &PathBuf::from(&local_path)).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::Manifests => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(list_manifests(&dir).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::ApproveManifest { id } => {
+            serde_json::to_value(approve_manifest(app, &id).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::RejectManifest { id } => {
+            let dir = app.config.lock().await.get_path();
+            serde_json::to_value(reject_manifest(&dir, &id).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::SetLogging { silent, level } => {
+            serde_json::to_value(set_log_options(silent, &level)?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::WaitUntilSynced { source, timeout } => {
+            serde_json::to_value(wait_until_synced(app, &source, timeout).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::FolderAt { source, timestamp, target } => {
+            serde_json::to_value(get_folder_at(app, &source, timestamp, &target).await?).map_err(str_err_prefix("Error JSON Encode"))
+        }
+        IpcRequest::SwitchConfig { path } => {
+            app.switch_config_dir(&PathBuf::from(path)).await?;
+            Ok(serde_json::Value::Null)
+        }
+    }
+}
+
+async fn write_line<T: Serialize>(writer: &mut OwnedWriteHalf, value: &T) -> bool {
+    let mut payload = serde_json::to_string(value).unwrap();
+    payload.push('\n');
+    writer.write_all(payload.as_bytes()).await.is_ok()
+}
+
+// Acknowledged like any request, then events follow until the client goes away
+async fn stream_events(writer: &mut OwnedWriteHalf) {
+    let mut progress = subscribe_progress();
+    let mut hash_progress = subscribe_hash_progress();
+    if !write_line(writer, &IpcResponse { ok: true, data: serde_json::Value::Null, error: None }).await {
+        return;
+    }
+    loop {
+        let event = tokio::select! {
+            progress = progress.recv() => match progress {
+                Ok(progress) => IpcEvent::TransferProgress(progress),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+            progress = hash_progress.recv() => match progress {
+                Ok(progress) => IpcEvent::HashProgress(progress),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+        };
+        if !write_line(writer, &event).await {
+            return;
+        }
+    }
+}
+
+async fn handle_connection(app: App, stream: TcpStream, token: String) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let result = match serde_json::from_str::<IpcMessage>(&line) {
+            Ok(message) => {
+                if !is_same_secret(&message.token, &token) {
+                    Err("Invalid IPC token".to_string())
+                } else if message.request == IpcRequest::Subscribe {
+                    return stream_events(&mut writer).await;
+                } else {
+                    log::info!("IPC request: {:?}", &message.request);
+                    handle_request(&app, message.request).await
+                }
+            }
+            Err(e) => Err(format!("Invalid IPC request: {}", e)),
+        };
+        let response = match result {
+            Ok(data) => IpcResponse { ok: true, data, error: None },
+            Err(e) => IpcResponse { ok: false, data: serde_json::Value::Null, error: Some(e) },
+        };
+        if !write_line(&mut writer, &response).await {
+            break;
+        }
+    }
+}
+
+pub async fn start_ipc(app: &App) -> Result<(), String> {
+    let dir = app.config.lock().await.get_path();
+    let listener = TcpListener::bind("127.0.0.1:0").await.map_err(str_err_prefix("Error IPC Bind"))?;
+    let port = listener.local_addr().map_err(str_err_prefix("Error IPC Address"))?.port();
+    let token = generate_secret()?;
+
+    write_private_json_file(dir.join(IPC_FILE), &IpcEndpointJSON { port, token: token.clone() }).await?;
+    log::info!("IPC listening on 127.0.0.1:{}", port);
+
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(app.clone(), stream, token.clone()));
+                }
+                Err(e) => log::error!("IPC accept failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bundle::SherryBundleJSON;
+use crate::config::{SherryConfigStorageJSON, SyncMode};
+use crate::progress::{HashProgress, TransferProgress};
+use crate::server::types::ApiCreateFolderRequest;
+
+// Arguments left out when a request is logged
+const REDACTED_ARGS: &[&str] = &["apiKey", "bundle", "password"];
+
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(tag = "command", content = "args", rename_all = "camelCase")]
+pub enum IpcRequest {
+    Prune,
+    Status,
+    // keeps the connection open and pushes an IpcEvent per line until the client disconnects
+    Subscribe,
+    ConfigHistory,
+    ConfigDiff,
+    #[serde(rename_all = "camelCase")]
+    ConfigRollback { file: String, timestamp: Option<i128> },
+    #[serde(rename_all = "camelCase")]
+    AddWatcher { folder_id: String, local_path: String, user_id: Option<String>, mode: Option<SyncMode>, template: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    AddStorageWatcher { local_path: String, storage: SherryConfigStorageJSON, username: String, password: String, mode: Option<SyncMode> },
+    #[serde(rename_all = "camelCase")]
+    CreateFolder { local_path: String, user_id: Option<String>, template: Option<String>, folder: ApiCreateFolderRequest },
+    #[serde(rename_all = "camelCase")]
+    DeleteFolder { source: String, confirm: bool },
+    #[serde(rename_all = "camelCase")]
+    ArchiveFolder { source: String, archived: bool },
+    ExportBundle,
+    #[serde(rename_all = "camelCase")]
+    ImportBundle { bundle: Box<SherryBundleJSON>, path_map: Vec<(String, String)> },
+    #[serde(rename_all = "camelCase")]
+    IncludeWatcherPath { local_path: String, path: String },
+    #[serde(rename_all = "camelCase")]
+    ExcludeWatcherPath { local_path: String, path: String },
+    #[serde(rename_all = "camelCase")]
+    RemoveSource { source: String },
+    #[serde(rename_all = "camelCase")]
+    SetDefaultUser { user_id: String },
+    StartLogin,
+    #[serde(rename_all = "camelCase")]
+    AddApiKey { api_key: String },
+    #[serde(rename_all = "camelCase")]
+    FinishLogin { device_code: String, interval: u64, expires_in: u64 },
+    #[serde(rename_all = "camelCase")]
+    FetchPath { source: String, path: String },
+    #[serde(rename_all = "camelCase")]
+    DiffSource { source: String },
+    #[serde(rename_all = "camelCase")]
+    CheckConsistency { user_id: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    ShareFile { local_path: String, expires_in: Option<u64> },
+    DeadLetters,
+    Notifications,
+    #[serde(rename_all = "camelCase")]
+    ResubmitDeadLetters { id: Option<String> },
+    Quarantine,
+    #[serde(rename_all = "camelCase")]
+    ClearQuarantine { path: Option<String> },
+    Holds,
+    #[serde(rename_all = "camelCase")]
+    HoldPath { local_path: String },
+    #[serde(rename_all = "camelCase")]
+    ReleasePath { local_path: String },
+    Manifests,
+    #[serde(rename_all = "camelCase")]
+    ApproveManifest { id: String },
+    #[serde(rename_all = "camelCase")]
+    RejectManifest { id: String },
+    #[serde(rename_all = "camelCase")]
+    SetLogging { silent: Option<bool>, level: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    SwitchConfig { path: String },
+    #[serde(rename_all = "camelCase")]
+    WaitUntilSynced { source: String, timeout: Option<u64> },
+    #[serde(rename_all = "camelCase")]
+    FolderAt { source: String, timestamp: i128, target: Option<String> },
+}
+
+// Requests are logged as they are sent, with the values of secrets and bundles left out
+impl fmt::Debug for IpcRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        if let Some(args) = value.get_mut("args").and_then(|a| a.as_object_mut()) {
+            for (_, arg) in args.iter_mut().filter(|(k, _)| REDACTED_ARGS.contains(&k.as_str())) {
+                *arg = serde_json::Value::String("<redacted>".to_string());
+            }
+        }
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IpcMessage {
+    pub token: String,
+    pub request: IpcRequest,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IpcResponse {
+    pub ok: bool,
+    pub data: serde_json::Value,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "camelCase")]
+pub enum IpcEvent {
+    TransferProgress(TransferProgress),
+    HashProgress(HashProgress),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IpcEndpointJSON {
+    pub port: u16,
+    pub token: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageKind;
+
+    #[test]
+    fn redacts_secrets_of_logged_requests() {
+        let logged = format!("{:?}", IpcRequest::AddApiKey { api_key: "sk-secret".to_string() });
+        assert!(logged.contains("addApiKey"));
+        assert!(!logged.contains("sk-secret"));
+
+        let logged = format!("{:?}", IpcRequest::AddStorageWatcher {
+            local_path: "/data/docs".to_string(),
+            storage: SherryConfigStorageJSON { kind: StorageKind::Webdav, url: "https://dav.example.com/docs".to_string(), region: None },
+            username: "me".to_string(),
+            password: "hunter2".to_string(),
+            mode: None,
+        });
+        assert!(logged.contains("/data/docs"));
+        assert!(!logged.contains("hunter2"));
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use keyring::Entry;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::KEYCHAIN_SERVICE;
+
+static KEYCHAIN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KeychainTokensJSON {
+    access_token: String,
+    refresh_token: String,
+}
+
+pub fn set_keychain(enabled: bool) {
+    KEYCHAIN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_keychain() -> bool {
+    KEYCHAIN.load(Ordering::SeqCst)
+}
+
+fn get_entry(user_id: &str) -> Result<Entry, String> {
+    Entry::new(KEYCHAIN_SERVICE, user_id).map_err(|e| format!("Error Keychain Access for {}: {}", user_id, e))
+}
+
+pub fn store_tokens(user_id: &str, access_token: &str, refresh_token: &str) -> Result<(), String> {
+    let tokens = serde_json::to_string(&KeychainTokensJSON {
+        access_token: access_token.to_string(),
+        refresh_token: refresh_token.to_string(),
+    }).unwrap();
+    get_entry(user_id)?.set_password(&tokens).map_err(|e| format!("Error Keychain Write for {}: {}", user_id, e))
+}
+
+// (access token, refresh token)
+pub fn load_tokens(user_id: &str) -> Result<(String, String), String> {
+    let tokens = get_entry(user_id)?.get_password().map_err(|e| format!("Error Keychain Read for {}: {}", user_id, e))?;
+    let tokens = serde_json::from_str::<KeychainTokensJSON>(&tokens).map_err(|e| format!("Invalid Keychain Entry for {}: {}", user_id, e))?;
+    Ok((tokens.access_token, tokens.refresh_token))
+}
+
+// Kept in entries of their own, next to the tokens of the user
+fn get_folder_entry(user_id: &str, folder_id: &str) -> Result<Entry, String> {
+    get_entry(&format!("{}:{}", user_id, folder_id))
+}
+
+pub fn store_folder_token(user_id: &str, folder_id: &str, access_token: &str) -> Result<(), String> {
+    get_folder_entry(user_id, folder_id)?.set_password(access_token).map_err(|e| format!("Error Keychain Write for {} ({}): {}", user_id, folder_id, e))
+}
+
+pub fn load_folder_token(user_id: &str, folder_id: &str) -> Result<String, String> {
+    get_folder_entry(user_id, folder_id)?.get_password().map_err(|e| format!("Error Keychain Read for {} ({}): {}", user_id, folder_id, e))
+}
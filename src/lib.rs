@@ -0,0 +1,45 @@
+pub mod event;
+pub mod config;
+pub mod app;
+pub mod logs;
+pub mod hash;
+pub mod hash_store;
+pub mod auth;
+pub mod helpers;
+pub mod constants;
+pub mod server;
+pub mod files;
+pub mod watchers;
+pub mod ipc;
+pub mod maintenance;
+pub mod fs_watcher;
+pub mod health;
+pub mod status;
+pub mod history;
+pub mod cli;
+pub mod bandwidth;
+pub mod integrity;
+pub mod self_writes;
+pub mod keychain;
+pub mod available;
+pub mod watchdog;
+pub mod notifications;
+pub mod templates;
+pub mod bundle;
+pub mod messages;
+pub mod features;
+pub mod governor;
+pub mod startup;
+pub mod progress;
+pub mod change_journal;
+pub mod names;
+pub mod standby;
+pub mod drift;
+pub mod quiesce;
+pub mod barrier;
+pub mod reconcile;
+pub mod consistency;
+pub mod ownership;
+pub mod share;
+pub mod snapshot;
+pub mod container;
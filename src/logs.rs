@@ -1,27 +1,63 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use chrono::Utc;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::Handle;
 use log::LevelFilter;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::constants::LOGS_DIR;
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LogOptionsJSON {
+    pub silent: bool,
+    pub level: String,
+}
 
-pub fn initialize_logs(config_dir: &PathBuf, silent: bool) {
-    let log_filename = format!("{:}.log", Regex::new(r"[:.+ ]").unwrap().replace_all(Utc::now().to_rfc3339().as_str(), "-"));
+struct LogSettings {
+    // none in container mode, logs only go to the console there
+    file: Option<PathBuf>,
+    silent: bool,
+    level: LevelFilter,
+}
 
+static HANDLE: OnceLock<Handle> = OnceLock::new();
+static SETTINGS: std::sync::Mutex<Option<LogSettings>> = std::sync::Mutex::new(None);
+
+fn build_json_config(level: LevelFilter) -> log4rs::Config {
+    log4rs::config::runtime::Config::builder()
+        .appender(
+            log4rs::config::Appender::builder().build("console", Box::new(
+                ConsoleAppender::builder()
+                    .encoder(Box::new(JsonEncoder::new()))
+                    .build(),
+            ))
+        )
+        .build(log4rs::config::Root::builder().appender("console").build(level))
+        .unwrap()
+}
+
+fn build_config(settings: &LogSettings) -> log4rs::Config {
+    let file = match &settings.file {
+        Some(file) => file,
+        None => return build_json_config(settings.level),
+    };
+
+    // Appends, so the file is kept when the config is rebuilt at runtime
     let mut config_builder = log4rs::config::runtime::Config::builder()
         .appender(
             log4rs::config::Appender::builder().build("logfile", Box::new(
                 FileAppender::builder()
                     .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%dT%H:%M:%S)} | {({l}):5.5} | {m}{n}")))
-                    .build(config_dir.join(LOGS_DIR).join(log_filename)).unwrap()),
+                    .build(file).unwrap()),
             )
         );
 
-    if !silent {
+    if !settings.silent {
         config_builder = config_builder.appender(
             log4rs::config::Appender::builder().build("console", Box::new(
                 ConsoleAppender::builder()
@@ -35,10 +71,47 @@ pub fn initialize_logs(config_dir: &PathBuf, silent: bool) {
     let mut log_builder = log4rs::config::Root::builder()
         .appender("logfile");
 
-    if !silent {
+    if !settings.silent {
         log_builder = log_builder.appender("console");
     }
 
-    log4rs::init_config(config_builder.build(log_builder.build(LevelFilter::Info)).unwrap()).unwrap();
+    config_builder.build(log_builder.build(settings.level)).unwrap()
+}
+
+pub fn initialize_logs(logs_dir: &Path, silent: bool, json: bool) {
+    let file = if json {
+        None
+    } else {
+        let log_filename = format!("{:}.log", Regex::new(r"[:.+ ]").unwrap().replace_all(Utc::now().to_rfc3339().as_str(), "-"));
+        Some(logs_dir.join(log_filename))
+    };
+    let settings = LogSettings { file, silent, level: LevelFilter::Info };
+
+    HANDLE.set(log4rs::init_config(build_config(&settings)).unwrap()).ok();
+    *SETTINGS.lock().unwrap() = Some(settings);
     log::info!("Logs initialized");
-}
\ No newline at end of file
+}
+
+// Console output and level of the running demon, the log file keeps being written
+pub fn set_log_options(silent: Option<bool>, level: &Option<String>) -> Result<LogOptionsJSON, String> {
+    let level = match level {
+        Some(level) => Some(level.parse::<LevelFilter>().map_err(|_| format!("Invalid log level {}", level))?),
+        None => None,
+    };
+    let mut settings = SETTINGS.lock().unwrap();
+    let settings = settings.as_mut().ok_or("Logs are not initialized".to_string())?;
+    if settings.file.is_none() && silent == Some(true) {
+        return Err("Container logs only go to the console, they can't be silenced".to_string());
+    }
+    settings.silent = silent.unwrap_or(settings.silent);
+    settings.level = level.unwrap_or(settings.level);
+    if let Some(handle) = HANDLE.get() {
+        handle.set_config(build_config(settings));
+    }
+    log::info!("Logs set to level {}{}", settings.level, if settings.silent { ", console silenced" } else { "" });
+    Ok(LogOptionsJSON { silent: settings.silent, level: settings.level.to_string() })
+}
+
+pub fn is_silent() -> bool {
+    SETTINGS.lock().unwrap().as_ref().is_some_and(|s| s.silent)
+}
@@ -2,23 +2,11 @@ use std::env;
 use std::path::PathBuf;
 
 use clap::Parser;
-use home::home_dir;
-use path_clean::PathClean;
 
-use crate::app::App;
-use crate::constants::{CONFIG_DIR, ENV_CONFIG_DIR};
-
-mod event;
-mod config;
-mod app;
-mod logs;
-mod hash;
-mod auth;
-mod helpers;
-mod constants;
-mod server;
-mod files;
-mod watchers;
+use sherry_demon::app::{App, AppOptions};
+use sherry_demon::cli::{Command, run_command};
+use sherry_demon::constants::{CONTAINER_CONFIG_DIR, ENV_CONFIG_DIR, ENV_CONTAINER};
+use sherry_demon::helpers::{absolute_path, get_default_config_dir};
 
 #[derive(Parser)]
 struct Args {
@@ -27,23 +15,33 @@ struct Args {
 
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     silent: Option<bool>,
+
+    /// Docker friendly mode: JSON logs to stdout, health endpoint and polling watchers
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    container: Option<bool>,
+
+    /// Use polling instead of native filesystem events
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    polling: Option<bool>,
+
+    /// Wait until the instance running on the same config directory stops, then take over its folders
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    standby: Option<bool>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn resolve_config_dir(config: Option<String>) -> PathBuf {
+fn resolve_config_dir(config: Option<String>, container: bool) -> PathBuf {
     match config {
-        Some(config) => {
-            let path = PathBuf::from(config);
-            if path.is_absolute() {
-                path
-            } else {
-                env::current_dir().unwrap().join(path)
-            }.clean()
-        }
+        Some(config) => absolute_path(&config),
         None => {
             if let Ok(res) = env::var(ENV_CONFIG_DIR) {
                 PathBuf::from(res)
+            } else if container {
+                PathBuf::from(CONTAINER_CONFIG_DIR)
             } else {
-                home_dir().unwrap().join(CONFIG_DIR)
+                get_default_config_dir()
             }
         }
     }
@@ -53,9 +51,19 @@ fn resolve_config_dir(config: Option<String>) -> PathBuf {
 async fn main() -> Result<(), String> {
     let args = Args::parse();
 
-    let config_dir = resolve_config_dir(args.config);
+    let container = args.container.unwrap_or(false) || env::var(ENV_CONTAINER).is_ok_and(|v| v == "1" || v == "true");
+    let config_dir = resolve_config_dir(args.config, container);
+
+    if let Some(command) = &args.command {
+        return run_command(&config_dir, command).await;
+    }
 
-    let app = App::new(&config_dir, args.silent.unwrap_or(false)).await;
+    let app = App::new(&config_dir, &AppOptions {
+        silent: args.silent.unwrap_or(false),
+        container,
+        polling: args.polling.unwrap_or(false) || container,
+        standby: args.standby.unwrap_or(false),
+    }).await;
     if app.is_err() { return Err("Demon start failed".to_string()); }
     let mut app = app.unwrap();
 
@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::config::{get_hashes_dir, get_logs_dir, SherryConfigJSON};
+use crate::constants::{CHANGES_RETENTION, DEAD_LETTERS_FILE, DEAD_LETTERS_RETENTION, HASHES_DB, HASHES_DB_CORRUPT, JOURNAL_FILE, LOGS_RETENTION, QUARANTINE_FILE};
+use crate::event::dead_letters::prune_dead_letters;
+use crate::event::journal::prune_journal;
+use crate::event::quarantine::prune_quarantine;
+use crate::hash_store::{prune_changes, prune_stores};
+use crate::helpers::{get_default_state_dir, get_now_as_millis, str_err_prefix};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PruneReport {
+    pub removed_hashes: Vec<String>,
+    pub removed_changes: usize,
+    pub removed_logs: Vec<String>,
+    pub removed_journal_entries: usize,
+    pub removed_dead_letters: usize,
+    pub removed_quarantine_entries: usize,
+    pub reclaimed_bytes: u64,
+}
+
+async fn list_files(dir: &Path) -> Vec<(PathBuf, std::fs::Metadata)> {
+    let mut files = vec![];
+    if let Ok(mut entries) = fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Ok(metadata) = entry.metadata().await {
+                if metadata.is_file() {
+                    files.push((entry.path(), metadata));
+                }
+            }
+        }
+    }
+    files
+}
+
+async fn remove_file(path: &PathBuf, size: u64, removed: &mut Vec<String>, report_bytes: &mut u64) -> Result<(), String> {
+    fs::remove_file(path).await.map_err(str_err_prefix(format!("Error File Remove at {}", path.to_str().unwrap())))?;
+    removed.push(path.to_str().unwrap().to_string());
+    *report_bytes += size;
+    Ok(())
+}
+
+async fn get_file_size(path: &Path) -> u64 {
+    fs::metadata(path).await.map_or(0, |m| m.len())
+}
+
+// Entries dropped from one of the state files, the file shrinks by the space they took
+async fn prune_entries(path: PathBuf, prune: impl Future<Output=Result<usize, String>>, report_bytes: &mut u64) -> Result<usize, String> {
+    let size = get_file_size(&path).await;
+    let removed = prune.await?;
+    *report_bytes += size.saturating_sub(get_file_size(&path).await);
+    Ok(removed)
+}
+
+pub async fn prune_state(dir: &Path, config: &SherryConfigJSON) -> Result<PruneReport, String> {
+    let mut report = PruneReport::default();
+
+    let hashes_dir = get_hashes_dir(dir, config);
+    let hashes_ids = config.watchers.iter().map(|w| w.hashes_id.clone()).collect::<HashSet<String>>();
+    // The database, its journal and backup, plus stores not moved into it yet. Corrupted copies go.
+    for (path, metadata) in list_files(&hashes_dir).await {
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let is_used = (name.starts_with(HASHES_DB) && name != HASHES_DB_CORRUPT) || name.strip_suffix(".json").is_some_and(|id| hashes_ids.contains(id));
+        if !is_used {
+            remove_file(&path, metadata.len(), &mut report.removed_hashes, &mut report.reclaimed_bytes).await?;
+        }
+    }
+    report.removed_hashes.extend(prune_stores(&hashes_dir, &hashes_ids).await?);
+    // The file history only goes back so far, older changes are folded into the state they left
+    let before = get_now_as_millis() - CHANGES_RETENTION as i128 * 1000;
+    report.removed_changes = prune_changes(&hashes_dir, before).await?;
+
+    // Journaled events, dead letters and quarantined files of sources that were removed, dead letters nobody resubmitted
+    // and quarantined files that are gone
+    let state_dir = get_default_state_dir(dir);
+    let sources = config.sources.keys().cloned().collect::<HashSet<String>>();
+    let folders = config.sources.values().map(|s| s.id.clone()).collect::<HashSet<String>>();
+    let before = get_now_as_millis() - DEAD_LETTERS_RETENTION as i128 * 1000;
+    report.removed_journal_entries = prune_entries(state_dir.join(JOURNAL_FILE), prune_journal(dir, &sources), &mut report.reclaimed_bytes).await?;
+    report.removed_dead_letters = prune_entries(state_dir.join(DEAD_LETTERS_FILE), prune_dead_letters(dir, &folders, before), &mut report.reclaimed_bytes).await?;
+    report.removed_quarantine_entries = prune_entries(state_dir.join(QUARANTINE_FILE), prune_quarantine(dir, &sources), &mut report.reclaimed_bytes).await?;
+
+    let threshold = SystemTime::now() - Duration::from_secs(LOGS_RETENTION);
+    for (path, metadata) in list_files(&get_logs_dir(dir, config)).await {
+        if metadata.modified().is_ok_and(|m| m < threshold) {
+            remove_file(&path, metadata.len(), &mut report.removed_logs, &mut report.reclaimed_bytes).await?;
+        }
+    }
+
+    log::info!("Pruned state, reclaimed {} bytes: {:?}", report.reclaimed_bytes, &report);
+    Ok(report)
+}
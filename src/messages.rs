@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Conditions the user has to know about. The codes are stable, GUIs react to them and translate the texts from
+// the code and its params, the English texts below are only the default.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MessageCode {
+    // params: user
+    AuthExpired,
+    // params: user, reason
+    AuthInvalid,
+    // params: count
+    UploadMismatch,
+    // params: path
+    FileQuarantined,
+    // params: folder
+    QuotaExceeded,
+    // params: path, other
+    WatcherOverlap,
+    // params: path, other
+    WatcherDuplicate,
+    // params: id, folder, files, bytes
+    BatchNeedsApproval,
+    // params: path, owner
+    WatcherClaimed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserMessage {
+    pub code: MessageCode,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+impl UserMessage {
+    pub fn new(code: MessageCode, params: &[(&str, &str)]) -> Self {
+        UserMessage {
+            code,
+            params: params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+    pub fn get_title(&self) -> &'static str {
+        match self.code {
+            MessageCode::AuthExpired => "Sherry login expired",
+            MessageCode::AuthInvalid => "Sherry login invalid",
+            MessageCode::UploadMismatch => "Sherry upload mismatch",
+            MessageCode::FileQuarantined => "Sherry file quarantined",
+            MessageCode::QuotaExceeded => "Sherry quota exceeded",
+            MessageCode::WatcherOverlap | MessageCode::WatcherDuplicate | MessageCode::WatcherClaimed => "Sherry watcher refused",
+            MessageCode::BatchNeedsApproval => "Sherry upload waits for approval",
+        }
+    }
+    pub fn get_text(&self) -> String {
+        let template = match self.code {
+            MessageCode::AuthExpired => "{user} has to log in again, its folders are paused until then (sherry-demon user login)",
+            MessageCode::AuthInvalid => "Credentials of {user} are invalid ({reason}), its folders are suspended until it logs in again",
+            MessageCode::UploadMismatch => "{count} uploads don't match what the server recorded, see the logs",
+            MessageCode::FileQuarantined => "{path} keeps failing to upload and is skipped until cleared (sherry-demon quarantine clear)",
+            MessageCode::QuotaExceeded => "Folder {folder} is out of space, uploads to it are rejected",
+            MessageCode::WatcherOverlap => "{path} overlaps with the watcher at {other} and is not watched",
+            MessageCode::WatcherDuplicate => "{path} syncs the same folder as the watcher at {other} and is not watched",
+            MessageCode::WatcherClaimed => "{path} is synced by another Sherry demon ({owner}) and is not watched",
+            MessageCode::BatchNeedsApproval => "{files} changes ({bytes} bytes) of folder {folder} wait for approval (sherry-demon manifest approve {id})",
+        };
+        self.params.iter().fold(template.to_string(), |text, (k, v)| text.replace(&format!("{{{}}}", k), v))
+    }
+}
@@ -0,0 +1,188 @@
+// Remote names that can't exist on this platform are stored under an escaped local name and turned back on the way up,
+// so the server always sees the original. Only Windows needs it: reserved device names (`CON`, `COM1.txt`, ...),
+// `<>:"|?*`, control characters and trailing dots or spaces are written as `%XX`. A `%` that would read as such an
+// escape is escaped itself, so any local name maps back to exactly one remote name.
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_escaping() -> bool {
+    cfg!(windows)
+}
+
+// The part before the first dot decides, `nul.tar.gz` is as reserved as `NUL`
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem))
+}
+
+// Both slashes are separators of sync paths and never part of a name
+fn is_invalid_char(c: char) -> bool {
+    c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*')
+}
+
+// Characters an escape may stand for, the first letter of a reserved name only at the start
+fn is_escaped_char(c: char, at_start: bool, rest: &str) -> bool {
+    is_invalid_char(c) || matches!(c, '.' | ' ') || (at_start && c.is_ascii_alphabetic() && is_reserved_name(&format!("{}{}", c, rest)))
+}
+
+// The character an escape at the start of `text` stands for, with the text after it. Whether a name is reserved is
+// decided on the original name, so an escaped rest is unescaped first.
+fn read_escape(text: &str, at_start: bool, is_rest_escaped: bool) -> Option<(char, &str)> {
+    let hex = text.strip_prefix('%')?.get(..2)?;
+    let c = u8::from_str_radix(hex, 16).ok().filter(|b| b.is_ascii())? as char;
+    let rest = &text[3..];
+    let is_escape = match c {
+        // only in front of what would read as an escape otherwise
+        '%' => read_escape(&format!("%{}", rest), at_start, is_rest_escaped).is_some(),
+        c => {
+            let original_rest = if at_start && is_rest_escaped { unescape_from(rest, false) } else { rest.to_string() };
+            is_escaped_char(c, at_start, &original_rest)
+        }
+    };
+    if is_escape { Some((c, rest)) } else { None }
+}
+
+fn escape_char(escaped: &mut String, c: char) {
+    escaped.push_str(&format!("%{:02X}", c as u32));
+}
+
+fn escape_windows_name(name: &str) -> String {
+    let trailing = name.len() - name.trim_end_matches(['.', ' ']).len();
+    let mut escaped = String::with_capacity(name.len());
+    for (i, c) in name.char_indices() {
+        let at_start = i == 0;
+        let needs_escape = is_invalid_char(c)
+            || (at_start && is_reserved_name(name))
+            || i >= name.len() - trailing
+            || (c == '%' && read_escape(&name[i..], at_start, false).is_some());
+        if needs_escape {
+            escape_char(&mut escaped, c);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape_from(name: &str, at_start: bool) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        match read_escape(rest, at_start && rest.len() == name.len(), true) {
+            Some((c, after)) => {
+                unescaped.push(c);
+                rest = after;
+            }
+            None => {
+                unescaped.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    unescaped
+}
+
+fn unescape_windows_name(name: &str) -> String {
+    unescape_from(name, true)
+}
+
+pub fn escape_name(name: &str) -> String {
+    if is_escaping() { escape_windows_name(name) } else { name.to_string() }
+}
+
+pub fn unescape_name(name: &str) -> String {
+    if is_escaping() { unescape_windows_name(name) } else { name.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn round_trip(name: &str) -> String {
+        unescape_windows_name(&escape_windows_name(name))
+    }
+
+    #[test]
+    fn escapes_reserved_names() {
+        assert_eq!(escape_windows_name("CON"), "%43ON");
+        assert_eq!(escape_windows_name("com1.txt"), "%63om1.txt");
+        assert_eq!(escape_windows_name("nul.tar.gz"), "%6Eul.tar.gz");
+        assert_eq!(escape_windows_name("AUX .txt"), "%41UX .txt");
+        assert_eq!(escape_windows_name("CONSOLE"), "CONSOLE");
+        assert_eq!(escape_windows_name("COM10"), "COM10");
+        for name in ["CON", "com1.txt", "nul.tar.gz", "AUX .txt", "CONSOLE", "LPT9"] {
+            assert_eq!(round_trip(name), name);
+        }
+    }
+
+    #[test]
+    fn escapes_trailing_dots_and_spaces() {
+        assert_eq!(escape_windows_name("name."), "name%2E");
+        assert_eq!(escape_windows_name("name. "), "name%2E%20");
+        assert_eq!(escape_windows_name("..."), "%2E%2E%2E");
+        assert_eq!(escape_windows_name(".hidden"), ".hidden");
+        assert_eq!(escape_windows_name("a b.c"), "a b.c");
+        for name in ["name.", "name. ", "...", " ", ".hidden", "a b.c"] {
+            assert_eq!(round_trip(name), name);
+        }
+    }
+
+    #[test]
+    fn escapes_invalid_characters() {
+        assert_eq!(escape_windows_name("a:b"), "a%3Ab");
+        assert_eq!(escape_windows_name("what?*"), "what%3F%2A");
+        assert_eq!(escape_windows_name("tab\t"), "tab%09");
+        for name in ["a:b", "what?*", "tab\t", "<\"|>"] {
+            assert_eq!(round_trip(name), name);
+        }
+    }
+
+    #[test]
+    fn keeps_literal_percent_signs() {
+        assert_eq!(escape_windows_name("100%"), "100%");
+        assert_eq!(escape_windows_name("%41"), "%41");
+        assert_eq!(escape_windows_name("%zz"), "%zz");
+        // would read as the escape of `:` and `.`
+        assert_eq!(escape_windows_name("%3A"), "%253A");
+        assert_eq!(escape_windows_name("a%2E"), "a%252E");
+        for name in ["100%", "%41", "%zz", "%3A", "a%2E", "%253A", "%%3A"] {
+            assert_eq!(round_trip(name), name);
+        }
+    }
+
+    #[test]
+    fn escapes_already_escaped_names() {
+        for name in ["%43ON", "name%2E", "a%3Ab", "%253A"] {
+            let escaped = escape_windows_name(name);
+            assert_eq!(unescape_windows_name(&escaped), name);
+            assert_eq!(unescape_windows_name(&unescape_windows_name(&escape_windows_name(&escaped))), name);
+        }
+        // names no escaping could have produced are kept as they are
+        assert_eq!(unescape_windows_name("%41BC"), "%41BC");
+        assert_eq!(unescape_windows_name("100%"), "100%");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn leaves_names_alone_elsewhere() {
+        assert_eq!(escape_name("CON."), "CON.");
+        assert_eq!(unescape_name("%43ON"), "%43ON");
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(name in "(CON|com1|nul|LPT9)?[a-zA-Z0-9%. :?]{0,8}") {
+            let escaped = escape_windows_name(&name);
+            prop_assert_eq!(unescape_windows_name(&escaped), name);
+            prop_assert!(!escaped.chars().any(is_invalid_char));
+            prop_assert!(!escaped.ends_with(['.', ' ']));
+            prop_assert!(!is_reserved_name(&escaped));
+        }
+    }
+}
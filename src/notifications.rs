@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+use std::process;
+
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{NOTIFICATIONS_REPEAT_DELAY, NOTIFICATIONS_SIZE};
+use crate::helpers::get_now_as_millis;
+use crate::messages::UserMessage;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationJSON {
+    pub timestamp: i128,
+    #[serde(flatten)]
+    pub user_message: UserMessage,
+    // English defaults of the message
+    pub title: String,
+    pub message: String,
+}
+
+// Newest last, read over IPC by `notifications`
+static NOTIFICATIONS: std::sync::Mutex<VecDeque<NotificationJSON>> = std::sync::Mutex::new(VecDeque::new());
+
+// Best effort, headless machines simply don't have a notifier
+fn show_desktop_notification(title: &String, message: &String) {
+    let res = if cfg!(target_os = "macos") {
+        process::Command::new("osascript")
+            .arg("-e")
+            .arg(format!("display notification {:?} with title {:?}", message, title))
+            .spawn()
+    } else if cfg!(target_os = "linux") {
+        process::Command::new("notify-send").arg(title).arg(message).spawn()
+    } else {
+        return;
+    };
+    if let Err(e) = res {
+        log::debug!("Desktop notification failed: {}", e);
+    }
+}
+
+pub fn notify(user_message: UserMessage) {
+    let notification = NotificationJSON {
+        timestamp: get_now_as_millis(),
+        title: user_message.get_title().to_string(),
+        message: user_message.get_text(),
+        user_message,
+    };
+    log::warn!("{}: {}", &notification.title, &notification.message);
+    let mut notifications = NOTIFICATIONS.lock().unwrap();
+    // The same condition hit by a batch of files is shown once
+    if notifications.iter().any(|n| n.user_message == notification.user_message && notification.timestamp - n.timestamp < NOTIFICATIONS_REPEAT_DELAY as i128 * 1000) {
+        return;
+    }
+    show_desktop_notification(&notification.title, &notification.message);
+    notifications.push_back(notification);
+    while notifications.len() > NOTIFICATIONS_SIZE {
+        notifications.pop_front();
+    }
+}
+
+pub fn list_notifications() -> Vec<NotificationJSON> {
+    NOTIFICATIONS.lock().unwrap().iter().cloned().collect()
+}
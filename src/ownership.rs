@@ -0,0 +1,101 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::config::SherryConfigWatcherJSON;
+use crate::constants::{OWNER_HEARTBEAT_INTERVAL, OWNER_STALE_TIMEOUT, WATCHER_OWNER_FILE};
+use crate::files::{read_json_file, write_json_file_atomic};
+use crate::helpers::get_now_as_millis;
+use crate::self_writes::with_self_writes;
+use crate::standby::get_instance_id;
+
+// Kept in the root of every watched folder, so a demon running on another config directory finds out the folder is
+// already synced instead of uploading the same changes twice and fighting over downloads
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatcherOwnerJSON {
+    pub instance_id: String,
+    pub config_dir: String,
+    pub host: String,
+    pub pid: u32,
+    pub heartbeat: i128,
+}
+
+impl WatcherOwnerJSON {
+    pub fn describe(&self) -> String {
+        format!("pid {} on {}, config {}", self.pid, self.host, &self.config_dir)
+    }
+}
+
+fn get_marker_paths(local_path: &str) -> Vec<PathBuf> {
+    let root = Path::new(local_path);
+    vec![root.join(WATCHER_OWNER_FILE), root.join(format!(".{}.tmp", WATCHER_OWNER_FILE))]
+}
+
+// The marker and the temporary file it is written through are never synced
+pub fn is_owner_marker(sync_path: &str) -> bool {
+    sync_path == WATCHER_OWNER_FILE || sync_path == format!(".{}.tmp", WATCHER_OWNER_FILE)
+}
+
+// Instances on the same config directory are a standby taking over or this demon restarted, they share the folder
+// on purpose. Heartbeats are compared with the local clock, clocks of machines sharing a network folder are assumed
+// to roughly agree.
+pub async fn get_foreign_owner(local_path: &str, dir: &Path) -> Option<WatcherOwnerJSON> {
+    let owner: WatcherOwnerJSON = read_json_file(&get_marker_paths(local_path)[0]).await.ok()?;
+    let is_alive = get_now_as_millis() - owner.heartbeat < (OWNER_STALE_TIMEOUT * 1000) as i128;
+    let is_foreign = &owner.instance_id != get_instance_id() && owner.config_dir != dir.to_str().unwrap();
+    if is_alive && is_foreign { Some(owner) } else { None }
+}
+
+async fn write_marker(local_path: &str, dir: &Path) -> Result<(), String> {
+    let paths = get_marker_paths(local_path);
+    with_self_writes(&paths, "", write_json_file_atomic(&paths[0], &WatcherOwnerJSON {
+        instance_id: get_instance_id().clone(),
+        config_dir: dir.to_str().unwrap().to_string(),
+        host: std::env::var("HOSTNAME").or(std::env::var("COMPUTERNAME")).unwrap_or_default(),
+        pid: std::process::id(),
+        heartbeat: get_now_as_millis(),
+    })).await.map(|_| ())
+}
+
+// Read-only folders can't hold a marker, they are synced without one
+pub async fn claim_watchers(watchers: &[SherryConfigWatcherJSON], dir: &Path) {
+    for w in watchers {
+        if let Err(e) = write_marker(&w.local_path, dir).await {
+            log::warn!("Failed to mark {} as synced by this demon: {}", &w.local_path, e);
+        }
+    }
+}
+
+pub async fn release_watchers(watchers: &[SherryConfigWatcherJSON]) {
+    for w in watchers {
+        let paths = get_marker_paths(&w.local_path);
+        let is_own = read_json_file::<WatcherOwnerJSON, _>(&paths[0]).await.is_ok_and(|o| &o.instance_id == get_instance_id());
+        if is_own {
+            with_self_writes(&paths, "", tokio::fs::remove_file(&paths[0])).await.ok();
+        }
+    }
+}
+
+// Markers taken over by another demon mean this one was written off as stopped, the folder is left to it from the
+// next revalidation on
+pub fn start_owner_heartbeat(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(OWNER_HEARTBEAT_INTERVAL)).await;
+            let (dir, config) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await)
+            };
+            for w in &config.watchers {
+                match get_foreign_owner(&w.local_path, &dir).await {
+                    Some(owner) => log::error!("{} was taken over by another demon ({})", &w.local_path, owner.describe()),
+                    None => claim_watchers(std::slice::from_ref(w), &dir).await,
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,145 @@
+use std::sync::OnceLock;
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::bandwidth::Direction;
+use crate::constants::{PROGRESS_STEP, PROGRESS_SUBSCRIBER_BACKLOG};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferProgress {
+    pub direction: Direction,
+    pub source_id: String,
+    pub path: String,
+    pub bytes: u64,
+    // 0 when the size isn't known up front
+    pub total: u64,
+    pub percent: Option<u8>,
+    // the stream ended, successfully or not
+    pub done: bool,
+}
+
+// Files of a watcher hashed so far, sent while a whole tree is hashed (start, revalidation, rebuilt stores)
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HashProgress {
+    pub local_path: String,
+    pub hashed: usize,
+    pub total: usize,
+    pub done: bool,
+}
+
+static PROGRESS: OnceLock<broadcast::Sender<TransferProgress>> = OnceLock::new();
+static HASH_PROGRESS: OnceLock<broadcast::Sender<HashProgress>> = OnceLock::new();
+
+fn get_sender() -> &'static broadcast::Sender<TransferProgress> {
+    PROGRESS.get_or_init(|| broadcast::channel(PROGRESS_SUBSCRIBER_BACKLOG).0)
+}
+
+fn get_hash_sender() -> &'static broadcast::Sender<HashProgress> {
+    HASH_PROGRESS.get_or_init(|| broadcast::channel(PROGRESS_SUBSCRIBER_BACKLOG).0)
+}
+
+// Subscribers that fall behind skip ahead instead of slowing transfers down
+pub fn subscribe_progress() -> broadcast::Receiver<TransferProgress> {
+    get_sender().subscribe()
+}
+
+pub fn subscribe_hash_progress() -> broadcast::Receiver<HashProgress> {
+    get_hash_sender().subscribe()
+}
+
+pub struct HashProgressTracker {
+    progress: HashProgress,
+    reported: usize,
+}
+
+impl HashProgressTracker {
+    pub fn new(local_path: &str, total: usize) -> Self {
+        HashProgressTracker {
+            progress: HashProgress { local_path: local_path.to_string(), hashed: 0, total, done: false },
+            reported: 0,
+        }
+    }
+
+    // Once per percent, like transfers
+    pub fn advance(&mut self) {
+        self.progress.hashed += 1;
+        let percent = self.progress.hashed * 100 / self.progress.total.max(1);
+        if percent > self.reported {
+            self.reported = percent;
+            get_hash_sender().send(self.progress.clone()).ok();
+        }
+    }
+}
+
+impl Drop for HashProgressTracker {
+    fn drop(&mut self) {
+        self.progress.done = true;
+        get_hash_sender().send(self.progress.clone()).ok();
+    }
+}
+
+struct ProgressTracker {
+    progress: TransferProgress,
+    reported: u64,
+}
+
+impl ProgressTracker {
+    fn get_percent(&self) -> Option<u8> {
+        (self.progress.bytes * 100).checked_div(self.progress.total).map(|p| p.min(100) as u8)
+    }
+
+    // Once per percent, or per step when the size is unknown, so a big file doesn't flood the subscribers
+    fn advance(&mut self, amount: u64) {
+        self.progress.bytes += amount;
+        let percent = self.get_percent();
+        let is_due = match (percent, self.progress.percent) {
+            (Some(percent), Some(reported)) => percent > reported,
+            (Some(_), None) => true,
+            (None, _) => self.progress.bytes - self.reported >= PROGRESS_STEP,
+        };
+        if is_due {
+            self.progress.percent = percent;
+            self.reported = self.progress.bytes;
+            get_sender().send(self.progress.clone()).ok();
+        }
+    }
+}
+
+// Reports the end even when the transfer fails or is dropped halfway
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        self.progress.done = true;
+        self.progress.percent = self.get_percent();
+        get_sender().send(self.progress.clone()).ok();
+    }
+}
+
+pub fn track_progress<S, T, E>(source_id: &str, direction: Direction, path: &str, total: u64, stream: S) -> impl Stream<Item=Result<T, E>> + Unpin + Send
+    where
+        S: Stream<Item=Result<T, E>> + Unpin + Send + 'static,
+        T: AsRef<[u8]> + Send + 'static,
+        E: Send + 'static,
+{
+    let mut tracker = ProgressTracker {
+        progress: TransferProgress {
+            direction,
+            source_id: source_id.to_string(),
+            path: path.to_string(),
+            bytes: 0,
+            total,
+            percent: None,
+            done: false,
+        },
+        reported: 0,
+    };
+    stream.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            tracker.advance(chunk.as_ref().len() as u64);
+        }
+        chunk
+    })
+}
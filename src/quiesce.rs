@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::app::App;
+use crate::config::{get_hashes_dir, SherryConfigWatcherJSON};
+use crate::constants::{QUIESCE_SETTLE, QUIESCE_TICK};
+use crate::event::event_processing::send_events;
+use crate::event::file_event::{filter_events, get_file_event, SyncEventKind};
+use crate::hash::{find_local_changes, get_hashes};
+
+struct Quiesce {
+    // none while the watchers are still being set up
+    resume_at: Option<Instant>,
+    watchers: HashSet<String>,
+}
+
+// source key -> intake paused while its watchers are unwatched and watched again
+static QUIESCED: std::sync::Mutex<BTreeMap<String, Quiesce>> = std::sync::Mutex::new(BTreeMap::new());
+
+// Events of the source are dropped until it resumes, a later update of the same source keeps it paused
+pub fn quiesce_watchers(watchers: &Vec<SherryConfigWatcherJSON>) {
+    let mut quiesced = QUIESCED.lock().unwrap();
+    for w in watchers {
+        let entry = quiesced.entry(w.source.clone()).or_insert_with(|| {
+            log::info!("Pausing events of source {} while its watchers are updated", &w.source);
+            Quiesce { resume_at: None, watchers: HashSet::new() }
+        });
+        entry.resume_at = None;
+        entry.watchers.insert(w.local_path.clone());
+    }
+}
+
+// The notify churn of the new watches is still debounced when the setup returns, so intake resumes a bit later
+pub fn settle_watchers() {
+    let resume_at = Instant::now() + Duration::from_secs(QUIESCE_SETTLE);
+    for q in QUIESCED.lock().unwrap().values_mut().filter(|q| q.resume_at.is_none()) {
+        q.resume_at = Some(resume_at);
+    }
+}
+
+pub fn is_quiesced(source: &String) -> bool {
+    QUIESCED.lock().unwrap().contains_key(source)
+}
+
+// Changes dropped while paused are found by comparing the watchers with their hash stores. Content the store already
+// has is skipped by `send_events`, so only real changes are uploaded.
+async fn check_consistency(app: &App, source_key: &String, watcher_paths: HashSet<String>) {
+    let (dir, config) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await)
+    };
+    let source = match config.sources.get(source_key) {
+        Some(source) if source.can_upload() => source,
+        _ => return,
+    };
+    let hashes_dir = get_hashes_dir(&dir, &config);
+    let mut events = vec![];
+    for watcher in config.watchers.iter().filter(|w| &w.source == source_key && w.complete && w.mode.can_upload() && watcher_paths.contains(&w.local_path)) {
+        let base = Path::new(&watcher.local_path);
+        let hashes = match get_hashes(&hashes_dir, source, base, &watcher.hashes_id).await {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                log::error!("Failed to check watcher {} after its update: {}", &watcher.local_path, e);
+                continue;
+            }
+        };
+        let (changed, removed) = find_local_changes(source, &hashes).await;
+        events.extend(changed.iter().map(|p| get_file_event(&source.id, base, p, SyncEventKind::Updated))
+            .chain(removed.iter().map(|p| get_file_event(&source.id, base, p, SyncEventKind::Deleted)))
+            .filter(|e| watcher.is_included(&e.sync_path)));
+    }
+    let events = filter_events(source, &events);
+    if events.is_empty() {
+        return;
+    }
+    log::info!("{} changes of source {} found after its watchers were updated", events.len(), source_key);
+    send_events(app.clone(), source_key, events).await;
+}
+
+pub fn start_quiesce_resume(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(QUIESCE_TICK)).await;
+            let due = {
+                let mut quiesced = QUIESCED.lock().unwrap();
+                let now = Instant::now();
+                let keys = quiesced.iter().filter(|(_, q)| q.resume_at.is_some_and(|r| r <= now)).map(|(k, _)| k.clone()).collect::<Vec<String>>();
+                keys.into_iter().filter_map(|k| quiesced.remove(&k).map(|q| (k, q))).collect::<Vec<(String, Quiesce)>>()
+            };
+            for (source, q) in due {
+                log::info!("Resuming events of source {}", &source);
+                check_consistency(&app, &source, q.watchers).await;
+            }
+        }
+    });
+}
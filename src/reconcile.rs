@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::app::App;
+use crate::config::get_hashes_dir;
+use crate::constants::RECONCILE_TICK;
+use crate::event::event_processing::submit_events;
+use crate::hash::rescan_hashes;
+use crate::quiesce::is_quiesced;
+use crate::watchdog::watch;
+use crate::watchers::{fetch_watcher_files, take_fetched_changes};
+
+// Spread over the interval by path, so folders added together aren't rescanned together
+fn get_stagger(local_path: &String, interval: u64) -> Duration {
+    Duration::from_secs(seahash::hash(local_path.as_bytes()) % interval)
+}
+
+// Filesystem watchers miss events (queue overflows, sleep, network shares). With `reconcileInterval` every watcher is
+// hashed again and fetched, one at a time, which downloads whatever was missed. Missed local changes go through the
+// event pipeline, the next watcher waits until they are queued.
+pub fn start_reconcile_scan(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        // local path -> next scan
+        let mut due: BTreeMap<String, Instant> = BTreeMap::new();
+        loop {
+            tokio::time::sleep(Duration::from_secs(RECONCILE_TICK)).await;
+            let (dir, config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_auth().await)
+            };
+            let interval = match config.get_reconcile_interval() {
+                Some(interval) => interval,
+                None => {
+                    due.clear();
+                    continue;
+                }
+            };
+            due.retain(|path, _| config.watchers.iter().any(|w| &w.local_path == path));
+            let hashes_dir = get_hashes_dir(&dir, &config);
+            for w in config.watchers.iter().filter(|w| w.complete) {
+                let now = Instant::now();
+                let next = *due.entry(w.local_path.clone()).or_insert(now + get_stagger(&w.local_path, interval));
+                if next > now {
+                    continue;
+                }
+                let (source, user) = match (config.sources.get(&w.source), auth.records.get(&w.user_id)) {
+                    (Some(source), Some(user)) if user.is_usable() && !is_quiesced(&w.source) => (source, user),
+                    _ => continue,
+                };
+                due.insert(w.local_path.clone(), now + Duration::from_secs(interval));
+                log::info!("Reconciling watcher {}", &w.local_path);
+                if let Err(e) = rescan_hashes(&hashes_dir, &w.hashes_id, source, &PathBuf::from(&w.local_path)).await {
+                    log::error!("Failed to rescan watcher {}: {}", &w.local_path, e);
+                    continue;
+                }
+                match watch(format!("Reconciliation of watcher {}", &w.local_path), fetch_watcher_files(&hashes_dir, &config, w, source, user)).await {
+                    Ok((_, Err(e))) | Err(e) => log::error!("Failed to reconcile watcher {}: {}", &w.local_path, e),
+                    Ok(_) => {}
+                }
+                let events = take_fetched_changes(&w.source);
+                if !events.is_empty() {
+                    submit_events(app.clone(), &w.source, events).await;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::constants::SELF_WRITE_WINDOW;
+use crate::hash::has_file_hash;
+use crate::helpers::normalize_path;
+
+struct SelfWrite {
+    // empty for removals
+    hash: String,
+    // None while the write is in progress
+    expires: Option<Instant>,
+}
+
+// Files written by the demon produce filesystem events too, they are dropped before reaching the event pipeline
+static SELF_WRITES: std::sync::Mutex<BTreeMap<PathBuf, SelfWrite>> = std::sync::Mutex::new(BTreeMap::new());
+
+pub async fn with_self_writes<F: Future>(paths: &Vec<PathBuf>, hash: &str, write: F) -> F::Output {
+    {
+        let mut writes = SELF_WRITES.lock().unwrap();
+        for path in paths {
+            writes.insert(normalize_path(path), SelfWrite { hash: hash.to_string(), expires: None });
+        }
+    }
+    let res = write.await;
+    {
+        let mut writes = SELF_WRITES.lock().unwrap();
+        let now = Instant::now();
+        writes.retain(|_, w| w.expires.is_none_or(|e| e > now));
+        for path in paths {
+            if let Some(w) = writes.get_mut(&normalize_path(path)) {
+                w.expires = Some(now + Duration::from_secs(SELF_WRITE_WINDOW));
+            }
+        }
+    }
+    res
+}
+
+fn is_active(write: &SelfWrite) -> bool {
+    write.expires.is_none_or(|e| e > Instant::now())
+}
+
+// A path only counts as written by us while its content is still what we wrote, later user edits go through
+pub async fn is_self_write(path: &Path) -> bool {
+    let path = normalize_path(&path.to_path_buf());
+    let hash = {
+        let writes = SELF_WRITES.lock().unwrap();
+        match writes.get(&path) {
+            Some(SelfWrite { expires: None, .. }) => return true,
+            Some(w) if is_active(w) => w.hash.clone(),
+            // Removing a directory reports every file inside of it too
+            _ if !path.exists() => {
+                return path.ancestors().skip(1).any(|p| writes.get(p).is_some_and(|w| w.hash.is_empty() && is_active(w)));
+            }
+            _ => return false,
+        }
+    };
+    if hash.is_empty() {
+        !path.exists()
+    } else {
+        has_file_hash(&path, &hash).await
+    }
+}
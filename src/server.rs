@@ -1,3 +1,13 @@
 pub mod socket;
 pub mod api;
 pub mod types;
+pub mod queue;
+pub mod held;
+pub mod http;
+pub mod scheduler;
+pub mod sequence;
+pub mod session;
+pub mod metrics;
+pub mod storage;
+pub mod webdav;
+pub mod s3;
@@ -1,15 +1,23 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt::Display;
 
 use log4rs::append::Append;
-use reqwest::{Body, Method, multipart, RequestBuilder, Url};
+use reqwest::{Body, Method, multipart, RequestBuilder, Response, StatusCode, Url};
 use serde_json::json;
 use tokio::fs::File;
+use tokio::time::Instant;
 use tokio_util::codec::{BytesCodec, FramedRead};
 
+use crate::bandwidth::{Direction, limit_stream};
 use crate::constants::{DEFAULT_API_URL, ENV_API_URL};
 use crate::event::file_event::{SyncEvent, SyncEventKind};
-use crate::server::types::{ApiAuthResponse, ApiFileResponse, ApiFolderResponse};
+use crate::helpers::generate_random_id;
+use crate::progress::track_progress;
+use crate::server::http::build_http_client;
+use crate::server::metrics::record_request;
+use crate::server::session::{get_auth_header, get_token, refresh_session};
+use crate::server::types::{ApiAuthResponse, ApiCreateFolderRequest, ApiDeviceCodeResponse, ApiFileResponse, ApiFolderResponse, ApiFolderTokenResponse, ApiShareLinkResponse, ApiUserResponse};
 
 #[derive(Clone)]
 pub struct ApiClient {
@@ -24,49 +32,134 @@ impl ApiClient {
     {
         Url::parse(&format!("{}{}", self.base, path)).unwrap()
     }
-    fn get_client<T>(&self, method: Method, path: T) -> RequestBuilder
+    fn get_client<T>(&self, method: Method, path: T, token: &String) -> RequestBuilder
         where
             T: Into<String> + Display,
     {
-        reqwest::Client::new()
+        let (header, value) = get_auth_header(token);
+        build_http_client()
             .request(method, self.build_url(path))
-            .header("Authorization", format!("Bearer {}", &self.auth))
+            .header(header, value)
     }
 
-    pub async fn refresh_token(&self, refresh_token: &String) -> Result<ApiAuthResponse, reqwest::Error> {
-        self.get_client(Method::POST, "/auth/refresh")
-            .json(&json!({"refreshToken": refresh_token}))
-            .send().await?
-            .json::<ApiAuthResponse>().await
+    // Every request goes through here, so it is timed and can be found in the server logs by its id
+    async fn execute(&self, endpoint: &str, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let request_id = generate_random_id();
+        let started = Instant::now();
+        let res = request.header("X-Request-Id", &request_id).send().await;
+        record_request(endpoint, &request_id, started.elapsed(), &res);
+        res
     }
 
-    pub async fn get_folder(&self, folder_id: &String) -> Result<ApiFolderResponse, reqwest::Error> {
-        self.get_client(Method::GET, format!("/sherry/{folder_id}")).send().await?.json::<ApiFolderResponse>().await
+    async fn is_refreshed(&self, res: &Response, token: &String) -> bool {
+        res.status() == StatusCode::UNAUTHORIZED && refresh_session(&self.base, token).await
     }
 
-    pub async fn send_file(&self, event: &SyncEvent) -> Result<reqwest::Response, reqwest::Error> {
+    // A rejected token is refreshed once and the request is replayed with the new one
+    async fn send<F>(&self, endpoint: &str, method: Method, path: String, build: F) -> Result<Response, reqwest::Error>
+        where
+            F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let token = get_token(&self.auth);
+        let res = self.execute(endpoint, build(self.get_client(method.clone(), &path, &token))).await?;
+        if !self.is_refreshed(&res, &token).await {
+            return Ok(res);
+        }
+        self.execute(endpoint, build(self.get_client(method, &path, &get_token(&self.auth)))).await
+    }
+
+    async fn build_event_form(event: &SyncEvent, sequence: u64) -> multipart::Form {
         let mut form = multipart::Form::new();
         if event.kind == SyncEventKind::Created || event.kind == SyncEventKind::Updated {
+            let stream = limit_stream(&event.source_id, Direction::Upload, FramedRead::new(
+                File::open(&event.local_path).await.unwrap(),
+                BytesCodec::new(),
+            ));
             form = form
-                .part("file", multipart::Part::stream(Body::wrap_stream(FramedRead::new(
-                    File::open(&event.local_path).await.unwrap(),
-                    BytesCodec::new(),
-                ))).file_name("file"));
+                .part("file", multipart::Part::stream(Body::wrap_stream(track_progress(&event.source_id, Direction::Upload, &event.sync_path, event.size, stream))).file_name("file"));
         };
 
-        form = form.text("sherryId", event.source_id.to_string())
+        if let Some(attributes) = &event.attributes {
+            form = form.text("attributes", serde_json::to_string(attributes).unwrap());
+        }
+
+        form.text("sherryId", event.source_id.to_string())
             .text("eventType", event.kind.to_string().to_uppercase())
             .text("fileType", event.file_type.to_string().to_uppercase())
             .text("path", event.sync_path.to_string())
             .text("oldPath", event.old_sync_path.to_string())
             .text("size", event.size.to_string())
-            .text("hash", event.update_hash.to_string());
+            .text("hash", event.update_hash.to_string())
+            .text("sequence", sequence.to_string())
+    }
+
+    // Not replayed on 401, the refresh token itself is what was rejected
+    pub async fn refresh_token(&self, refresh_token: &String) -> Result<ApiAuthResponse, reqwest::Error> {
+        let request = self.get_client(Method::POST, "/auth/refresh", &self.auth).json(&json!({"refreshToken": refresh_token}));
+        self.execute("POST /auth/refresh", request).await?.error_for_status()?.json::<ApiAuthResponse>().await
+    }
+
+    // The client has to be created with the key itself, it isn't a known session yet
+    pub async fn get_api_key_user(&self) -> Result<ApiUserResponse, reqwest::Error> {
+        let request = build_http_client().request(Method::GET, self.build_url("/auth/me")).header("X-Api-Key", &self.auth);
+        self.execute("GET /auth/me", request).await?.error_for_status()?.json::<ApiUserResponse>().await
+    }
+
+    pub async fn request_device_code(&self) -> Result<ApiDeviceCodeResponse, reqwest::Error> {
+        self.execute("POST /auth/device", self.get_client(Method::POST, "/auth/device", &self.auth)).await?.json::<ApiDeviceCodeResponse>().await
+    }
+
+    pub async fn poll_device_token(&self, device_code: &String) -> Result<reqwest::Response, reqwest::Error> {
+        let request = self.get_client(Method::POST, "/auth/device/token", &self.auth).json(&json!({"deviceCode": device_code}));
+        self.execute("POST /auth/device/token", request).await
+    }
+
+    pub async fn get_folder(&self, folder_id: &String) -> Result<ApiFolderResponse, reqwest::Error> {
+        self.send("GET /sherry/:id", Method::GET, format!("/sherry/{folder_id}"), |r| r).await?.json::<ApiFolderResponse>().await
+    }
 
-        self.get_client(Method::POST, "/file/event").multipart(form).send().await
+    pub async fn create_folder(&self, folder: &ApiCreateFolderRequest) -> Result<ApiFolderResponse, reqwest::Error> {
+        self.send("POST /sherry", Method::POST, "/sherry".to_string(), |r| r.json(folder)).await?.error_for_status()?.json::<ApiFolderResponse>().await
     }
 
-    pub async fn check_file(&self, event: &SyncEvent) -> Result<reqwest::Response, reqwest::Error> {
-        self.get_client(Method::POST, "/file/verify").json(&json!({
+    pub async fn delete_folder(&self, folder_id: &String) -> Result<reqwest::Response, reqwest::Error> {
+        self.send("DELETE /sherry/:id", Method::DELETE, format!("/sherry/{folder_id}"), |r| r).await?.error_for_status()
+    }
+
+    // Token that only grants access to the folder, 404 when the server can't mint them
+    pub async fn create_folder_token(&self, folder_id: &String) -> Result<ApiFolderTokenResponse, reqwest::Error> {
+        self.send("POST /sherry/:id/token", Method::POST, format!("/sherry/{folder_id}/token"), |r| r).await?.error_for_status()?.json::<ApiFolderTokenResponse>().await
+    }
+
+    pub async fn set_folder_archived(&self, folder_id: &String, archived: bool) -> Result<ApiFolderResponse, reqwest::Error> {
+        let body = json!({"archived": archived});
+        self.send("PATCH /sherry/:id", Method::PATCH, format!("/sherry/{folder_id}"), |r| r.json(&body)).await?.error_for_status()?.json::<ApiFolderResponse>().await
+    }
+
+    // The upload stream can't be cloned, so the form is built again for the replay
+    pub async fn send_file(&self, event: &SyncEvent, sequence: u64) -> Result<reqwest::Response, reqwest::Error> {
+        let token = get_token(&self.auth);
+        let request = self.get_client(Method::POST, "/file/event", &token).multipart(Self::build_event_form(event, sequence).await);
+        let res = self.execute("POST /file/event", request).await?;
+        if !self.is_refreshed(&res, &token).await {
+            return Ok(res);
+        }
+        let request = self.get_client(Method::POST, "/file/event", &get_token(&self.auth)).multipart(Self::build_event_form(event, sequence).await);
+        self.execute("POST /file/event", request).await
+    }
+
+    // Removes the directory and everything in it with one request
+    pub async fn delete_dir(&self, event: &SyncEvent, sequence: u64) -> Result<reqwest::Response, reqwest::Error> {
+        let body = json!({
+            "sherryId": event.source_id,
+            "path": event.sync_path.to_string(),
+            "sequence": sequence,
+        });
+        self.send("POST /file/dir/delete", Method::POST, "/file/dir/delete".to_string(), |r| r.json(&body)).await
+    }
+
+    pub async fn check_file(&self, event: &SyncEvent, sequence: u64) -> Result<reqwest::Response, reqwest::Error> {
+        let body = json!({
             "sherryId": event.source_id,
             "eventType": event.kind.to_string().to_uppercase(),
             "fileType": event.file_type.to_string().to_uppercase(),
@@ -74,15 +167,42 @@ impl ApiClient {
             "oldPath": event.old_sync_path.to_string(),
             "size": event.size,
             "hash": event.update_hash.to_string(),
-        })).send().await
+            "sequence": sequence,
+        });
+        self.send("POST /file/verify", Method::POST, "/file/verify".to_string(), |r| r.json(&body)).await
+    }
+
+    pub async fn get_folder_files(&self, sherry_id: &str) -> Result<Vec<ApiFileResponse>, reqwest::Error> {
+        self.send("GET /file/:id", Method::GET, format!("/file/{sherry_id}"), |r| r).await?.json().await
+    }
+
+    pub async fn get_file(&self, sherry_id: &str, path: &str) -> Result<reqwest::Response, reqwest::Error> {
+        self.send("GET /file/instance/:id", Method::GET, format!("/file/instance/{sherry_id}?path={path}"), |r| r).await
+    }
+
+    // Photos and videos the server can't render `variant` of come back as the original, see `VARIANT_HEADER`
+    pub async fn get_file_variant(&self, sherry_id: &str, path: &str, variant: &str) -> Result<reqwest::Response, reqwest::Error> {
+        self.send("GET /file/instance/:id", Method::GET, format!("/file/instance/{sherry_id}?path={path}&variant={variant}"), |r| r).await
+    }
+
+    // The content the file had when its hash was `hash`, 404 once the server dropped that version
+    pub async fn get_file_version(&self, sherry_id: &str, path: &str, hash: &str) -> Result<reqwest::Response, reqwest::Error> {
+        self.send("GET /file/instance/:id", Method::GET, format!("/file/instance/{sherry_id}?path={path}&hash={hash}"), |r| r).await
     }
 
-    pub async fn get_folder_files(&self, sherry_id: &String) -> Result<Vec<ApiFileResponse>, reqwest::Error> {
-        self.get_client(Method::GET, format!("/file/{sherry_id}")).send().await?.json().await
+    // Public download link of the file that stops working after `expires_in` seconds, 404 when the server can't create them
+    pub async fn create_share_link(&self, sherry_id: &String, path: &String, expires_in: u64) -> Result<ApiShareLinkResponse, reqwest::Error> {
+        let body = json!({
+            "sherryId": sherry_id,
+            "path": path,
+            "expiresIn": expires_in,
+        });
+        self.send("POST /file/share", Method::POST, "/file/share".to_string(), |r| r.json(&body)).await?.error_for_status()?.json::<ApiShareLinkResponse>().await
     }
 
-    pub async fn get_file(&self, sherry_id: &String, path: &String) -> Result<reqwest::Response, reqwest::Error> {
-        self.get_client(Method::GET, format!("/file/instance/{sherry_id}?path={path}")).send().await
+    // feature name -> enabled for the account, lets risky features be rolled out and back without a release
+    pub async fn get_features(&self) -> Result<BTreeMap<String, bool>, reqwest::Error> {
+        self.send("GET /features", Method::GET, "/features".to_string(), |r| r).await?.error_for_status()?.json().await
     }
 
     pub fn new(base: &String, auth: &String) -> Self {
@@ -0,0 +1,54 @@
+use std::collections::BTreeSet;
+
+use crate::config::SherryConfigJSON;
+use crate::constants::HELD_EVENTS_MAX;
+
+type Release = Box<dyn FnOnce() + Send>;
+
+struct HeldEvents {
+    // folder ids with a watcher that is still fetching its files
+    incomplete: BTreeSet<String>,
+    // (folder id, pushes the event to the path queue), in the order they were received
+    events: Vec<(String, Release)>,
+}
+
+// Remote file events of a folder that is being fetched would race with the fetch and could be applied twice,
+// they wait until every watcher of the folder is complete
+static HELD: std::sync::Mutex<HeldEvents> = std::sync::Mutex::new(HeldEvents {
+    incomplete: BTreeSet::new(),
+    events: Vec::new(),
+});
+
+// `all` for the first update, which fetches every watcher
+pub fn set_incomplete_folders(config: &SherryConfigJSON, all: bool) {
+    let incomplete = config.watchers.iter()
+        .filter(|w| all || !w.complete)
+        .filter_map(|w| config.sources.get(&w.source).map(|s| s.id.clone()))
+        .collect::<BTreeSet<String>>();
+
+    let mut held = HELD.lock().unwrap();
+    let (released, kept) = std::mem::take(&mut held.events).into_iter()
+        .partition::<Vec<(String, Release)>, _>(|(folder_id, _)| !incomplete.contains(folder_id));
+    held.incomplete = incomplete;
+    held.events = kept;
+    if !released.is_empty() {
+        log::info!("Releasing {} remote events held during fetches", released.len());
+    }
+    // Still under the lock, so newer events can't overtake them
+    for (_, release) in released {
+        release();
+    }
+}
+
+pub fn hold_or_release(folder_id: &String, release: Release) {
+    let mut held = HELD.lock().unwrap();
+    if !held.incomplete.contains(folder_id) {
+        release();
+        return;
+    }
+    if held.events.len() >= HELD_EVENTS_MAX {
+        log::warn!("Too many remote events held, dropping the oldest, the next full fetch of its folder catches up on it");
+        drop(held.events.remove(0));
+    }
+    held.events.push((folder_id.clone(), release));
+}
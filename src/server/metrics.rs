@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use reqwest::{Error, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::constants::SLOW_REQUEST_THRESHOLD;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiEndpointStats {
+    pub requests: u64,
+    // transport errors and responses with an error status
+    pub errors: u64,
+    pub slow: u64,
+    // time until the response headers arrive, body downloads are not included
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+// "METHOD /path/:param" -> stats
+static API_STATS: std::sync::Mutex<BTreeMap<String, ApiEndpointStats>> = std::sync::Mutex::new(BTreeMap::new());
+
+pub fn record_request(endpoint: &str, request_id: &String, elapsed: Duration, res: &Result<Response, Error>) {
+    let ms = elapsed.as_millis() as u64;
+    let is_slow = elapsed >= Duration::from_secs(SLOW_REQUEST_THRESHOLD);
+    let error = match res {
+        Ok(res) if res.status().is_client_error() || res.status().is_server_error() => Some(res.status().to_string()),
+        Ok(_) => None,
+        Err(e) => Some(e.to_string()),
+    };
+    if is_slow {
+        log::warn!("Slow API request {} {}: {}ms", request_id, endpoint, ms);
+    }
+    if let Some(error) = &error {
+        log::warn!("API request {} {} failed after {}ms: {}", request_id, endpoint, ms, error);
+    }
+
+    let mut stats = API_STATS.lock().unwrap();
+    let entry = stats.entry(endpoint.to_string()).or_default();
+    entry.requests += 1;
+    entry.errors += error.is_some() as u64;
+    entry.slow += is_slow as u64;
+    entry.total_ms += ms;
+    entry.max_ms = entry.max_ms.max(ms);
+}
+
+pub fn get_api_stats() -> BTreeMap<String, ApiEndpointStats> {
+    API_STATS.lock().unwrap().clone()
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+type Job = BoxFuture<'static, ()>;
+
+// Runs jobs with the same key one after another in the order they were pushed,
+// jobs with different keys run concurrently
+#[derive(Clone, Default)]
+pub struct PathQueue {
+    queues: Arc<std::sync::Mutex<HashMap<String, mpsc::UnboundedSender<Job>>>>,
+}
+
+impl PathQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, key: String, job: Job) {
+        let mut queues = self.queues.lock().unwrap();
+        let job = match queues.get(&key) {
+            Some(tx) => match tx.send(job) {
+                Ok(_) => return,
+                Err(e) => e.0,
+            },
+            None => job,
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+        tx.send(job).ok();
+        queues.insert(key.clone(), tx);
+
+        let queues = Arc::clone(&self.queues);
+        tokio::spawn(async move {
+            loop {
+                if let Ok(job) = rx.try_recv() {
+                    job.await;
+                    continue;
+                }
+                // Jobs are only sent under the map lock, so checking again under it can't miss one
+                let job = {
+                    let mut queues = queues.lock().unwrap();
+                    match rx.try_recv() {
+                        Ok(job) => Some(job),
+                        Err(_) => {
+                            queues.remove(&key);
+                            None
+                        }
+                    }
+                };
+                match job {
+                    Some(job) => job.await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.lock().unwrap().is_empty()
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::time::Instant;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::bandwidth::{Direction, limit_stream};
+use crate::constants::{DEFAULT_S3_REGION, S3_HASH_TAG, S3_TAG_CONCURRENCY};
+use crate::event::file_event::{FileType, SyncEvent};
+use crate::helpers::{canonicalize_sync_path, generate_random_id, get_xml_element, PATH_SEP, str_err_prefix};
+use crate::progress::track_progress;
+use crate::server::http::build_http_client;
+use crate::server::metrics::record_request;
+use crate::server::storage::{ByteStream, FOREIGN_HASH_PREFIX, RemoteStorage, StorageCheck};
+use crate::server::types::ApiFileResponse;
+
+// A prefix of an S3-compatible bucket (AWS, MinIO, ...), addressed path-style. Objects are keyed by their sync path,
+// the content hash is kept in an object tag.
+pub struct S3Storage {
+    // scheme and host, empty when the URL is invalid
+    endpoint: String,
+    // with the port if it isn't the default one
+    host: String,
+    bucket: String,
+    // without a trailing slash, empty for the whole bucket
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+struct S3Object {
+    key: String,
+    etag: String,
+    size: u64,
+    modified: i128,
+}
+
+// object key -> (etag, hash), tags are only fetched again once the object changed
+static HASHES: std::sync::Mutex<Option<HashMap<String, (String, String)>>> = std::sync::Mutex::new(None);
+
+fn get_cached_hash(key: &String, etag: &String) -> Option<String> {
+    HASHES.lock().unwrap().as_ref()?.get(key).filter(|(e, _)| e == etag).map(|(_, h)| h.clone())
+}
+
+fn set_cached_hash(key: &str, etag: &str, hash: &str) {
+    HASHES.lock().unwrap().get_or_insert_with(HashMap::new).insert(key.to_string(), (etag.to_string(), hash.to_string()));
+}
+
+// RFC 3986 as SigV4 wants it, `/` is kept in paths
+fn encode(text: &str, keep_slash: bool) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if keep_slash => "/".to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn parse_objects(body: &str) -> Vec<S3Object> {
+    Regex::new(r"(?s)<Contents>(.*?)</Contents>").unwrap()
+        .captures_iter(body)
+        .filter_map(|c| {
+            let object = &c[1];
+            Some(S3Object {
+                key: get_xml_element(object, "Key")?,
+                etag: get_xml_element(object, "ETag").unwrap_or_default().trim_matches('"').to_string(),
+                size: get_xml_element(object, "Size").and_then(|s| s.parse().ok()).unwrap_or(0),
+                modified: get_xml_element(object, "LastModified")
+                    .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
+                    .map_or(0, |d| d.timestamp_millis() as i128),
+            })
+        })
+        .collect()
+}
+
+fn parse_hash_tag(body: &str) -> Option<String> {
+    Regex::new(r"(?s)<Tag>(.*?)</Tag>").unwrap()
+        .captures_iter(body)
+        .find(|c| get_xml_element(&c[1], "Key").is_some_and(|k| k == S3_HASH_TAG))
+        .and_then(|c| get_xml_element(&c[1], "Value"))
+        .filter(|h| !h.is_empty())
+}
+
+async fn expect_success(res: Response, also: &[StatusCode]) -> Result<Response, String> {
+    if res.status().is_success() || also.contains(&res.status()) {
+        return Ok(res);
+    }
+    Err(format!("S3 request failed with {}: {}", res.status(), res.text().await.unwrap_or_default()))
+}
+
+impl S3Storage {
+    // `url` is the endpoint followed by the bucket and an optional prefix, e.g. https://minio.example.com/sync/laptop.
+    // An invalid URL fails every request.
+    pub fn new(url: &str, region: &Option<String>, access_key: &str, secret_key: &str) -> Self {
+        let parsed = reqwest::Url::parse(url).ok().filter(|u| u.host_str().is_some());
+        let path = parsed.as_ref().map(|u| canonicalize_sync_path(u.path())).unwrap_or_default();
+        let (bucket, prefix) = path.split_once(PATH_SEP).unwrap_or((&path, ""));
+        S3Storage {
+            endpoint: parsed.as_ref().map(|u| u.origin().ascii_serialization()).unwrap_or_default(),
+            host: parsed.as_ref().map(|u| match u.port() {
+                Some(port) => format!("{}:{}", u.host_str().unwrap(), port),
+                None => u.host_str().unwrap().to_string(),
+            }).unwrap_or_default(),
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            region: region.clone().unwrap_or(DEFAULT_S3_REGION.to_string()),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        }
+    }
+
+    fn get_key(&self, sync_path: &str) -> String {
+        let sync_path = canonicalize_sync_path(sync_path);
+        if self.prefix.is_empty() {
+            sync_path
+        } else {
+            format!("{}{}{}", &self.prefix, PATH_SEP, sync_path)
+        }
+    }
+
+    fn get_object_path(&self, key: &str) -> String {
+        format!("/{}/{}", encode(&self.bucket, false), encode(key, true))
+    }
+
+    // Signature V4 with an unsigned payload, so uploads can be streamed. `query` is sorted and encoded already.
+    fn sign(&self, method: &Method, path: &str, query: &str, headers: &[(String, String)], date: &chrono::DateTime<chrono::Utc>) -> String {
+        let day = date.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", day, &self.region);
+        let mut headers = headers.iter().map(|(k, v)| (k.to_lowercase(), v.trim().to_string())).collect::<Vec<(String, String)>>();
+        headers.sort();
+        let signed_headers = headers.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\nUNSIGNED-PAYLOAD",
+            method,
+            path,
+            query,
+            headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect::<String>(),
+            &signed_headers,
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date.format("%Y%m%dT%H%M%SZ"),
+            &scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let key = ["s3", "aws4_request"].iter().fold(
+            hmac(&hmac(format!("AWS4{}", &self.secret_key).as_bytes(), &day), &self.region),
+            |key, part| hmac(&key, part),
+        );
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            &self.access_key, &scope, &signed_headers, hex::encode(hmac(&key, &string_to_sign)),
+        )
+    }
+
+    // `query` as (name, value) pairs, `headers` are the x-amz ones to sign next to the defaults
+    async fn execute<F>(&self, method: Method, key: Option<&str>, query: &[(&str, &str)], headers: &[(&str, String)], build: F) -> Result<Response, String>
+        where
+            F: FnOnce(RequestBuilder) -> RequestBuilder,
+    {
+        if self.endpoint.is_empty() || self.bucket.is_empty() {
+            return Err("Invalid S3 URL, expected the endpoint followed by the bucket".to_string());
+        }
+        let path = match key {
+            Some(key) => self.get_object_path(key),
+            None => format!("/{}", encode(&self.bucket, false)),
+        };
+        let mut query = query.iter().map(|(k, v)| format!("{}={}", encode(k, false), encode(v, false))).collect::<Vec<String>>();
+        query.sort();
+        let query = query.join("&");
+
+        let date = chrono::Utc::now();
+        let mut signed = vec![
+            ("host".to_string(), self.host.clone()),
+            ("x-amz-content-sha256".to_string(), "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date".to_string(), date.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        signed.extend(headers.iter().map(|(k, v)| (k.to_string(), v.clone())));
+        let authorization = self.sign(&method, &path, &query, &signed, &date);
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", &self.endpoint, &path),
+            false => format!("{}{}?{}", &self.endpoint, &path, &query),
+        };
+        let endpoint = format!("S3 {}", method);
+        let request_id = generate_random_id();
+        let started = Instant::now();
+        let mut request = build_http_client().request(method, url).header("Authorization", authorization);
+        for (k, v) in signed.into_iter().filter(|(k, _)| k != "host") {
+            request = request.header(k, v);
+        }
+        let res = build(request).send().await;
+        record_request(&endpoint, &request_id, started.elapsed(), &res);
+        res.map_err(|e| e.to_string())
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<S3Object>, String> {
+        let mut objects = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let res = self.execute(Method::GET, None, &query, &[], |r| r).await?;
+            let body = expect_success(res, &[]).await?.text().await.map_err(str_err_prefix("Error S3 Listing"))?;
+            objects.extend(parse_objects(&body));
+            token = match get_xml_element(&body, "IsTruncated").as_deref() {
+                Some("true") => get_xml_element(&body, "NextContinuationToken"),
+                _ => None,
+            };
+            if token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    // Objects written by other clients have no tag, they are told apart by their ETag only
+    async fn get_hash(&self, object: &S3Object) -> Result<String, String> {
+        if let Some(hash) = get_cached_hash(&object.key, &object.etag) {
+            return Ok(hash);
+        }
+        let res = self.execute(Method::GET, Some(&object.key), &[("tagging", "")], &[], |r| r).await?;
+        let body = expect_success(res, &[]).await?.text().await.map_err(str_err_prefix("Error S3 Tagging"))?;
+        let hash = parse_hash_tag(&body).unwrap_or(format!("{}{}", FOREIGN_HASH_PREFIX, &object.etag));
+        set_cached_hash(&object.key, &object.etag, &hash);
+        Ok(hash)
+    }
+
+    async fn list_files(&self, folder_id: &str) -> Result<Vec<ApiFileResponse>, String> {
+        let prefix = match self.prefix.is_empty() {
+            true => "".to_string(),
+            false => format!("{}{}", &self.prefix, PATH_SEP),
+        };
+        // Keys ending with a slash are the directory markers some consoles create
+        let objects = self.list_objects(&prefix).await?.into_iter()
+            .filter(|o| !o.key.ends_with(PATH_SEP))
+            .collect::<Vec<S3Object>>();
+        let hashes = futures::stream::iter(objects.iter().map(|o| self.get_hash(o).boxed()).collect::<Vec<_>>())
+            .buffered(S3_TAG_CONCURRENCY)
+            .collect::<Vec<Result<String, String>>>().await;
+
+        objects.iter().zip(hashes).map(|(object, hash)| {
+            let sync_path = canonicalize_sync_path(&object.key[prefix.len()..]);
+            Ok(ApiFileResponse {
+                sherry_file_id: object.etag.clone(),
+                sherry_id: folder_id.to_string(),
+                path: sync_path.clone(),
+                old_path: sync_path,
+                hash: hash?,
+                size: object.size,
+                created_at: object.modified,
+                updated_at: object.modified,
+                file_type: FileType::File,
+                attributes: None,
+            })
+        }).collect()
+    }
+
+    async fn put_file(&self, event: &SyncEvent) -> Result<(), String> {
+        // S3 has no directories, they appear with their first file
+        if event.file_type == FileType::Dir {
+            return Ok(());
+        }
+        let file = File::open(&event.local_path).await.map_err(str_err_prefix("Error File Open"))?;
+        let size = file.metadata().await.map_err(str_err_prefix("Error File Metadata"))?.len();
+        let stream = limit_stream(&event.source_id, Direction::Upload, FramedRead::new(file, BytesCodec::new()));
+        let body = Body::wrap_stream(track_progress(&event.source_id, Direction::Upload, &event.sync_path, size, stream));
+        let tagging = format!("{}={}", S3_HASH_TAG, encode(&event.update_hash, false));
+        let key = self.get_key(&event.sync_path);
+        let res = self.execute(Method::PUT, Some(&key), &[], &[("x-amz-tagging", tagging)], |r| {
+            r.header("Content-Length", size).body(body)
+        }).await?;
+        let res = expect_success(res, &[]).await?;
+        if let Some(etag) = res.headers().get("ETag").and_then(|e| e.to_str().ok()) {
+            set_cached_hash(&key, etag.trim_matches('"'), &event.update_hash);
+        }
+        Ok(())
+    }
+
+    // Every object under the key of a directory
+    async fn get_keys(&self, event_path: &str, file_type: &FileType) -> Result<Vec<String>, String> {
+        let key = self.get_key(event_path);
+        if *file_type != FileType::Dir {
+            return Ok(vec![key]);
+        }
+        Ok(self.list_objects(&format!("{}{}", key, PATH_SEP)).await?.into_iter().map(|o| o.key).collect())
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), String> {
+        let res = self.execute(Method::DELETE, Some(key), &[], &[], |r| r).await?;
+        expect_success(res, &[StatusCode::NOT_FOUND]).await.map(|_| ())
+    }
+
+    async fn delete_path(&self, event: &SyncEvent) -> Result<(), String> {
+        for key in self.get_keys(&event.sync_path, &event.file_type).await? {
+            self.delete_object(&key).await?;
+        }
+        Ok(())
+    }
+
+    // Copies keep their tags, so the hash comes along
+    async fn move_file(&self, event: &SyncEvent) -> Result<(), String> {
+        let old_key = self.get_key(&event.old_sync_path);
+        let new_key = self.get_key(&event.sync_path);
+        for key in self.get_keys(&event.old_sync_path, &event.file_type).await? {
+            let destination = format!("{}{}", &new_key, &key[old_key.len()..]);
+            let res = self.execute(Method::PUT, Some(&destination), &[], &[("x-amz-copy-source", self.get_object_path(&key))], |r| r).await?;
+            expect_success(res, &[]).await?;
+            self.delete_object(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+impl RemoteStorage for S3Storage {
+    fn list<'a>(&'a self, folder_id: &'a str) -> BoxFuture<'a, Result<Vec<ApiFileResponse>, String>> {
+        self.list_files(folder_id).boxed()
+    }
+
+    fn get<'a>(&'a self, _folder_id: &'a str, path: &'a str) -> BoxFuture<'a, Result<ByteStream, String>> {
+        async move {
+            let res = self.execute(Method::GET, Some(&self.get_key(path)), &[], &[], |r| r).await?;
+            let res = expect_success(res, &[]).await?;
+            Ok(res.bytes_stream().map(|chunk| chunk.map_err(|e| e.to_string())).boxed())
+        }.boxed()
+    }
+
+    // Buckets have no upload policy to ask
+    fn check<'a>(&'a self, _event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<StorageCheck, String>> {
+        async move { Ok(StorageCheck::Accepted) }.boxed()
+    }
+
+    fn put<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.put_file(event).boxed()
+    }
+
+    fn delete<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.delete_path(event).boxed()
+    }
+
+    fn move_path<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.move_file(event).boxed()
+    }
+}
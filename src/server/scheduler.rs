@@ -0,0 +1,175 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::config::SherryConfigJSON;
+use crate::constants::{TRANSFER_CONCURRENCY, TRANSFER_CONCURRENCY_MAX, TRANSFER_CONCURRENCY_MIN, TRANSFER_THROUGHPUT_DROP};
+use crate::governor::yield_to_load;
+
+// Shared by uploads, socket events and watcher fetches, so a burst of changes can't open unlimited connections and files.
+// The limit follows the observed throughput: one more slot while it improves, half of them on errors or when it collapses.
+static LIMIT: AtomicUsize = AtomicUsize::new(TRANSFER_CONCURRENCY);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// Free slots go to the first waiter: higher source priority, then deletes, then smaller files, then the oldest.
+// A multi-GB upload can't hold up dozens of small document changes behind it.
+type QueueKey = (Reverse<i32>, bool, u64, u64);
+
+static QUEUE: std::sync::Mutex<BTreeMap<QueueKey, oneshot::Sender<()>>> = std::sync::Mutex::new(BTreeMap::new());
+static NEXT_WAITER: AtomicU64 = AtomicU64::new(0);
+// folder id -> priority, higher first
+static PRIORITIES: std::sync::Mutex<Option<HashMap<String, i32>>> = std::sync::Mutex::new(None);
+
+// Transfers completed since the limit was last adjusted
+struct TransferWindow {
+    started: Option<Instant>,
+    completed: usize,
+    errors: usize,
+    bytes: u64,
+    // bytes per second of the previous window
+    throughput: f64,
+}
+
+static WINDOW: std::sync::Mutex<TransferWindow> = std::sync::Mutex::new(TransferWindow {
+    started: None,
+    completed: 0,
+    errors: 0,
+    bytes: 0,
+    throughput: 0.0,
+});
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferStats {
+    pub active: usize,
+    pub queued: usize,
+    pub limit: usize,
+}
+
+// Several users may sync the same folder, the highest priority wins
+pub fn set_transfer_priorities(config: &SherryConfigJSON) {
+    let mut priorities: HashMap<String, i32> = HashMap::new();
+    for source in config.sources.values() {
+        let priority = priorities.entry(source.id.clone()).or_insert(i32::MIN);
+        *priority = (*priority).max(source.priority.unwrap_or(0));
+    }
+    *PRIORITIES.lock().unwrap() = Some(priorities);
+}
+
+fn get_priority(folder_id: &str) -> i32 {
+    PRIORITIES.lock().unwrap().as_ref().and_then(|p| p.get(folder_id).copied()).unwrap_or(0)
+}
+
+// Hands free slots to the first waiters, waiters that went away are skipped
+fn grant_slots() {
+    let mut queue = QUEUE.lock().unwrap();
+    while ACTIVE.load(Ordering::SeqCst) < LIMIT.load(Ordering::SeqCst) {
+        let (_, waiter) = match queue.pop_first() {
+            Some(entry) => entry,
+            None => return,
+        };
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        if waiter.send(()).is_err() {
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Frees the slot even when the transfer is dropped halfway
+struct Slot;
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        grant_slots();
+    }
+}
+
+// Leaves the queue when the transfer is dropped while waiting, a slot granted in the meantime is given back
+struct Waiter {
+    key: QueueKey,
+    granted: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        QUEUE.lock().unwrap().remove(&self.key);
+        if self.granted.try_recv().is_ok() {
+            drop(Slot);
+        }
+    }
+}
+
+async fn acquire_slot(key: QueueKey) -> Slot {
+    let (tx, rx) = oneshot::channel();
+    let mut waiter = Waiter { key, granted: rx };
+    QUEUE.lock().unwrap().insert(key, tx);
+    grant_slots();
+    // The sender only leaves the queue by granting the slot, once received the waiter has nothing to give back
+    (&mut waiter.granted).await.ok();
+    Slot
+}
+
+// A window spans one round of transfers at the current limit
+fn record_transfer(bytes: u64, is_ok: bool) {
+    let mut window = WINDOW.lock().unwrap();
+    let started = *window.started.get_or_insert_with(Instant::now);
+    window.completed += 1;
+    window.errors += !is_ok as usize;
+    window.bytes += bytes;
+
+    let limit = LIMIT.load(Ordering::SeqCst);
+    if window.completed < limit {
+        return;
+    }
+    let throughput = window.bytes as f64 / started.elapsed().as_secs_f64().max(0.001);
+    let new_limit = if window.errors > 0 || throughput < window.throughput * TRANSFER_THROUGHPUT_DROP {
+        (limit / 2).max(TRANSFER_CONCURRENCY_MIN)
+    } else if throughput >= window.throughput {
+        (limit + 1).min(TRANSFER_CONCURRENCY_MAX)
+    } else {
+        limit
+    };
+    *window = TransferWindow {
+        started: Some(Instant::now()),
+        completed: 0,
+        errors: 0,
+        bytes: 0,
+        throughput,
+    };
+
+    if new_limit != limit {
+        log::info!("Transfer concurrency {} -> {} at {:.0} KB/s", limit, new_limit, throughput / 1024.0);
+        LIMIT.store(new_limit, Ordering::SeqCst);
+        grant_slots();
+    }
+}
+
+// `size` is the expected number of bytes, errors make the scheduler back off
+pub async fn schedule_transfer<F, T, E>(folder_id: &str, size: u64, is_delete: bool, transfer: F) -> Result<T, E>
+    where
+        F: Future<Output=Result<T, E>>,
+{
+    // Before queueing, so waiting for the load doesn't hold a slot other folders could use once it is over
+    yield_to_load().await;
+    let slot = acquire_slot((Reverse(get_priority(folder_id)), !is_delete, size, NEXT_WAITER.fetch_add(1, Ordering::SeqCst))).await;
+
+    let res = transfer.await;
+    record_transfer(size, res.is_ok());
+
+    drop(slot);
+    res
+}
+
+pub fn get_transfer_stats() -> TransferStats {
+    TransferStats {
+        active: ACTIVE.load(Ordering::SeqCst),
+        queued: QUEUE.lock().unwrap().len(),
+        limit: LIMIT.load(Ordering::SeqCst),
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::event::file_event::SyncEvent;
+use crate::helpers::get_now_as_millis;
+
+// source id:sync path -> last sequence number
+static SEQUENCES: std::sync::Mutex<BTreeMap<String, u64>> = std::sync::Mutex::new(BTreeMap::new());
+// source id:sync path -> lock held while an event for the path is in flight
+static IN_FLIGHT: std::sync::Mutex<BTreeMap<String, Arc<Mutex<()>>>> = std::sync::Mutex::new(BTreeMap::new());
+
+pub struct InFlightEvent {
+    _guards: Vec<OwnedMutexGuard<()>>,
+    // sent with every attempt of the event, so the server can order events and drop replayed attempts
+    pub sequence: u64,
+}
+
+fn get_event_keys(e: &SyncEvent) -> Vec<String> {
+    let mut keys = vec![format!("{}:{}", &e.source_id, &e.sync_path), format!("{}:{}", &e.source_id, &e.old_sync_path)];
+    // Sorted, so two moves between the same paths can't deadlock
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+// Seeded from the clock, so numbers keep growing across restarts
+fn next_sequence(key: &String) -> u64 {
+    let mut sequences = SEQUENCES.lock().unwrap();
+    let next = sequences.get(key).map_or(0, |s| s + 1).max(get_now_as_millis() as u64);
+    sequences.insert(key.clone(), next);
+    next
+}
+
+// Waits until no other event for the same paths is in flight
+pub async fn begin_event(e: &SyncEvent) -> InFlightEvent {
+    let keys = get_event_keys(e);
+    let locks = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        in_flight.retain(|_, lock| Arc::strong_count(lock) > 1);
+        keys.iter().map(|k| Arc::clone(in_flight.entry(k.clone()).or_default())).collect::<Vec<_>>()
+    };
+    let mut guards = vec![];
+    for lock in locks {
+        guards.push(lock.lock_owned().await);
+    }
+    InFlightEvent {
+        _guards: guards,
+        sequence: next_sequence(&format!("{}:{}", &e.source_id, &e.sync_path)),
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+
+use crate::auth::{Credentials, is_refresh_rejected, response_to_user, SherryAuthorizationConfigJSON};
+use crate::server::api::ApiClient;
+
+// access token -> credentials it belongs to
+static SESSIONS: std::sync::Mutex<BTreeMap<String, Credentials>> = std::sync::Mutex::new(BTreeMap::new());
+// access token -> token it was replaced with, so clients created with an old token keep working
+static REPLACED: std::sync::Mutex<BTreeMap<String, String>> = std::sync::Mutex::new(BTreeMap::new());
+// Held for the whole refresh, so a burst of 401s results in a single refresh
+static REFRESH: Mutex<()> = Mutex::const_new(());
+static REFRESHED: std::sync::Mutex<Option<UnboundedSender<Credentials>>> = std::sync::Mutex::new(None);
+
+pub fn set_sessions(auth: &SherryAuthorizationConfigJSON) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let mut replaced = REPLACED.lock().unwrap();
+    for (token, user) in sessions.iter() {
+        if let Some(new_user) = auth.records.get(&user.user_id) {
+            if &new_user.access_token != token {
+                replaced.insert(token.clone(), new_user.access_token.clone());
+            }
+        }
+    }
+    *sessions = auth.records.values().map(|u| (u.access_token.clone(), u.clone())).collect();
+}
+
+pub fn get_token(token: &String) -> String {
+    let replaced = REPLACED.lock().unwrap();
+    let mut token = token;
+    while let Some(next) = replaced.get(token) {
+        token = next;
+    }
+    token.clone()
+}
+
+// Refreshed credentials, and users whose refresh token was rejected, are sent here to be stored in auth.json
+pub fn subscribe_refreshed() -> UnboundedReceiver<Credentials> {
+    let (sender, receiver) = unbounded_channel();
+    *REFRESHED.lock().unwrap() = Some(sender);
+    receiver
+}
+
+// Tokens of unknown users are sent as bearer tokens
+pub fn get_auth_header(token: &String) -> (&'static str, String) {
+    match SESSIONS.lock().unwrap().get(token) {
+        Some(user) => user.get_auth_header(),
+        None => ("Authorization", format!("Bearer {}", token)),
+    }
+}
+
+// true when the request rejected with `token` can be replayed with a new token
+pub async fn refresh_session(api_url: &String, token: &String) -> bool {
+    let _refresh = REFRESH.lock().await;
+    if &get_token(token) != token {
+        return true;
+    }
+    // API keys and WebDAV logins can't be refreshed
+    let user = match SESSIONS.lock().unwrap().get(token) {
+        Some(user) if user.is_refreshable() => user.clone(),
+        _ => return false,
+    };
+
+    log::info!("Token of {} was rejected, refreshing", user.username);
+    let user = match ApiClient::new(api_url, token).refresh_token(&user.refresh_token).await {
+        Ok(res) => Credentials { folder_tokens: user.folder_tokens.clone(), ..response_to_user(res) },
+        Err(e) => {
+            log::error!("Failed to refresh token for {}: {}", user.username, e);
+            if is_refresh_rejected(&e) {
+                if let Some(sender) = REFRESHED.lock().unwrap().as_ref() {
+                    sender.send(Credentials { expired: true, ..user }).ok();
+                }
+            }
+            return false;
+        }
+    };
+    REPLACED.lock().unwrap().insert(token.clone(), user.access_token.clone());
+    SESSIONS.lock().unwrap().insert(user.access_token.clone(), user.clone());
+    if let Some(sender) = REFRESHED.lock().unwrap().as_ref() {
+        sender.send(user).ok();
+    }
+    true
+}
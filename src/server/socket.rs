@@ -1,19 +1,29 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use rust_socketio::{Error, Payload};
+use rust_socketio::{Error, Payload, TransportType};
 use rust_socketio::asynchronous::{Client, ClientBuilder, ReconnectSettings};
 use tokio::sync::Mutex;
 
-use crate::auth::SherryAuthorizationConfigJSON;
-use crate::config::{SherryConfig, SherryConfigJSON, SherryConfigSourceJSON, SherryConfigWatcherJSON};
-use crate::files::{delete_path, rename_path, write_files_from_stream};
-use crate::hash::{FileHashJSON, get_hashes, update_hashes};
-use crate::helpers::normalize_path;
-use crate::server::api::ApiClient;
+use crate::auth::{Credentials, SherryAuthorizationConfigJSON};
+use crate::available::{add_available_path, remove_available_path};
+use crate::bandwidth::{Direction, limit_download};
+use crate::config::{get_hashes_dir, SherryConfig, SherryConfigJSON, SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::drift::{DriftSide, record_drift};
+use crate::files::{apply_file_attributes, delete_path, rename_path, set_file_created, write_files_from_stream};
+use crate::hash::{FileHashJSON, get_hashes, get_modified_millis, has_file_hash, update_hashes};
+use crate::helpers::{canonicalize_sync_path, check_sync_path, normalize_path, sync_path_to_local};
+use crate::integrity::{download_file, download_variant, is_verify_all, verify_download};
+use crate::progress::track_progress;
+use crate::self_writes::with_self_writes;
+use crate::server::http::{build_tls_connector, is_proxied, set_proxy, set_tls};
+use crate::server::held::hold_or_release;
+use crate::server::queue::PathQueue;
+use crate::server::scheduler::schedule_transfer;
+use crate::server::storage::{get_storage, RemoteStorage};
 use crate::server::types::ApiFileResponse;
 
 type Context = Arc<Mutex<SocketClient>>;
@@ -60,10 +70,12 @@ struct FilePayloadProcessResult {
     remote_file: ApiFileResponse,
     config: SherryConfigJSON,
     auth: SherryAuthorizationConfigJSON,
-    dir: PathBuf,
+    hashes_dir: PathBuf,
     sources: HashMap<String, SherryConfigSourceJSON>,
     watchers_paths: Vec<(SherryConfigWatcherJSON, PathBuf)>,
-    client: ApiClient,
+    // watchers of the source that leave the path out
+    excluded_watchers: Vec<SherryConfigWatcherJSON>,
+    storage: Arc<dyn RemoteStorage>,
 }
 
 async fn process_file_payload(ctx: Context, payload: Payload) -> Option<FilePayloadProcessResult> {
@@ -71,6 +83,15 @@ async fn process_file_payload(ctx: Context, payload: Payload) -> Option<FilePayl
         Payload::Text(res) => serde_json::from_value::<ApiFileResponse>(res.first().unwrap().clone()).unwrap(),
         _ => { return None; }
     };
+    let remote_file = ApiFileResponse {
+        path: canonicalize_sync_path(&remote_file.path),
+        old_path: canonicalize_sync_path(&remote_file.old_path),
+        ..remote_file
+    };
+    if let Err(e) = check_sync_path(&remote_file.path).and(check_sync_path(&remote_file.old_path)) {
+        log::error!("Ignoring change of {}: {}", &remote_file.sherry_id, e);
+        return None;
+    }
     let (config, auth, dir) = async {
         let c = ctx.lock().await;
         let c = c.config.lock().await;
@@ -92,37 +113,30 @@ async fn process_file_payload(ctx: Context, payload: Payload) -> Option<FilePayl
 
     let watchers_paths = config.watchers.iter()
         .filter_map(|w| {
-            if sources.contains_key(&w.source) {
-                Some((w.clone(), PathBuf::from(&w.local_path).join(&remote_file.path)))
+            if sources.contains_key(&w.source) && w.mode.can_download() && w.is_included(&remote_file.path) {
+                sync_path_to_local(Path::new(&w.local_path), &remote_file.path).ok().map(|p| (w.clone(), p))
             } else {
                 None
             }
         })
         .collect::<Vec<(SherryConfigWatcherJSON, PathBuf)>>();
+    let excluded_watchers = config.watchers.iter()
+        .filter(|w| sources.contains_key(&w.source) && !w.is_included(&remote_file.path))
+        .cloned()
+        .collect::<Vec<SherryConfigWatcherJSON>>();
 
-    let mut user = None;
-    for (_, source) in sources.iter() {
-        user = auth.records.get(&source.user_id);
-        if user.is_some() {
-            break;
-        }
-    };
-
-    if user.is_none() {
-        return None;
-    }
-    let user = user.unwrap();
-
-    let client = ApiClient::new(&config.api_url, &user.access_token);
+    let (source, user) = sources.values().find_map(|s| auth.records.get(&s.user_id).map(|u| (s, u)))?;
+    let storage = get_storage(&config.api_url, source, user);
 
     Some(FilePayloadProcessResult {
         remote_file,
         auth,
+        hashes_dir: get_hashes_dir(&dir, &config),
         config,
-        dir,
         sources,
         watchers_paths,
-        client,
+        excluded_watchers,
+        storage,
     })
 }
 
@@ -134,33 +148,120 @@ fn folder_file_upserted_handler<'a>(ctx: Context, payload: Payload, socket: Clie
             Some(res) => res,
             None => { return; }
         };
-        let dir = result.dir;
+        let dir = result.hashes_dir;
         let remote_file = result.remote_file;
         let sources = result.sources;
         let watchers_paths = result.watchers_paths;
-        let client = result.client;
+        let storage = result.storage;
+        for watcher in result.excluded_watchers.iter() {
+            add_available_path(watcher, &remote_file.path);
+        }
 
-        log::info!("==========TO UPSERT\n{:?}", &watchers_paths);
+        // Our own uploads are broadcast back to us, the hash store already knows their content
+        let watchers_paths = futures::future::join_all(watchers_paths.into_iter().map(|(watcher, file_path)| {
+            let dir = dir.clone();
+            let source = sources.get(&watcher.source).unwrap().clone();
+            let remote_hash = remote_file.hash.clone();
+            let remote_attributes = remote_file.attributes.clone();
+            async move {
+                let hashes = get_hashes(&dir, &source, &PathBuf::from(&watcher.local_path), &watcher.hashes_id).await.ok()?;
+                // A permission change alone still has to be applied
+                let is_known = file_path.is_file() && hashes.hashes.get(normalize_path(&file_path).to_str().unwrap()).is_some_and(|h| {
+                    h.hash == remote_hash && (!source.sync_permissions || remote_attributes.is_none() || h.attributes == remote_attributes)
+                });
+                if is_known {
+                    log::info!("Skipping download of {:?}, local content is up to date", file_path);
+                    return None;
+                }
+                // Content may already be there (e.g. copied by the user), then only the hash store is stale
+                let is_identical = has_file_hash(&file_path, &remote_hash).await;
+                Some((watcher, file_path, !is_identical))
+            }
+        })).await.into_iter().flatten().collect::<Vec<(SherryConfigWatcherJSON, PathBuf, bool)>>();
 
-        let file_content = client.get_file(&remote_file.sherry_id, &remote_file.path).await;
-        if file_content.is_err() {
+        if watchers_paths.is_empty() {
             return;
         }
 
-        write_files_from_stream(&watchers_paths.iter().map(|(_, p)| p.clone()).collect(), file_content.unwrap().bytes_stream()).await.ok();
+        // Variants are downloaded for each watcher on its own, the original is written to every other path at once
+        let get_variant = |watcher: &SherryConfigWatcherJSON| sources.get(&watcher.source).and_then(|s| s.get_download_variant(watcher.mode)).cloned();
+        let to_write = watchers_paths.iter()
+            .filter_map(|(w, p, is_write)| if *is_write && get_variant(w).is_none() { Some(p.clone()) } else { None })
+            .collect::<Vec<PathBuf>>();
+        log::info!("==========TO UPSERT\n{:?}", &to_write);
+
+        if !to_write.is_empty() {
+            let is_written = schedule_transfer(&remote_file.sherry_id, remote_file.size, false, async {
+                let file_content = storage.get(&remote_file.sherry_id, &remote_file.path).await?;
+                let file_content = track_progress(&remote_file.sherry_id, Direction::Download, &remote_file.path, remote_file.size, limit_download(&remote_file.sherry_id, file_content));
+                if let Err(e) = with_self_writes(&to_write, &remote_file.hash, write_files_from_stream(&to_write, file_content)).await {
+                    for (watcher, _, _) in watchers_paths.iter().filter(|(_, _, is_write)| *is_write) {
+                        record_drift(&watcher.source, &remote_file.path, DriftSide::Download, format!("write failed: {}", e));
+                    }
+                }
+                Ok::<(), String>(())
+            }).await.is_ok();
+            if !is_written {
+                return;
+            }
+            for path in to_write.iter() {
+                set_file_created(path, remote_file.created_at).ok();
+            }
+        }
+
+        let mut corrupted = vec![];
+        for path in to_write.iter() {
+            if verify_download(&remote_file.sherry_id, path, &remote_file.hash).await {
+                continue;
+            }
+            // Without retries the store would claim content the file doesn't have, the drift repair downloads it again
+            if !is_verify_all(&remote_file.sherry_id) {
+                for (watcher, _, _) in watchers_paths.iter().filter(|(_, p, _)| p == path) {
+                    record_drift(&watcher.source, &remote_file.path, DriftSide::Download, "checksum mismatch after download".to_string());
+                }
+                continue;
+            }
+            if download_file(storage.as_ref(), &remote_file.sherry_id, &remote_file.path, path, &remote_file.hash, remote_file.size).await.is_err() {
+                corrupted.push(path.clone());
+            }
+        }
+
+        let mut variants = HashMap::new();
+        for (watcher, path, _) in watchers_paths.iter().filter(|(_, _, is_write)| *is_write) {
+            let variant = match get_variant(watcher) {
+                Some(variant) => variant,
+                None => continue,
+            };
+            match download_variant(storage.as_ref(), &remote_file.sherry_id, &remote_file.path, path, &remote_file.hash, remote_file.size, Some(&variant)).await {
+                Ok(variant) => {
+                    set_file_created(path, remote_file.created_at).ok();
+                    if let Some(variant) = variant {
+                        variants.insert(path.clone(), variant);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to download {} of {}: {}", &variant, &remote_file.path, e);
+                    corrupted.push(path.clone());
+                }
+            }
+        }
 
         let dir = dir.clone();
-        futures::future::join_all(watchers_paths.iter().map(|(watcher, file_path)| {
+        futures::future::join_all(watchers_paths.iter().filter(|(_, p, _)| !corrupted.contains(p)).map(|(watcher, file_path, _)| {
             let dir = dir.clone();
             let local_path = PathBuf::from(&watcher.local_path);
             let remote_file = remote_file.clone();
             let source = sources.get(&watcher.source).unwrap();
+            let variant = variants.get(file_path).cloned();
             async move {
                 let mut hashes = get_hashes(&dir, &source, &local_path, &watcher.hashes_id).await.unwrap();
                 hashes.hashes.insert(normalize_path(&file_path).to_str().unwrap().to_string(), FileHashJSON {
                     hash: remote_file.hash.clone(),
                     timestamp: remote_file.updated_at,
-                    size: remote_file.size,
+                    size: if variant.is_some() { file_path.metadata().map_or(0, |m| m.len()) } else { remote_file.size },
+                    modified: get_modified_millis(file_path),
+                    attributes: if source.sync_permissions { apply_file_attributes(file_path, &remote_file.attributes) } else { None },
+                    variant,
                 });
                 update_hashes(&dir, &hashes).await.ok();
             }
@@ -177,32 +278,43 @@ fn folder_file_moved_handler<'a>(ctx: Context, payload: Payload, socket: Client)
             None => { return; }
         };
         let remote_file = result.remote_file;
-        let dir = result.dir;
+        let dir = result.hashes_dir;
         let sources = result.sources;
         let watchers_paths = result.watchers_paths;
+        for watcher in result.config.watchers.iter().filter(|w| sources.contains_key(&w.source)) {
+            remove_available_path(watcher, &remote_file.old_path);
+        }
+        for watcher in result.excluded_watchers.iter() {
+            add_available_path(watcher, &remote_file.path);
+        }
 
-        futures::future::join_all(watchers_paths.iter().map(|(watcher, new_file_path)| {
+        futures::future::join_all(watchers_paths.iter().filter_map(|(watcher, new_file_path)| {
             let remote_file = remote_file.clone();
             let dir = dir.clone();
             let source = sources.get(&watcher.source).unwrap();
             let local_path = normalize_path(&PathBuf::from(&watcher.local_path));
-            let old_path = normalize_path(&PathBuf::from(&local_path).join(&remote_file.old_path));
+            let old_path = normalize_path(&sync_path_to_local(&local_path, &remote_file.old_path).ok()?);
             let old_path_string = old_path.to_str().unwrap().to_string();
-            async move {
-                if let Err(_) = rename_path(&old_path, new_file_path).await { return; }
+            Some(async move {
+                let new_paths = vec![new_file_path.clone()];
+                let rename = with_self_writes(&new_paths, &remote_file.hash, rename_path(&old_path, new_file_path));
+                if with_self_writes(&vec![old_path.clone()], "", rename).await.is_err() { return; }
                 let mut hashes = get_hashes(&dir, &source, &local_path, &watcher.hashes_id).await.unwrap();
-                for (k, _) in hashes.hashes.clone().iter() {
+                for (k, h) in hashes.hashes.clone().iter() {
                     if k.starts_with(&old_path.to_str().unwrap().to_string()) {
                         hashes.hashes.remove(k);
                         hashes.hashes.insert(new_file_path.join(&k.strip_prefix(&old_path_string).unwrap()).to_str().unwrap().to_string(), FileHashJSON {
                             hash: remote_file.hash.clone(),
                             timestamp: remote_file.updated_at,
-                            size: remote_file.size,
+                            size: if h.variant.is_some() { h.size } else { remote_file.size },
+                            modified: h.modified,
+                            attributes: h.attributes.clone(),
+                            variant: h.variant.clone(),
                         });
                     }
                 }
                 update_hashes(&dir, &hashes).await.ok();
-            }
+            })
         })).await;
     }.boxed()
 }
@@ -215,18 +327,27 @@ fn folder_file_deleted_handler<'a>(ctx: Context, payload: Payload, socket: Clien
             Some(res) => res,
             None => { return; }
         };
-        let dir = result.dir;
+        let dir = result.hashes_dir;
         let sources = result.sources;
         let watchers_paths = result.watchers_paths;
+        let remote_path = &result.remote_file.path;
+        for watcher in result.excluded_watchers.iter() {
+            remove_available_path(watcher, remote_path);
+        }
 
         futures::future::join_all(watchers_paths.iter().map(|(watcher, file_path)| {
             let dir = dir.clone();
             let source = sources.get(&watcher.source).unwrap();
             let local_path = PathBuf::from(&watcher.local_path);
             async move {
-                if let Err(_) = delete_path(file_path).await { return; }
+                if let Err(e) = with_self_writes(&vec![file_path.clone()], "", delete_path(file_path)).await {
+                    record_drift(&watcher.source, remote_path, DriftSide::Download, format!("removal failed: {}", e));
+                    return;
+                }
                 let mut hashes = get_hashes(&dir, &source, &local_path, &watcher.hashes_id).await.unwrap();
-                hashes.hashes.remove(&normalize_path(&file_path).to_str().unwrap().to_string());
+                // Removed directories take their files along
+                let file_path = normalize_path(file_path);
+                hashes.hashes.retain(|p, _| !Path::new(p).starts_with(&file_path));
                 update_hashes(&dir, &hashes).await.ok();
             }
         })).await;
@@ -240,19 +361,24 @@ fn error_handler<'a>(ctx: Context, payload: Payload, socket: Client) -> BoxFutur
     async move {}.boxed()
 }
 
-fn reconnect_handler<'a>(ctx: Context) -> BoxFuture<'a, ReconnectSettings> {
-    log::info!("Socket Reconnect");
+// Only the connection of this user is reestablished, with its current token
+fn reconnect_handler<'a>(ctx: Context, user_id: String) -> BoxFuture<'a, ReconnectSettings> {
+    log::info!("Socket Reconnect for {}", &user_id);
 
     async move {
         let mut config = {
-            let mut client = ctx.lock().await;
-            client.reconnect().await;
+            let client = ctx.lock().await;
             let a = client.config.lock().await;
             a.clone()
         };
+        let mut settings = ReconnectSettings::new();
+        if let Some(user) = config.get_auth().await.records.get(&user_id) {
+            let (header, value) = user.get_socket_auth_header();
+            settings.opening_header(header, value);
+        }
 
         config.reinitialize().await;
-        ReconnectSettings::new()
+        settings
     }.boxed()
 }
 
@@ -263,68 +389,153 @@ fn get_cb_with_ctx<'a, R>(ctx: &Context, cb: fn(Context, Payload, Client) -> Box
     }
 }
 
-fn get_reconnect_cb_with_ctx<'a>(ctx: &Context, cb: fn(Context) -> BoxFuture<'a, ReconnectSettings>) -> impl FnMut() -> BoxFuture<'a, ReconnectSettings> {
+// (folder id, queue key)
+fn get_payload_queue_key(payload: &Payload) -> (String, String) {
+    match payload {
+        Payload::Text(res) => match res.first().and_then(|v| serde_json::from_value::<ApiFileResponse>(v.clone()).ok()) {
+            Some(file) => (file.sherry_id.clone(), format!("{}:{}", file.sherry_id, canonicalize_sync_path(&file.path))),
+            None => ("".to_string(), "".to_string()),
+        },
+        _ => ("".to_string(), "".to_string()),
+    }
+}
+
+// File events for the same path are applied in the order they were received, events of folders being fetched are held
+fn get_queued_cb_with_ctx(ctx: &Context, queue: &PathQueue, cb: fn(Context, Payload, Client) -> BoxFuture<'static, ()>) -> impl FnMut(Payload, Client) -> BoxFuture<'static, ()> {
     let ctx = ctx.clone();
+    let queue = queue.clone();
+    move |payload: Payload, socket: Client| {
+        let (folder_id, key) = get_payload_queue_key(&payload);
+        let ctx = ctx.clone();
+        let queue = queue.clone();
+        hold_or_release(&folder_id, Box::new(move || queue.push(key, cb(ctx, payload, socket))));
+        async move {}.boxed()
+    }
+}
+
+fn get_reconnect_cb_with_ctx<'a>(ctx: &Context, user_id: &str, cb: fn(Context, String) -> BoxFuture<'a, ReconnectSettings>) -> impl FnMut() -> BoxFuture<'a, ReconnectSettings> {
+    let ctx = ctx.clone();
+    let user_id = user_id.to_string();
     move || {
-        cb(ctx.clone())
+        cb(ctx.clone(), user_id.clone())
     }
 }
 
 #[derive(Clone)]
 pub struct SocketClient {
     pub _is_up: Arc<Mutex<bool>>,
-    pub client: Arc<Mutex<Option<Client>>>,
+    // user id -> connection, so one rejected token doesn't cut off the other users
+    pub clients: Arc<Mutex<HashMap<String, Client>>>,
+    // users whose connection is retried in the background
+    retrying: Arc<Mutex<HashSet<String>>>,
     pub config: Arc<Mutex<SherryConfig>>,
+    pub queue: PathQueue,
 }
 
 impl SocketClient {
-    async fn is_up(&self) -> bool {
-        self._is_up.lock().await.clone()
+    pub async fn is_up(&self) -> bool {
+        *self._is_up.lock().await && !self.clients.lock().await.is_empty()
     }
-    async fn connect(&mut self) {
+    pub async fn get_connected_users(&self) -> Vec<String> {
+        let mut users = self.clients.lock().await.keys().cloned().collect::<Vec<String>>();
+        users.sort();
+        users
+    }
+    async fn get_config(&self) -> (SherryConfigJSON, SherryAuthorizationConfigJSON) {
+        let config = self.config.lock().await;
+        (config.get_main().await, config.get_auth().await)
+    }
+    async fn connect_user(&self, data: &SherryConfigJSON, user: &Credentials) -> Result<Client, Error> {
         let ctx = Arc::new(Mutex::new(self.clone()));
-        let mut res: Result<Client, Error> = Err(Error::StoppedEngineIoSocket);
-
-        while res.is_err() {
-            let (data, auth) = async {
-                let config = self.config.lock().await;
-                (config.get_main().await, config.get_auth().await)
-            }.await;
-            let tokens = auth.records.iter().filter(|(_, v)| !v.expired).map(|(_, v)| v.access_token.clone()).collect::<Vec<String>>().join(";");
-            res = ClientBuilder::new(&data.socket_url)
-                .opening_header("authorization", tokens.clone())
-                .on("FOLDER:CREATED", get_cb_with_ctx(&ctx, folder_created_handler))
-                .on("FOLDER:UPDATED", get_cb_with_ctx(&ctx, folder_updated_handler))
-                .on("FOLDER:DELETED", get_cb_with_ctx(&ctx, folder_deleted_handler))
-
-                .on("FOLDER:PERMISSION:GRANTED", get_cb_with_ctx(&ctx, folder_permission_granted_handler))
-                .on("FOLDER:PERMISSION:REVOKED", get_cb_with_ctx(&ctx, folder_permission_revoked_handler))
-
-                .on("FOLDER:FILE:CREATED", get_cb_with_ctx(&ctx, folder_file_upserted_handler))
-                .on("FOLDER:FILE:UPDATED", get_cb_with_ctx(&ctx, folder_file_upserted_handler))
-                .on("FOLDER:FILE:MOVED", get_cb_with_ctx(&ctx, folder_file_moved_handler))
-                .on("FOLDER:FILE:DELETED", get_cb_with_ctx(&ctx, folder_file_deleted_handler))
-
-                .on("error", get_cb_with_ctx(&ctx, error_handler))
-                .on_reconnect(get_reconnect_cb_with_ctx(&ctx, reconnect_handler))
-
-                .reconnect_on_disconnect(true)
-                .connect().await;
-            if res.is_err() {
-                log::warn!("Failed to connect to socket.io server, retrying in 10 seconds...");
+        let (header, value) = user.get_socket_auth_header();
+        let builder = ClientBuilder::new(&data.socket_url)
+            .opening_header(header, value)
+            .on("FOLDER:CREATED", get_cb_with_ctx(&ctx, folder_created_handler))
+            .on("FOLDER:UPDATED", get_cb_with_ctx(&ctx, folder_updated_handler))
+            .on("FOLDER:DELETED", get_cb_with_ctx(&ctx, folder_deleted_handler))
+
+            .on("FOLDER:PERMISSION:GRANTED", get_cb_with_ctx(&ctx, folder_permission_granted_handler))
+            .on("FOLDER:PERMISSION:REVOKED", get_cb_with_ctx(&ctx, folder_permission_revoked_handler))
+
+            .on("FOLDER:FILE:CREATED", get_queued_cb_with_ctx(&ctx, &self.queue, folder_file_upserted_handler))
+            .on("FOLDER:FILE:UPDATED", get_queued_cb_with_ctx(&ctx, &self.queue, folder_file_upserted_handler))
+            .on("FOLDER:FILE:MOVED", get_queued_cb_with_ctx(&ctx, &self.queue, folder_file_moved_handler))
+            .on("FOLDER:FILE:DELETED", get_queued_cb_with_ctx(&ctx, &self.queue, folder_file_deleted_handler))
+
+            .on("error", get_cb_with_ctx(&ctx, error_handler))
+            .on_reconnect(get_reconnect_cb_with_ctx(&ctx, &user.user_id, reconnect_handler))
+
+            .reconnect_on_disconnect(true);
+        // Only the polling transport goes through reqwest and therefore through the proxy
+        let builder = if is_proxied() { builder.transport_type(TransportType::Polling) } else { builder };
+        let builder = match build_tls_connector() {
+            Some(connector) => builder.tls_config(connector),
+            None => builder,
+        };
+        builder.connect().await
+    }
+    // Gives up once the user is gone, suspended or connected by someone else
+    fn retry_user(&self, user_id: &str) {
+        let socket = self.clone();
+        let user_id = user_id.to_string();
+        tokio::spawn(async move {
+            if !socket.retrying.lock().await.insert(user_id.clone()) {
+                return;
+            }
+            loop {
+                log::warn!("Failed to connect to socket.io server as {}, retrying in 10 seconds...", &user_id);
                 tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                let (data, auth) = socket.get_config().await;
+                let user = match auth.records.get(&user_id) {
+                    Some(user) if user.is_usable() && user.is_sherry() => user.clone(),
+                    _ => break,
+                };
+                if socket.clients.lock().await.contains_key(&user_id) {
+                    break;
+                }
+                if let Ok(client) = socket.connect_user(&data, &user).await {
+                    socket.clients.lock().await.insert(user_id.clone(), client);
+                    break;
+                }
             }
-        };
-
-        *self.client.lock().await = Some(res.unwrap());
+            socket.retrying.lock().await.remove(&user_id);
+        });
+    }
+    async fn connect_users(&mut self, user_ids: &Vec<String>) {
+        let (data, auth) = self.get_config().await;
+        // The socket may connect before the first config update is applied
+        set_proxy(&data.proxy);
+        set_tls(&data.tls);
+        for user_id in user_ids {
+            if let Some(client) = self.clients.lock().await.remove(user_id) {
+                let _ = client.disconnect().await;
+            }
+            // WebDAV servers are polled instead
+            let user = match auth.records.get(user_id) {
+                Some(user) if user.is_usable() && user.is_sherry() => user,
+                _ => continue,
+            };
+            match self.connect_user(&data, user).await {
+                Ok(client) => {
+                    self.clients.lock().await.insert(user_id.clone(), client);
+                }
+                Err(_) => self.retry_user(user_id),
+            }
+        }
+    }
+    async fn connect(&mut self) {
+        let users = self.get_config().await.1.records.keys().cloned().collect::<Vec<String>>();
+        self.connect_users(&users).await;
         *self._is_up.lock().await = true;
     }
 
     pub async fn new(config: &SherryConfig) -> Self {
         let mut res = Self {
-            client: Arc::new(Mutex::new(None)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            retrying: Arc::new(Mutex::new(HashSet::new())),
             config: Arc::new(Mutex::new(config.clone())),
             _is_up: Arc::new(Mutex::new(false)),
+            queue: PathQueue::new(),
         };
 
         res.connect().await;
@@ -332,14 +543,8 @@ impl SocketClient {
         res
     }
 
-    pub async fn reconnect(&mut self) {
-        *self._is_up.lock().await = false;
-        match self.client.lock().await.clone() {
-            Some(c) => {
-                let _ = c.disconnect().await;
-            }
-            _ => {}
-        }
-        self.connect().await;
+    // Connections of other users are left alone
+    pub async fn reconnect_users(&mut self, user_ids: &Vec<String>) {
+        self.connect_users(user_ids).await;
     }
 }
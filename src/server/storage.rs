@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use reqwest::StatusCode;
+use tokio_util::bytes::Bytes;
+
+use crate::auth::Credentials;
+use crate::config::{SherryConfigSourceJSON, StorageKind};
+use crate::constants::VARIANT_HEADER;
+use crate::event::file_event::{FileType, SyncEvent};
+use crate::server::api::ApiClient;
+use crate::server::s3::S3Storage;
+use crate::server::types::ApiFileResponse;
+use crate::server::webdav::WebdavStorage;
+
+pub type ByteStream = BoxStream<'static, Result<Bytes, String>>;
+
+// Hashes of servers that don't store ours, they only tell whether the file changed and can't verify its content
+pub const FOREIGN_HASH_PREFIX: &str = "etag:";
+
+pub enum StorageCheck {
+    Accepted,
+    // final, retrying won't change the answer
+    Rejected(String),
+    QuotaExceeded(String),
+}
+
+// What the sync engine needs from a remote, so other protocols (S3-compatible, WebDAV, a v2 API) can be plugged in
+// per source without touching it. Futures are boxed to keep the trait object safe.
+pub trait RemoteStorage: Send + Sync {
+    // every file of the folder
+    fn list<'a>(&'a self, folder_id: &'a str) -> BoxFuture<'a, Result<Vec<ApiFileResponse>, String>>;
+    fn get<'a>(&'a self, folder_id: &'a str, path: &'a str) -> BoxFuture<'a, Result<ByteStream, String>>;
+    // `variant` rendered by the server instead of the original, true when the server sent one
+    fn get_variant<'a>(&'a self, folder_id: &'a str, path: &'a str, _variant: &'a str) -> BoxFuture<'a, Result<(ByteStream, bool), String>> {
+        async move { Ok((self.get(folder_id, path).await?, false)) }.boxed()
+    }
+    // an earlier content of the file, by its hash
+    fn get_version<'a>(&'a self, _folder_id: &'a str, path: &'a str, _hash: &'a str) -> BoxFuture<'a, Result<ByteStream, String>> {
+        async move { Err(format!("The storage keeps no earlier versions of {}", path)) }.boxed()
+    }
+    // asked before the content of an event is sent, `sequence` orders the events of a path
+    fn check<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<StorageCheck, String>>;
+    fn put<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>>;
+    fn delete<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>>;
+    fn move_path<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>>;
+}
+
+pub fn get_storage(api_url: &String, source: &SherryConfigSourceJSON, user: &Credentials) -> Arc<dyn RemoteStorage> {
+    match &source.storage {
+        Some(storage) => match storage.kind {
+            StorageKind::Webdav => Arc::new(WebdavStorage::new(&storage.url, &user.username, &user.access_token)),
+            StorageKind::S3 => Arc::new(S3Storage::new(&storage.url, &storage.region, &user.username, &user.access_token)),
+        },
+        None => Arc::new(ApiClient::new(api_url, user.get_folder_token(&source.id))),
+    }
+}
+
+impl ApiClient {
+    // The API takes every kind of event on the same endpoint
+    async fn send_storage_event(&self, event: &SyncEvent, sequence: u64) -> Result<(), String> {
+        let res = self.send_file(event, sequence).await.map_err(|e| e.to_string())?;
+        if res.status() == 200 {
+            return Ok(());
+        }
+        Err(res.text().await.unwrap_or_default())
+    }
+}
+
+impl RemoteStorage for ApiClient {
+    fn list<'a>(&'a self, folder_id: &'a str) -> BoxFuture<'a, Result<Vec<ApiFileResponse>, String>> {
+        async move { self.get_folder_files(folder_id).await.map_err(|e| e.to_string()) }.boxed()
+    }
+
+    fn get<'a>(&'a self, folder_id: &'a str, path: &'a str) -> BoxFuture<'a, Result<ByteStream, String>> {
+        async move {
+            let res = self.get_file(folder_id, path).await.map_err(|e| e.to_string())?;
+            Ok(res.bytes_stream().map(|chunk| chunk.map_err(|e| e.to_string())).boxed())
+        }.boxed()
+    }
+
+    fn get_variant<'a>(&'a self, folder_id: &'a str, path: &'a str, variant: &'a str) -> BoxFuture<'a, Result<(ByteStream, bool), String>> {
+        async move {
+            let res = self.get_file_variant(folder_id, path, variant).await.map_err(|e| e.to_string())?;
+            let is_variant = res.headers().get(VARIANT_HEADER).is_some_and(|v| v.as_bytes() == variant.as_bytes());
+            Ok((res.bytes_stream().map(|chunk| chunk.map_err(|e| e.to_string())).boxed(), is_variant))
+        }.boxed()
+    }
+
+    fn get_version<'a>(&'a self, folder_id: &'a str, path: &'a str, hash: &'a str) -> BoxFuture<'a, Result<ByteStream, String>> {
+        async move {
+            let res = self.get_file_version(folder_id, path, hash).await.map_err(|e| e.to_string())?;
+            if res.status() != StatusCode::OK {
+                return Err(res.text().await.unwrap_or_default());
+            }
+            Ok(res.bytes_stream().map(|chunk| chunk.map_err(|e| e.to_string())).boxed())
+        }.boxed()
+    }
+
+    fn check<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<StorageCheck, String>> {
+        async move {
+            let res = self.check_file(event, sequence).await.map_err(|e| e.to_string())?;
+            Ok(match res.status() {
+                StatusCode::OK => StorageCheck::Accepted,
+                StatusCode::INSUFFICIENT_STORAGE => StorageCheck::QuotaExceeded(res.text().await.unwrap_or_default()),
+                _ => StorageCheck::Rejected(res.text().await.unwrap_or_default()),
+            })
+        }.boxed()
+    }
+
+    fn put<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.send_storage_event(event, sequence).boxed()
+    }
+
+    fn delete<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        if event.file_type != FileType::Dir {
+            return self.send_storage_event(event, sequence).boxed();
+        }
+        async move {
+            let res = self.delete_dir(event, sequence).await.map_err(|e| e.to_string())?;
+            if res.status() == 200 {
+                return Ok(());
+            }
+            Err(res.text().await.unwrap_or_default())
+        }.boxed()
+    }
+
+    fn move_path<'a>(&'a self, event: &'a SyncEvent, sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.send_storage_event(event, sequence).boxed()
+    }
+}
@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
 
 use crate::event::file_event::FileType;
+use crate::files::FileAttributesJSON;
 
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -14,6 +15,62 @@ pub struct ApiAuthResponse {
     pub expires_in: u64, // timestamp in seconds
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiUserResponse {
+    pub user_id: String,
+    pub email: String,
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiDeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub expires_in: u64, // seconds
+    pub interval: u64, // seconds between polls
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiDeviceTokenError {
+    // authorization_pending, slow_down, access_denied or expired_token
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiFolderTokenResponse {
+    pub access_token: String,
+    pub expires_in: u64, // timestamp in seconds
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiShareLinkResponse {
+    pub url: String,
+    pub expires_at: u64, // timestamp in seconds
+}
+
+// Settings left out are chosen by the server
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiCreateFolderRequest {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_dir_size: Option<u64>,
+    #[serde(default)]
+    pub allow_dir: bool,
+    #[serde(default)]
+    pub allowed_file_names: Vec<String>,
+    #[serde(default)]
+    pub allowed_file_types: Vec<String>,
+}
+
 #[derive(SerdeDiff, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ApiFileResponse {
@@ -26,6 +83,9 @@ pub struct ApiFileResponse {
     pub created_at: i128,
     pub updated_at: i128,
     pub file_type: FileType,
+    // permissions of the last upload, when it sent them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributesJSON>,
 }
 
 
@@ -75,4 +135,6 @@ pub struct ApiFolderResponse {
     pub allowed_file_names: Vec<ApiFolderAllowedFileNameResponse>,
     pub allowed_file_types: Vec<ApiFolderAllowedFileTypeResponse>,
     pub sherry_permission: Vec<ApiFolderPermissionResponse>,
+    #[serde(default)]
+    pub archived: bool,
 }
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use regex::Regex;
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode, Url};
+use tokio::fs::File;
+use tokio::time::Instant;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::bandwidth::{Direction, limit_stream};
+use crate::constants::WEBDAV_NAMESPACE;
+use crate::event::file_event::{FileType, SyncEvent};
+use crate::helpers::{canonicalize_sync_path, generate_random_id, get_xml_element, PATH_SEP, str_err_prefix};
+use crate::progress::track_progress;
+use crate::server::http::build_http_client;
+use crate::server::metrics::record_request;
+use crate::server::storage::{ByteStream, FOREIGN_HASH_PREFIX, RemoteStorage, StorageCheck};
+use crate::server::types::ApiFileResponse;
+
+// A folder on a plain WebDAV server (Nextcloud, ownCloud, Apache mod_dav, ...). Content hashes live in a dead property
+// of each file, files written by other clients don't have one until they are uploaded from here.
+pub struct WebdavStorage {
+    // collection of the folder
+    url: String,
+    username: String,
+    password: String,
+}
+
+struct WebdavEntry {
+    href: String,
+    is_collection: bool,
+    size: u64,
+    modified: i128,
+    etag: String,
+    hash: Option<String>,
+}
+
+fn get_method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).unwrap()
+}
+
+fn get_propfind_body() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:" xmlns:s="{}"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><d:getetag/><s:hash/></d:prop></d:propfind>"#,
+        WEBDAV_NAMESPACE,
+    )
+}
+
+fn get_proppatch_body(hash: &String) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><d:propertyupdate xmlns:d="DAV:" xmlns:s="{}"><d:set><d:prop><s:hash>{}</s:hash></d:prop></d:set></d:propertyupdate>"#,
+        WEBDAV_NAMESPACE, hash,
+    )
+}
+
+fn decode_percent(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn parse_multistatus(body: &str) -> Vec<WebdavEntry> {
+    let collection = Regex::new(r"<(?:[\w-]+:)?collection\s*/>").unwrap();
+    Regex::new(r"(?s)<(?:[\w-]+:)?response(?:\s[^>]*)?>(.*?)</(?:[\w-]+:)?response>").unwrap()
+        .captures_iter(body)
+        .filter_map(|c| {
+            let response = &c[1];
+            Some(WebdavEntry {
+                href: get_xml_element(response, "href")?,
+                is_collection: collection.is_match(response),
+                size: get_xml_element(response, "getcontentlength").and_then(|s| s.parse().ok()).unwrap_or(0),
+                modified: get_xml_element(response, "getlastmodified")
+                    .and_then(|s| chrono::DateTime::parse_from_rfc2822(&s).ok())
+                    .map_or(0, |d| d.timestamp_millis() as i128),
+                etag: get_xml_element(response, "getetag").unwrap_or_default().trim_matches('"').to_string(),
+                hash: get_xml_element(response, "hash").filter(|h| !h.is_empty()),
+            })
+        })
+        .collect()
+}
+
+async fn expect_success(res: Response, also: &[StatusCode]) -> Result<Response, String> {
+    if res.status().is_success() || also.contains(&res.status()) {
+        return Ok(res);
+    }
+    Err(format!("WebDAV request failed with {}: {}", res.status(), res.text().await.unwrap_or_default()))
+}
+
+impl WebdavStorage {
+    pub fn new(url: &str, username: &str, password: &str) -> Self {
+        WebdavStorage {
+            url: url.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    // Collections get a trailing slash, some servers redirect without it
+    fn get_url(&self, sync_path: &str, is_collection: bool) -> Result<Url, String> {
+        let mut url = Url::parse(&self.url).map_err(str_err_prefix("Invalid WebDAV URL"))?;
+        {
+            let mut segments = url.path_segments_mut().map_err(|_| format!("Invalid WebDAV URL {}", &self.url))?;
+            segments.pop_if_empty();
+            for segment in canonicalize_sync_path(sync_path).split(PATH_SEP).filter(|s| !s.is_empty()) {
+                segments.push(segment);
+            }
+            if is_collection {
+                segments.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    async fn execute<F>(&self, method: Method, url: Url, build: F) -> Result<Response, String>
+        where
+            F: FnOnce(RequestBuilder) -> RequestBuilder,
+    {
+        let endpoint = format!("WEBDAV {}", method);
+        let request_id = generate_random_id();
+        let started = Instant::now();
+        let request = build(build_http_client().request(method, url).basic_auth(&self.username, Some(&self.password)));
+        let res = request.send().await;
+        record_request(&endpoint, &request_id, started.elapsed(), &res);
+        res.map_err(|e| e.to_string())
+    }
+
+    async fn list_files(&self, folder_id: &str) -> Result<Vec<ApiFileResponse>, String> {
+        let root = self.get_url("", true)?;
+        let mut files = vec![];
+        // Depth 1 level by level, many servers refuse infinite depth
+        let mut collections = VecDeque::from([root.clone()]);
+        while let Some(collection) = collections.pop_front() {
+            let res = self.execute(get_method("PROPFIND"), collection.clone(), |r| {
+                r.header("Depth", "1").header("Content-Type", "application/xml; charset=utf-8").body(get_propfind_body())
+            }).await?;
+            let body = expect_success(res, &[]).await?.text().await.map_err(str_err_prefix("Error WebDAV Listing"))?;
+            for entry in parse_multistatus(&body) {
+                let url = collection.join(&entry.href).map_err(str_err_prefix("Invalid WebDAV href"))?;
+                if url.path().trim_end_matches('/') == collection.path().trim_end_matches('/') {
+                    continue;
+                }
+                let sync_path = match url.path().strip_prefix(root.path()) {
+                    Some(path) => canonicalize_sync_path(&decode_percent(path)),
+                    None => continue,
+                };
+                if entry.is_collection {
+                    collections.push_back(url);
+                    continue;
+                }
+                files.push(ApiFileResponse {
+                    sherry_file_id: entry.etag.clone(),
+                    sherry_id: folder_id.to_string(),
+                    path: sync_path.clone(),
+                    old_path: sync_path,
+                    hash: entry.hash.unwrap_or(format!("{}{}", FOREIGN_HASH_PREFIX, &entry.etag)),
+                    size: entry.size,
+                    created_at: entry.modified,
+                    updated_at: entry.modified,
+                    file_type: FileType::File,
+                    attributes: None,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    async fn make_collections(&self, sync_path: &str) -> Result<(), String> {
+        let segments = canonicalize_sync_path(sync_path).split(PATH_SEP).map(|s| s.to_string()).collect::<Vec<String>>();
+        for i in 1..=segments.len() {
+            let res = self.execute(get_method("MKCOL"), self.get_url(&segments[..i].join(PATH_SEP), true)?, |r| r).await?;
+            // 405 when it already exists
+            expect_success(res, &[StatusCode::METHOD_NOT_ALLOWED]).await?;
+        }
+        Ok(())
+    }
+
+    async fn make_parents(&self, sync_path: &str) -> Result<(), String> {
+        match canonicalize_sync_path(sync_path).rsplit_once(PATH_SEP) {
+            Some((parent, _)) => self.make_collections(parent).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn upload(&self, event: &SyncEvent) -> Result<Response, String> {
+        let file = File::open(&event.local_path).await.map_err(str_err_prefix("Error File Open"))?;
+        let size = file.metadata().await.map_err(str_err_prefix("Error File Metadata"))?.len();
+        let stream = limit_stream(&event.source_id, Direction::Upload, FramedRead::new(file, BytesCodec::new()));
+        let body = Body::wrap_stream(track_progress(&event.source_id, Direction::Upload, &event.sync_path, size, stream));
+        self.execute(Method::PUT, self.get_url(&event.sync_path, false)?, |r| r.header("Content-Length", size).body(body)).await
+    }
+
+    async fn put_file(&self, event: &SyncEvent) -> Result<(), String> {
+        if event.file_type == FileType::Dir {
+            return self.make_collections(&event.sync_path).await;
+        }
+        // 409 when the parent collection is missing, created only then to save requests
+        let mut res = self.upload(event).await?;
+        if res.status() == StatusCode::CONFLICT {
+            self.make_parents(&event.sync_path).await?;
+            res = self.upload(event).await?;
+        }
+        expect_success(res, &[]).await?;
+
+        let res = self.execute(get_method("PROPPATCH"), self.get_url(&event.sync_path, false)?, |r| {
+            r.header("Content-Type", "application/xml; charset=utf-8").body(get_proppatch_body(&event.update_hash))
+        }).await?;
+        expect_success(res, &[]).await.map(|_| ())
+    }
+
+    async fn delete_path(&self, event: &SyncEvent) -> Result<(), String> {
+        let res = self.execute(Method::DELETE, self.get_url(&event.sync_path, event.file_type == FileType::Dir)?, |r| r).await?;
+        expect_success(res, &[StatusCode::NOT_FOUND]).await.map(|_| ())
+    }
+
+    async fn send_move(&self, event: &SyncEvent) -> Result<Response, String> {
+        let is_collection = event.file_type == FileType::Dir;
+        let destination = self.get_url(&event.sync_path, is_collection)?;
+        self.execute(get_method("MOVE"), self.get_url(&event.old_sync_path, is_collection)?, |r| {
+            r.header("Destination", destination.as_str()).header("Overwrite", "T")
+        }).await
+    }
+
+    async fn move_file(&self, event: &SyncEvent) -> Result<(), String> {
+        let mut res = self.send_move(event).await?;
+        if res.status() == StatusCode::CONFLICT {
+            self.make_parents(&event.sync_path).await?;
+            res = self.send_move(event).await?;
+        }
+        expect_success(res, &[]).await.map(|_| ())
+    }
+}
+
+impl RemoteStorage for WebdavStorage {
+    fn list<'a>(&'a self, folder_id: &'a str) -> BoxFuture<'a, Result<Vec<ApiFileResponse>, String>> {
+        self.list_files(folder_id).boxed()
+    }
+
+    fn get<'a>(&'a self, _folder_id: &'a str, path: &'a str) -> BoxFuture<'a, Result<ByteStream, String>> {
+        async move {
+            let res = self.execute(Method::GET, self.get_url(path, false)?, |r| r).await?;
+            let res = expect_success(res, &[]).await?;
+            Ok(res.bytes_stream().map(|chunk| chunk.map_err(|e| e.to_string())).boxed())
+        }.boxed()
+    }
+
+    // Plain WebDAV has no policy to ask, a full server answers the upload itself with 507
+    fn check<'a>(&'a self, _event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<StorageCheck, String>> {
+        async move { Ok(StorageCheck::Accepted) }.boxed()
+    }
+
+    fn put<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.put_file(event).boxed()
+    }
+
+    fn delete<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.delete_path(event).boxed()
+    }
+
+    fn move_path<'a>(&'a self, event: &'a SyncEvent, _sequence: u64) -> BoxFuture<'a, Result<(), String>> {
+        self.move_file(event).boxed()
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use reqwest::StatusCode;
+
+use crate::app::App;
+use crate::constants::{DEFAULT_SHARE_LINK_EXPIRY, SHARE_LINK_EXPIRY_MAX};
+use crate::event::file_event::get_sync_path;
+use crate::helpers::{normalize_path, str_err_prefix};
+use crate::server::api::ApiClient;
+use crate::server::types::ApiShareLinkResponse;
+
+// The link points at what the server has, a local change that isn't uploaded yet isn't part of it
+pub async fn create_share_link(app: &App, local_path: &String, expires_in: Option<u64>) -> Result<ApiShareLinkResponse, String> {
+    let (config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_main().await, config.get_auth().await)
+    };
+    let local_path = normalize_path(&PathBuf::from(local_path));
+    if !local_path.is_file() {
+        return Err(format!("{:?} is not a file", local_path));
+    }
+    let watcher = config.watchers.iter()
+        .find(|w| local_path.starts_with(normalize_path(&PathBuf::from(&w.local_path))))
+        .ok_or(format!("{:?} is not inside a watcher", local_path))?;
+    let sync_path = get_sync_path(&local_path, &normalize_path(&PathBuf::from(&watcher.local_path)));
+    if !watcher.is_included(&sync_path) {
+        return Err(format!("{} is not synced by the watcher at {}", sync_path, &watcher.local_path));
+    }
+    let source = config.sources.get(&watcher.source).ok_or(format!("Unknown source {}", &watcher.source))?;
+    if source.storage.is_some() {
+        return Err(format!("Folder {} is on another server, share links need the Sherry API", &source.id));
+    }
+    let user = auth.records.get(&source.user_id).filter(|u| u.is_usable()).ok_or(format!("{} has to log in again", &source.user_id))?;
+
+    let expires_in = expires_in.unwrap_or(DEFAULT_SHARE_LINK_EXPIRY).clamp(1, SHARE_LINK_EXPIRY_MAX);
+    match ApiClient::new(&config.api_url, &user.access_token).create_share_link(&source.id, &sync_path, expires_in).await {
+        Ok(link) => {
+            log::info!("Created a share link for {} of folder {}, expiring in {}s", &sync_path, &source.id, expires_in);
+            Ok(link)
+        }
+        Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => Err("The server doesn't support share links, or the file isn't uploaded yet".to_string()),
+        Err(e) => Err(str_err_prefix("Error Share Link")(e)),
+    }
+}
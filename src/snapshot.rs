@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::app::App;
+use crate::auth::Credentials;
+use crate::config::{get_hashes_dir, is_overlapping_path, SherryConfigJSON, SherryConfigSourceJSON};
+use crate::event::file_event::get_sync_path;
+use crate::files::write_file_from_stream;
+use crate::hash::has_file_hash;
+use crate::hash_store::list_changes_at;
+use crate::helpers::{canonicalize_sync_path, str_err_prefix, sync_path_to_local};
+use crate::integrity::{download_file, verify_download};
+use crate::server::storage::{get_storage, RemoteStorage};
+use crate::server::types::ApiFileResponse;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotFileJSON {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    // when this machine synced the content, the start of the history for files that didn't change since
+    pub changed_at: i128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderSnapshotJSON {
+    // userId@folderId
+    pub source: String,
+    pub timestamp: i128,
+    // oldest moment the history of the source goes back to
+    pub history_since: i128,
+    pub files: Vec<SnapshotFileJSON>,
+    // directory the files were written into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    // sync path -> why it couldn't be written into the target
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing: BTreeMap<String, String>,
+}
+
+// The history of every watcher of the source, a path synced by several of them has the content synced last
+async fn list_files_at(hashes_dir: &Path, config: &SherryConfigJSON, key: &String, timestamp: i128) -> Result<(i128, Vec<SnapshotFileJSON>), String> {
+    let mut since: Option<i128> = None;
+    let mut files: BTreeMap<String, SnapshotFileJSON> = BTreeMap::new();
+    for watcher in config.watchers.iter().filter(|w| &w.source == key) {
+        let base = PathBuf::from(&watcher.local_path);
+        let (watcher_since, changes) = list_changes_at(hashes_dir, &watcher.hashes_id, timestamp).await?;
+        since = match (since, watcher_since) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        for change in changes.into_iter().filter(|c| Path::new(&c.path).starts_with(&base)) {
+            let path = get_sync_path(Path::new(&change.path), &base);
+            if files.get(&path).is_some_and(|f| f.changed_at > change.timestamp) {
+                continue;
+            }
+            files.insert(path.clone(), SnapshotFileJSON { path, hash: change.hash, size: change.size, changed_at: change.timestamp });
+        }
+    }
+    match since {
+        Some(since) if since <= timestamp => Ok((since, files.into_values().collect())),
+        Some(since) => Err(format!("History of {} only goes back to {}", key, since)),
+        None => Err(format!("No history of {} yet", key)),
+    }
+}
+
+// Written into a new or empty directory of its own, never into a watcher, they would sync the old state
+async fn check_target(dir: &Path, config: &SherryConfigJSON, target: &String) -> Result<PathBuf, String> {
+    let path = PathBuf::from(target);
+    if !path.is_absolute() {
+        return Err(format!("{} is not an absolute path", target));
+    }
+    if is_overlapping_path(target, dir.to_str().unwrap()) {
+        return Err(format!("{} overlaps with the config directory", target));
+    }
+    if let Some(watcher) = config.watchers.iter().find(|w| is_overlapping_path(target, &w.local_path)) {
+        return Err(format!("{} overlaps with the watcher at {}", target, &watcher.local_path));
+    }
+    fs::create_dir_all(&path).await.map_err(str_err_prefix("Error Dir Creation"))?;
+    let mut entries = fs::read_dir(&path).await.map_err(str_err_prefix("Error Dir Read"))?;
+    if entries.next_entry().await.map_err(str_err_prefix("Error Dir Read"))?.is_some() {
+        return Err(format!("{} is not empty", target));
+    }
+    Ok(path)
+}
+
+// A watcher still holding the content is copied, otherwise the server sends the file while it is unchanged, or the
+// version it kept
+async fn write_file(config: &SherryConfigJSON, key: &String, source: &SherryConfigSourceJSON, storage: &dyn RemoteStorage, remote: &HashMap<String, ApiFileResponse>, file: &SnapshotFileJSON, path: &PathBuf) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(str_err_prefix("Error Dir Creation"))?;
+    }
+    for watcher in config.watchers.iter().filter(|w| &w.source == key) {
+        let local_path = sync_path_to_local(Path::new(&watcher.local_path), &file.path)?;
+        if has_file_hash(&local_path, &file.hash).await {
+            return fs::copy(&local_path, path).await.map(|_| ()).map_err(str_err_prefix("Error File Copy"));
+        }
+    }
+    if let Some(r) = remote.get(&file.path).filter(|r| r.hash == file.hash) {
+        return download_file(storage, &source.id, &r.path, path, &file.hash, file.size).await;
+    }
+    let stream = storage.get_version(&source.id, &file.path, &file.hash).await.map_err(str_err_prefix("Error File Download"))?;
+    write_file_from_stream(path, stream).await?;
+    if !verify_download(&source.id, path, &file.hash).await {
+        return Err(format!("Checksum mismatch for {:?}", path));
+    }
+    Ok(())
+}
+
+async fn materialize(config: &SherryConfigJSON, key: &String, source: &SherryConfigSourceJSON, user: &Credentials, target: &Path, files: &Vec<SnapshotFileJSON>) -> BTreeMap<String, String> {
+    let storage = get_storage(&config.api_url, source, user);
+    let remote = match storage.list(&source.id).await {
+        Ok(remote) => remote.into_iter().map(|f| (canonicalize_sync_path(&f.path), f)).collect(),
+        Err(e) => {
+            log::warn!("Failed to list source {}, only earlier versions are downloaded: {}", key, e);
+            HashMap::new()
+        }
+    };
+    let mut missing = BTreeMap::new();
+    for file in files {
+        let res = match sync_path_to_local(target, &file.path) {
+            Ok(path) => write_file(config, key, source, storage.as_ref(), &remote, file, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log::warn!("Failed to restore {} of {}: {}", &file.path, key, &e);
+            missing.insert(file.path.clone(), e);
+        }
+    }
+    missing
+}
+
+// What the folder looked like on this machine at `timestamp`, from the changes recorded by its hash stores. With a target
+// that state is written there, the watchers are left as they are.
+pub async fn get_folder_at(app: &App, source: &String, timestamp: i128, target: &Option<String>) -> Result<FolderSnapshotJSON, String> {
+    let (dir, config, auth) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await)
+    };
+    let (key, source) = config.sources.iter()
+        .find(|(k, s)| *k == source || &s.id == source)
+        .ok_or(format!("Unknown source {}", source))?;
+    let user = auth.records.get(&source.user_id).ok_or(format!("Unknown user {}", source.user_id))?;
+    let (history_since, files) = list_files_at(&get_hashes_dir(&dir, &config), &config, key, timestamp).await?;
+
+    let mut snapshot = FolderSnapshotJSON { source: key.clone(), timestamp, history_since, files, target: None, missing: BTreeMap::new() };
+    if let Some(target) = target {
+        let path = check_target(&dir, &config, target).await?;
+        snapshot.missing = materialize(&config, key, source, user, &path, &snapshot.files).await;
+        snapshot.target = Some(target.clone());
+    }
+    Ok(snapshot)
+}
@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::constants::{PRIMARY_CLAIM_SETTLE, PRIMARY_FILE, PRIMARY_HEARTBEAT_INTERVAL, PRIMARY_TAKEOVER_TIMEOUT};
+use crate::files::{read_json_file, write_json_file_atomic};
+use crate::helpers::{generate_random_id, get_default_state_dir, get_now_as_millis};
+
+// Written by the instance that syncs a config directory. Standbys only compare it with what they read before,
+// so the clocks of the machines sharing the directory don't have to agree.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PrimaryLeaseJSON {
+    pub instance_id: String,
+    pub host: String,
+    pub pid: u32,
+    pub heartbeat: i128,
+}
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+pub fn get_instance_id() -> &'static String {
+    INSTANCE_ID.get_or_init(generate_random_id)
+}
+
+fn get_lease_path(dir: &Path) -> PathBuf {
+    get_default_state_dir(dir).join(PRIMARY_FILE)
+}
+
+async fn read_lease(dir: &Path) -> Option<PrimaryLeaseJSON> {
+    read_json_file(get_lease_path(dir)).await.ok()
+}
+
+async fn write_lease(dir: &Path) -> Result<(), String> {
+    write_json_file_atomic(get_lease_path(dir), &PrimaryLeaseJSON {
+        instance_id: get_instance_id().clone(),
+        host: std::env::var("HOSTNAME").or(std::env::var("COMPUTERNAME")).unwrap_or_default(),
+        pid: std::process::id(),
+        heartbeat: get_now_as_millis(),
+    }).await.map(|_| ())
+}
+
+// Starting without `--standby` takes over right away, an instance still running on the directory steps down
+pub async fn claim_primary(dir: &Path) {
+    if let Err(e) = write_lease(dir).await {
+        log::error!("Failed to write {}: {}", PRIMARY_FILE, e);
+    }
+}
+
+// Returns once the primary stopped sending heartbeats for `PRIMARY_TAKEOVER_TIMEOUT` seconds (or never sent one)
+// and this instance won the claim against other standbys. Journaled events, holds and the hash stores live in the
+// shared directory, so the new primary picks up where the old one stopped.
+pub async fn wait_for_takeover(dir: &Path) {
+    log::info!("Standing by, waiting for the primary to stop");
+    let mut last_seen = read_lease(dir).await;
+    let mut last_change = Instant::now();
+    loop {
+        tokio::time::sleep(Duration::from_secs(PRIMARY_HEARTBEAT_INTERVAL)).await;
+        let lease = read_lease(dir).await;
+        if lease != last_seen {
+            last_seen = lease;
+            last_change = Instant::now();
+            continue;
+        }
+        if last_change.elapsed() < Duration::from_secs(PRIMARY_TAKEOVER_TIMEOUT) {
+            continue;
+        }
+        if let Err(e) = write_lease(dir).await {
+            log::error!("Failed to claim {}: {}", PRIMARY_FILE, e);
+            continue;
+        }
+        tokio::time::sleep(Duration::from_secs(PRIMARY_CLAIM_SETTLE)).await;
+        match read_lease(dir).await {
+            Some(lease) if &lease.instance_id == get_instance_id() => {
+                log::warn!("Primary {} stopped sending heartbeats, taking over", last_seen.map_or("(none)".to_string(), |l| format!("{} on {}", l.pid, l.host)));
+                return;
+            }
+            lease => {
+                last_seen = lease;
+                last_change = Instant::now();
+            }
+        }
+    }
+}
+
+// An instance that finds its lease taken was written off (it hung, or lost the share for too long) and exits before
+// two instances sync the same folders
+pub fn start_primary_heartbeat(dir: &Path) {
+    let dir = dir.to_path_buf();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(PRIMARY_HEARTBEAT_INTERVAL)).await;
+            match read_lease(&dir).await {
+                Some(lease) if &lease.instance_id != get_instance_id() => {
+                    log::error!("Instance {} on {} took over this config directory, stopping", lease.pid, lease.host);
+                    std::process::exit(1);
+                }
+                _ => {}
+            }
+            if let Err(e) = write_lease(&dir).await {
+                log::error!("Failed to write {}: {}", PRIMARY_FILE, e);
+            }
+        }
+    });
+}
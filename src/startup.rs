@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StartupPhase {
+    pub name: String,
+    pub millis: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StartupReport {
+    // in the order they finished
+    pub phases: Vec<StartupPhase>,
+    // source key -> folder settings fetch
+    pub folders: BTreeMap<String, u64>,
+    // watcher local path -> hash validation
+    pub hashing: BTreeMap<String, u64>,
+    // None while the demon is still starting
+    pub total_millis: Option<u64>,
+}
+
+struct StartupState {
+    started: Instant,
+    report: StartupReport,
+}
+
+// Only the first run is recorded, the same code paths run again on every config change
+static STARTUP: std::sync::Mutex<Option<StartupState>> = std::sync::Mutex::new(None);
+
+fn update_report<F: FnOnce(&mut StartupReport)>(update: F) {
+    if let Some(state) = STARTUP.lock().unwrap().as_mut().filter(|s| s.report.total_millis.is_none()) {
+        update(&mut state.report);
+    }
+}
+
+pub fn begin_startup() {
+    *STARTUP.lock().unwrap() = Some(StartupState { started: Instant::now(), report: StartupReport::default() });
+}
+
+pub fn record_phase(name: &str, elapsed: Duration) {
+    update_report(|r| r.phases.push(StartupPhase { name: name.to_string(), millis: elapsed.as_millis() as u64 }));
+}
+
+pub fn record_folder_fetch(source: &str, elapsed: Duration) {
+    update_report(|r| {
+        r.folders.insert(source.to_string(), elapsed.as_millis() as u64);
+    });
+}
+
+pub fn record_hashing(local_path: &str, elapsed: Duration) {
+    update_report(|r| {
+        r.hashing.insert(local_path.to_string(), elapsed.as_millis() as u64);
+    });
+}
+
+// Slowest folders and watchers are logged by name, they are usually what a long startup comes down to
+pub fn finish_startup() {
+    let mut startup = STARTUP.lock().unwrap();
+    let state = match startup.as_mut().filter(|s| s.report.total_millis.is_none()) {
+        Some(state) => state,
+        None => return,
+    };
+    let total = state.started.elapsed().as_millis() as u64;
+    state.report.total_millis = Some(total);
+
+    let report = &state.report;
+    let phases = report.phases.iter().map(|p| format!("{} {}ms", p.name, p.millis)).collect::<Vec<String>>();
+    log::info!("Started in {}ms: {}", total, phases.join(", "));
+    if let Some((source, millis)) = report.folders.iter().max_by_key(|(_, m)| **m) {
+        log::info!("Slowest folder fetch: {} {}ms", source, millis);
+    }
+    if let Some((path, millis)) = report.hashing.iter().max_by_key(|(_, m)| **m) {
+        log::info!("Slowest hash validation: {} {}ms", path, millis);
+    }
+}
+
+pub fn get_startup_report() -> StartupReport {
+    STARTUP.lock().unwrap().as_ref().map(|s| s.report.clone()).unwrap_or_default()
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::auth::Credentials;
+use crate::available::get_available_paths;
+use crate::config::SyncMode;
+use crate::drift::{DriftJSON, get_drifts};
+use crate::event::event_processing::{EventQueueStats, get_event_queue_stats};
+use crate::event::limits::{get_skipped_uploads, SkippedUpload};
+use crate::event::retry_queue::{get_retry_status, RetryStatus};
+use crate::features::get_source_features;
+use crate::governor::is_system_busy;
+use crate::integrity::{get_integrity_stats, IntegrityStats};
+use crate::messages::{MessageCode, UserMessage};
+use crate::server::metrics::{ApiEndpointStats, get_api_stats};
+use crate::server::scheduler::{get_transfer_stats, TransferStats};
+use crate::startup::{get_startup_report, StartupReport};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatcherStatus {
+    pub local_path: String,
+    pub source: String,
+    pub complete: bool,
+    pub mode: SyncMode,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_paths: Vec<String>,
+    // remote paths left out by include paths, summarized by their topmost excluded parent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub available_paths: Vec<String>,
+    #[serde(default)]
+    pub available_files: usize,
+    // the user's login expired, the watcher is paused until `user login`
+    #[serde(default)]
+    pub needs_reauth: bool,
+    // the user's credentials are broken, the watcher is suspended until they are fixed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended: Option<String>,
+    // AUTH_EXPIRED or AUTH_INVALID, for GUIs that show their own texts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<UserMessage>,
+    // feature name -> whether it is on for the watcher's folder
+    #[serde(default)]
+    pub features: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusReport {
+    pub config_dir: PathBuf,
+    pub config_valid: bool,
+    // file name -> error
+    pub config_errors: HashMap<String, String>,
+    pub socket_connected: bool,
+    // users with a live socket connection
+    pub socket_users: Vec<String>,
+    // paths with remote changes being applied
+    pub remote_queue_paths: usize,
+    pub watchers: Vec<WatcherStatus>,
+    // source id -> downloads checked against their checksum
+    pub integrity: BTreeMap<String, IntegrityStats>,
+    pub transfers: TransferStats,
+    // "METHOD /path/:param" -> latency and errors of API requests
+    pub api: BTreeMap<String, ApiEndpointStats>,
+    // failed uploads waiting for their next attempt
+    pub retries: Vec<RetryStatus>,
+    // source id -> changes waiting to be batched
+    pub event_queues: BTreeMap<String, EventQueueStats>,
+    // hashing and transfers are held back until the load governor sees the machine calm down
+    pub system_busy: bool,
+    // how long each phase of the startup took
+    pub startup: StartupReport,
+    // source id -> uploads left out because they exceed the folder's limits
+    #[serde(default)]
+    pub skipped_uploads: BTreeMap<String, Vec<SkippedUpload>>,
+    // paths the hash store was found to disagree with, and how they were repaired
+    #[serde(default)]
+    pub drift: Vec<DriftJSON>,
+}
+
+fn get_auth_messages(user: &Credentials) -> Vec<UserMessage> {
+    let mut messages = vec![];
+    if user.expired {
+        messages.push(UserMessage::new(MessageCode::AuthExpired, &[("user", &user.username)]));
+    }
+    if let Some(reason) = user.get_invalid_reason() {
+        messages.push(UserMessage::new(MessageCode::AuthInvalid, &[("user", &user.user_id), ("reason", reason)]));
+    }
+    messages
+}
+
+pub async fn get_status(app: &App) -> StatusReport {
+    let (dir, config, auth, errors) = {
+        let config = app.config.lock().await;
+        (config.get_path(), config.get_main().await, config.get_auth().await, config.get_errors().await)
+    };
+    // The socket mutex is held for the whole reconnection, so a busy lock means we are offline
+    let (socket_connected, socket_users, remote_queue_paths) = match app.socket.try_lock() {
+        Ok(socket) => (socket.is_up().await, socket.get_connected_users().await, socket.queue.len()),
+        Err(_) => (false, vec![], 0),
+    };
+
+    StatusReport {
+        config_dir: dir,
+        config_valid: errors.is_empty(),
+        config_errors: errors,
+        socket_connected,
+        socket_users,
+        remote_queue_paths,
+        watchers: config.watchers.iter().map(|w| {
+            let (available_paths, available_files) = get_available_paths(w);
+            WatcherStatus {
+                local_path: w.local_path.clone(),
+                source: w.source.clone(),
+                complete: w.complete,
+                mode: w.mode,
+                include_paths: w.include_paths.clone(),
+                available_paths,
+                available_files,
+                needs_reauth: auth.records.get(&w.user_id).is_some_and(|u| u.expired),
+                suspended: auth.records.get(&w.user_id).and_then(|u| u.get_invalid_reason()).map(|r| r.to_string()),
+                messages: auth.records.get(&w.user_id).map_or(vec![], get_auth_messages),
+                features: get_source_features(&w.source),
+            }
+        }).collect(),
+        integrity: get_integrity_stats(),
+        transfers: get_transfer_stats(),
+        api: get_api_stats(),
+        retries: get_retry_status(),
+        event_queues: get_event_queue_stats(),
+        system_busy: is_system_busy(),
+        startup: get_startup_report(),
+        skipped_uploads: get_skipped_uploads(),
+        drift: get_drifts(),
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use tokio::fs;
+
+use crate::config::SherryConfigTemplateJSON;
+use crate::helpers::str_err_prefix;
+
+// Existing files are never overwritten, so applying a template to a non-empty directory is safe
+pub async fn apply_template(template: &SherryConfigTemplateJSON, local_path: &String) -> Result<(), String> {
+    let base = PathBuf::from(local_path);
+    for folder in &template.folders {
+        fs::create_dir_all(base.join(folder)).await.map_err(str_err_prefix("Error Template Folder Create"))?;
+    }
+    for (path, seed) in &template.files {
+        let target = base.join(path);
+        if target.exists() {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await.map_err(str_err_prefix("Error Template Folder Create"))?;
+        }
+        fs::copy(seed, &target).await.map_err(str_err_prefix(format!("Error Template File Copy {}", seed)))?;
+    }
+    Ok(())
+}
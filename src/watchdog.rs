@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::SherryConfigWatchdogJSON;
+
+struct RunState {
+    name: String,
+    started: Instant,
+    stage: String,
+    current_file: String,
+    current_size: u64,
+    done_files: u64,
+    done_bytes: u64,
+}
+
+static WATCHDOG: std::sync::Mutex<Option<SherryConfigWatchdogJSON>> = std::sync::Mutex::new(None);
+// run id -> progress of an event batch or watcher fetch
+static RUNS: std::sync::Mutex<BTreeMap<u64, RunState>> = std::sync::Mutex::new(BTreeMap::new());
+static NEXT_RUN: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static RUN_ID: u64;
+}
+
+// Removes the run even when the watched future is dropped halfway
+struct RunGuard(u64);
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNS.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn set_watchdog(watchdog: &Option<SherryConfigWatchdogJSON>) {
+    *WATCHDOG.lock().unwrap() = watchdog.clone();
+}
+
+// Progress is reported from inside a watched run, calls outside of one are ignored
+fn update_run<F: FnOnce(&mut RunState)>(update: F) {
+    if let Ok(id) = RUN_ID.try_with(|id| *id) {
+        if let Some(run) = RUNS.lock().unwrap().get_mut(&id) {
+            update(run);
+        }
+    }
+}
+
+pub fn set_stage(stage: &str) {
+    update_run(|run| run.stage = stage.to_string());
+}
+
+pub fn start_file(path: &str, size: u64) {
+    update_run(|run| {
+        run.current_file = path.to_string();
+        run.current_size = size;
+    });
+}
+
+pub fn finish_file(size: u64) {
+    update_run(|run| {
+        run.done_files += 1;
+        run.done_bytes += size;
+    });
+}
+
+fn log_stall(id: u64) {
+    if let Some(run) = RUNS.lock().unwrap().get(&id) {
+        log::error!(
+            "{} is stalled: running for {}s, stage {}, current file {:?} ({} bytes), {} files ({} bytes) done",
+            run.name, run.started.elapsed().as_secs(), run.stage, run.current_file, run.current_size, run.done_files, run.done_bytes,
+        );
+    }
+}
+
+// Err when the run exceeded the budget and was aborted
+pub async fn watch<F: Future>(name: String, run: F) -> Result<F::Output, String> {
+    let id = NEXT_RUN.fetch_add(1, Ordering::SeqCst);
+    RUNS.lock().unwrap().insert(id, RunState {
+        name: name.clone(),
+        started: Instant::now(),
+        stage: "starting".to_string(),
+        current_file: "".to_string(),
+        current_size: 0,
+        done_files: 0,
+        done_bytes: 0,
+    });
+    let _guard = RunGuard(id);
+
+    RUN_ID.scope(id, async {
+        tokio::pin!(run);
+        loop {
+            let watchdog = WATCHDOG.lock().unwrap().clone();
+            let watchdog = match watchdog {
+                Some(watchdog) => watchdog,
+                None => return Ok(run.await),
+            };
+            tokio::select! {
+                res = &mut run => return Ok(res),
+                _ = tokio::time::sleep(Duration::from_secs(watchdog.budget.max(1))) => {
+                    log_stall(id);
+                    if watchdog.abort {
+                        return Err(format!("{} aborted after exceeding the {}s budget", name, watchdog.budget));
+                    }
+                }
+            }
+        }
+    }).await
+}
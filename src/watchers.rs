@@ -1,18 +1,63 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use futures::future;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
+use crate::app::App;
 use crate::auth::Credentials;
-use crate::config::{SherryConfigJSON, SherryConfigSourceJSON, SherryConfigWatcherJSON};
-use crate::event::file_event::{FileType, get_sync_path, SyncEvent, SyncEventKind};
-use crate::files::{delete_path, write_file_from_stream};
-use crate::hash::{FileHashJSON, recreate_hashes, update_hashes};
-use crate::helpers::normalize_path;
-use crate::server::api::ApiClient;
+use crate::available::set_available_paths;
+use crate::config::{get_hashes_dir, SherryConfigJSON, SherryConfigSourceJSON, SherryConfigWatcherJSON};
+use crate::constants::{FETCHED_CHANGES_TICK, STORAGE_POLL_INTERVAL};
+use crate::event::event_processing::submit_events;
+use crate::event::file_event::{get_file_event, get_sync_path, SyncEvent, SyncEventKind};
+use crate::event::limits::set_remote_usage;
+use crate::files::{apply_file_attributes, delete_path, set_file_created};
+use crate::hash::{FileHashJSON, get_hashes, get_modified_millis, has_file_hash, is_in_unchanged_dir, revalidate_hashes, roll_up_directories, update_hashes};
+use crate::helpers::{canonicalize_sync_path, normalize_path, str_err_prefix, sync_path_to_local};
+use crate::integrity::{download_file, download_variant};
+use crate::self_writes::with_self_writes;
+use crate::server::storage::get_storage;
 use crate::server::types::ApiFileResponse;
+use crate::startup::record_hashing;
+use crate::watchdog::{finish_file, set_stage, start_file, watch};
 
-pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watcher: &SherryConfigWatcherJSON, source: &SherryConfigSourceJSON, user: &Credentials) -> (SherryConfigWatcherJSON, Result<(), String>) {
+// source key -> local changes found by fetches. Fetches of the config revalidation run before the app exists, so they
+// are sent by `start_fetched_changes`.
+static FETCHED_CHANGES: std::sync::Mutex<BTreeMap<String, Vec<SyncEvent>>> = std::sync::Mutex::new(BTreeMap::new());
+
+fn queue_fetched_changes(source: &String, events: Vec<SyncEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    log::info!("{} local changes of source {} found by a fetch", events.len(), source);
+    FETCHED_CHANGES.lock().unwrap().entry(source.clone()).or_default().extend(events);
+}
+
+pub fn take_fetched_changes(source: &String) -> Vec<SyncEvent> {
+    FETCHED_CHANGES.lock().unwrap().remove(source).unwrap_or_default()
+}
+
+pub fn count_fetched_changes(source: &String) -> usize {
+    FETCHED_CHANGES.lock().unwrap().get(source).map_or(0, |e| e.len())
+}
+
+pub fn start_fetched_changes(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(FETCHED_CHANGES_TICK)).await;
+            let fetched = std::mem::take(&mut *FETCHED_CHANGES.lock().unwrap());
+            for (source, events) in fetched {
+                submit_events(app.clone(), &source, events).await;
+            }
+        }
+    });
+}
+
+pub async fn fetch_watcher_files(hashes_dir: &PathBuf, config: &SherryConfigJSON, watcher: &SherryConfigWatcherJSON, source: &SherryConfigSourceJSON, user: &Credentials) -> (SherryConfigWatcherJSON, Result<(), String>) {
     log::info!("Fetching watcher files for {}, {}, {}", &watcher.local_path, &user.user_id, &source.id);
 
     let path = Path::new(&watcher.local_path);
@@ -20,18 +65,46 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
         return (watcher.clone(), Err("Folder not exist or deleted".to_string()));
     }
 
-    let client = ApiClient::new(&config.api_url, &user.access_token);
+    let storage = get_storage(&config.api_url, source, user);
 
     let watcher_path = PathBuf::from(&watcher.local_path);
 
-    let mut local_hashes = match recreate_hashes(dir, &watcher.hashes_id, source, &watcher_path).await {
+    set_stage("hashing");
+    let started = Instant::now();
+    let mut local_hashes = match revalidate_hashes(hashes_dir, &watcher.hashes_id, source, &watcher_path, config.change_journal.unwrap_or(false)).await {
         Ok(h) => h,
         Err(e) => return (watcher.clone(), Err(e.to_string()))
     };
-    let mut remote_hashes = match client.get_folder_files(&source.id).await {
-        Ok(h) => h,
+    record_hashing(&watcher.local_path, started.elapsed());
+    set_stage("listing remote files");
+    let (mut remote_hashes, available) = match storage.list(&source.id).await {
+        Ok(h) => {
+            set_remote_usage(&source.id, &h);
+            h.into_iter()
+                .map(|f| ApiFileResponse { path: canonicalize_sync_path(&f.path), ..f })
+                .partition::<Vec<ApiFileResponse>, _>(|f| watcher.is_included(&f.path))
+        }
         Err(e) => return (watcher.clone(), Err(e.to_string())),
     };
+    // Subtrees both sides agree on are left out of the comparison. Permissions aren't part of the directory hashes,
+    // sources syncing them compare every file, as do watchers holding variants they don't ask for anymore.
+    let variant = source.get_download_variant(watcher.mode);
+    let is_variant_changed = local_hashes.hashes.values().any(|h| h.variant.is_some() && h.variant.as_ref() != variant);
+    let unchanged = if source.sync_permissions || is_variant_changed {
+        HashSet::new()
+    } else {
+        roll_up_directories(remote_hashes.iter().chain(available.iter()).map(|f| (f.path.clone(), f.hash.clone())))
+            .into_iter()
+            .filter(|(dir, hash)| local_hashes.directories.get(dir) == Some(hash))
+            .map(|(dir, _)| dir)
+            .collect::<HashSet<String>>()
+    };
+    set_available_paths(watcher, available.into_iter().map(|f| f.path).collect());
+    let remote_count = remote_hashes.len();
+    remote_hashes.retain(|f| f.hash.is_empty() || !is_in_unchanged_dir(&f.path, &unchanged));
+    if remote_count > remote_hashes.len() {
+        log::info!("Skipping {} files of {} in unchanged directories", remote_count - remote_hashes.len(), &watcher.local_path);
+    }
 
     let mut to_download = vec![];
     let mut to_delete = vec![];
@@ -40,9 +113,22 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
     for (local_path, hash) in local_hashes.hashes.iter() {
         let local_path = PathBuf::from(&local_path);
         let sync_path = get_sync_path(&local_path, &watcher_path);
+        if !watcher.is_included(&sync_path) || (!hash.hash.is_empty() && is_in_unchanged_dir(&sync_path, &unchanged)) {
+            continue;
+        }
         if let Some(index) = remote_hashes.iter().position(|f| f.path == sync_path) {
             let remote = remote_hashes.swap_remove(index);
+            // Variants no longer asked for (e.g. the watcher uploads now) are replaced, they must never be uploaded
+            if remote.hash == hash.hash && hash.variant.is_some() && hash.variant.as_ref() != variant {
+                to_download.push((local_path, sync_path, remote));
+                continue;
+            }
             if remote.hash == hash.hash {
+                // Permissions changed elsewhere while stopped
+                let is_newer = remote.updated_at > hash.timestamp && remote.attributes.is_some() && remote.attributes != hash.attributes;
+                if source.sync_permissions && is_newer {
+                    to_sync.push((Some(remote), SyncEventKind::Updated, normalize_path(&local_path).to_str().unwrap().to_string()));
+                }
                 continue;
             }
 
@@ -64,51 +150,63 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
         }
     }
     for remote in remote_hashes {
-        to_download.push((watcher_path.join(PathBuf::from(&remote.path)), remote.path.clone(), remote.clone()))
+        match sync_path_to_local(&watcher_path, &remote.path) {
+            Ok(local_path) => to_download.push((local_path, remote.path.clone(), remote.clone())),
+            Err(e) => log::error!("Skipping download to {}: {}", &watcher.local_path, e),
+        }
+    }
+
+    if !watcher.mode.can_download() {
+        to_download.clear();
+        to_delete.clear();
+    }
+    if !watcher.mode.can_upload() || !source.can_upload() {
+        to_upload.clear();
     }
 
+    set_stage("downloading");
+    // local path -> variant downloaded instead of the original
+    let mut variants = HashMap::new();
     futures::future::join_all(to_download.iter().map(|(local_path, sync_path, hash)| {
         log::info!("Downloading to {}", &local_path.to_str().unwrap());
-        let client = client.clone();
+        let storage = storage.clone();
         async move {
-            match client.get_file(&source.id, &sync_path).await {
-                Ok(res) => match write_file_from_stream(&local_path, res.bytes_stream()).await {
-                    Ok(_) => Some((hash.clone(), normalize_path(&local_path).to_str().unwrap().to_string())),
-                    Err(_) => None
+            if has_file_hash(local_path, &hash.hash).await {
+                log::info!("Skipping download to {}, local content is identical", &local_path.to_str().unwrap());
+                return Some((hash.clone(), normalize_path(local_path).to_str().unwrap().to_string(), None));
+            }
+            start_file(sync_path, hash.size);
+            let res = download_variant(storage.as_ref(), &source.id, sync_path, local_path, &hash.hash, hash.size, variant).await;
+            finish_file(hash.size);
+            match res {
+                Ok(variant) => {
+                    set_file_created(local_path, hash.created_at).ok();
+                    Some((hash.clone(), normalize_path(local_path).to_str().unwrap().to_string(), variant))
                 }
                 Err(_) => None
             }
         }
     })).await.iter().for_each(|to_update| {
         match to_update {
-            Some((hash, path)) => to_sync.push((Some(hash.clone()), SyncEventKind::Updated, path.clone())),
+            Some((hash, path, variant)) => {
+                if let Some(variant) = variant {
+                    variants.insert(path.clone(), variant.clone());
+                }
+                to_sync.push((Some(hash.clone()), SyncEventKind::Updated, path.clone()))
+            }
             None => {}
         }
     });
 
-    futures::future::join_all(to_upload.iter().map(|(local_path, sync_path, hash, kind)| {
-        let client = client.clone();
-        let watcher_path = watcher_path.clone();
-        async move {
-            client.send_file(&SyncEvent {
-                source_id: source.id.clone(),
-                base: watcher_path.clone(),
-                file_type: FileType::File,
-                kind: kind.clone(),
-                local_path: local_path.clone(),
-                old_local_path: local_path.clone(),
-                sync_path: sync_path.clone(),
-                old_sync_path: sync_path.clone(),
-                update_hash: hash.hash.clone(),
-                size: local_path.metadata().unwrap().len(),
-                timestamp: hash.timestamp,
-            }).await.ok()
-        }
-    })).await;
+    // Sent through the event pipeline like any other local change, so holds, quarantine, limits and retries apply
+    let uploads = to_upload.iter()
+        .map(|(local_path, _, _, kind)| get_file_event(&source.id, &watcher_path, local_path, *kind))
+        .collect::<Vec<SyncEvent>>();
 
+    set_stage("deleting");
     futures::future::join_all(to_delete.iter().map(|(local_path, sync_path, hash)| {
         async move {
-            match delete_path(&local_path).await {
+            match with_self_writes(&vec![local_path.clone()], "", delete_path(local_path)).await {
                 Ok(_) => Some((hash.clone(), normalize_path(&local_path).to_str().unwrap().to_string())),
                 Err(_) => None
             }
@@ -124,10 +222,17 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
         match kind {
             SyncEventKind::Created | SyncEventKind::Updated => {
                 let remote = remote.unwrap();
+                let attributes = if source.sync_permissions { apply_file_attributes(Path::new(&key), &remote.attributes) } else { None };
+                let modified = get_modified_millis(Path::new(&key));
+                let variant = variants.remove(&key);
+                let size = if variant.is_some() { Path::new(&key).metadata().map_or(0, |m| m.len()) } else { remote.size };
                 local_hashes.hashes.insert(key, FileHashJSON {
                     hash: remote.hash.clone(),
                     timestamp: remote.updated_at,
-                    size: remote.size,
+                    size,
+                    modified,
+                    attributes,
+                    variant,
                 });
             }
             SyncEventKind::Deleted => {
@@ -137,7 +242,12 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
         }
     }
 
-    update_hashes(dir, &local_hashes).await.ok();
+    // Out of the store until they are sent, otherwise `send_events` skips them as already synced
+    for e in uploads.iter() {
+        local_hashes.hashes.remove(e.local_path.to_str().unwrap());
+    }
+    update_hashes(hashes_dir, &local_hashes).await.ok();
+    queue_fetched_changes(&watcher.source, uploads);
 
     (
         SherryConfigWatcherJSON {
@@ -148,13 +258,91 @@ pub async fn fetch_watcher_files(dir: &PathBuf, config: &SherryConfigJSON, watch
     )
 }
 
+// Downloads a single remote file or subtree right away, regardless of the watcher's include paths and mode
+pub async fn fetch_watcher_path(hashes_dir: &PathBuf, config: &SherryConfigJSON, watcher: &SherryConfigWatcherJSON, source: &SherryConfigSourceJSON, user: &Credentials, sync_path: &String) -> Result<Vec<String>, String> {
+    log::info!("Fetching {} for watcher {}", sync_path, &watcher.local_path);
+
+    let storage = get_storage(&config.api_url, source, user);
+    let watcher_path = PathBuf::from(&watcher.local_path);
+    let sync_path = canonicalize_sync_path(sync_path);
+    let subtree_prefix = format!("{}/", &sync_path);
+
+    let remote_files = storage.list(&source.id).await.map_err(str_err_prefix("Error Folder Files Fetch"))?.into_iter()
+        .map(|f| ApiFileResponse { path: canonicalize_sync_path(&f.path), ..f })
+        .filter(|f| !f.hash.is_empty() && (sync_path.is_empty() || f.path == sync_path || f.path.starts_with(&subtree_prefix)))
+        .collect::<Vec<ApiFileResponse>>();
+    if remote_files.is_empty() {
+        return Err(format!("Nothing to fetch at {}", sync_path));
+    }
+
+    let fetched = future::join_all(remote_files.iter().map(|remote| {
+        let storage = storage.clone();
+        let local_path = sync_path_to_local(&watcher_path, &remote.path);
+        async move {
+            let local_path = local_path.map_err(|e| log::error!("Skipping fetch to {}: {}", &watcher.local_path, e)).ok()?;
+            if !has_file_hash(&local_path, &remote.hash).await {
+                download_file(storage.as_ref(), &source.id, &remote.path, &local_path, &remote.hash, remote.size).await.ok()?;
+                set_file_created(&local_path, remote.created_at).ok();
+            }
+            Some((normalize_path(&local_path).to_str().unwrap().to_string(), FileHashJSON {
+                hash: remote.hash.clone(),
+                timestamp: remote.updated_at,
+                size: remote.size,
+                modified: get_modified_millis(&local_path),
+                attributes: if source.sync_permissions { apply_file_attributes(&local_path, &remote.attributes) } else { None },
+                variant: None,
+            }))
+        }
+    })).await.into_iter().flatten().collect::<Vec<(String, FileHashJSON)>>();
+
+    let mut hashes = get_hashes(hashes_dir, source, &watcher_path, &watcher.hashes_id).await?;
+    let paths = fetched.iter().map(|(path, _)| path.clone()).collect();
+    hashes.hashes.extend(fetched);
+    update_hashes(hashes_dir, &hashes).await?;
+
+    Ok(paths)
+}
+
+// Sync paths of a watcher, by where they are
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatcherDiff {
+    pub local_path: String,
+    pub local_only: Vec<String>,
+    pub remote_only: Vec<String>,
+    pub differing: Vec<String>,
+}
+
+// Compares the hash store with the remote listing without hashing or transferring anything, so it reflects what the
+// next sync would start from. Deleted entries on either side count as missing.
+pub async fn diff_watcher(hashes_dir: &PathBuf, config: &SherryConfigJSON, watcher: &SherryConfigWatcherJSON, source: &SherryConfigSourceJSON, user: &Credentials) -> Result<WatcherDiff, String> {
+    let watcher_path = PathBuf::from(&watcher.local_path);
+    let local = get_hashes(hashes_dir, source, &watcher_path, &watcher.hashes_id).await?.hashes.into_iter()
+        .filter(|(_, h)| !h.hash.is_empty())
+        .map(|(path, h)| (get_sync_path(&PathBuf::from(path), &watcher_path), h.hash))
+        .filter(|(path, _)| watcher.is_included(path))
+        .collect::<BTreeMap<String, String>>();
+    let remote = get_storage(&config.api_url, source, user).list(&source.id).await.map_err(str_err_prefix("Error Folder Files Fetch"))?.into_iter()
+        .filter(|f| !f.hash.is_empty())
+        .map(|f| (canonicalize_sync_path(&f.path), f.hash))
+        .filter(|(path, _)| watcher.is_included(path))
+        .collect::<BTreeMap<String, String>>();
+
+    Ok(WatcherDiff {
+        local_path: watcher.local_path.clone(),
+        local_only: local.keys().filter(|p| !remote.contains_key(*p)).cloned().collect(),
+        remote_only: remote.keys().filter(|p| !local.contains_key(*p)).cloned().collect(),
+        differing: local.iter().filter(|(p, h)| remote.get(*p).is_some_and(|r| r != *h)).map(|(p, _)| p.clone()).collect(),
+    })
+}
+
 pub struct ActualizedWatcherMeta {
     pub invalid_watchers: Vec<SherryConfigWatcherJSON>,
     pub valid_watchers: Vec<SherryConfigWatcherJSON>,
 }
 
 pub async fn actualize_watchers(
-    dir: &PathBuf,
+    hashes_dir: &PathBuf,
     config: &SherryConfigJSON,
     users: &HashMap<String, Credentials>,
     sources: &HashMap<String, SherryConfigSourceJSON>,
@@ -167,7 +355,16 @@ pub async fn actualize_watchers(
     for w in watchers {
         if let Some(user) = users.get(&w.user_id) {
             if let Some(source) = sources.get(&w.source) {
-                futures.push(fetch_watcher_files(dir, config, w, source, user));
+                futures.push(async move {
+                    match watch(format!("Fetch of watcher {}", &w.local_path), fetch_watcher_files(hashes_dir, config, w, source, user)).await {
+                        Ok(res) => res,
+                        // Kept incomplete instead of invalid, so it is fetched again on the next revalidation
+                        Err(e) => {
+                            log::error!("{}", e);
+                            (SherryConfigWatcherJSON { complete: false, ..w.clone() }, Ok(()))
+                        }
+                    }
+                });
             } else {
                 invalid_watchers.push(w.clone());
             }
@@ -188,3 +385,29 @@ pub async fn actualize_watchers(
 
     ActualizedWatcherMeta { invalid_watchers, valid_watchers }
 }
+
+// Only the Sherry API pushes remote changes, folders on other servers are fetched again on a timer.
+// The fetch only touches the hash store, so the config is left alone.
+pub fn start_storage_polling(app: &App) {
+    let app = app.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(STORAGE_POLL_INTERVAL)).await;
+            let (dir, config, auth) = {
+                let config = app.config.lock().await;
+                (config.get_path(), config.get_main().await, config.get_auth().await)
+            };
+            let hashes_dir = get_hashes_dir(&dir, &config);
+            for w in config.watchers.iter().filter(|w| w.complete) {
+                let (source, user) = match (config.sources.get(&w.source), auth.records.get(&w.user_id)) {
+                    (Some(source), Some(user)) if source.storage.is_some() && user.is_usable() => (source, user),
+                    _ => continue,
+                };
+                match watch(format!("Poll of watcher {}", &w.local_path), fetch_watcher_files(&hashes_dir, &config, w, source, user)).await {
+                    Ok((_, Err(e))) | Err(e) => log::error!("Failed to poll watcher {}: {}", &w.local_path, e),
+                    Ok(_) => {}
+                }
+            }
+        }
+    });
+}